@@ -9,7 +9,14 @@
 extern crate solana_rbpf;
 extern crate test;
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 use solana_rbpf::{
     ebpf,
     memory_region::MemoryRegion,
@@ -48,7 +55,14 @@ fn bench_init_interpreter_start(bencher: &mut Bencher) {
     });
 }
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 #[bench]
 fn bench_init_jit_start(bencher: &mut Bencher) {
     let mut file = File::open("tests/elfs/rodata_section_sbpfv0.so").unwrap();
@@ -75,7 +89,14 @@ fn bench_init_jit_start(bencher: &mut Bencher) {
     });
 }
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 fn bench_jit_vs_interpreter(
     bencher: &mut Bencher,
     assembly: &str,
@@ -134,7 +155,14 @@ fn bench_jit_vs_interpreter(
     );
 }
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 #[bench]
 fn bench_jit_vs_interpreter_address_translation(bencher: &mut Bencher) {
     bench_jit_vs_interpreter(
@@ -152,7 +180,14 @@ fn bench_jit_vs_interpreter_address_translation(bencher: &mut Bencher) {
     );
 }
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 static ADDRESS_TRANSLATION_STACK_CODE: &str = "
     mov r1, r2
     and r1, 4095
@@ -164,7 +199,14 @@ static ADDRESS_TRANSLATION_STACK_CODE: &str = "
     jlt r2, 0x10000, -8
     exit";
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 #[bench]
 fn bench_jit_vs_interpreter_address_translation_stack_fixed(bencher: &mut Bencher) {
     bench_jit_vs_interpreter(
@@ -179,7 +221,14 @@ fn bench_jit_vs_interpreter_address_translation_stack_fixed(bencher: &mut Benche
     );
 }
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 #[bench]
 fn bench_jit_vs_interpreter_address_translation_stack_dynamic(bencher: &mut Bencher) {
     bench_jit_vs_interpreter(
@@ -191,7 +240,14 @@ fn bench_jit_vs_interpreter_address_translation_stack_dynamic(bencher: &mut Benc
     );
 }
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 #[bench]
 fn bench_jit_vs_interpreter_empty_for_loop(bencher: &mut Bencher) {
     bench_jit_vs_interpreter(
@@ -208,7 +264,14 @@ fn bench_jit_vs_interpreter_empty_for_loop(bencher: &mut Bencher) {
     );
 }
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 #[bench]
 fn bench_jit_vs_interpreter_call_depth_fixed(bencher: &mut Bencher) {
     bench_jit_vs_interpreter(
@@ -238,7 +301,14 @@ fn bench_jit_vs_interpreter_call_depth_fixed(bencher: &mut Bencher) {
     );
 }
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 #[bench]
 fn bench_jit_vs_interpreter_call_depth_dynamic(bencher: &mut Bencher) {
     bench_jit_vs_interpreter(
@@ -0,0 +1,15 @@
+use std::{env, process::Command};
+
+fn main() {
+    // The version of the compiler is part of the JIT cache key, as it determines the layout of the
+    // structs which the machine code accesses
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=RBPF_RUSTC_VERSION={}", version.trim());
+    println!("cargo:rerun-if-env-changed=RUSTC");
+}
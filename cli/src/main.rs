@@ -4,6 +4,7 @@ use solana_rbpf::{
     assembler::assemble,
     ebpf,
     elf::Executable,
+    error::ProgramResult,
     memory_region::{MemoryMapping, MemoryRegion},
     program::{BuiltinProgram, FunctionRegistry},
     static_analysis::Analysis,
@@ -59,7 +60,14 @@ fn main() {
                 .short('u')
                 .long("use")
                 .takes_value(true)
-                .possible_values(&["cfg", "debugger", "disassembler", "interpreter", "jit"])
+                .possible_values(&[
+                    "cfg",
+                    "debugger",
+                    "disassembler",
+                    "interpreter",
+                    "jit",
+                    "jit-listing",
+                ])
                 .required(true),
         )
         .arg(
@@ -131,8 +139,11 @@ fn main() {
             memory
         }
     };
-    #[cfg(all(not(target_os = "windows"), target_arch = "x86_64"))]
-    if matches.value_of("use") == Some("jit") {
+    #[cfg(all(
+        not(target_os = "windows"),
+        any(target_arch = "x86_64", all(target_arch = "aarch64", feature = "jit-aarch64"))
+    ))]
+    if matches.value_of("use") == Some("jit") || matches.value_of("use") == Some("jit-listing") {
         executable.jit_compile().unwrap();
     }
     let mut context_object = TestContextObject::new(
@@ -180,6 +191,7 @@ fn main() {
 
     let analysis = if matches.value_of("use") == Some("cfg")
         || matches.value_of("use") == Some("disassembler")
+        || matches.value_of("use") == Some("jit-listing")
         || matches.is_present("trace")
         || matches.is_present("profile")
     {
@@ -206,6 +218,19 @@ fn main() {
                 .unwrap();
             return;
         }
+        #[cfg(all(
+            not(target_os = "windows"),
+            any(target_arch = "x86_64", all(target_arch = "aarch64", feature = "jit-aarch64"))
+        ))]
+        Some("jit-listing") => {
+            let stdout = std::io::stdout();
+            analysis
+                .as_ref()
+                .unwrap()
+                .disassemble_jit(&mut stdout.lock())
+                .unwrap();
+            return;
+        }
         _ => {}
     }
 
@@ -215,6 +240,11 @@ fn main() {
     let (instruction_count, result) =
         vm.execute_program(&executable, matches.value_of("use").unwrap() != "jit");
     println!("Result: {result:?}");
+    if let ProgramResult::Err(error) = &result {
+        if let Some(fault_location) = error.fault_location() {
+            println!("Fault Location: {fault_location}");
+        }
+    }
     println!("Instruction Count: {instruction_count}");
     if matches.is_present("trace") {
         println!("Trace:\n");
@@ -222,7 +252,11 @@ fn main() {
         analysis
             .as_ref()
             .unwrap()
-            .disassemble_trace_log(&mut stdout.lock(), &vm.context_object_pointer.trace_log)
+            .disassemble_trace_log_with_events(
+                &mut stdout.lock(),
+                &vm.context_object_pointer.trace_log,
+                &vm.context_object_pointer.event_log,
+            )
             .unwrap();
     }
     if matches.is_present("profile") {
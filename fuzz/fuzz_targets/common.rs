@@ -2,7 +2,7 @@ use std::mem::size_of;
 
 use arbitrary::{Arbitrary, Unstructured};
 
-use solana_rbpf::vm::Config;
+use solana_rbpf::vm::{Config, InstructionCosts};
 
 #[derive(Debug)]
 pub struct ConfigTemplate {
@@ -13,6 +13,9 @@ pub struct ConfigTemplate {
     enable_symbol_and_section_labels: bool,
     sanitize_user_provided_values: bool,
     optimize_rodata: bool,
+    eliminate_redundant_bounds_checks: bool,
+    enable_exact_instruction_meter: bool,
+    instruction_costs: Option<([u8; 8], [(u8, u8); 4])>,
 }
 
 impl<'a> Arbitrary<'a> for ConfigTemplate {
@@ -26,6 +29,14 @@ impl<'a> Arbitrary<'a> for ConfigTemplate {
             enable_symbol_and_section_labels: bools & (1 << 1) != 0,
             sanitize_user_provided_values: bools & (1 << 3) != 0,
             optimize_rodata: bools & (1 << 9) != 0,
+            eliminate_redundant_bounds_checks: bools & (1 << 10) != 0,
+            enable_exact_instruction_meter: bools & (1 << 11) != 0,
+            // Costs per instruction class and for some single opcodes
+            instruction_costs: if bools & (1 << 12) != 0 {
+                Some(Arbitrary::arbitrary(u)?)
+            } else {
+                None
+            },
         })
     }
 
@@ -48,6 +59,9 @@ impl From<ConfigTemplate> for Config {
                 enable_symbol_and_section_labels,
                 sanitize_user_provided_values,
                 optimize_rodata,
+                eliminate_redundant_bounds_checks,
+                enable_exact_instruction_meter,
+                instruction_costs,
             } => Config {
                 max_call_depth,
                 enable_stack_frame_gaps,
@@ -56,6 +70,20 @@ impl From<ConfigTemplate> for Config {
                 noop_instruction_rate,
                 sanitize_user_provided_values,
                 optimize_rodata,
+                eliminate_redundant_bounds_checks,
+                enable_exact_instruction_meter,
+                instruction_costs: instruction_costs
+                    .map(|(class_costs, opcode_costs)| {
+                        let mut costs = InstructionCosts::default();
+                        for (class, cost) in class_costs.iter().enumerate() {
+                            costs = costs.with_class_cost(class as u8, u32::from(*cost % 4) + 1);
+                        }
+                        for (opc, cost) in opcode_costs.iter() {
+                            costs = costs.with_opcode_cost(*opc, u32::from(*cost % 16) + 1);
+                        }
+                        costs
+                    })
+                    .unwrap_or_default(),
                 ..Default::default()
             },
         }
@@ -22,6 +22,7 @@ mod grammar_aware;
 #[derive(arbitrary::Arbitrary, Debug)]
 struct FuzzData {
     template: ConfigTemplate,
+    fixed_stack_frames: bool,
     exit_dst: u8,
     exit_src: u8,
     exit_off: i16,
@@ -39,13 +40,18 @@ fuzz_target!(|data: FuzzData| {
         .set_imm(data.exit_imm)
         .push();
     let config = data.template.into();
+    let sbpf_version = if data.fixed_stack_frames {
+        SBPFVersion::V0
+    } else {
+        SBPFVersion::V3
+    };
     let function_registry = FunctionRegistry::default();
     let syscall_registry = FunctionRegistry::<BuiltinFunction<TestContextObject>>::default();
 
     if RequisiteVerifier::verify(
         prog.into_bytes(),
         &config,
-        SBPFVersion::V3,
+        sbpf_version,
         &function_registry,
         &syscall_registry,
     )
@@ -62,7 +68,7 @@ fuzz_target!(|data: FuzzData| {
             config,
             FunctionRegistry::default(),
         )),
-        SBPFVersion::V3,
+        sbpf_version,
         function_registry,
     )
     .unwrap();
@@ -81,7 +87,13 @@ fuzz_target!(|data: FuzzData| {
     #[allow(unused)]
     let (_interp_ins_count, interp_res) = interp_vm.execute_program(&executable, true);
 
-    #[cfg(all(not(target_os = "windows"), target_arch = "x86_64"))]
+    #[cfg(all(
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
     if executable.jit_compile().is_ok() {
         let mut jit_mem = data.mem;
         let mut jit_context_object = TestContextObject::new(1 << 16);
@@ -10,7 +10,7 @@ use solana_rbpf::{
     memory_region::MemoryRegion,
     program::{BuiltinFunction, BuiltinProgram, FunctionRegistry, SBPFVersion},
     verifier::{RequisiteVerifier, Verifier},
-    vm::TestContextObject,
+    vm::{Config, TestContextObject},
 };
 use test_utils::create_vm;
 
@@ -28,7 +28,8 @@ struct FuzzData {
 
 fuzz_target!(|data: FuzzData| {
     let prog = make_program(&data.prog);
-    let config = data.template.into();
+    let config: Config = data.template.into();
+    let enable_exact_instruction_meter = config.enable_exact_instruction_meter;
     let function_registry = FunctionRegistry::default();
     let syscall_registry = FunctionRegistry::<BuiltinFunction<TestContextObject>>::default();
 
@@ -71,7 +72,13 @@ fuzz_target!(|data: FuzzData| {
     #[allow(unused)]
     let (_interp_ins_count, interp_res) = interp_vm.execute_program(&executable, true);
 
-    #[cfg(all(not(target_os = "windows"), target_arch = "x86_64"))]
+    #[cfg(all(
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
     if executable.jit_compile().is_ok() {
         let mut jit_mem = data.mem;
         let mut jit_context_object = TestContextObject::new(1 << 16);
@@ -88,7 +95,8 @@ fuzz_target!(|data: FuzzData| {
         let (_jit_ins_count, jit_res) = jit_vm.execute_program(&executable, false);
         if format!("{:?}", interp_res) != format!("{:?}", jit_res) {
             // spot check: there's a meaningless bug where ExceededMaxInstructions is different due to jump calculations
-            if format!("{:?}", interp_res).contains("ExceededMaxInstructions")
+            if !enable_exact_instruction_meter
+                && format!("{:?}", interp_res).contains("ExceededMaxInstructions")
                 && format!("{:?}", jit_res).contains("ExceededMaxInstructions")
             {
                 return;
@@ -96,8 +104,8 @@ fuzz_target!(|data: FuzzData| {
             eprintln!("{:#?}", &data.prog);
             panic!("Expected {:?}, but got {:?}", interp_res, jit_res);
         }
-        if interp_res.is_ok() {
-            // we know jit res must be ok if interp res is by this point
+        if interp_res.is_ok() || enable_exact_instruction_meter {
+            // we know the results match by this point, and the exact instruction meter stops where the interpreter does
             if interp_context_object.remaining != jit_context_object.remaining {
                 panic!(
                     "Expected {} insts remaining, but got {}",
@@ -1,10 +1,7 @@
 #![allow(clippy::arithmetic_side_effects)]
 #![allow(clippy::upper_case_acronyms)]
 #![allow(dead_code)]
-use crate::{
-    jit::{JitCompiler, OperandSize},
-    vm::ContextObject,
-};
+use crate::{jit::OperandSize, jit_aarch64::JitCompiler, vm::ContextObject};
 
 macro_rules! exclude_operand_sizes {
     ($size:expr, $($to_exclude:path)|+ $(,)?) => {
@@ -32,8 +29,9 @@ pub const X12: u8 = 12;
 pub const X13: u8 = 13;
 pub const X14: u8 = 14;
 pub const X15: u8 = 15;
+pub const X16: u8 = 16; // IP0, may be clobbered by veneers of the linker, but not by our own code
+pub const X17: u8 = 17; // IP1, may be clobbered by veneers of the linker, but not by our own code
 
-// There are more registers, but I'm not sure we have a use for them
 // NOTE: x18 is reserved on Apple platforms
 
 pub const X19: u8 = 19;
@@ -85,6 +83,22 @@ impl Condition {
     // Aliases
     pub const HS: Condition = Condition::CS; // unsigned >=
     pub const LO: Condition = Condition::CC; // unsigned <
+
+    /// The condition which holds exactly when this one does not
+    pub fn invert(self) -> Self {
+        match self {
+            Condition::EQ => Condition::NE,
+            Condition::NE => Condition::EQ,
+            Condition::CS => Condition::CC,
+            Condition::CC => Condition::CS,
+            Condition::HI => Condition::LS,
+            Condition::LS => Condition::HI,
+            Condition::GE => Condition::LT,
+            Condition::LT => Condition::GE,
+            Condition::GT => Condition::LE,
+            Condition::LE => Condition::GT,
+        }
+    }
 }
 
 pub struct ARM64BitwiseImm {
@@ -117,6 +131,7 @@ pub enum ARM64Instruction {
     LogicalRegister(ARM64InstructionLogicalShiftedRegister),
     AddSubRegister(ARM64InstructionLogicalShiftedRegister),
     AddSubImm(ARM64InstructionAddSubImm),
+    ConditionalCompareImm(ARM64InstructionConditionalCompare),
     ConditionalBranch(ARM64InstructionConditionalBranch),
     LogicalImm(ARM64InstructionLogicalImm),
     BitfieldImm(ARM64InstructionLogicalImm),
@@ -126,9 +141,13 @@ pub enum ARM64Instruction {
     DataProcessing3Src(ARM64InstructionDataProcessing),
     BranchImm26(ARM64InstructionImm26),
     BLR(ARM64InstructionBLR),
+    BR(ARM64InstructionBLR),
+    ADR(ARM64InstructionADR),
+    LoadLiteral(ARM64InstructionLoadLiteral),
     Load(ARM64InstructionLoadStore),
     Store(ARM64InstructionLoadStore),
     RET,
+    NOP,
 }
 
 #[derive(Copy, Clone)]
@@ -254,6 +273,16 @@ impl Default for ARM64InstructionWideImm {
     }
 }
 
+#[derive(Copy, Clone)]
+pub struct ARM64InstructionConditionalCompare {
+    pub size: OperandSize,
+    pub opcode: u8, // 1 bit, 0 is CCMN and 1 is CCMP
+    pub cond: u8,   // 4 bits
+    pub nzcv: u8,   // 4 bits, the flags if the condition does not hold
+    pub src: u8,    // Rn, 5 bits
+    pub imm5: u8,   // unsigned imm5
+}
+
 #[derive(Copy, Clone)]
 pub struct ARM64InstructionConditionalBranch {
     pub cond: u8,   // 4 bits
@@ -271,6 +300,19 @@ pub struct ARM64InstructionBLR {
     pub target: u8, // 5 bit target register
 }
 
+#[derive(Copy, Clone)]
+pub struct ARM64InstructionADR {
+    pub dest: u8,   // Rd, 5 bits
+    pub imm21: i32, // offset from current instruction in bytes
+}
+
+#[derive(Copy, Clone)]
+pub struct ARM64InstructionLoadLiteral {
+    pub opcode: u8, // 2 bits, 01 is LDR (64 bit) and 10 is LDRSW
+    pub data: u8,   // Rt, 5 bits
+    pub imm19: i32, // offset from current instruction, divided by 4
+}
+
 // Load
 
 #[derive(Copy, Clone)]
@@ -294,6 +336,10 @@ impl Default for ARM64InstructionLoadStore {
 
 impl ARM64Instruction {
     pub fn emit<C: ContextObject>(&self, jit: &mut JitCompiler<C>) {
+        jit.emit::<u32>(self.encode());
+    }
+
+    pub fn encode(&self) -> u32 {
         let mut ins: u32 = 0;
 
         match self {
@@ -410,6 +456,21 @@ impl ARM64Instruction {
 
                 ins |= (sf as u32) << 31;
             }
+            ARM64Instruction::ConditionalCompareImm(s) => {
+                ins |= (s.nzcv & 0b1111) as u32;
+                ins |= ((s.src & 0b11111) as u32) << 5;
+                ins |= 0b1u32 << 11;
+                ins |= ((s.cond & 0b1111) as u32) << 12;
+                ins |= ((s.imm5 & 0b11111) as u32) << 16;
+                ins |= 0b111010010u32 << 21;
+                ins |= ((s.opcode & 0b1) as u32) << 30;
+                let sf: u8 = match s.size {
+                    OperandSize::S64 => 1,
+                    _ => 0,
+                };
+
+                ins |= (sf as u32) << 31;
+            }
             ARM64Instruction::ConditionalBranch(s) => {
                 ins |= (s.cond & 0b1111) as u32;
                 ins |= ((s.imm19 as u32) & ((1u32 << 19) - 1u32)) << 5;
@@ -423,9 +484,28 @@ impl ARM64Instruction {
                 ins |= 0b11010110001111110000000000000000u32;
                 ins |= ((s.target & 0b11111) as u32) << 5;
             }
+            ARM64Instruction::BR(s) => {
+                ins |= 0b11010110000111110000000000000000u32;
+                ins |= ((s.target & 0b11111) as u32) << 5;
+            }
+            ARM64Instruction::ADR(s) => {
+                ins |= (s.dest & 0b11111) as u32;
+                ins |= (((s.imm21 >> 2) as u32) & ((1u32 << 19) - 1u32)) << 5;
+                ins |= ((s.imm21 as u32) & 0b11) << 29;
+                ins |= 0b10000u32 << 24;
+            }
+            ARM64Instruction::LoadLiteral(s) => {
+                ins |= (s.data & 0b11111) as u32;
+                ins |= ((s.imm19 as u32) & ((1u32 << 19) - 1u32)) << 5;
+                ins |= 0b011000u32 << 24;
+                ins |= ((s.opcode & 0b11) as u32) << 30;
+            }
             ARM64Instruction::RET => {
                 ins = 0xd65f03c0;
             }
+            ARM64Instruction::NOP => {
+                ins = 0xd503201f;
+            }
             ARM64Instruction::Load(s) | ARM64Instruction::Store(s) => {
                 ins |= (s.data & 0b11111) as u32;
                 ins |= ((s.base & 0b11111) as u32) << 5;
@@ -496,7 +576,7 @@ impl ARM64Instruction {
             }
         }
 
-        jit.emit::<u32>(ins);
+        ins
     }
 
     /// Move source to destination
@@ -666,6 +746,20 @@ impl ARM64Instruction {
         })
     }
 
+    // Compares src with -imm5 if cond holds, otherwise sets the flags to nzcv
+    #[must_use]
+    pub fn ccmn_imm(size: OperandSize, src: u8, imm5: u8, nzcv: u8, cond: Condition) -> Self {
+        debug_assert!(imm5 < (1u8 << 5) && nzcv < (1u8 << 4));
+        Self::ConditionalCompareImm(ARM64InstructionConditionalCompare {
+            size,
+            opcode: 0,
+            cond: cond as u8,
+            nzcv,
+            src,
+            imm5,
+        })
+    }
+
     #[must_use]
     pub fn zero_extend_to_u64(from_size: OperandSize, source: u8, destination: u8) -> Self {
         match from_size {
@@ -840,6 +934,44 @@ impl ARM64Instruction {
         Self::BLR(ARM64InstructionBLR { target })
     }
 
+    // indirect jump
+    #[must_use]
+    pub fn br(target: u8) -> Self {
+        Self::BR(ARM64InstructionBLR { target })
+    }
+
+    // pc relative address, the offset is in bytes
+    #[must_use]
+    pub fn adr(destination: u8, imm21: i32) -> Self {
+        debug_assert!((-(1 << 20)..(1 << 20)).contains(&imm21));
+        Self::ADR(ARM64InstructionADR {
+            dest: destination,
+            imm21,
+        })
+    }
+
+    // load a 64 bit value from a pc relative address
+    // WARNING: You need to divide the byte offset by 4 before passing as imm19
+    #[must_use]
+    pub fn ldr_literal(destination: u8, imm19: i32) -> Self {
+        Self::LoadLiteral(ARM64InstructionLoadLiteral {
+            opcode: 0b01,
+            data: destination,
+            imm19,
+        })
+    }
+
+    // load a 32 bit value from a pc relative address and sign extend it to 64 bit
+    // WARNING: You need to divide the byte offset by 4 before passing as imm19
+    #[must_use]
+    pub fn ldrsw_literal(destination: u8, imm19: i32) -> Self {
+        Self::LoadLiteral(ARM64InstructionLoadLiteral {
+            opcode: 0b10,
+            data: destination,
+            imm19,
+        })
+    }
+
     // jump
     #[must_use]
     pub fn b(imm26: i32) -> Self {
@@ -854,6 +986,24 @@ impl ARM64Instruction {
         Self::RET
     }
 
+    #[must_use]
+    pub fn nop() -> Self {
+        Self::NOP
+    }
+
+    // movz (64-bit)
+    #[must_use]
+    pub fn movz(destination: u8, shift_16: u8, immediate: u16) -> Self {
+        debug_assert!((0..4).contains(&shift_16));
+        Self::MovWideImm(ARM64InstructionWideImm {
+            size: OperandSize::S64,
+            dest: destination,
+            hw: shift_16,
+            imm16: immediate,
+            opcode: 2,
+        })
+    }
+
     // movk (64-bit)
     #[must_use]
     pub fn movk(destination: u8, shift_16: u8, immediate: u16) -> Self {
@@ -989,6 +1139,40 @@ impl ARM64Instruction {
         })
     }
 
+    // multiply
+    #[must_use]
+    pub fn mul(size: OperandSize, src1: u8, src2: u8, destination: u8) -> Self {
+        Self::madd(size, src1, src2, SP_XZR, destination)
+    }
+
+    // upper 64 bits of the unsigned 128 bit product
+    #[must_use]
+    pub fn umulh(src1: u8, src2: u8, destination: u8) -> Self {
+        Self::DataProcessing3Src(ARM64InstructionDataProcessing {
+            size: OperandSize::S64,
+            opcode: 0b110,
+            dest: destination,
+            src1,
+            src2,
+            src3: SP_XZR,
+            o0: 0,
+        })
+    }
+
+    // upper 64 bits of the signed 128 bit product
+    #[must_use]
+    pub fn smulh(src1: u8, src2: u8, destination: u8) -> Self {
+        Self::DataProcessing3Src(ARM64InstructionDataProcessing {
+            size: OperandSize::S64,
+            opcode: 0b010,
+            dest: destination,
+            src1,
+            src2,
+            src3: SP_XZR,
+            o0: 0,
+        })
+    }
+
     #[must_use]
     pub fn udiv(size: OperandSize, src1: u8, src2: u8, destination: u8) -> Self {
         Self::DataProcessing2Src(ARM64InstructionDataProcessing {
@@ -1013,3 +1197,322 @@ impl ARM64Instruction {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jit::OperandSize::{S16, S32, S64, S8};
+
+    #[test]
+    fn test_encoding() {
+        // Expected encodings were produced with `llvm-mc -triple=aarch64 -show-encoding`
+        for (instruction, expected, assembly) in [
+            (ARM64Instruction::mov(S64, X1, X0), 0xaa0103e0, "mov x0, x1"),
+            (ARM64Instruction::mov(S32, X1, X0), 0x2a0103e0, "mov w0, w1"),
+            (
+                ARM64Instruction::orr(S64, X2, X19),
+                0xaa020273,
+                "orr x19, x19, x2",
+            ),
+            (
+                ARM64Instruction::and(S32, X4, X5, X3),
+                0x0a050083,
+                "and w3, w4, w5",
+            ),
+            (
+                ARM64Instruction::eor(S64, X21, X20),
+                0xca150294,
+                "eor x20, x20, x21",
+            ),
+            (ARM64Instruction::tst(S64, X2, X1), 0xea02003f, "tst x1, x2"),
+            (
+                ARM64Instruction::tst_imm(X9, ARM64BitwiseImm::ONE),
+                0xf240013f,
+                "tst x9, #1",
+            ),
+            (
+                ARM64Instruction::add(S64, X1, X2, X0),
+                0x8b020020,
+                "add x0, x1, x2",
+            ),
+            (
+                ARM64Instruction::add(S32, X1, X2, X0),
+                0x0b020020,
+                "add w0, w1, w2",
+            ),
+            (
+                ARM64Instruction::add_imm(S64, X25, 40, X9),
+                0x9100a329,
+                "add x9, x25, #40",
+            ),
+            (
+                ARM64Instruction::add_imm(S64, SP_XZR, 0, X9),
+                0x910003e9,
+                "mov x9, sp",
+            ),
+            (
+                ARM64Instruction::add_imm(S64, SP_XZR, 48, SP_XZR),
+                0x9100c3ff,
+                "add sp, sp, #48",
+            ),
+            (
+                ARM64Instruction::sub(S64, X1, X2, X0),
+                0xcb020020,
+                "sub x0, x1, x2",
+            ),
+            (
+                ARM64Instruction::sub(S32, SP_XZR, X19, X19),
+                0x4b1303f3,
+                "neg w19, w19",
+            ),
+            (
+                ARM64Instruction::sub_imm(S64, X9, 1, X9),
+                0xd1000529,
+                "sub x9, x9, #1",
+            ),
+            (
+                ARM64Instruction::sub_imm(S64, SP_XZR, 48, SP_XZR),
+                0xd100c3ff,
+                "sub sp, sp, #48",
+            ),
+            (ARM64Instruction::cmp(S64, X2, X1), 0xeb02003f, "cmp x1, x2"),
+            (ARM64Instruction::cmp(S32, X4, X3), 0x6b04007f, "cmp w3, w4"),
+            (
+                ARM64Instruction::cmp_imm(S64, X10, 0),
+                0xf100015f,
+                "cmp x10, #0",
+            ),
+            (
+                ARM64Instruction::cmp_imm(S32, X1, 0),
+                0x7100003f,
+                "cmp w1, #0",
+            ),
+            (
+                ARM64Instruction::ccmn_imm(S64, X1, 1, 0, Condition::EQ),
+                0xba410820,
+                "ccmn x1, #1, #0, eq",
+            ),
+            (
+                ARM64Instruction::ccmn_imm(S32, X1, 1, 0, Condition::EQ),
+                0x3a410820,
+                "ccmn w1, #1, #0, eq",
+            ),
+            (
+                ARM64Instruction::zero_extend_to_u64(S8, X1, X0),
+                0x53001c20,
+                "uxtb w0, w1",
+            ),
+            (
+                ARM64Instruction::zero_extend_to_u64(S16, X1, X0),
+                0x53003c20,
+                "uxth w0, w1",
+            ),
+            (
+                ARM64Instruction::sign_extend_to_i64(S8, X1, X0),
+                0x93401c20,
+                "sxtb x0, w1",
+            ),
+            (
+                ARM64Instruction::sign_extend_to_i64(S32, X1, X0),
+                0x93407c20,
+                "sxtw x0, w1",
+            ),
+            (
+                ARM64Instruction::lsl_imm(X1, 32, X0),
+                0xd3607c20,
+                "lsl x0, x1, #32",
+            ),
+            (
+                ARM64Instruction::lsr_imm(X1, 3, X0),
+                0xd343fc20,
+                "lsr x0, x1, #3",
+            ),
+            (
+                ARM64Instruction::lsl_reg(S64, X1, X2, X0),
+                0x9ac22020,
+                "lsl x0, x1, x2",
+            ),
+            (
+                ARM64Instruction::lsl_reg(S32, X1, X2, X0),
+                0x1ac22020,
+                "lsl w0, w1, w2",
+            ),
+            (
+                ARM64Instruction::lsr_reg(S64, X1, X2, X0),
+                0x9ac22420,
+                "lsr x0, x1, x2",
+            ),
+            (
+                ARM64Instruction::asr_reg(S32, X1, X2, X0),
+                0x1ac22820,
+                "asr w0, w1, w2",
+            ),
+            (
+                ARM64Instruction::rev(S16, X1, X0),
+                0x5ac00420,
+                "rev16 w0, w1",
+            ),
+            (ARM64Instruction::rev(S32, X1, X0), 0x5ac00820, "rev w0, w1"),
+            (ARM64Instruction::rev(S64, X1, X0), 0xdac00c20, "rev x0, x1"),
+            (
+                ARM64Instruction::b_cond(Condition::EQ, 2),
+                0x54000040,
+                "b.eq #8",
+            ),
+            (
+                ARM64Instruction::b_cond(Condition::HI, -4),
+                0x54ffff88,
+                "b.hi #-16",
+            ),
+            (ARM64Instruction::bl(16), 0x94000010, "bl #64"),
+            (ARM64Instruction::b(-1), 0x17ffffff, "b #-4"),
+            (ARM64Instruction::blr(X9), 0xd63f0120, "blr x9"),
+            (ARM64Instruction::br(X9), 0xd61f0120, "br x9"),
+            (ARM64Instruction::ret(), 0xd65f03c0, "ret"),
+            (ARM64Instruction::nop(), 0xd503201f, "nop"),
+            (ARM64Instruction::adr(X9, 12), 0x10000069, "adr x9, #12"),
+            (
+                ARM64Instruction::ldr_literal(X9, 2),
+                0x58000049,
+                "ldr x9, #8",
+            ),
+            (
+                ARM64Instruction::ldrsw_literal(X16, 2),
+                0x98000050,
+                "ldrsw x16, #8",
+            ),
+            (
+                ARM64Instruction::movz(X0, 1, 0x1234),
+                0xd2a24680,
+                "movz x0, #0x1234, lsl #16",
+            ),
+            (
+                ARM64Instruction::movk(X0, 1, 0x1234),
+                0xf2a24680,
+                "movk x0, #0x1234, lsl #16",
+            ),
+            (ARM64Instruction::movn(X0, 0, 0), 0x92800000, "movn x0, #0"),
+            (ARM64Instruction::mvn(S64, X1, X0), 0xaa2103e0, "mvn x0, x1"),
+            (
+                ARM64Instruction::load(S64, X1, ARM64MemoryOperand::Offset(0), X0),
+                0xf8400020,
+                "ldur x0, [x1]",
+            ),
+            (
+                ARM64Instruction::load(S32, X1, ARM64MemoryOperand::Offset(-4), X0),
+                0xb85fc020,
+                "ldur w0, [x1, #-4]",
+            ),
+            (
+                ARM64Instruction::load(S8, X1, ARM64MemoryOperand::Offset(1), X0),
+                0x38401020,
+                "ldurb w0, [x1, #1]",
+            ),
+            (
+                ARM64Instruction::load(S16, X1, ARM64MemoryOperand::Offset(2), X0),
+                0x78402020,
+                "ldurh w0, [x1, #2]",
+            ),
+            (
+                ARM64Instruction::store(S64, X0, X1, ARM64MemoryOperand::Offset(8)),
+                0xf8008020,
+                "stur x0, [x1, #8]",
+            ),
+            (
+                ARM64Instruction::store(S8, X0, X1, ARM64MemoryOperand::Offset(0)),
+                0x38000020,
+                "sturb w0, [x1]",
+            ),
+            (
+                ARM64Instruction::load(S64, X1, ARM64MemoryOperand::OffsetScaled(2), X0),
+                0xf9400820,
+                "ldr x0, [x1, #16]",
+            ),
+            (
+                ARM64Instruction::store(S64, X9, SP_XZR, ARM64MemoryOperand::OffsetScaled(5)),
+                0xf90017e9,
+                "str x9, [sp, #40]",
+            ),
+            (
+                ARM64Instruction::load(
+                    S64,
+                    X25,
+                    ARM64MemoryOperand::OffsetIndexShift(X16, false),
+                    X0,
+                ),
+                0xf8706b20,
+                "ldr x0, [x25, x16]",
+            ),
+            (
+                ARM64Instruction::load(
+                    S64,
+                    X9,
+                    ARM64MemoryOperand::OffsetIndexShift(X27, true),
+                    X0,
+                ),
+                0xf87b7920,
+                "ldr x0, [x9, x27, lsl #3]",
+            ),
+            (
+                ARM64Instruction::store(
+                    S64,
+                    X0,
+                    X25,
+                    ARM64MemoryOperand::OffsetIndexShift(X16, false),
+                ),
+                0xf8306b20,
+                "str x0, [x25, x16]",
+            ),
+            (
+                ARM64Instruction::push64(X0),
+                0xf81f0fe0,
+                "str x0, [sp, #-16]!",
+            ),
+            (ARM64Instruction::pop64(X0), 0xf84107e0, "ldr x0, [sp], #16"),
+            (
+                ARM64Instruction::madd(S64, X1, X2, X3, X0),
+                0x9b020c20,
+                "madd x0, x1, x2, x3",
+            ),
+            (
+                ARM64Instruction::msub(S32, X1, X2, X3, X0),
+                0x1b028c20,
+                "msub w0, w1, w2, w3",
+            ),
+            (
+                ARM64Instruction::mul(S64, X1, X2, X0),
+                0x9b027c20,
+                "mul x0, x1, x2",
+            ),
+            (
+                ARM64Instruction::umulh(X1, X2, X0),
+                0x9bc27c20,
+                "umulh x0, x1, x2",
+            ),
+            (
+                ARM64Instruction::smulh(X1, X2, X0),
+                0x9b427c20,
+                "smulh x0, x1, x2",
+            ),
+            (
+                ARM64Instruction::udiv(S64, X1, X2, X0),
+                0x9ac20820,
+                "udiv x0, x1, x2",
+            ),
+            (
+                ARM64Instruction::sdiv(S32, X1, X2, X0),
+                0x1ac20c20,
+                "sdiv w0, w1, w2",
+            ),
+        ] {
+            assert_eq!(
+                instruction.encode(),
+                expected,
+                "{}: {:#010x} != {:#010x}",
+                assembly,
+                instruction.encode(),
+                expected
+            );
+        }
+    }
+}
@@ -25,9 +25,40 @@ use crate::{
 };
 
 #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
-use crate::jit::{JitCompiler, JitProgram};
+use crate::jit::JitCompiler;
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    target_arch = "aarch64",
+    feature = "jit-aarch64"
+))]
+use crate::jit_aarch64::JitCompiler;
 use byteorder::{ByteOrder, LittleEndian};
 use std::{collections::BTreeMap, fmt::Debug, mem, ops::Range, str};
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
+use {
+    crate::{
+        jit::{self, JitProgram},
+        jit_pool::{BackgroundCompilation, JitCompilationHandle, JitCompilationPool},
+        tiered::TieredCompilation,
+    },
+    std::{
+        fs,
+        path::Path,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            OnceLock,
+        },
+        thread,
+    },
+};
 
 #[cfg(not(feature = "shuttle-test"))]
 use std::sync::Arc;
@@ -213,7 +244,7 @@ impl BpfRelocationType {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum Section {
     /// Owned section data.
     ///
@@ -248,8 +279,35 @@ pub struct Executable<C: ContextObject> {
     /// Loader built-in program
     loader: Arc<BuiltinProgram<C>>,
     /// Compiled program and argument
-    #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
     compiled_program: Option<JitProgram>,
+    /// Invocation counters and background compilation of tiered execution
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    tiered_compilation: Option<TieredCompilation<C>>,
+    /// Compiled program installed by a [crate::jit_pool::JitCompilationPool]
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    background_compilation: BackgroundCompilation,
 }
 
 impl<C: ContextObject> Executable<C> {
@@ -303,9 +361,49 @@ impl<C: ContextObject> Executable<C> {
     }
 
     /// Get the JIT compiled program
-    #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+    ///
+    /// This is either the program of [Executable::jit_compile] or the one which was installed
+    /// by tiered compilation, see [Executable::enable_tiered_compilation].
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
     pub fn get_compiled_program(&self) -> Option<&JitProgram> {
-        self.compiled_program.as_ref()
+        self.compiled_program
+            .as_ref()
+            .or_else(|| self.background_compilation.get_compiled_program())
+            .or_else(|| self.tiered_compilation.as_ref()?.get_compiled_program())
+    }
+
+    /// Returns true while a [crate::jit_pool::JitCompilationPool] compiles the executable
+    ///
+    /// In the meantime the executable runs in the interpreter, even if the JIT was requested.
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub fn is_compiling_in_background(&self) -> bool {
+        self.background_compilation.is_pending()
+    }
+
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub(crate) fn get_background_compilation(&self) -> &BackgroundCompilation {
+        &self.background_compilation
     }
 
     /// Verify the executable
@@ -321,13 +419,225 @@ impl<C: ContextObject> Executable<C> {
     }
 
     /// JIT compile the executable
-    #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
     pub fn jit_compile(&mut self) -> Result<(), crate::error::EbpfError> {
+        self.compiled_program = Some(self.compile_program()?);
+        Ok(())
+    }
+
+    /// JIT compile the executable without installing the compiled program
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub(crate) fn compile_program(&self) -> Result<JitProgram, EbpfError> {
         let jit = JitCompiler::<C>::new(self)?;
-        self.compiled_program = Some(jit.compile()?);
+        let mut compiled_program = jit.compile()?;
+        if self.get_config().enable_profiler_symbols {
+            compiled_program.register_symbols(self);
+        }
+        Ok(compiled_program)
+    }
+
+    /// Prepares the executable for tiered execution
+    ///
+    /// Runs which request the JIT start out in the interpreter until a compiled program is
+    /// installed, and the interpreter counts the invocations of functions. Once all functions
+    /// together were invoked `Config::tiered_compilation_threshold` times, the compilation of a
+    /// copy of the whole executable is queued in the `pool`. The compiled program is installed
+    /// atomically and from then on the interpreter continues in it at the next function entry.
+    /// Runs which request the interpreter never switch to the compiled program.
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub fn enable_tiered_compilation(&mut self, pool: Arc<JitCompilationPool>)
+    where
+        C: 'static,
+    {
+        self.tiered_compilation = Some(TieredCompilation::new(pool, spawn_compilation::<C>));
+    }
+
+    /// Takes the handle of the compilation which tiered execution queued
+    ///
+    /// Returns None until the executable reached the threshold and once the handle was taken.
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub fn take_tiered_compilation_handle(&self) -> Option<JitCompilationHandle> {
+        self.tiered_compilation.as_ref()?.take_compilation_handle()
+    }
+
+    /// Returns true if the executable runs in the interpreter until tiered compilation installs a program
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub(crate) fn awaits_tiered_compilation(&self) -> bool {
+        self.tiered_compilation.is_some() && self.get_compiled_program().is_none()
+    }
+
+    /// Counts an invocation of a function and returns the compiled program once it is installed
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub(crate) fn enter_function(&self) -> Option<&JitProgram> {
+        self.tiered_compilation.as_ref()?.enter_function(self)
+    }
+
+    /// Copies everything the JIT compiler needs, without the compiled program
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub(crate) fn copy_for_compilation(&self) -> Self {
+        Self {
+            elf_bytes: self.elf_bytes.clone(),
+            sbpf_version: self.sbpf_version,
+            ro_section: self.ro_section.clone(),
+            text_section_vaddr: self.text_section_vaddr,
+            text_section_range: self.text_section_range.clone(),
+            entry_pc: self.entry_pc,
+            function_registry: self.function_registry.clone(),
+            loader: Arc::clone(&self.loader),
+            compiled_program: None,
+            tiered_compilation: None,
+            background_compilation: BackgroundCompilation::default(),
+        }
+    }
+
+    /// Get the key which identifies the JIT compiled program in a cache
+    ///
+    /// A SHA-256 digest of the text section, the config, the SBPF version, the function registries
+    /// and the build of this crate. It is stored in serialized programs and checked when loading them.
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub fn get_jit_cache_key(&self) -> [u8; 32] {
+        jit::cache_key(self)
+    }
+
+    /// Serialize the JIT compiled program, see [Executable::load_compiled_program]
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub fn serialize_compiled_program(&self) -> Result<Vec<u8>, EbpfError> {
+        self.get_compiled_program()
+            .ok_or(EbpfError::JitNotCompiled)?
+            .serialize(self)
+    }
+
+    /// Load a JIT compiled program serialized by [Executable::serialize_compiled_program]
+    ///
+    /// The bytes are executed as machine code, so they must come from a trusted source.
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub fn load_compiled_program(&mut self, bytes: &[u8]) -> Result<(), EbpfError> {
+        let mut compiled_program = JitProgram::deserialize(self, bytes)?;
+        if self.get_config().enable_profiler_symbols {
+            compiled_program.register_symbols(self);
+        }
+        self.compiled_program = Some(compiled_program);
         Ok(())
     }
 
+    /// JIT compile the executable or load it from the cache in `directory`
+    ///
+    /// Entries are named after the hex encoded [Executable::get_jit_cache_key]. If there is no entry or it can
+    /// not be loaded, the executable is compiled and the entry is (re)written. The directory must
+    /// only be writable by trusted users, as the entries are executed as machine code.
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub fn jit_compile_cached(&mut self, directory: &Path) -> Result<(), EbpfError> {
+        let key = self
+            .get_jit_cache_key()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let path = directory.join(format!("{key}.jit"));
+        if let Ok(bytes) = fs::read(&path) {
+            if self.load_compiled_program(&bytes).is_ok() {
+                return Ok(());
+            }
+        }
+        self.jit_compile()?;
+        let bytes = self.serialize_compiled_program()?;
+        // Write to a temporary file first so that concurrent readers never see a partial entry.
+        // Its name is unique among all writers, including the threads of this process.
+        static TEMPORARY_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let thread_id = format!("{:?}", thread::current().id())
+            .chars()
+            .filter(char::is_ascii_digit)
+            .collect::<String>();
+        let temporary_path = path.with_extension(format!(
+            "jit.{}.{}.{}",
+            std::process::id(),
+            thread_id,
+            TEMPORARY_FILE_COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+        fs::write(&temporary_path, bytes)
+            .and_then(|()| fs::rename(&temporary_path, &path))
+            .map_err(|error| {
+                let _ = fs::remove_file(&temporary_path);
+                EbpfError::JitCacheError(error.to_string())
+            })
+    }
+
     /// Get the function registry
     pub fn get_function_registry(&self) -> &FunctionRegistry<usize> {
         &self.function_registry
@@ -365,8 +675,33 @@ impl<C: ContextObject> Executable<C> {
             entry_pc,
             function_registry,
             loader,
-            #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
             compiled_program: None,
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
+            tiered_compilation: None,
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
+            background_compilation: BackgroundCompilation::default(),
         })
     }
 
@@ -599,8 +934,33 @@ impl<C: ContextObject> Executable<C> {
             entry_pc,
             function_registry,
             loader,
-            #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
             compiled_program: None,
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
+            tiered_compilation: None,
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
+            background_compilation: BackgroundCompilation::default(),
         })
     }
 
@@ -698,8 +1058,33 @@ impl<C: ContextObject> Executable<C> {
             entry_pc,
             function_registry,
             loader,
-            #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
             compiled_program: None,
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
+            tiered_compilation: None,
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
+            background_compilation: BackgroundCompilation::default(),
         })
     }
 
@@ -719,10 +1104,10 @@ impl<C: ContextObject> Executable<C> {
             // bpf functions
             .saturating_add(self.function_registry.mem_size());
 
-        #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+        #[cfg(all(feature = "jit", not(target_os = "windows"), any(target_arch = "x86_64", all(target_arch = "aarch64", feature = "jit-aarch64"))))]
         {
             // compiled programs
-            total = total.saturating_add(self.compiled_program.as_ref().map_or(0, |program| program.mem_size()));
+            total = total.saturating_add(self.get_compiled_program().map_or(0, |program| program.mem_size()));
         }
 
         total
@@ -1376,6 +1761,29 @@ pub(crate) fn get_ro_region(ro_section: &Section, elf: &[u8]) -> MemoryRegion {
     MemoryRegion::new_readonly(ro_data, offset as u64)
 }
 
+/// Queues the compilation of a copy of the executable, see [Executable::enable_tiered_compilation]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
+fn spawn_compilation<C: ContextObject + 'static>(
+    executable: &Executable<C>,
+    pool: &JitCompilationPool,
+    compiled_program: Arc<OnceLock<JitProgram>>,
+) -> JitCompilationHandle {
+    let executable = executable.copy_for_compilation();
+    pool.spawn(move || {
+        // If the compilation fails the executable simply stays in the interpreter
+        executable.compile_program().map(|program| {
+            let _ = compiled_program.set(program);
+        })
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -15,6 +15,8 @@ pub const ELFDATA2MSB: u8 = 2;
 pub const EI_OSABI: u8 = 7;
 pub const ELFOSABI_NONE: u8 = 0;
 
+pub const EM_X86_64: Elf64Half = 62;
+pub const EM_AARCH64: Elf64Half = 183;
 pub const EM_BPF: Elf64Half = 247;
 pub const EM_SBPF: Elf64Half = 263;
 
@@ -110,6 +112,10 @@ pub const DT_PREINIT_ARRAYSZ: Elf64Xword = 33;
 pub const DT_SYMTAB_SHNDX: Elf64Xword = 34;
 pub const DT_NUM: usize = 35;
 
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+
 pub const STT_NOTYPE: u8 = 0;
 pub const STT_OBJECT: u8 = 1;
 pub const STT_FUNC: u8 = 2;
@@ -7,11 +7,14 @@
 //! This module contains error and result types
 
 use {
-    crate::{elf::ElfError, memory_region::AccessType, verifier::VerifierError},
-    std::error::Error,
+    crate::{elf::ElfError, memory_region::AccessType, verifier::VerifierError, vm::FaultLocation},
+    std::{borrow::Cow, error::Error},
 };
 
 /// Error definitions
+///
+/// Runtime errors returned by an execution are wrapped in [EbpfError::Fault], which carries the
+/// location of the faulting instruction.
 #[derive(Debug, thiserror::Error)]
 #[repr(u64)] // discriminant size, used in emit_exception_kind in JIT
 pub enum EbpfError {
@@ -21,6 +24,9 @@ pub enum EbpfError {
     /// Function was already registered
     #[error("function #{0} was already registered")]
     FunctionAlreadyRegistered(usize),
+    /// Function is not registered
+    #[error("function {0} is not registered")]
+    FunctionNotRegistered(String),
     /// Exceeded max BPF to BPF call depth
     #[error("exceeded max BPF to BPF call depth")]
     CallDepthExceeded,
@@ -42,6 +48,12 @@ pub enum EbpfError {
     /// Exceeded max instructions allowed
     #[error("exceeded CUs meter at BPF instruction")]
     ExceededMaxInstructions,
+    /// Execution was suspended because the instruction meter ran out, see [EbpfVm::resume_program](crate::vm::EbpfVm::resume_program)
+    #[error("execution suspended at BPF instruction, can be resumed")]
+    ExecutionSuspended,
+    /// Execution was stopped through a [CancellationHandle](crate::vm::CancellationHandle)
+    #[error("execution cancelled at BPF instruction")]
+    ExecutionCancelled,
     /// Program has not been JIT-compiled
     #[error("program has not been JIT-compiled")]
     JitNotCompiled,
@@ -53,7 +65,7 @@ pub enum EbpfError {
     InvalidMemoryRegion(usize),
     /// Access violation (general)
     #[error("Access violation in {3} section at address {1:#x} of size {2:?}")]
-    AccessViolation(AccessType, u64, u64, &'static str),
+    AccessViolation(AccessType, u64, u64, Cow<'static, str>),
     /// Access violation (stack specific)
     #[error("Access violation in stack frame {3} at address {1:#x} of size {2:?}")]
     StackAccessViolation(AccessType, u64, u64, i64),
@@ -75,6 +87,41 @@ pub enum EbpfError {
     /// Syscall error
     #[error("Syscall error: {0}")]
     SyscallError(Box<dyn Error>),
+    /// JIT compiled program could not be loaded from or stored in a cache
+    #[error("JIT cache error: {0}")]
+    JitCacheError(String),
+    /// JIT compiled program accesses the stack frames directly, but the stack region does not fit
+    #[error("stack region is incompatible with the JIT compiled program")]
+    IncompatibleStackRegion,
+    /// Runtime error together with the location of the faulting instruction
+    #[error("{0} (instruction {1})")]
+    Fault(Box<EbpfError>, FaultLocation),
+}
+
+impl EbpfError {
+    /// Location of the faulting instruction, if the error is an [EbpfError::Fault]
+    pub fn fault_location(&self) -> Option<&FaultLocation> {
+        match self {
+            Self::Fault(_error, location) => Some(location),
+            _ => None,
+        }
+    }
+
+    /// The error without the location which [EbpfError::Fault] attaches
+    pub fn without_location(&self) -> &EbpfError {
+        match self {
+            Self::Fault(error, _location) => error,
+            error => error,
+        }
+    }
+
+    /// Splits the error into the one without location and the location, if any
+    pub fn split_location(self) -> (EbpfError, Option<FaultLocation>) {
+        match self {
+            Self::Fault(error, location) => (*error, Some(location)),
+            error => (error, None),
+        }
+    }
 }
 
 /// Same as `Result` but provides a stable memory layout
@@ -140,7 +187,10 @@ impl<T: std::fmt::Debug, E: std::fmt::Debug> StableResult<T, E> {
         any(
             not(feature = "jit"),
             target_os = "windows",
-            not(target_arch = "x86_64")
+            not(any(
+                target_arch = "x86_64",
+                all(target_arch = "aarch64", feature = "jit-aarch64")
+            ))
         ),
         allow(dead_code)
     )]
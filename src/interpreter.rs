@@ -16,8 +16,9 @@ use crate::{
     ebpf,
     elf::Executable,
     error::{EbpfError, ProgramResult},
+    memory_region::AccessType,
     program::BuiltinFunction,
-    vm::{Config, ContextObject, EbpfVm},
+    vm::{Config, ContextObject, EbpfVm, TraceEvent},
 };
 
 /// Virtual memory operation helper.
@@ -34,15 +35,26 @@ macro_rules! translate_memory_access {
         }
     };
 
-    // MemoryMapping::load()
-    ($self:ident, load, $vm_addr:ident, $T:ty) => {
-        translate_memory_access!(_impl, $self, load, $vm_addr, $T,)
-    };
+    // MemoryMapping::handled_load()
+    ($self:ident, load, $vm_addr:ident, $T:ty) => {{
+        let value = translate_memory_access!(_impl, $self, handled_load, $vm_addr, $T,);
+        if let Err(err) = $self.trace_memory_access(AccessType::Load, $vm_addr, std::mem::size_of::<$T>(), value) {
+            throw_error!($self, err);
+        }
+        value
+    }};
 
-    // MemoryMapping::store()
-    ($self:ident, store, $value:expr, $vm_addr:ident, $T:ty) => {
-        translate_memory_access!(_impl, $self, store, $vm_addr, $T, ($value) as $T);
-    };
+    // MemoryMapping::handled_store()
+    ($self:ident, store, $value:expr, $vm_addr:ident, $T:ty) => {{
+        let value = ($value) as $T;
+        if let Err(err) = $self.watch_store($vm_addr, std::mem::size_of::<$T>(), value as u64) {
+            throw_error!($self, err);
+        }
+        translate_memory_access!(_impl, $self, handled_store, $vm_addr, $T, value);
+        if let Err(err) = $self.trace_memory_access(AccessType::Store, $vm_addr, std::mem::size_of::<$T>(), value as u64) {
+            throw_error!($self, err);
+        }
+    }};
 }
 
 macro_rules! throw_error {
@@ -99,6 +111,36 @@ pub struct Interpreter<'a, 'b, C: ContextObject> {
 
     /// General purpose registers and pc
     pub reg: [u64; 12],
+    /// Set when the interpreter stopped to continue in the JIT compiled program
+    pub(crate) tier_up: bool,
+    /// Whether every instruction costs one unit, which spares the lookup in `Config::instruction_costs`
+    uniform_instruction_costs: bool,
+    /// Set if the JIT was requested, so that the run may continue in the JIT compiled program
+    #[cfg_attr(
+        not(all(
+            feature = "jit",
+            not(target_os = "windows"),
+            any(
+                target_arch = "x86_64",
+                all(target_arch = "aarch64", feature = "jit-aarch64")
+            )
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) may_tier_up: bool,
+    /// Set while the JIT waits for the run of instructions which its budget might not cover to end, see `Config::enable_exact_instruction_meter`
+    #[cfg_attr(
+        not(all(
+            feature = "jit",
+            not(target_os = "windows"),
+            any(
+                target_arch = "x86_64",
+                all(target_arch = "aarch64", feature = "jit-aarch64")
+            )
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) tier_up_at_checkpoint: bool,
 
     #[cfg(feature = "debugger")]
     pub(crate) debug_state: DebugState,
@@ -120,6 +162,10 @@ impl<'a, 'b, C: ContextObject> Interpreter<'a, 'b, C> {
             program,
             program_vm_addr,
             reg: registers,
+            tier_up: false,
+            uniform_instruction_costs: executable.get_config().instruction_costs.is_uniform(),
+            may_tier_up: false,
+            tier_up_at_checkpoint: false,
             #[cfg(feature = "debugger")]
             debug_state: DebugState::Continue,
             #[cfg(feature = "debugger")]
@@ -156,6 +202,90 @@ impl<'a, 'b, C: ContextObject> Interpreter<'a, 'b, C> {
         true
     }
 
+    fn watch_store(&mut self, vm_addr: u64, len: usize, value: u64) -> Result<(), EbpfError> {
+        let config = self.executable.get_config();
+        if config.enable_memory_watchpoints && config.enable_address_translation {
+            self.vm
+                .watch_store(self.reg[11], vm_addr, len as u64, value)?;
+        }
+        Ok(())
+    }
+
+    fn trace_memory_access(
+        &mut self,
+        access_type: AccessType,
+        vm_addr: u64,
+        len: usize,
+        value: u64,
+    ) -> Result<(), EbpfError> {
+        let config = self.executable.get_config();
+        if (config.enable_instruction_tracing
+            || config.enable_execution_stats
+            || config.enable_memory_watchpoints)
+            && config.enable_address_translation
+        {
+            self.vm
+                .trace_memory_access(self.reg[11], access_type, vm_addr, len as u64, value)?;
+        }
+        Ok(())
+    }
+
+    fn trace_event(&mut self, event: TraceEvent) {
+        if self.executable.get_config().enable_instruction_tracing {
+            self.vm.context_object_pointer.trace_event(&event);
+        }
+    }
+
+    fn record_call(&mut self, target_pc: u64) {
+        let remaining = self
+            .vm
+            .previous_instruction_meter
+            .saturating_sub(self.vm.due_insn_count);
+        if let Some(execution_stats) = self.vm.execution_stats.as_mut() {
+            execution_stats.call(target_pc as usize, remaining);
+        }
+    }
+
+    fn record_return(&mut self) {
+        let remaining = self
+            .vm
+            .previous_instruction_meter
+            .saturating_sub(self.vm.due_insn_count);
+        if let Some(execution_stats) = self.vm.execution_stats.as_mut() {
+            execution_stats.ret(remaining);
+        }
+    }
+
+    /// Counts the call for tiered compilation, see [Executable::enable_tiered_compilation]
+    pub(crate) fn enter_function(&mut self) {
+        // A debugging session stays in the interpreter
+        #[cfg(feature = "debugger")]
+        if self.vm.debug_port.is_some() {
+            return;
+        }
+        #[cfg(all(
+            feature = "jit",
+            not(target_os = "windows"),
+            any(
+                target_arch = "x86_64",
+                all(target_arch = "aarch64", feature = "jit-aarch64")
+            )
+        ))]
+        {
+            // Runs which requested the interpreter stay in it
+            if !self.may_tier_up {
+                return;
+            }
+            // JIT compiled code which accesses the stack directly only runs on a stack it located
+            self.tier_up = self.executable.enter_function().is_some()
+                && (self.vm.stack_host_address != 0
+                    || !crate::jit::accesses_stack_frames_directly(
+                        self.executable.get_config(),
+                        self.executable.get_sbpf_version(),
+                    ));
+        }
+    }
+
     fn sign_extension(&self, value: i32) -> u64 {
         if self
             .executable
@@ -175,19 +305,42 @@ impl<'a, 'b, C: ContextObject> Interpreter<'a, 'b, C> {
     pub fn step(&mut self) -> bool {
         let config = &self.executable.get_config();
 
-        if config.enable_instruction_meter && self.vm.due_insn_count >= self.vm.previous_instruction_meter {
+        let insn_cost = if self.uniform_instruction_costs {
+            1
+        } else {
+            self.program
+                .get(self.reg[11] as usize * ebpf::INSN_SIZE)
+                .map(|opc| config.instruction_costs.get(*opc))
+                .unwrap_or(1)
+        };
+        if config.enable_instruction_meter && self.vm.due_insn_count + insn_cost > self.vm.previous_instruction_meter {
+            if config.enable_resumable_execution {
+                // Keep the registers so that Self::new() can pick them up again
+                self.vm.registers = self.reg;
+                throw_error!(self, EbpfError::ExecutionSuspended);
+            }
+            // Running out of budget consumes all of it, even if the last instruction cost less than what was left
+            self.vm.due_insn_count = self.vm.previous_instruction_meter;
             throw_error!(self, EbpfError::ExceededMaxInstructions);
         }
-        self.vm.due_insn_count += 1;
+        self.vm.due_insn_count += insn_cost;
         if self.reg[11] as usize * ebpf::INSN_SIZE >= self.program.len() {
             throw_error!(self, EbpfError::ExecutionOverrun);
         }
+        if config.enable_cancellation_checks {
+            self.vm.cancellation_countdown = self.vm.cancellation_countdown.saturating_sub(1);
+            if self.vm.cancellation_countdown == 0 && self.vm.poll_cancellation() {
+                throw_error!(self, EbpfError::ExecutionCancelled);
+            }
+        }
         let mut next_pc = self.reg[11] + 1;
         let mut insn = ebpf::get_insn_unchecked(self.program, self.reg[11] as usize);
         let dst = insn.dst as usize;
         let src = insn.src as usize;
 
         if config.enable_instruction_tracing {
+            // Keep the pc of the traced instruction in registers[11] like the JIT does
+            self.vm.registers[11] = self.reg[11];
             self.vm.context_object_pointer.trace(self.reg);
         }
 
@@ -511,9 +664,12 @@ impl<'a, 'b, C: ContextObject> Interpreter<'a, 'b, C> {
                     return false;
                 }
                 check_pc!(self, next_pc, target_pc.wrapping_sub(self.program_vm_addr) / ebpf::INSN_SIZE as u64);
+                self.trace_event(TraceEvent::Call { pc: self.reg[11], target_pc: next_pc, call_depth: self.vm.call_depth });
+                self.record_call(next_pc);
                 if self.executable.get_sbpf_version().static_syscalls() && self.executable.get_function_registry().lookup_by_key(next_pc as u32).is_none() {
                     throw_error!(self, EbpfError::UnsupportedInstruction);
                 }
+                self.enter_function();
             },
 
             // Do not delegate the check to the verifier, since self.registered functions can be
@@ -541,6 +697,9 @@ impl<'a, 'b, C: ContextObject> Interpreter<'a, 'b, C> {
                         return false;
                     }
                     check_pc!(self, next_pc, target_pc as u64);
+                    self.trace_event(TraceEvent::Call { pc: self.reg[11], target_pc: next_pc, call_depth: self.vm.call_depth });
+                    self.record_call(next_pc);
+                    self.enter_function();
                 } else {
                     throw_error!(self, EbpfError::UnsupportedInstruction);
                 }
@@ -567,11 +726,16 @@ impl<'a, 'b, C: ContextObject> Interpreter<'a, 'b, C> {
                     if config.enable_instruction_meter && self.vm.due_insn_count > self.vm.previous_instruction_meter {
                         throw_error!(self, EbpfError::ExceededMaxInstructions);
                     }
+                    // Keep the final register file, except for the frame pointer of the root frame
+                    self.vm.registers[0..ebpf::FRAME_PTR_REG].copy_from_slice(&self.reg[0..ebpf::FRAME_PTR_REG]);
+                    self.vm.registers[11] = self.reg[11];
                     self.vm.program_result = ProgramResult::Ok(self.reg[0]);
                     return false;
                 }
                 // Return from BPF to BPF call
                 self.vm.call_depth -= 1;
+                self.trace_event(TraceEvent::Return { pc: self.reg[11], call_depth: self.vm.call_depth });
+                self.record_return();
                 let frame = &self.vm.call_frames[self.vm.call_depth as usize];
                 self.reg[ebpf::FRAME_PTR_REG] = frame.frame_pointer;
                 self.reg[ebpf::FIRST_SCRATCH_REG
@@ -583,14 +747,48 @@ impl<'a, 'b, C: ContextObject> Interpreter<'a, 'b, C> {
         }
 
         self.reg[11] = next_pc;
+        // Every branch except `callx` leads to the start of a run, at which the JIT validates the budget for the entire run
+        if self.tier_up_at_checkpoint
+            && insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_JMP
+            && insn.opc != ebpf::CALL_REG
+        {
+            self.tier_up = true;
+        }
+        if self.tier_up {
+            // Hand the registers over to the JIT compiled program, see EbpfVm::run()
+            self.vm.registers = self.reg;
+            return false;
+        }
         true
     }
 
     fn dispatch_syscall(&mut self, function: BuiltinFunction<C>) -> &ProgramResult {
         self.vm.due_insn_count = self.vm.previous_instruction_meter - self.vm.due_insn_count;
         self.vm.registers[0..6].copy_from_slice(&self.reg[0..6]);
+        self.trace_event(TraceEvent::SyscallEntry {
+            pc: self.reg[11],
+            arguments: [
+                self.reg[1],
+                self.reg[2],
+                self.reg[3],
+                self.reg[4],
+                self.reg[5],
+            ],
+        });
+        let remaining_before_syscall = self.vm.remaining_before_syscall();
         self.vm.invoke_function(function);
+        self.vm.record_syscall(function, remaining_before_syscall);
         self.vm.due_insn_count = 0;
+        if self.vm.program_result.is_err() {
+            // Report the pc of the syscall instruction like throw_error! does
+            self.vm.registers[11] = self.reg[11];
+        }
+        if let ProgramResult::Ok(result) = self.vm.program_result {
+            self.trace_event(TraceEvent::SyscallExit {
+                pc: self.reg[11],
+                result,
+            });
+        }
         &self.vm.program_result
     }
 }
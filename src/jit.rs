@@ -10,48 +10,134 @@
 // the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-#[cfg(not(feature = "shuttle-test"))]
+#[cfg(all(target_arch = "x86_64", not(feature = "shuttle-test")))]
 use rand::{thread_rng, Rng};
 
-#[cfg(feature = "shuttle-test")]
+#[cfg(all(target_arch = "x86_64", feature = "shuttle-test"))]
 use shuttle::rand::{thread_rng, Rng};
 
+use byteorder::{ByteOrder, LittleEndian};
+#[cfg(target_arch = "x86_64")]
 use rand::{
     distributions::{Distribution, Uniform},
     rngs::SmallRng,
     SeedableRng,
 };
-use std::{fmt::Debug, mem, ptr};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    io::{self, Write},
+    mem, ptr,
+};
 
 use crate::{
-    ebpf::{self, FIRST_SCRATCH_REG, FRAME_PTR_REG, INSN_SIZE, SCRATCH_REGS},
+    aligned_memory::Pod,
+    ebpf,
     elf::Executable,
     error::{EbpfError, ProgramResult},
+    jit_symbols::{JitSymbol, JitSymbolRegistration},
     memory_management::{
-        allocate_pages, free_pages, get_system_page_size, protect_pages, round_to_page_size,
+        allocate_pages, free_pages, get_system_page_size, invalidate_instruction_cache,
+        protect_pages, round_to_page_size,
     },
     memory_region::{AccessType, MemoryMapping},
-    program::BuiltinFunction,
-    vm::{get_runtime_environment_key, Config, ContextObject, EbpfVm},
+    program::{BuiltinFunction, SBPFVersion},
+    vm::{get_runtime_environment_key, Config, ContextObject, EbpfVm, TraceEvent},
+};
+#[cfg(target_arch = "x86_64")]
+use crate::{
+    ebpf::{FIRST_SCRATCH_REG, FRAME_PTR_REG, INSN_SIZE, SCRATCH_REGS},
+    memory_region::jit_layout,
+    static_analysis::{Analysis, BoundsCheckProof},
     x86::*,
 };
 
+#[cfg(target_arch = "x86_64")]
 const MAX_EMPTY_PROGRAM_MACHINE_CODE_LENGTH: usize = 4096;
+#[cfg(target_arch = "x86_64")]
 const MAX_MACHINE_CODE_LENGTH_PER_INSTRUCTION: usize = 110;
+#[cfg(target_arch = "x86_64")]
 const MACHINE_CODE_PER_INSTRUCTION_METER_CHECKPOINT: usize = 13;
-const MAX_START_PADDING_LENGTH: usize = 256;
+#[cfg(target_arch = "x86_64")]
+const MACHINE_CODE_PER_RESUMABLE_INSTRUCTION_METER_VALIDATION: usize = 7;
+#[cfg(target_arch = "x86_64")]
+const MACHINE_CODE_PER_CANCELLATION_CHECK: usize = 12;
+pub(crate) const MAX_START_PADDING_LENGTH: usize = 256;
 
 pub struct JitProgram {
     /// OS page size in bytes and the alignment of the sections
     page_size: usize,
     /// A `*const u8` pointer into the text_section for each BPF instruction
-    pc_section: &'static mut [usize],
-    /// The x86 machinecode
-    text_section: &'static mut [u8],
+    pub(crate) pc_section: &'static mut [usize],
+    /// The machine code of the host architecture
+    pub(crate) text_section: &'static mut [u8],
+    /// Host address of every BPF instruction and of the end of the text section, only kept for resumable execution
+    pub(crate) resume_addresses: Vec<usize>,
+    /// The target_pc and host return address of every BPF to BPF call, only kept for resumable execution
+    pub(crate) return_addresses: Vec<(usize, usize)>,
+    /// Accumulated instruction costs of all instructions before each pc, empty if every instruction costs one unit
+    pub(crate) instruction_cost_prefix: Vec<u64>,
+    /// Cost of the most expensive run of instructions between two checkpoints, only computed for `Config::enable_exact_instruction_meter`
+    pub(crate) max_checkpoint_interval_cost: u64,
+    /// Locations in the text_section which depend on where things are loaded, only used for serialization
+    pub(crate) relocations: Vec<Relocation>,
+    /// The runtime environment key the environment displacements were emitted with
+    pub(crate) runtime_environment_key: i32,
+    /// Host address of every anchor, zero if it was not emitted
+    pub(crate) anchors: [usize; ANCHOR_COUNT],
+    /// Keeps the symbols published to host profilers alive, see `register_symbols()`
+    symbol_registration: Option<JitSymbolRegistration>,
+}
+
+/// Location in the text_section which has to be patched when the program is deserialized
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Relocation {
+    /// 64 bit absolute host address
+    Address(usize),
+    /// 32 bit displacement relative to REGISTER_PTR_TO_VM, which depends on the runtime environment key
+    Environment(usize),
+}
+
+/// What a relocated address points to, independent of where it is loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RelocationTarget {
+    /// Offset into the pc_section
+    PcSection(u64),
+    /// Offset into the text_section
+    TextSection(u64),
+    /// Start of the instruction_cost_prefix
+    InstructionCostPrefix,
+    /// Index into rust_functions()
+    RustFunction(u64),
+    /// Key in the function registry of the loader
+    BuiltinFunction(u64),
+}
+
+/// Decodes the host instruction at the start of `bytes`, which is located at `offset` in the text section
+///
+/// Falls back to a single raw byte if the instruction is not understood.
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+fn disassemble_host_instruction(bytes: &[u8], offset: usize) -> (usize, String) {
+    #[cfg(target_arch = "x86_64")]
+    if let Some(result) = crate::x86::decode(bytes, offset) {
+        return result;
+    }
+    #[cfg(target_arch = "aarch64")]
+    if bytes.len() >= 4 {
+        return (4, format!(".inst {:#010x}", LittleEndian::read_u32(bytes)));
+    }
+    (1, format!(".byte {:#04x}", bytes[0]))
 }
 
+/// Magic number and format version at the start of a serialized JitProgram
+const SERIALIZED_PROGRAM_MAGIC: [u8; 8] = *b"RBPFJIT7";
+
+/// Length of the SHA-256 checksum at the end of a serialized JitProgram
+const SERIALIZED_PROGRAM_CHECKSUM_LEN: usize = 32;
+
 impl JitProgram {
-    fn new(pc: usize, code_size: usize) -> Result<Self, EbpfError> {
+    pub(crate) fn new(pc: usize, code_size: usize) -> Result<Self, EbpfError> {
         let page_size = get_system_page_size();
         let pc_loc_table_size = round_to_page_size(pc * 8, page_size);
         let over_allocated_code_size = round_to_page_size(code_size, page_size);
@@ -64,11 +150,19 @@ impl JitProgram {
                     raw.add(pc_loc_table_size),
                     over_allocated_code_size,
                 ),
+                resume_addresses: Vec::new(),
+                return_addresses: Vec::new(),
+                instruction_cost_prefix: Vec::new(),
+                max_checkpoint_interval_cost: 0,
+                relocations: Vec::new(),
+                runtime_environment_key: 0,
+                anchors: [0; ANCHOR_COUNT],
+                symbol_registration: None,
             })
         }
     }
 
-    fn seal(&mut self, text_section_usage: usize) -> Result<(), EbpfError> {
+    pub(crate) fn seal(&mut self, text_section_usage: usize) -> Result<(), EbpfError> {
         if self.page_size == 0 {
             return Ok(());
         }
@@ -77,10 +171,14 @@ impl JitProgram {
         let over_allocated_code_size = round_to_page_size(self.text_section.len(), self.page_size);
         let code_size = round_to_page_size(text_section_usage, self.page_size);
         unsafe {
-            // Fill with debugger traps
+            // Fill with debugger traps (int3 on x86, udf #0 on AArch64)
             std::ptr::write_bytes(
                 raw.add(pc_loc_table_size).add(text_section_usage),
-                0xcc,
+                if cfg!(target_arch = "aarch64") {
+                    0x00
+                } else {
+                    0xcc
+                },
                 code_size - text_section_usage,
             );
             if over_allocated_code_size > code_size {
@@ -91,6 +189,7 @@ impl JitProgram {
             }
             self.text_section =
                 std::slice::from_raw_parts_mut(raw.add(pc_loc_table_size), text_section_usage);
+            invalidate_instruction_cache(self.text_section.as_ptr(), text_section_usage);
             protect_pages(
                 self.pc_section.as_mut_ptr().cast::<u8>(),
                 pc_loc_table_size,
@@ -107,6 +206,35 @@ impl JitProgram {
         vm: &mut EbpfVm<C>,
         registers: [u64; 12],
     ) {
+        // Rebuild the host stack of a suspended execution, see save_call_frames()
+        let mut host_stack = Vec::with_capacity(vm.call_depth as usize * (ebpf::SCRATCH_REGS + 2));
+        for frame in vm.call_frames.iter().take(vm.call_depth as usize) {
+            let Ok(index) = self
+                .return_addresses
+                .binary_search_by_key(&frame.target_pc, |(target_pc, _)| *target_pc as u64)
+            else {
+                vm.program_result = ProgramResult::Err(EbpfError::CallOutsideTextSegment);
+                return;
+            };
+            host_stack.extend_from_slice(&frame.caller_saved_registers);
+            host_stack.push(frame.frame_pointer);
+            host_stack.push(self.return_addresses[index].1 as u64);
+        }
+        let host_target_address = if self.resume_addresses.is_empty() {
+            self.pc_section[registers[11] as usize]
+        } else if let Some(host_target_address) = self.resume_addresses.get(registers[11] as usize)
+        {
+            *host_target_address
+        } else {
+            vm.program_result = ProgramResult::Err(EbpfError::ExecutionOverrun);
+            return;
+        };
+        let instruction_cost_prefix = self
+            .instruction_cost_prefix
+            .get(registers[11] as usize)
+            .copied()
+            .unwrap_or(registers[11]);
+        #[cfg(target_arch = "x86_64")]
         unsafe {
             std::arch::asm!(
                 // RBP and RBX must be saved and restored manually in the current version of rustc and llvm.
@@ -116,7 +244,18 @@ impl JitProgram {
                 "add QWORD PTR [{host_stack_pointer}], -8",
                 // RBP is zeroed out in order not to compromise the runtime environment (RDI) encryption.
                 "xor rbp, rbp",
-                "mov [rsp-8], rax",
+                // Push the return address and the call frames of a resumed execution
+                "lea rbx, [rip + 4f]",
+                "push rbx",
+                "test rcx, rcx",
+                "jz 3f",
+                "2:",
+                "push QWORD PTR [rdx]",
+                "add rdx, 8",
+                "dec rcx",
+                "jnz 2b",
+                "3:",
+                "push rax",
                 "mov rax, [r11 + 0x00]",
                 "mov rsi, [r11 + 0x08]",
                 "mov rdx, [r11 + 0x10]",
@@ -129,19 +268,79 @@ impl JitProgram {
                 "mov r14, [r11 + 0x48]",
                 "mov r15, [r11 + 0x50]",
                 "mov r11, [r11 + 0x58]",
-                "call [rsp-8]",
+                "ret",
+                "4:",
                 "pop rbp",
                 "pop rbx",
                 host_stack_pointer = in(reg) &mut vm.host_stack_pointer,
                 inlateout("rdi") std::ptr::addr_of_mut!(*vm).cast::<u64>().offset(get_runtime_environment_key() as isize) => _,
-                inlateout("r10") (vm.previous_instruction_meter as i64).wrapping_add(registers[11] as i64) => _,
-                inlateout("rax") self.pc_section[registers[11] as usize] => _,
+                inlateout("r10") (vm.previous_instruction_meter as i64).wrapping_add(instruction_cost_prefix as i64) => _,
+                inlateout("rax") host_target_address => _,
                 inlateout("r11") &registers => _,
-                lateout("rsi") _, lateout("rdx") _, lateout("rcx") _, lateout("r8") _,
+                inlateout("rcx") host_stack.len() => _,
+                inlateout("rdx") host_stack.as_ptr() => _,
+                lateout("rsi") _, lateout("r8") _,
                 lateout("r9") _, lateout("r12") _, lateout("r13") _, lateout("r14") _, lateout("r15") _,
                 // lateout("rbp") _, lateout("rbx") _,
             );
         }
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            std::arch::asm!(
+                // X19 and X29 must be saved and restored manually in the current version of rustc and llvm.
+                "stp x19, x29, [sp, #-16]!",
+                // The return address gets a 16 byte slot to keep SP aligned, the epilogue restores SP from host_stack_pointer
+                "adr x9, 4f",
+                "stp x9, xzr, [sp, #-16]!",
+                "mov x9, sp",
+                "str x9, [x15]",
+                // X29 is zeroed out in order not to compromise the runtime environment (X25) encryption.
+                "mov x29, xzr",
+                // Push the call frames of a resumed execution
+                "mov x10, sp",
+                "cbz x11, 3f",
+                "2:",
+                "ldr x9, [x12], #8",
+                "str x9, [x10, #-8]!",
+                "subs x11, x11, #1",
+                "b.ne 2b",
+                "3:",
+                "mov sp, x10",
+                "ldr x19, [x13, #0x00]",
+                "ldp x1, x2, [x13, #0x08]",
+                "ldp x3, x4, [x13, #0x18]",
+                "ldr x5, [x13, #0x28]",
+                "ldp x20, x21, [x13, #0x30]",
+                "ldp x22, x23, [x13, #0x40]",
+                "ldp x24, x27, [x13, #0x50]",
+                "br x14",
+                "4:",
+                "ldp x19, x29, [sp], #16",
+                inlateout("x15") &mut vm.host_stack_pointer => _,
+                inlateout("x25") std::ptr::addr_of_mut!(*vm).cast::<u64>().offset(get_runtime_environment_key() as isize) => _,
+                inlateout("x26") (vm.previous_instruction_meter as i64).wrapping_add(instruction_cost_prefix as i64) => _,
+                inlateout("x14") host_target_address => _,
+                inlateout("x13") &registers => _,
+                inlateout("x11") host_stack.len() => _,
+                inlateout("x12") host_stack.as_ptr() => _,
+                lateout("x20") _, lateout("x21") _, lateout("x22") _, lateout("x23") _,
+                lateout("x24") _, lateout("x27") _, lateout("x28") _,
+                clobber_abi("C"),
+            );
+        }
+        if let ProgramResult::Err(EbpfError::ExecutionSuspended) = vm.program_result {
+            // save_call_frames() stored the host return addresses, translate them back to BPF
+            for frame in vm.call_frames.iter_mut().take(vm.call_depth as usize) {
+                if let Ok(index) = self
+                    .return_addresses
+                    .binary_search_by_key(&frame.target_pc, |(_, return_address)| {
+                        *return_address as u64
+                    })
+                {
+                    frame.target_pc = self.return_addresses[index].0 as u64;
+                }
+            }
+        }
     }
 
     pub fn machine_code_length(&self) -> usize {
@@ -153,10 +352,670 @@ impl JitProgram {
         let code_size = round_to_page_size(self.text_section.len(), self.page_size);
         pc_loc_table_size + code_size
     }
+
+    /// Publishes the functions and anchors to host profilers and debuggers until the program is dropped
+    ///
+    /// Every function of the function registry extends up to the next function or anchor.
+    pub(crate) fn register_symbols<C: ContextObject>(&mut self, executable: &Executable<C>) {
+        let text_section_base = self.text_section.as_ptr() as usize;
+        let text_section_range = text_section_base..text_section_base + self.text_section.len();
+        let mut symbol_starts = BTreeMap::new();
+        for (anchor, address) in self.anchors.iter().enumerate() {
+            if text_section_range.contains(address) {
+                symbol_starts.insert(*address, format!("solana_rbpf::{}", ANCHOR_NAMES[anchor]));
+            }
+        }
+        for (_key, (name, pc)) in executable.get_function_registry().iter() {
+            let Some(address) = self.pc_section.get(pc) else {
+                continue;
+            };
+            if text_section_range.contains(address) && !self.anchors.contains(address) {
+                let name = if name.is_empty() {
+                    format!("function_{pc}")
+                } else {
+                    String::from_utf8_lossy(name).to_string()
+                };
+                symbol_starts.insert(*address, name);
+            }
+        }
+        let symbol_ends = symbol_starts
+            .keys()
+            .skip(1)
+            .copied()
+            .chain(std::iter::once(text_section_range.end));
+        let symbols = symbol_starts
+            .iter()
+            .zip(symbol_ends)
+            .map(|((start, name), end)| JitSymbol {
+                name: name.clone(),
+                range: *start..end,
+            })
+            .collect::<Vec<_>>();
+        self.symbol_registration = Some(JitSymbolRegistration::new(text_section_range, &symbols));
+    }
+
+    /// Writes the machine code as a listing which is annotated with the anchors and the BPF instructions
+    ///
+    /// `annotate` is called with the pc of each BPF instruction before the host instructions generated for it.
+    /// Instructions which can not be jumped to have no entry in the pc_section, like the second slot of `lddw` or
+    /// (with static syscalls, unless resumable execution is enabled) everything but the start of a function.
+    /// These are annotated together with the preceding instruction.
+    pub fn disassemble<W: Write>(
+        &self,
+        output: &mut W,
+        mut annotate: impl FnMut(&mut W, usize) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let text_section_base = self.text_section.as_ptr() as usize;
+        let text_section_range = text_section_base..text_section_base + self.text_section.len();
+        let mut labels = BTreeMap::<usize, (Vec<usize>, Vec<usize>)>::new();
+        for (anchor, address) in self.anchors.iter().enumerate() {
+            if text_section_range.contains(address) {
+                labels
+                    .entry(address - text_section_base)
+                    .or_default()
+                    .0
+                    .push(anchor);
+            }
+        }
+        let addresses = if self.resume_addresses.is_empty() {
+            &self.pc_section[..]
+        } else {
+            &self.resume_addresses[..self.pc_section.len()]
+        };
+        let mut offsets = Vec::with_capacity(addresses.len());
+        let mut last_offset = None;
+        for address in addresses.iter() {
+            if text_section_range.contains(address) && !self.anchors.contains(address) {
+                last_offset = Some(address - text_section_base);
+            }
+            offsets.push(last_offset);
+        }
+        if let Some(first_offset) = offsets.iter().find_map(|offset| *offset) {
+            for (pc, offset) in offsets.into_iter().enumerate() {
+                labels
+                    .entry(offset.unwrap_or(first_offset))
+                    .or_default()
+                    .1
+                    .push(pc);
+            }
+        }
+        let mut offset = 0;
+        while offset < self.text_section.len() {
+            if let Some((anchors, pcs)) = labels.get(&offset) {
+                for anchor in anchors {
+                    writeln!(output, "<{}>:", ANCHOR_NAMES[*anchor])?;
+                }
+                for pc in pcs {
+                    annotate(output, *pc)?;
+                }
+            }
+            let end = labels
+                .range(offset + 1..)
+                .next()
+                .map(|(end, _)| *end)
+                .unwrap_or(self.text_section.len());
+            let (length, text) =
+                disassemble_host_instruction(&self.text_section[offset..end], offset);
+            let bytes = self.text_section[offset..offset + length]
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            writeln!(output, "    {offset:8x}:  {bytes:<32} {text}")?;
+            offset += length;
+        }
+        Ok(())
+    }
+
+    /// Serializes the machine code so that it can be loaded by `JitProgram::deserialize()` in another process
+    ///
+    /// The bytes end with a SHA-256 checksum of everything before it.
+    pub fn serialize<C: ContextObject>(
+        &self,
+        executable: &Executable<C>,
+    ) -> Result<Vec<u8>, EbpfError> {
+        let text_section_base = self.text_section.as_ptr() as usize;
+        let text_section_offset = |address: usize| {
+            if address == 0 {
+                u64::MAX
+            } else {
+                address.wrapping_sub(text_section_base) as u64
+            }
+        };
+        let mut bytes = SERIALIZED_PROGRAM_MAGIC.to_vec();
+        bytes.extend_from_slice(&cache_key(executable));
+        for value in [
+            self.runtime_environment_key as i64 as u64,
+            self.max_checkpoint_interval_cost,
+            self.text_section.len() as u64,
+            self.pc_section.len() as u64,
+            self.resume_addresses.len() as u64,
+            self.return_addresses.len() as u64,
+            self.instruction_cost_prefix.len() as u64,
+            self.relocations.len() as u64,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(self.text_section);
+        for address in self
+            .pc_section
+            .iter()
+            .chain(self.resume_addresses.iter())
+            .chain(self.anchors.iter())
+        {
+            bytes.extend_from_slice(&text_section_offset(*address).to_le_bytes());
+        }
+        for (target_pc, return_address) in self.return_addresses.iter() {
+            bytes.extend_from_slice(&(*target_pc as u64).to_le_bytes());
+            bytes.extend_from_slice(&text_section_offset(*return_address).to_le_bytes());
+        }
+        for cost in self.instruction_cost_prefix.iter() {
+            bytes.extend_from_slice(&cost.to_le_bytes());
+        }
+        let rust_functions = rust_functions::<C>();
+        let function_registry = executable
+            .get_loader()
+            .get_function_registry(executable.get_sbpf_version());
+        for relocation in self.relocations.iter() {
+            let (tag, offset, value) = match *relocation {
+                Relocation::Environment(offset) => (0, offset, 0),
+                Relocation::Address(offset) => {
+                    let address = unsafe {
+                        ptr::read_unaligned(self.text_section.as_ptr().add(offset).cast::<u64>())
+                    } as usize;
+                    let pc_section_base = self.pc_section.as_ptr() as usize;
+                    let target = if address == self.instruction_cost_prefix.as_ptr() as usize {
+                        RelocationTarget::InstructionCostPrefix
+                    } else if (pc_section_base..pc_section_base + self.pc_section.len() * 8)
+                        .contains(&address)
+                    {
+                        RelocationTarget::PcSection((address - pc_section_base) as u64)
+                    } else if (text_section_base..text_section_base + self.text_section.len())
+                        .contains(&address)
+                    {
+                        RelocationTarget::TextSection((address - text_section_base) as u64)
+                    } else if let Some(index) = rust_functions
+                        .iter()
+                        .position(|function| *function == address)
+                    {
+                        RelocationTarget::RustFunction(index as u64)
+                    } else if let Some((key, _)) = function_registry
+                        .iter()
+                        .find(|(_key, (_name, function))| *function as usize == address)
+                    {
+                        RelocationTarget::BuiltinFunction(key as u64)
+                    } else {
+                        return Err(EbpfError::JitCacheError(format!(
+                            "unknown address {address:#x} at offset {offset:#x}"
+                        )));
+                    };
+                    match target {
+                        RelocationTarget::PcSection(value) => (1, offset, value),
+                        RelocationTarget::TextSection(value) => (2, offset, value),
+                        RelocationTarget::InstructionCostPrefix => (3, offset, 0),
+                        RelocationTarget::RustFunction(value) => (4, offset, value),
+                        RelocationTarget::BuiltinFunction(value) => (5, offset, value),
+                    }
+                }
+            };
+            for value in [tag, offset as u64, value] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        let checksum = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&checksum);
+        Ok(bytes)
+    }
+
+    /// Loads the machine code serialized by `JitProgram::serialize()` and patches its relocations
+    ///
+    /// Fails if the checksum does not match, so that a corrupted entry is never sealed as code.
+    pub fn deserialize<C: ContextObject>(
+        executable: &Executable<C>,
+        bytes: &[u8],
+    ) -> Result<Self, EbpfError> {
+        let (bytes, checksum) = bytes.split_at(
+            bytes
+                .len()
+                .checked_sub(SERIALIZED_PROGRAM_CHECKSUM_LEN)
+                .ok_or_else(|| EbpfError::JitCacheError("unexpected end".to_string()))?,
+        );
+        let mut reader = SerializedProgramReader { bytes, offset: 0 };
+        if reader.read_bytes(SERIALIZED_PROGRAM_MAGIC.len())? != SERIALIZED_PROGRAM_MAGIC {
+            return Err(EbpfError::JitCacheError("unknown format".to_string()));
+        }
+        if reader.read_bytes(32)? != cache_key(executable) {
+            return Err(EbpfError::JitCacheError("key mismatch".to_string()));
+        }
+        if Sha256::digest(bytes).as_slice() != checksum {
+            return Err(EbpfError::JitCacheError("checksum mismatch".to_string()));
+        }
+        let runtime_environment_key = reader.read_u64()? as i64 as i32;
+        let max_checkpoint_interval_cost = reader.read_u64()?;
+        let text_section_len = reader.read_usize()?;
+        let pc_section_len = reader.read_usize()?;
+        let resume_addresses_len = reader.read_usize()?;
+        let return_addresses_len = reader.read_usize()?;
+        let instruction_cost_prefix_len = reader.read_usize()?;
+        let relocations_len = reader.read_usize()?;
+        let text_section = reader.read_bytes(text_section_len)?;
+        let mut result = Self::new(pc_section_len, text_section_len)?;
+        result.max_checkpoint_interval_cost = max_checkpoint_interval_cost;
+        result.text_section[0..text_section_len].copy_from_slice(text_section);
+        let text_section_base = result.text_section.as_ptr() as usize;
+        let read_address = |reader: &mut SerializedProgramReader| {
+            let offset = reader.read_u64()?;
+            if offset == u64::MAX {
+                Ok(0)
+            } else if offset < text_section_len as u64 {
+                Ok(text_section_base + offset as usize)
+            } else {
+                Err(EbpfError::JitCacheError(format!(
+                    "invalid offset {offset:#x}"
+                )))
+            }
+        };
+        for index in 0..pc_section_len {
+            result.pc_section[index] = read_address(&mut reader)?;
+        }
+        for _ in 0..resume_addresses_len {
+            let address = read_address(&mut reader)?;
+            result.resume_addresses.push(address);
+        }
+        for index in 0..ANCHOR_COUNT {
+            result.anchors[index] = read_address(&mut reader)?;
+        }
+        for _ in 0..return_addresses_len {
+            let target_pc = reader.read_usize()?;
+            let return_address = read_address(&mut reader)?;
+            result.return_addresses.push((target_pc, return_address));
+        }
+        for _ in 0..instruction_cost_prefix_len {
+            let cost = reader.read_u64()?;
+            result.instruction_cost_prefix.push(cost);
+        }
+        let rust_functions = rust_functions::<C>();
+        let function_registry = executable
+            .get_loader()
+            .get_function_registry(executable.get_sbpf_version());
+        let current_runtime_environment_key = get_runtime_environment_key();
+        for _ in 0..relocations_len {
+            let tag = reader.read_u64()?;
+            let offset = reader.read_usize()?;
+            let value = reader.read_u64()?;
+            if tag == 0 {
+                if offset.saturating_add(4) > text_section_len {
+                    return Err(EbpfError::JitCacheError(format!(
+                        "invalid offset {offset:#x}"
+                    )));
+                }
+                unsafe {
+                    let location = result.text_section.as_mut_ptr().add(offset).cast::<i32>();
+                    let displacement =
+                        ptr::read_unaligned(location).wrapping_add(8i32.wrapping_mul(
+                            runtime_environment_key.wrapping_sub(current_runtime_environment_key),
+                        ));
+                    ptr::write_unaligned(location, displacement);
+                }
+                result.relocations.push(Relocation::Environment(offset));
+                continue;
+            }
+            let address = match tag {
+                1 if value < (pc_section_len * 8) as u64 => {
+                    result.pc_section.as_ptr() as usize + value as usize
+                }
+                2 if value < text_section_len as u64 => text_section_base + value as usize,
+                3 => result.instruction_cost_prefix.as_ptr() as usize,
+                4 if value < rust_functions.len() as u64 => rust_functions[value as usize],
+                5 => function_registry
+                    .lookup_by_key(value as u32)
+                    .map(|(_name, function)| function as usize)
+                    .ok_or_else(|| {
+                        EbpfError::JitCacheError(format!(
+                            "builtin function {value:#x} is not registered"
+                        ))
+                    })?,
+                _ => {
+                    return Err(EbpfError::JitCacheError(format!(
+                        "invalid relocation {tag} {value:#x}"
+                    )))
+                }
+            };
+            if offset.saturating_add(8) > text_section_len {
+                return Err(EbpfError::JitCacheError(format!(
+                    "invalid offset {offset:#x}"
+                )));
+            }
+            unsafe {
+                ptr::write_unaligned(
+                    result.text_section.as_mut_ptr().add(offset).cast::<u64>(),
+                    address as u64,
+                );
+            }
+            result.relocations.push(Relocation::Address(offset));
+        }
+        if reader.offset != bytes.len() {
+            return Err(EbpfError::JitCacheError("trailing bytes".to_string()));
+        }
+        result.runtime_environment_key = current_runtime_environment_key;
+        result.seal(text_section_len)?;
+        Ok(result)
+    }
+}
+
+/// Reads the fields of a serialized JitProgram
+struct SerializedProgramReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SerializedProgramReader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], EbpfError> {
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or_else(|| EbpfError::JitCacheError("unexpected end".to_string()))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, EbpfError> {
+        Ok(LittleEndian::read_u64(
+            self.read_bytes(mem::size_of::<u64>())?,
+        ))
+    }
+
+    fn read_usize(&mut self) -> Result<usize, EbpfError> {
+        // The JIT only supports 64 bit targets
+        Ok(self.read_u64()? as usize)
+    }
+}
+
+/// Identifies the machine code of an executable, used to look it up in a cache
+///
+/// A SHA-256 digest over everything the compiler depends on: the build (crate and compiler version,
+/// host architecture and the offsets of the structs the machine code accesses), the text bytes,
+/// the `Config`, the SBPF version and the function registries, but not the addresses of the builtin
+/// functions which are relocated. Variable length inputs are prefixed by their length.
+pub(crate) fn cache_key<C: ContextObject>(executable: &Executable<C>) -> [u8; 32] {
+    use crate::memory_region::jit_layout;
+    let mut hasher = Sha256::new();
+    let mut hash_bytes = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+    hash_bytes(env!("CARGO_PKG_VERSION").as_bytes());
+    hash_bytes(env!("RBPF_RUSTC_VERSION").as_bytes());
+    hash_bytes(std::env::consts::ARCH.as_bytes());
+    hash_bytes(std::any::type_name::<C>().as_bytes());
+    for layout in [
+        mem::offset_of!(EbpfVm<C>, host_stack_pointer),
+        mem::offset_of!(EbpfVm<C>, call_depth),
+        mem::offset_of!(EbpfVm<C>, context_object_pointer),
+        mem::offset_of!(EbpfVm<C>, previous_instruction_meter),
+        mem::offset_of!(EbpfVm<C>, due_insn_count),
+        mem::offset_of!(EbpfVm<C>, stopwatch_numerator),
+        mem::offset_of!(EbpfVm<C>, stopwatch_denominator),
+        mem::offset_of!(EbpfVm<C>, cancellation_countdown),
+        mem::offset_of!(EbpfVm<C>, stack_host_address),
+        mem::offset_of!(EbpfVm<C>, registers),
+        mem::offset_of!(EbpfVm<C>, program_result),
+        mem::offset_of!(EbpfVm<C>, memory_mapping),
+        mem::size_of::<ProgramResult>(),
+        jit_layout::MAPPING_REGIONS_ADDRESS,
+        jit_layout::MAPPING_REGIONS_COUNT,
+        jit_layout::REGION_SIZE,
+        jit_layout::REGION_HOST_ADDR,
+        jit_layout::REGION_VM_ADDR,
+        jit_layout::REGION_LEN,
+        jit_layout::REGION_VM_GAP_SHIFT,
+        jit_layout::REGION_STATE,
+        jit_layout::REGION_WRITTEN_START,
+        jit_layout::REGION_WRITTEN_END,
+        jit_layout::REGION_DIRTY_PAGES_ADDRESS,
+        jit_layout::REGION_PROTECTED_RANGES_ADDRESS,
+    ] {
+        hash_bytes(&(layout as u64).to_le_bytes());
+    }
+    hash_bytes(format!("{:?}", executable.get_config()).as_bytes());
+    hash_bytes(format!("{:?}", executable.get_sbpf_version()).as_bytes());
+    let (text_vaddr, text_bytes) = executable.get_text_bytes();
+    hash_bytes(&text_vaddr.to_le_bytes());
+    hash_bytes(text_bytes);
+    hash_bytes(&(executable.get_entrypoint_instruction_offset() as u64).to_le_bytes());
+    for (key, (name, pc)) in executable.get_function_registry().iter() {
+        hash_bytes(&key.to_le_bytes());
+        hash_bytes(name);
+        hash_bytes(&(pc as u64).to_le_bytes());
+    }
+    for (key, (name, _function)) in executable
+        .get_loader()
+        .get_function_registry(executable.get_sbpf_version())
+        .iter()
+    {
+        hash_bytes(&key.to_le_bytes());
+        hash_bytes(name);
+    }
+    hasher.finalize().into()
+}
+
+/// The Rust functions the machine code calls, relocated by their index when a program is deserialized
+pub(crate) fn rust_functions<C: ContextObject>() -> [usize; 24] {
+    [
+        C::trace as *const u8 as usize,
+        trace_call::<C> as *const u8 as usize,
+        trace_return::<C> as *const u8 as usize,
+        stopwatch_result as *const u8 as usize,
+        save_call_frames::<C> as *const u8 as usize,
+        pc_from_instruction_cost as *const u8 as usize,
+        poll_cancellation::<C> as *const u8 as usize,
+        traced_syscall::<C> as *const u8 as usize,
+        traced_load::<C, u8> as *const u8 as usize,
+        traced_load::<C, u16> as *const u8 as usize,
+        traced_load::<C, u32> as *const u8 as usize,
+        traced_load::<C, u64> as *const u8 as usize,
+        traced_store::<C, u8> as *const u8 as usize,
+        traced_store::<C, u16> as *const u8 as usize,
+        traced_store::<C, u32> as *const u8 as usize,
+        traced_store::<C, u64> as *const u8 as usize,
+        MemoryMapping::handled_load::<u8> as *const u8 as usize,
+        MemoryMapping::handled_load::<u16> as *const u8 as usize,
+        MemoryMapping::handled_load::<u32> as *const u8 as usize,
+        MemoryMapping::handled_load::<u64> as *const u8 as usize,
+        MemoryMapping::handled_store::<u8> as *const u8 as usize,
+        MemoryMapping::handled_store::<u16> as *const u8 as usize,
+        MemoryMapping::handled_store::<u32> as *const u8 as usize,
+        MemoryMapping::handled_store::<u64> as *const u8 as usize,
+    ]
+}
+
+/// Prints the result of the stop watch, called from ANCHOR_EPILOGUE
+fn stopwatch_result(numerator: u64, denominator: u64) {
+    println!(
+        "Stop watch: {} / {} = {}",
+        numerator,
+        denominator,
+        if denominator == 0 {
+            0.0
+        } else {
+            numerator as f64 / denominator as f64
+        }
+    );
+}
+
+/// Copies the call frames of BPF to BPF calls from the host stack into `EbpfVm::call_frames`
+///
+/// Instead of the `target_pc` this stores the host return address, which is translated by `JitProgram::invoke()`.
+pub(crate) fn save_call_frames<C: ContextObject>(vm: *mut EbpfVm<C>) {
+    let vm = unsafe { &mut *decrypt_vm_pointer(vm) };
+    let mut host_stack = vm.host_stack_pointer;
+    for frame in vm.call_frames.iter_mut().take(vm.call_depth as usize) {
+        unsafe {
+            for register in frame.caller_saved_registers.iter_mut() {
+                host_stack = host_stack.sub(1);
+                *register = host_stack.read();
+            }
+            host_stack = host_stack.sub(1);
+            frame.frame_pointer = host_stack.read();
+            host_stack = host_stack.sub(1);
+            frame.target_pc = host_stack.read();
+        }
+    }
+}
+
+/// Finds the pc of the instruction during which the instruction meter ran out
+///
+/// This is the inverse of `JitProgram::instruction_cost_prefix`, given its pointer and length.
+pub(crate) fn pc_from_instruction_cost(
+    instruction_cost_prefix: *const u64,
+    len: usize,
+    instruction_meter: u64,
+) -> u64 {
+    let instruction_cost_prefix =
+        unsafe { std::slice::from_raw_parts(instruction_cost_prefix, len) };
+    instruction_cost_prefix
+        .partition_point(|cost| *cost <= instruction_meter)
+        .saturating_sub(1) as u64
+}
+
+/// Called from ANCHOR_POLL_CANCELLATION_SLOW, sets the ProgramResult if the execution was cancelled
+pub(crate) fn poll_cancellation<C: ContextObject>(vm: *mut EbpfVm<C>) {
+    let vm = unsafe { &mut *decrypt_vm_pointer(vm) };
+    if vm.poll_cancellation() {
+        vm.program_result = ProgramResult::Err(EbpfError::ExecutionCancelled);
+    }
+}
+
+/// Undoes the encryption of REGISTER_PTR_TO_VM
+pub(crate) unsafe fn decrypt_vm_pointer<C: ContextObject>(vm: *mut EbpfVm<C>) -> *mut EbpfVm<C> {
+    vm.cast::<u64>()
+        .offset(-(get_runtime_environment_key() as isize))
+        .cast::<EbpfVm<C>>()
+}
+
+/// MemoryMapping::handled_load() which reports the access to the ContextObject, used for instruction tracing, execution statistics and watchpoints
+///
+/// The pc is taken from `registers[11]`, which ANCHOR_TRACE or the access itself keeps up to date.
+pub(crate) fn traced_load<C: ContextObject, T: Pod + Into<u64>>(vm: *mut EbpfVm<C>, vm_addr: u64) {
+    let vm = unsafe { &mut *decrypt_vm_pointer(vm) };
+    vm.program_result = vm.memory_mapping.handled_load::<T>(vm_addr);
+    if let ProgramResult::Ok(value) = vm.program_result {
+        if let Err(error) = vm.trace_memory_access(
+            vm.registers[11],
+            AccessType::Load,
+            vm_addr,
+            mem::size_of::<T>() as u64,
+            value,
+        ) {
+            vm.program_result = ProgramResult::Err(error);
+        }
+    }
+}
+
+/// MemoryMapping::handled_store() which reports the access to the ContextObject, used for instruction tracing, execution statistics and watchpoints
+pub(crate) fn traced_store<C: ContextObject, T: Pod + Copy + Into<u64>>(
+    vm: *mut EbpfVm<C>,
+    value: T,
+    vm_addr: u64,
+) {
+    let vm = unsafe { &mut *decrypt_vm_pointer(vm) };
+    if let Err(error) = vm.watch_store(
+        vm.registers[11],
+        vm_addr,
+        mem::size_of::<T>() as u64,
+        value.into(),
+    ) {
+        vm.program_result = ProgramResult::Err(error);
+        return;
+    }
+    vm.program_result = vm.memory_mapping.handled_store::<T>(value, vm_addr);
+    if vm.program_result.is_ok() {
+        if let Err(error) = vm.trace_memory_access(
+            vm.registers[11],
+            AccessType::Store,
+            vm_addr,
+            mem::size_of::<T>() as u64,
+            value.into(),
+        ) {
+            vm.program_result = ProgramResult::Err(error);
+        }
+    }
+}
+
+/// Invokes a syscall and reports its entry and exit to the ContextObject, used for instruction tracing and execution statistics
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn traced_syscall<C: ContextObject>(
+    vm: *mut EbpfVm<C>,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+    function: BuiltinFunction<C>,
+) {
+    let env = unsafe { &mut *decrypt_vm_pointer(vm) };
+    let enable_instruction_tracing = env.loader.get_config().enable_instruction_tracing;
+    let pc = env.registers[11];
+    if enable_instruction_tracing {
+        env.context_object_pointer
+            .trace_event(&TraceEvent::SyscallEntry {
+                pc,
+                arguments: [arg1, arg2, arg3, arg4, arg5],
+            });
+    }
+    let remaining_before_syscall = env.remaining_before_syscall();
+    function(vm, arg1, arg2, arg3, arg4, arg5);
+    let env = unsafe { &mut *decrypt_vm_pointer(vm) };
+    env.record_syscall(function, remaining_before_syscall);
+    if let (true, ProgramResult::Ok(result)) = (enable_instruction_tracing, &env.program_result) {
+        env.context_object_pointer
+            .trace_event(&TraceEvent::SyscallExit {
+                pc,
+                result: *result,
+            });
+    }
+}
+
+/// Reports a BPF to BPF call to the ContextObject and the ExecutionStats
+///
+/// `remaining` is the instruction meter after the call instruction.
+pub(crate) fn trace_call<C: ContextObject>(vm: *mut EbpfVm<C>, target_pc: u64, remaining: u64) {
+    let vm = unsafe { &mut *decrypt_vm_pointer(vm) };
+    if let Some(execution_stats) = vm.execution_stats.as_mut() {
+        execution_stats.call(target_pc as usize, remaining);
+    }
+    if vm.loader.get_config().enable_instruction_tracing {
+        let event = TraceEvent::Call {
+            pc: vm.registers[11],
+            target_pc,
+            call_depth: vm.call_depth,
+        };
+        vm.context_object_pointer.trace_event(&event);
+    }
+}
+
+/// Reports a return from a BPF to BPF call to the ContextObject and the ExecutionStats
+///
+/// `remaining` is the instruction meter after the exit instruction.
+pub(crate) fn trace_return<C: ContextObject>(vm: *mut EbpfVm<C>, remaining: u64) {
+    let vm = unsafe { &mut *decrypt_vm_pointer(vm) };
+    if let Some(execution_stats) = vm.execution_stats.as_mut() {
+        execution_stats.ret(remaining);
+    }
+    if vm.loader.get_config().enable_instruction_tracing {
+        let event = TraceEvent::Return {
+            pc: vm.registers[11],
+            call_depth: vm.call_depth,
+        };
+        vm.context_object_pointer.trace_event(&event);
+    }
 }
 
 impl Drop for JitProgram {
     fn drop(&mut self) {
+        // Unpublish the symbols before the machine code is freed
+        self.symbol_registration = None;
         let pc_loc_table_size = round_to_page_size(self.pc_section.len() * 8, self.page_size);
         let code_size = round_to_page_size(self.text_section.len(), self.page_size);
         if pc_loc_table_size + code_size > 0 {
@@ -182,26 +1041,173 @@ impl PartialEq for JitProgram {
     }
 }
 
+/// Whether the JIT skips the bounds checks which static analysis proves to be redundant
+///
+/// Tracing, execution stats and watchpoints need to see every access, so they keep all bounds checks.
+pub(crate) fn eliminates_bounds_checks(config: &Config) -> bool {
+    config.eliminate_redundant_bounds_checks
+        && config.enable_address_translation
+        && !config.enable_instruction_tracing
+        && !config.enable_execution_stats
+        && !config.enable_memory_watchpoints
+}
+
+/// Whether the JIT may access the current stack frame without address translation
+///
+/// See [BoundsCheckProof::StackFrame] and `EbpfVm::prepare_stack_frames()`.
+pub(crate) fn accesses_stack_frames_directly(config: &Config, sbpf_version: SBPFVersion) -> bool {
+    eliminates_bounds_checks(config) && !sbpf_version.dynamic_stack_frames()
+}
+
+/// Whether the JIT validates the instruction meter for entire runs of instructions
+///
+/// See `Config::enable_exact_instruction_meter` and [exact_instruction_meter_checkpoints].
+pub(crate) fn meters_exactly(config: &Config) -> bool {
+    config.enable_exact_instruction_meter && config.enable_instruction_meter
+}
+
+/// Sorted pcs at which the JIT validates the instruction meter for the run of instructions up to the next one
+///
+/// Function starts, jump targets and the instructions after jumps, calls, syscalls and exits are
+/// checkpoints. Thus the execution can only enter a run at its start and only leave it at its end,
+/// except for throwing and for `callx` without static syscalls, which can target any instruction.
+/// `pc_count` is always the last checkpoint.
+pub(crate) fn exact_instruction_meter_checkpoints<C: ContextObject>(
+    executable: &Executable<C>,
+    pc_count: usize,
+) -> Vec<usize> {
+    let (_program_vm_addr, program) = executable.get_text_bytes();
+    let sbpf_version = executable.get_sbpf_version();
+    let mut checkpoints = BTreeSet::new();
+    checkpoints.insert(0);
+    checkpoints.insert(pc_count);
+    checkpoints.insert(executable.get_entrypoint_instruction_offset());
+    checkpoints.extend(
+        executable
+            .get_function_registry()
+            .iter()
+            .map(|(_key, (_name, pc))| pc),
+    );
+    let mut pc = 0;
+    while (pc + 1) * ebpf::INSN_SIZE <= program.len() {
+        let insn = ebpf::get_insn_unchecked(program, pc);
+        if insn.opc == ebpf::LD_DW_IMM && !sbpf_version.disable_lddw() {
+            pc += 2;
+            continue;
+        }
+        if insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_JMP {
+            checkpoints.insert(pc + 1);
+            if !matches!(
+                insn.opc,
+                ebpf::CALL_IMM | ebpf::CALL_REG | ebpf::EXIT | ebpf::RETURN
+            ) {
+                checkpoints.insert((pc as isize + insn.off as isize + 1) as usize);
+            }
+        }
+        pc += 1;
+    }
+    checkpoints
+        .into_iter()
+        .take_while(|checkpoint| *checkpoint <= pc_count)
+        .collect()
+}
+
+/// Cost of the most expensive run of instructions between two consecutive checkpoints
+///
+/// `instruction_cost_prefix` is empty if every instruction costs one unit, see `JitProgram::instruction_cost_prefix`.
+pub(crate) fn max_checkpoint_interval_cost(
+    checkpoints: &[usize],
+    instruction_cost_prefix: &[u64],
+) -> u64 {
+    let accumulated_cost = |pc: usize| {
+        instruction_cost_prefix
+            .get(pc)
+            .copied()
+            .unwrap_or(pc as u64)
+    };
+    checkpoints
+        .windows(2)
+        .map(|interval| accumulated_cost(interval[1]) - accumulated_cost(interval[0]))
+        .max()
+        .unwrap_or(0)
+}
+
 // Used to define subroutines and then call them
 // See JitCompiler::set_anchor() and JitCompiler::relative_to_anchor()
-const ANCHOR_TRACE: usize = 0;
-const ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS: usize = 1;
-const ANCHOR_EPILOGUE: usize = 2;
-const ANCHOR_THROW_EXCEPTION_UNCHECKED: usize = 3;
-const ANCHOR_EXIT: usize = 4;
-const ANCHOR_THROW_EXCEPTION: usize = 5;
-const ANCHOR_CALL_DEPTH_EXCEEDED: usize = 6;
-const ANCHOR_CALL_OUTSIDE_TEXT_SEGMENT: usize = 7;
-const ANCHOR_DIV_BY_ZERO: usize = 8;
-const ANCHOR_DIV_OVERFLOW: usize = 9;
-const ANCHOR_CALL_UNSUPPORTED_INSTRUCTION: usize = 10;
-const ANCHOR_EXTERNAL_FUNCTION_CALL: usize = 11;
-const ANCHOR_INTERNAL_FUNCTION_CALL_PROLOGUE: usize = 12;
-const ANCHOR_INTERNAL_FUNCTION_CALL_REG: usize = 13;
-const ANCHOR_CALL_REG_UNSUPPORTED_INSTRUCTION: usize = 14;
-const ANCHOR_TRANSLATE_MEMORY_ADDRESS: usize = 21;
-const ANCHOR_COUNT: usize = 30; // Update me when adding or removing anchors
+pub(crate) const ANCHOR_TRACE: usize = 0;
+pub(crate) const ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS: usize = 1;
+pub(crate) const ANCHOR_EPILOGUE: usize = 2;
+pub(crate) const ANCHOR_THROW_EXCEPTION_UNCHECKED: usize = 3;
+pub(crate) const ANCHOR_EXIT: usize = 4;
+pub(crate) const ANCHOR_THROW_EXCEPTION: usize = 5;
+pub(crate) const ANCHOR_CALL_DEPTH_EXCEEDED: usize = 6;
+pub(crate) const ANCHOR_CALL_OUTSIDE_TEXT_SEGMENT: usize = 7;
+pub(crate) const ANCHOR_DIV_BY_ZERO: usize = 8;
+pub(crate) const ANCHOR_DIV_OVERFLOW: usize = 9;
+pub(crate) const ANCHOR_CALL_UNSUPPORTED_INSTRUCTION: usize = 10;
+pub(crate) const ANCHOR_EXTERNAL_FUNCTION_CALL: usize = 11;
+pub(crate) const ANCHOR_INTERNAL_FUNCTION_CALL_PROLOGUE: usize = 12;
+pub(crate) const ANCHOR_INTERNAL_FUNCTION_CALL_REG: usize = 13;
+pub(crate) const ANCHOR_CALL_REG_UNSUPPORTED_INSTRUCTION: usize = 14;
+pub(crate) const ANCHOR_TRACE_CALL: usize = 15;
+pub(crate) const ANCHOR_TRACE_RETURN: usize = 16;
+pub(crate) const ANCHOR_POLL_CANCELLATION_SLOW: usize = 17;
+pub(crate) const ANCHOR_POLL_CANCELLATION: usize = 18;
+pub(crate) const ANCHOR_TRANSLATE_MEMORY_ADDRESS: usize = 21;
+pub(crate) const ANCHOR_TRANSLATE_MEMORY_ADDRESS_SLOW: usize = 29;
+pub(crate) const ANCHOR_TRANSLATE_MEMORY_ADDRESS_UNCHECKED: usize = 37;
+pub(crate) const ANCHOR_COUNT: usize = 45; // Update me when adding or removing anchors
+
+/// Labels of the anchors in `JitProgram::disassemble()`, empty for unused indices
+pub(crate) const ANCHOR_NAMES: [&str; ANCHOR_COUNT] = [
+    "trace",
+    "throw_exceeded_max_instructions",
+    "epilogue",
+    "throw_exception_unchecked",
+    "exit",
+    "throw_exception",
+    "call_depth_exceeded",
+    "call_outside_text_segment",
+    "div_by_zero",
+    "div_overflow",
+    "call_unsupported_instruction",
+    "external_function_call",
+    "internal_function_call_prologue",
+    "internal_function_call_reg",
+    "call_reg_unsupported_instruction",
+    "trace_call",
+    "trace_return",
+    "poll_cancellation_slow",
+    "poll_cancellation",
+    "",
+    "",
+    "translate_load_1",
+    "translate_load_2",
+    "translate_load_4",
+    "translate_load_8",
+    "translate_store_1",
+    "translate_store_2",
+    "translate_store_4",
+    "translate_store_8",
+    "translate_load_1_slow",
+    "translate_load_2_slow",
+    "translate_load_4_slow",
+    "translate_load_8_slow",
+    "translate_store_1_slow",
+    "translate_store_2_slow",
+    "translate_store_4_slow",
+    "translate_store_8_slow",
+    "translate_load_1_unchecked",
+    "translate_load_2_unchecked",
+    "translate_load_4_unchecked",
+    "translate_load_8_unchecked",
+    "translate_store_1_unchecked",
+    "translate_store_2_unchecked",
+    "translate_store_4_unchecked",
+    "translate_store_8_unchecked",
+];
 
+#[cfg(target_arch = "x86_64")]
 const REGISTER_MAP: [u8; 11] = [
     CALLER_SAVED_REGISTERS[0], // RAX
     ARGUMENT_REGISTERS[1],     // RSI
@@ -217,14 +1223,18 @@ const REGISTER_MAP: [u8; 11] = [
 ];
 
 /// RDI: Used together with slot_in_vm()
-const REGISTER_PTR_TO_VM: u8 = ARGUMENT_REGISTERS[0];
+#[cfg(target_arch = "x86_64")]
+pub(crate) const REGISTER_PTR_TO_VM: u8 = ARGUMENT_REGISTERS[0];
 /// R10: Program counter limit
+#[cfg(target_arch = "x86_64")]
 const REGISTER_INSTRUCTION_METER: u8 = CALLER_SAVED_REGISTERS[7];
 /// R11: Scratch register
+#[cfg(target_arch = "x86_64")]
 const REGISTER_SCRATCH: u8 = CALLER_SAVED_REGISTERS[8];
 
 #[derive(Copy, Clone, Debug)]
 pub enum OperandSize {
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
     S0 = 0,
     S8 = 8,
     S16 = 16,
@@ -232,37 +1242,43 @@ pub enum OperandSize {
     S64 = 64,
 }
 
-enum Value {
+pub(crate) enum Value {
     Register(u8),
     RegisterIndirect(u8, i32, bool),
     RegisterPlusConstant32(u8, i32, bool),
     RegisterPlusConstant64(u8, i64, bool),
     Constant64(i64, bool),
+    /// Absolute host address, relocated when the program is deserialized
+    Address(usize),
 }
 
-struct Argument {
-    index: usize,
-    value: Value,
+pub(crate) struct Argument {
+    pub(crate) index: usize,
+    pub(crate) value: Value,
 }
 
 #[derive(Debug)]
-struct Jump {
-    location: *const u8,
-    target_pc: usize,
+pub(crate) struct Jump {
+    pub(crate) location: *const u8,
+    pub(crate) target_pc: usize,
 }
 
 /// Indices of slots inside RuntimeEnvironment
-enum RuntimeEnvironmentSlot {
+pub(crate) enum RuntimeEnvironmentSlot {
     HostStackPointer = 0,
     CallDepth = 1,
     ContextObjectPointer = 2,
     PreviousInstructionMeter = 3,
     DueInsnCount = 4,
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
     StopwatchNumerator = 5,
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
     StopwatchDenominator = 6,
-    Registers = 7,
-    ProgramResult = 19,
-    MemoryMapping = 27,
+    CancellationCountdown = 7,
+    StackHostAddress = 8,
+    Registers = 9,
+    ProgramResult = 21,
+    MemoryMapping = 29,
 }
 
 /* Explanation of the Instruction Meter
@@ -312,8 +1328,17 @@ enum RuntimeEnvironmentSlot {
     and then after returning use the undo profiling. The trick is, that the undo profiling now
     has the current pc which is the BPF return address. The virtual target pc we count towards
     and undo again can be anything, so we just set it to zero.
+
+    Validating at a branch only covers the branch itself, so the instructions up to the next
+    validation may exceed the budget before the exception is thrown. Exact instruction metering
+    (see Config::enable_exact_instruction_meter) instead validates at the start of every run of
+    instructions which can only be entered at its start, that the budget covers the entire run up
+    to the next checkpoint. If it does not, the state is saved like for resumable execution and
+    the interpreter executes the run one instruction at a time. As `callx` without static syscalls
+    can enter a run anywhere, it additionally validates that the budget covers the longest run.
 */
 
+#[cfg(target_arch = "x86_64")]
 pub struct JitCompiler<'a, C: ContextObject> {
     result: JitProgram,
     text_section_jumps: Vec<Jump>,
@@ -330,8 +1355,11 @@ pub struct JitCompiler<'a, C: ContextObject> {
     runtime_environment_key: i32,
     diversification_rng: SmallRng,
     stopwatch_is_active: bool,
+    bounds_check_proofs: BTreeMap<usize, BoundsCheckProof>,
+    instruction_meter_checkpoints: Vec<usize>,
 }
 
+#[cfg(target_arch = "x86_64")]
 #[rustfmt::skip]
 impl<'a, C: ContextObject> JitCompiler<'a, C> {
     /// Constructs a new compiler and allocates memory for the compilation output
@@ -360,14 +1388,55 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         if config.instruction_meter_checkpoint_distance != 0 {
             code_length_estimate += pc / config.instruction_meter_checkpoint_distance * MACHINE_CODE_PER_INSTRUCTION_METER_CHECKPOINT;
         }
+        if meters_exactly(config) {
+            // At most one checkpoint per instruction, `callx` is covered by MAX_MACHINE_CODE_LENGTH_PER_INSTRUCTION
+            code_length_estimate += pc * MACHINE_CODE_PER_INSTRUCTION_METER_CHECKPOINT;
+        }
+        if config.enable_resumable_execution || meters_exactly(config) {
+            code_length_estimate += pc * MACHINE_CODE_PER_RESUMABLE_INSTRUCTION_METER_VALIDATION;
+        }
+        if config.enable_cancellation_checks {
+            code_length_estimate += pc * MACHINE_CODE_PER_CANCELLATION_CHECK;
+        }
         // Relative jump destinations limit the maximum output size
         debug_assert!(code_length_estimate < (i32::MAX as usize));
 
+        let mut result = JitProgram::new(pc, code_length_estimate)?;
+        if !config.instruction_costs.is_uniform() {
+            // One more entry for the pc after the last instruction, which throws ExecutionOverrun
+            result.instruction_cost_prefix = Vec::with_capacity(pc + 2);
+            let mut instruction_cost_prefix = 0;
+            for slot in 0..=pc {
+                result.instruction_cost_prefix.push(instruction_cost_prefix);
+                instruction_cost_prefix += program.get(slot * ebpf::INSN_SIZE).map(|opc| config.instruction_costs.get(*opc)).unwrap_or(1);
+            }
+            result.instruction_cost_prefix.push(instruction_cost_prefix);
+            // The accumulated instruction costs are used as 32 bit immediate values
+            if instruction_cost_prefix > i32::MAX as u64 {
+                return Err(EbpfError::ExhaustedTextSegment(pc));
+            }
+        }
+        let instruction_meter_checkpoints = if meters_exactly(config) {
+            exact_instruction_meter_checkpoints(executable, pc)
+        } else {
+            Vec::new()
+        };
+        result.max_checkpoint_interval_cost = max_checkpoint_interval_cost(&instruction_meter_checkpoints, &result.instruction_cost_prefix);
+        // The validation of `callx` adds this to the accumulated instruction costs in a 32 bit immediate value
+        if result.instruction_cost_prefix.last().copied().unwrap_or(pc as u64 + 1) + result.max_checkpoint_interval_cost > i32::MAX as u64 {
+            return Err(EbpfError::ExhaustedTextSegment(pc));
+        }
+
         let runtime_environment_key = get_runtime_environment_key();
         let mut diversification_rng = SmallRng::from_rng(thread_rng()).map_err(|_| EbpfError::JitNotCompiled)?;
+        let bounds_check_proofs = if eliminates_bounds_checks(config) {
+            Analysis::from_executable(executable)?.redundant_bounds_checks()
+        } else {
+            BTreeMap::new()
+        };
 
         Ok(Self {
-            result: JitProgram::new(pc, code_length_estimate)?,
+            result,
             text_section_jumps: vec![],
             anchors: [std::ptr::null(); ANCHOR_COUNT],
             offset_in_text_section: 0,
@@ -382,6 +1451,8 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
             runtime_environment_key,
             diversification_rng,
             stopwatch_is_active: false,
+            bounds_check_proofs,
+            instruction_meter_checkpoints,
         })
     }
 
@@ -407,9 +1478,13 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
             self.result.pc_section[self.pc] = unsafe { text_section_base.add(self.offset_in_text_section) } as usize;
 
             // Regular instruction meter checkpoints to prevent long linear runs from exceeding their budget
-            if self.last_instruction_meter_validation_pc + self.config.instruction_meter_checkpoint_distance <= self.pc {
+            if self.last_instruction_meter_validation_pc + self.config.instruction_meter_checkpoint_distance <= self.pc
+                || self.instruction_meter_checkpoints.binary_search(&self.pc).is_ok() {
                 self.emit_validate_instruction_count(Some(self.pc));
             }
+            if insn.opc == ebpf::CALL_REG && meters_exactly(self.config) && !self.executable.get_sbpf_version().static_syscalls() {
+                self.emit_validate_indirect_call_instruction_count();
+            }
 
             if self.config.enable_instruction_tracing {
                 self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, self.pc as i64));
@@ -778,14 +1853,15 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
                     let call_depth_access = X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::CallDepth));
                     // If env.call_depth == 0, we've reached the exit instruction of the entry point
                     self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S32, REGISTER_PTR_TO_VM, 0, Some(call_depth_access)));
-                    if self.config.enable_instruction_meter {
-                        self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, self.pc as i64));
-                    }
+                    self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, self.pc as i64));
                     // we're done
                     self.emit_ins(X86Instruction::conditional_jump_immediate(0x84, self.relative_to_anchor(ANCHOR_EXIT, 6)));
 
                     // else decrement and update env.call_depth
                     self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 5, REGISTER_PTR_TO_VM, 1, Some(call_depth_access))); // env.call_depth -= 1;
+                    if self.reports_calls() {
+                        self.emit_ins(X86Instruction::call_immediate(self.relative_to_anchor(ANCHOR_TRACE_RETURN, 5)));
+                    }
 
                     // and return
                     self.emit_profile_instruction_count(false, Some(0));
@@ -802,12 +1878,18 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         if self.offset_in_text_section + MAX_MACHINE_CODE_LENGTH_PER_INSTRUCTION * 2 >= self.result.text_section.len() {
             return Err(EbpfError::ExhaustedTextSegment(self.pc));
         }
+        if self.config.enable_resumable_execution {
+            self.result.resume_addresses = self.result.pc_section.to_vec();
+            self.result.resume_addresses.push(unsafe { text_section_base.add(self.offset_in_text_section) } as usize);
+        }
         self.emit_validate_and_profile_instruction_count(false, Some(self.pc + 1));
         self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, self.pc as i64)); // Save pc
         self.emit_set_exception_kind(EbpfError::ExecutionOverrun);
         self.emit_ins(X86Instruction::jump_immediate(self.relative_to_anchor(ANCHOR_THROW_EXCEPTION, 5)));
 
         self.resolve_jumps();
+        self.result.runtime_environment_key = self.runtime_environment_key;
+        self.result.anchors = self.anchors.map(|anchor| anchor as usize);
         self.result.seal(self.offset_in_text_section)?;
         Ok(self.result)
     }
@@ -847,6 +1929,20 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         self.offset_in_text_section += mem::size_of::<T>();
     }
 
+    /// Loads an absolute host address, which is relocated when the program is deserialized
+    #[inline]
+    fn emit_load_address(&mut self, destination: u8, address: usize) {
+        // The immediate follows the REX prefix and the opcode
+        self.result.relocations.push(Relocation::Address(self.offset_in_text_section + 2));
+        self.emit_ins(X86Instruction::load_address(destination, address as i64));
+    }
+
+    /// Marks the 32 bit displacement about to be emitted as depending on the runtime environment key
+    #[inline]
+    pub(crate) fn record_environment_relocation(&mut self) {
+        self.result.relocations.push(Relocation::Environment(self.offset_in_text_section));
+    }
+
     #[inline]
     pub(crate) fn emit_variable_length(&mut self, size: OperandSize, data: u64) {
         match size {
@@ -933,23 +2029,110 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         self.emit_ins(X86Instruction::pop(RDX));
     }
 
+    /// Whether BPF to BPF calls and returns are reported for instruction tracing or execution statistics
+    #[inline]
+    fn reports_calls(&self) -> bool {
+        self.config.enable_instruction_tracing || self.config.enable_execution_stats
+    }
+
     #[inline]
     fn emit_validate_instruction_count(&mut self, pc: Option<usize>) {
         if !self.config.enable_instruction_meter {
+            if let Some(pc) = pc {
+                self.last_instruction_meter_validation_pc = pc;
+                self.emit_poll_cancellation(pc);
+            }
             return;
         }
         // Update `MACHINE_CODE_PER_INSTRUCTION_METER_CHECKPOINT` if you change the code generation here
         if let Some(pc) = pc {
             self.last_instruction_meter_validation_pc = pc;
-            // instruction_meter >= self.pc
-            self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S64, REGISTER_INSTRUCTION_METER, pc as i64, None));
-        } else {
+            if self.saves_state_on_exceeded_budget() {
+                // Save pc in case the execution gets suspended
+                self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, pc as i64));
+            }
+            // instruction_meter >= self.instruction_cost_prefix(next_checkpoint) - 1
+            let next_checkpoint = self.next_instruction_meter_checkpoint(pc);
+            self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S64, REGISTER_INSTRUCTION_METER, self.instruction_cost_prefix(next_checkpoint) - 1, None));
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x86, self.relative_to_anchor(ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS, 6)));
+            self.emit_poll_cancellation(pc);
+            return;
+        } else if self.result.instruction_cost_prefix.is_empty() {
             // instruction_meter >= scratch_register
             self.emit_ins(X86Instruction::cmp(OperandSize::S64, REGISTER_SCRATCH, REGISTER_INSTRUCTION_METER, None));
+        } else {
+            // instruction_meter >= instruction_cost_prefix[scratch_register + 1]
+            self.emit_ins(X86Instruction::push(REGISTER_MAP[0], None));
+            self.emit_load_address(REGISTER_MAP[0], self.result.instruction_cost_prefix.as_ptr() as usize);
+            self.emit_ins(X86Instruction::cmp(OperandSize::S64, REGISTER_INSTRUCTION_METER, REGISTER_MAP[0], Some(X86IndirectAccess::OffsetIndexShift(8, REGISTER_SCRATCH, 3))));
+            self.emit_ins(X86Instruction::pop(REGISTER_MAP[0]));
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x87, self.relative_to_anchor(ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS, 6)));
+            return;
         }
         self.emit_ins(X86Instruction::conditional_jump_immediate(0x86, self.relative_to_anchor(ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS, 6)));
     }
 
+    /// Without static syscalls the target of `callx` can be in the middle of a run,
+    /// so the budget has to cover the longest run after this instruction.
+    /// Happens before the instruction is traced, as the interpreter repeats it if the validation fails.
+    #[inline]
+    fn emit_validate_indirect_call_instruction_count(&mut self) {
+        self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, self.pc as i64));
+        // instruction_meter >= self.instruction_cost_prefix(self.pc + 1) + max_checkpoint_interval_cost - 1
+        let threshold = self.instruction_cost_prefix(self.pc + 1) + self.result.max_checkpoint_interval_cost as i64 - 1;
+        self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S64, REGISTER_INSTRUCTION_METER, threshold, None));
+        self.emit_ins(X86Instruction::conditional_jump_immediate(0x86, self.relative_to_anchor(ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS, 6)));
+    }
+
+    #[inline]
+    fn emit_poll_cancellation(&mut self, pc: usize) {
+        if !self.config.enable_cancellation_checks {
+            return;
+        }
+        // Update `MACHINE_CODE_PER_CANCELLATION_CHECK` if you change the code generation here
+        if !self.saves_state_on_exceeded_budget() || !self.config.enable_instruction_meter {
+            self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, pc as i64));
+        }
+        self.emit_ins(X86Instruction::call_immediate(self.relative_to_anchor(ANCHOR_POLL_CANCELLATION, 5)));
+    }
+
+    /// Whether ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS saves the state to continue at the pc of the failed validation
+    ///
+    /// Either `EbpfVm::resume_program()` or the interpreter of exact instruction metering continues there.
+    #[inline]
+    fn saves_state_on_exceeded_budget(&self) -> bool {
+        self.config.enable_resumable_execution || meters_exactly(self.config)
+    }
+
+    /// The pc up to which a validation of the instruction meter at pc covers the instructions
+    #[inline]
+    fn next_instruction_meter_checkpoint(&self, pc: usize) -> usize {
+        let index = self.instruction_meter_checkpoints.partition_point(|checkpoint| *checkpoint <= pc);
+        self.instruction_meter_checkpoints.get(index).copied().unwrap_or(pc + 1)
+    }
+
+    /// Accumulated cost of all instructions before pc, which is pc itself if every instruction costs one unit
+    #[inline]
+    fn instruction_cost_prefix(&self, pc: usize) -> i64 {
+        self.result.instruction_cost_prefix.get(pc).map(|cost| *cost as i64).unwrap_or(pc as i64)
+    }
+
+    /// Adds or subtracts the accumulated cost of all instructions before (pc_reg + offset) to / from the instruction meter
+    fn emit_instruction_cost_prefix_alu(&mut self, add: bool, pc_reg: u8, offset: i64) {
+        if self.result.instruction_cost_prefix.is_empty() {
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, if add { 0x01 } else { 0x29 }, pc_reg, REGISTER_INSTRUCTION_METER, 0, None)); // instruction_meter +/-= pc_reg;
+            if offset != 0 {
+                self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, if add { 0 } else { 5 }, REGISTER_INSTRUCTION_METER, offset, None)); // instruction_meter +/-= offset;
+            }
+        } else {
+            let table_reg = if pc_reg == REGISTER_MAP[0] { REGISTER_MAP[1] } else { REGISTER_MAP[0] };
+            self.emit_ins(X86Instruction::push(table_reg, None));
+            self.emit_load_address(table_reg, self.result.instruction_cost_prefix.as_ptr() as usize);
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, if add { 0x03 } else { 0x2b }, REGISTER_INSTRUCTION_METER, table_reg, 0, Some(X86IndirectAccess::OffsetIndexShift(offset as i32 * 8, pc_reg, 3)))); // instruction_meter +/-= instruction_cost_prefix[pc_reg + offset];
+            self.emit_ins(X86Instruction::pop(table_reg));
+        }
+    }
+
     #[inline]
     fn emit_profile_instruction_count(&mut self, user_provided: bool, target_pc: Option<usize>) {
         if !self.config.enable_instruction_meter {
@@ -958,7 +2141,7 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         match target_pc {
             Some(target_pc) => {
                 // instruction_meter += target_pc - (self.pc + 1);
-                let immediate = target_pc as i64 - self.pc as i64 - 1;
+                let immediate = self.instruction_cost_prefix(target_pc) - self.instruction_cost_prefix(self.pc + 1);
                 if user_provided {
                     self.emit_sanitized_alu(OperandSize::S64, 0x01, 0, REGISTER_INSTRUCTION_METER, immediate);
                 } else {
@@ -966,8 +2149,8 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
                 }
             },
             None => {
-                self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 5, REGISTER_INSTRUCTION_METER, self.pc as i64 + 1, None)); // instruction_meter -= self.pc + 1;
-                self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x01, REGISTER_SCRATCH, REGISTER_INSTRUCTION_METER, 0, None)); // instruction_meter += target_pc;
+                self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 5, REGISTER_INSTRUCTION_METER, self.instruction_cost_prefix(self.pc + 1), None)); // instruction_meter -= self.pc + 1;
+                self.emit_instruction_cost_prefix_alu(true, REGISTER_SCRATCH, 0); // instruction_meter += target_pc;
             },
         }
     }
@@ -983,12 +2166,12 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         if self.config.enable_instruction_meter {
             match target_pc {
                 Value::Constant64(target_pc, _) => {
-                    self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 0, REGISTER_INSTRUCTION_METER, self.pc as i64 + 1 - target_pc, None)); // instruction_meter += (self.pc + 1) - target_pc;
+                    let immediate = self.instruction_cost_prefix(self.pc + 1) - self.instruction_cost_prefix(target_pc as usize);
+                    self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 0, REGISTER_INSTRUCTION_METER, immediate, None)); // instruction_meter += (self.pc + 1) - target_pc;
                 }
                 Value::Register(target_pc) => {
-                    self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x29, target_pc, REGISTER_INSTRUCTION_METER, 0, None)); // instruction_meter -= guest_target_pc
-                    self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 0, REGISTER_INSTRUCTION_METER, 1, None)); // instruction_meter += 1
-                    self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x01, REGISTER_SCRATCH, REGISTER_INSTRUCTION_METER, 0, None)); // instruction_meter += self.pc
+                    self.emit_instruction_cost_prefix_alu(false, target_pc, 0); // instruction_meter -= guest_target_pc
+                    self.emit_instruction_cost_prefix_alu(true, REGISTER_SCRATCH, 1); // instruction_meter += self.pc + 1
                 }
                 _ => debug_assert!(false),
             }
@@ -1072,6 +2255,10 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
                     debug_assert!(!user_provided && !is_stack_argument);
                     self.emit_ins(X86Instruction::load_immediate(dst, value));
                 },
+                Value::Address(address) => {
+                    debug_assert!(!is_stack_argument);
+                    self.emit_load_address(dst, address);
+                },
             }
         }
     
@@ -1081,7 +2268,7 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
             },
             Value::Constant64(value, user_provided) => {
                 debug_assert!(!user_provided);
-                self.emit_ins(X86Instruction::load_immediate(RAX, value));
+                self.emit_load_address(RAX, value as usize);
                 self.emit_ins(X86Instruction::call_reg(RAX, None));
             },
             _ => {
@@ -1119,6 +2306,7 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
                 self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_SCRATCH, RSP, X86IndirectAccess::OffsetIndexShift(-24, RSP, 0)));
                 // Move guest_target_address into REGISTER_SCRATCH
                 self.emit_ins(X86Instruction::mov(OperandSize::S64, reg, REGISTER_SCRATCH));
+                self.record_return_address(5);
                 self.emit_ins(X86Instruction::call_immediate(self.relative_to_anchor(ANCHOR_INTERNAL_FUNCTION_CALL_REG, 5)));
             },
             Value::Constant64(target_pc, user_provided) => {
@@ -1129,7 +2317,11 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
                 } else {
                     self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, target_pc));
                 }
+                if self.reports_calls() {
+                    self.emit_ins(X86Instruction::call_immediate(self.relative_to_anchor(ANCHOR_TRACE_CALL, 5)));
+                }
                 let jump_offset = self.relative_to_target_pc(target_pc as usize, 5);
+                self.record_return_address(5);
                 self.emit_ins(X86Instruction::call_immediate(jump_offset));
             },
             _ => {
@@ -1147,10 +2339,22 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         }
     }
 
+    // instruction_length = 5 (Call)
+    #[inline]
+    fn record_return_address(&mut self, instruction_length: usize) {
+        // Needed to rebuild the host stack from call_frames, see JitProgram::invoke()
+        if self.saves_state_on_exceeded_budget() || self.config.tiered_compilation_threshold != 0 {
+            let return_address = unsafe { self.result.text_section.as_ptr().add(self.offset_in_text_section).add(instruction_length) };
+            self.result.return_addresses.push((self.pc + 1, return_address as usize));
+        }
+    }
+
     #[inline]
     fn emit_syscall_dispatch(&mut self, function: BuiltinFunction<C>) {
         self.emit_validate_and_profile_instruction_count(false, Some(0));
-        self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, function as usize as i64));
+        // Store the pc in case the syscall fails, ANCHOR_EXTERNAL_FUNCTION_CALL does not know it
+        self.emit_ins(X86Instruction::store_immediate(OperandSize::S64, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * std::mem::size_of::<u64>() as i32), self.pc as i64)); // registers[11] = pc;
+        self.emit_load_address(REGISTER_SCRATCH, function as usize);
         self.emit_ins(X86Instruction::call_immediate(self.relative_to_anchor(ANCHOR_EXTERNAL_FUNCTION_CALL, 5)));
         self.emit_undo_profile_instruction_count(Value::Constant64(0, false));
     }
@@ -1175,7 +2379,12 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
             _ => {}
         }
 
+        let bounds_check_proof = self.bounds_check_proofs.get(&self.pc).cloned();
+        let stack_frame_access = self.config.enable_address_translation && bounds_check_proof == Some(BoundsCheckProof::StackFrame);
         match vm_addr {
+            Value::RegisterPlusConstant64(_reg, constant, _user_provided) if stack_frame_access => {
+                self.emit_stack_frame_address(constant);
+            },
             Value::RegisterPlusConstant64(reg, constant, user_provided) => {
                 if user_provided && self.should_sanitize_constant(constant) {
                     self.emit_sanitized_load_immediate(REGISTER_SCRATCH, constant);
@@ -1197,9 +2406,17 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
             },
         }
 
-        if self.config.enable_address_translation {
+        if self.config.enable_address_translation && !stack_frame_access {
             let access_type = if value.is_none() { AccessType::Load } else { AccessType::Store };
-            let anchor = ANCHOR_TRANSLATE_MEMORY_ADDRESS + len.trailing_zeros() as usize + 4 * (access_type as usize);
+            let anchor = if bounds_check_proof == Some(BoundsCheckProof::RepeatedAccess) && self.translates_aligned_addresses() {
+                ANCHOR_TRANSLATE_MEMORY_ADDRESS_UNCHECKED
+            } else {
+                ANCHOR_TRANSLATE_MEMORY_ADDRESS
+            } + len.trailing_zeros() as usize + 4 * (access_type as usize);
+            if self.config.enable_memory_watchpoints && !self.config.enable_instruction_tracing {
+                // Watchpoints report the pc, which ANCHOR_TRACE does not keep up to date here
+                self.emit_ins(X86Instruction::store_immediate(OperandSize::S64, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * std::mem::size_of::<u64>() as i32), self.pc as i64)); // registers[11] = pc;
+            }
             self.emit_ins(X86Instruction::push_immediate(OperandSize::S64, self.pc as i32));
             self.emit_ins(X86Instruction::call_immediate(self.relative_to_anchor(anchor, 5)));
             if let Some(dst) = dst {
@@ -1214,7 +2431,7 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
                 _ => unreachable!(),
             }
         } else {
-            self.emit_ins(X86Instruction::xchg(OperandSize::S64, RSP, REGISTER_MAP[0], Some(stack_slot_of_value_to_store))); // Save REGISTER_MAP[0] and retrieve value to store
+            self.emit_ins(X86Instruction::xchg(OperandSize::S64, REGISTER_MAP[0], RSP, Some(stack_slot_of_value_to_store))); // Save REGISTER_MAP[0] and retrieve value to store
             match len {
                 1 => self.emit_ins(X86Instruction::store(OperandSize::S8, REGISTER_MAP[0], REGISTER_SCRATCH, X86IndirectAccess::Offset(0))),
                 2 => self.emit_ins(X86Instruction::store(OperandSize::S16, REGISTER_MAP[0], REGISTER_SCRATCH, X86IndirectAccess::Offset(0))),
@@ -1222,10 +2439,33 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
                 8 => self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_MAP[0], REGISTER_SCRATCH, X86IndirectAccess::Offset(0))),
                 _ => unreachable!(),
             }
-            self.emit_ins(X86Instruction::xchg(OperandSize::S64, RSP, REGISTER_MAP[0], Some(stack_slot_of_value_to_store))); // Restore REGISTER_MAP[0]
+            self.emit_ins(X86Instruction::xchg(OperandSize::S64, REGISTER_MAP[0], RSP, Some(stack_slot_of_value_to_store))); // Restore REGISTER_MAP[0]
         }
     }
 
+    /// Translates the vm address `r10 + offset` to the host address in REGISTER_SCRATCH
+    ///
+    /// Only valid for accesses inside of the current stack frame, see [BoundsCheckProof::StackFrame].
+    /// Without gaps the host offset is `r10 - MM_STACK_START + offset`. With gaps `r10 - MM_STACK_START` is
+    /// `stack_frame_size * (2 * call_depth + 1)`, so the host offset is `(r10 - MM_STACK_START + stack_frame_size) / 2 + offset`.
+    fn emit_stack_frame_address(&mut self, offset: i64) {
+        let constant = if self.config.enable_stack_frame_gaps {
+            offset * 2 + self.config.stack_frame_size as i64
+        } else {
+            offset
+        } - ebpf::MM_STACK_START as i64;
+        if self.should_sanitize_constant(constant) {
+            self.emit_sanitized_load_immediate(REGISTER_SCRATCH, constant);
+        } else {
+            self.emit_ins(X86Instruction::load_immediate(REGISTER_SCRATCH, constant));
+        }
+        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x01, REGISTER_MAP[FRAME_PTR_REG], REGISTER_SCRATCH, 0, None));
+        if self.config.enable_stack_frame_gaps {
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0xc1, 5, REGISTER_SCRATCH, 1, None));
+        }
+        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x03, REGISTER_SCRATCH, REGISTER_PTR_TO_VM, 0, Some(X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::StackHostAddress)))));
+    }
+
     #[inline]
     fn emit_conditional_branch_reg(&mut self, op: u8, bitwise: bool, first_operand: u8, second_operand: u8, target_pc: usize) {
         self.emit_validate_and_profile_instruction_count(true, Some(target_pc));
@@ -1395,9 +2635,10 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
     fn emit_set_exception_kind(&mut self, err: EbpfError) {
         let err_kind = unsafe { *std::ptr::addr_of!(err).cast::<u64>() };
         let err_discriminant = ProgramResult::Err(err).discriminant();
-        self.emit_ins(X86Instruction::lea(OperandSize::S64, REGISTER_PTR_TO_VM, REGISTER_MAP[0], Some(X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult)))));
-        self.emit_ins(X86Instruction::store_immediate(OperandSize::S64, REGISTER_MAP[0], X86IndirectAccess::Offset(0), err_discriminant as i64)); // result.discriminant = err_discriminant;
-        self.emit_ins(X86Instruction::store_immediate(OperandSize::S64, REGISTER_MAP[0], X86IndirectAccess::Offset(std::mem::size_of::<u64>() as i32), err_kind as i64)); // err.kind = err_kind;
+        let result_offset = self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult);
+        // Does not clobber any registers, so that a suspended execution can be resumed from ANCHOR_THROW_EXCEPTION
+        self.emit_ins(X86Instruction::store_immediate(OperandSize::S64, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(result_offset), err_discriminant as i64)); // result.discriminant = err_discriminant;
+        self.emit_ins(X86Instruction::store_immediate(OperandSize::S64, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(result_offset + std::mem::size_of::<u64>() as i32), err_kind as i64)); // err.kind = err_kind;
     }
 
     fn emit_result_is_err(&mut self, destination: u8) {
@@ -1411,6 +2652,7 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         // Routine for instruction tracing
         if self.config.enable_instruction_tracing {
             self.set_anchor(ANCHOR_TRACE);
+            self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_SCRATCH, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * std::mem::size_of::<u64>() as i32))); // registers[11] = pc;
             // Save registers on stack
             self.emit_ins(X86Instruction::push(REGISTER_SCRATCH, None));
             for reg in REGISTER_MAP.iter().rev() {
@@ -1430,20 +2672,47 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
             self.emit_ins(X86Instruction::return_near());
         }
 
+        if self.reports_calls() {
+            // Routine for tracing BPF to BPF calls, expects the target pc in REGISTER_SCRATCH
+            self.set_anchor(ANCHOR_TRACE_CALL);
+            if self.config.enable_instruction_meter {
+                self.emit_instruction_cost_prefix_alu(false, REGISTER_SCRATCH, 0); // instruction_meter -= target_pc;
+            }
+            self.emit_rust_call(Value::Constant64(trace_call::<C> as *const u8 as i64, false), &[
+                Argument { index: 2, value: Value::Register(REGISTER_INSTRUCTION_METER) },
+                Argument { index: 1, value: Value::Register(REGISTER_SCRATCH) },
+                Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+            ], None);
+            if self.config.enable_instruction_meter {
+                self.emit_instruction_cost_prefix_alu(true, REGISTER_SCRATCH, 0); // instruction_meter += target_pc;
+            }
+            self.emit_ins(X86Instruction::return_near());
+
+            // Routine for tracing returns from BPF to BPF calls, expects the pc in REGISTER_SCRATCH
+            self.set_anchor(ANCHOR_TRACE_RETURN);
+            if self.config.enable_instruction_meter {
+                self.emit_instruction_cost_prefix_alu(false, REGISTER_SCRATCH, 1); // instruction_meter -= pc + 1;
+            }
+            self.emit_rust_call(Value::Constant64(trace_return::<C> as *const u8 as i64, false), &[
+                Argument { index: 1, value: Value::Register(REGISTER_INSTRUCTION_METER) },
+                Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+            ], None);
+            if self.config.enable_instruction_meter {
+                self.emit_instruction_cost_prefix_alu(true, REGISTER_SCRATCH, 1); // instruction_meter += pc + 1;
+            }
+            self.emit_ins(X86Instruction::return_near());
+        }
+
         // Epilogue
         self.set_anchor(ANCHOR_EPILOGUE);
         if self.config.enable_instruction_meter {
-            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 5, REGISTER_INSTRUCTION_METER, 1, None)); // REGISTER_INSTRUCTION_METER -= 1;
-            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x29, REGISTER_SCRATCH, REGISTER_INSTRUCTION_METER, 0, None)); // REGISTER_INSTRUCTION_METER -= pc;
+            self.emit_instruction_cost_prefix_alu(false, REGISTER_SCRATCH, 1); // REGISTER_INSTRUCTION_METER -= pc + 1;
             // *DueInsnCount = *PreviousInstructionMeter - REGISTER_INSTRUCTION_METER;
             self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x2B, REGISTER_INSTRUCTION_METER, REGISTER_PTR_TO_VM, 0, Some(X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::PreviousInstructionMeter))))); // REGISTER_INSTRUCTION_METER -= *PreviousInstructionMeter;
             self.emit_ins(X86Instruction::alu(OperandSize::S64, 0xf7, 3, REGISTER_INSTRUCTION_METER, 0, None)); // REGISTER_INSTRUCTION_METER = -REGISTER_INSTRUCTION_METER;
             self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_INSTRUCTION_METER, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::DueInsnCount)))); // *DueInsnCount = REGISTER_INSTRUCTION_METER;
         }
         // Print stop watch value
-        fn stopwatch_result(numerator: u64, denominator: u64) {
-            println!("Stop watch: {} / {} = {}", numerator, denominator, if denominator == 0 { 0.0 } else { numerator as f64 / denominator as f64 });
-        }
         if self.stopwatch_is_active {
             self.emit_rust_call(Value::Constant64(stopwatch_result as *const u8 as i64, false), &[
                 Argument { index: 1, value: Value::RegisterIndirect(REGISTER_PTR_TO_VM, self.slot_in_vm(RuntimeEnvironmentSlot::StopwatchDenominator), false) },
@@ -1456,9 +2725,31 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
 
         // Handler for EbpfError::ExceededMaxInstructions
         self.set_anchor(ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS);
-        self.emit_set_exception_kind(EbpfError::ExceededMaxInstructions);
-        self.emit_ins(X86Instruction::mov(OperandSize::S64, REGISTER_INSTRUCTION_METER, REGISTER_SCRATCH)); // REGISTER_SCRATCH = REGISTER_INSTRUCTION_METER;
-        // Fall through
+        if self.saves_state_on_exceeded_budget() {
+            // Save the registers, the pc of the instruction which was not executed yet and the call frames for EbpfVm::resume_program()
+            // or for the interpreter to continue exactly, see EbpfVm::run_compiled_program()
+            for (i, reg) in REGISTER_MAP.iter().chain(std::iter::once(&REGISTER_SCRATCH)).enumerate() {
+                self.emit_ins(X86Instruction::store(OperandSize::S64, *reg, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + (i * std::mem::size_of::<u64>()) as i32))); // registers[i] = reg;
+            }
+            self.emit_rust_call(Value::Constant64(save_call_frames::<C> as *const u8 as i64, false), &[
+                Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+            ], None);
+            self.emit_set_exception_kind(EbpfError::ExecutionSuspended);
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 5, REGISTER_SCRATCH, 1, None)); // REGISTER_SCRATCH -= 1; so that the epilogue does not count the instruction at pc
+            self.emit_ins(X86Instruction::jump_immediate(self.relative_to_anchor(ANCHOR_EPILOGUE, 5)));
+        } else {
+            self.emit_set_exception_kind(EbpfError::ExceededMaxInstructions);
+            if self.result.instruction_cost_prefix.is_empty() {
+                self.emit_ins(X86Instruction::mov(OperandSize::S64, REGISTER_INSTRUCTION_METER, REGISTER_SCRATCH)); // REGISTER_SCRATCH = REGISTER_INSTRUCTION_METER;
+            } else {
+                self.emit_rust_call(Value::Constant64(pc_from_instruction_cost as *const u8 as i64, false), &[
+                    Argument { index: 2, value: Value::Register(REGISTER_INSTRUCTION_METER) },
+                    Argument { index: 1, value: Value::Constant64(self.result.instruction_cost_prefix.len() as i64, false) },
+                    Argument { index: 0, value: Value::Address(self.result.instruction_cost_prefix.as_ptr() as usize) },
+                ], Some(REGISTER_SCRATCH));
+            }
+            // Fall through
+        }
 
         // Epilogue for errors
         self.set_anchor(ANCHOR_THROW_EXCEPTION_UNCHECKED);
@@ -1467,12 +2758,13 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
 
         // Quit gracefully
         self.set_anchor(ANCHOR_EXIT);
-        if self.config.enable_instruction_meter {
-            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x29, REGISTER_SCRATCH, REGISTER_INSTRUCTION_METER, 0, None)); // REGISTER_INSTRUCTION_METER -= pc;
+        // Keep the final register file, except for the frame pointer of the root frame
+        for (i, reg) in REGISTER_MAP.iter().take(FRAME_PTR_REG).enumerate() {
+            self.emit_ins(X86Instruction::store(OperandSize::S64, *reg, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + (i * std::mem::size_of::<u64>()) as i32))); // registers[i] = reg;
         }
-        self.emit_ins(X86Instruction::lea(OperandSize::S64, REGISTER_PTR_TO_VM, REGISTER_SCRATCH, Some(X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult)))));
-        self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_MAP[0], REGISTER_SCRATCH, X86IndirectAccess::Offset(std::mem::size_of::<u64>() as i32))); // result.return_value = R0;
-        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x31, REGISTER_SCRATCH, REGISTER_SCRATCH, 0, None)); // REGISTER_SCRATCH ^= REGISTER_SCRATCH; // REGISTER_SCRATCH = 0;
+        self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_SCRATCH, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * std::mem::size_of::<u64>() as i32))); // registers[11] = pc;
+        // REGISTER_SCRATCH still holds the pc of the exit instruction, which the epilogue charges to the instruction meter
+        self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_MAP[0], REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult) + std::mem::size_of::<u64>() as i32))); // result.return_value = R0;
         self.emit_ins(X86Instruction::jump_immediate(self.relative_to_anchor(ANCHOR_EPILOGUE, 5)));
 
         // Handler for exceptions which report their pc
@@ -1510,20 +2802,44 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         self.emit_set_exception_kind(EbpfError::UnsupportedInstruction);
         self.emit_ins(X86Instruction::jump_immediate(self.relative_to_anchor(ANCHOR_THROW_EXCEPTION, 5)));
 
+        // Routine for polling the cancellation handle, expects the pc in REGISTER_SCRATCH
+        if self.config.enable_cancellation_checks {
+            self.set_anchor(ANCHOR_POLL_CANCELLATION_SLOW);
+            self.emit_rust_call(Value::Constant64(poll_cancellation::<C> as *const u8 as i64, false), &[
+                Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+            ], None);
+            let ok_discriminant = ProgramResult::Ok(0).discriminant();
+            self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S64, REGISTER_PTR_TO_VM, ok_discriminant as i64, Some(X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult)))));
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x85, self.relative_to_anchor(ANCHOR_THROW_EXCEPTION_UNCHECKED, 6)));
+            self.emit_ins(X86Instruction::return_near());
+
+            self.set_anchor(ANCHOR_POLL_CANCELLATION);
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 5, REGISTER_PTR_TO_VM, 1, Some(X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::CancellationCountdown))))); // cancellation_countdown -= 1;
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x84, self.relative_to_anchor(ANCHOR_POLL_CANCELLATION_SLOW, 6)));
+            self.emit_ins(X86Instruction::return_near());
+        }
+
         // Routine for external functions
         self.set_anchor(ANCHOR_EXTERNAL_FUNCTION_CALL);
         self.emit_ins(X86Instruction::push_immediate(OperandSize::S64, -1)); // Used as PC value in error case, acts as stack padding otherwise
         if self.config.enable_instruction_meter {
             self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_INSTRUCTION_METER, REGISTER_PTR_TO_VM, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::DueInsnCount)))); // *DueInsnCount = REGISTER_INSTRUCTION_METER;
         }
-        self.emit_rust_call(Value::Register(REGISTER_SCRATCH), &[
+        let (target, function_argument) = if self.config.enable_instruction_tracing || self.config.enable_execution_stats {
+            // Call traced_syscall() instead and pass the syscall as additional argument
+            (Value::Constant64(traced_syscall::<C> as *const u8 as i64, false), Some(Argument { index: 6, value: Value::Register(REGISTER_SCRATCH) }))
+        } else {
+            (Value::Register(REGISTER_SCRATCH), None)
+        };
+        let arguments = function_argument.into_iter().chain([
             Argument { index: 5, value: Value::Register(ARGUMENT_REGISTERS[5]) },
             Argument { index: 4, value: Value::Register(ARGUMENT_REGISTERS[4]) },
             Argument { index: 3, value: Value::Register(ARGUMENT_REGISTERS[3]) },
             Argument { index: 2, value: Value::Register(ARGUMENT_REGISTERS[2]) },
             Argument { index: 1, value: Value::Register(ARGUMENT_REGISTERS[1]) },
             Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
-        ], None);
+        ]).collect::<Vec<_>>();
+        self.emit_rust_call(target, &arguments, None);
         if self.config.enable_instruction_meter {
             self.emit_ins(X86Instruction::load(OperandSize::S64, REGISTER_PTR_TO_VM, REGISTER_INSTRUCTION_METER, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::PreviousInstructionMeter)))); // REGISTER_INSTRUCTION_METER = *PreviousInstructionMeter;
         }
@@ -1578,12 +2894,22 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         let number_of_instructions = self.result.pc_section.len();
         self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S64, REGISTER_SCRATCH, (number_of_instructions * INSN_SIZE) as i64, None)); // guest_target_address.cmp(number_of_instructions * INSN_SIZE)
         self.emit_ins(X86Instruction::conditional_jump_immediate(0x83, self.relative_to_anchor(ANCHOR_CALL_OUTSIDE_TEXT_SEGMENT, 6)));
-        // First half of self.emit_profile_instruction_count(false, None);
-        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x2b, REGISTER_INSTRUCTION_METER, RSP, 0, Some(X86IndirectAccess::OffsetIndexShift(-8, RSP, 0)))); // instruction_meter -= guest_current_pc;
-        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 5, REGISTER_INSTRUCTION_METER, 1, None)); // instruction_meter -= 1;
+        if self.result.instruction_cost_prefix.is_empty() {
+            // First half of self.emit_profile_instruction_count(false, None);
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x2b, REGISTER_INSTRUCTION_METER, RSP, 0, Some(X86IndirectAccess::OffsetIndexShift(-8, RSP, 0)))); // instruction_meter -= guest_current_pc;
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 5, REGISTER_INSTRUCTION_METER, 1, None)); // instruction_meter -= 1;
+        } else {
+            // self.emit_profile_instruction_count(false, None) with the accumulated instruction costs
+            debug_assert_eq!(INSN_SIZE, std::mem::size_of::<u64>()); // Because the instruction size is also the table entry size we do not need to shift the offset
+            self.emit_load_address(REGISTER_MAP[0], self.result.instruction_cost_prefix.as_ptr() as usize);
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x03, REGISTER_INSTRUCTION_METER, REGISTER_MAP[0], 0, Some(X86IndirectAccess::OffsetIndexShift(0, REGISTER_SCRATCH, 0)))); // instruction_meter += instruction_cost_prefix[guest_target_pc];
+            self.emit_ins(X86Instruction::xchg(OperandSize::S64, REGISTER_SCRATCH, RSP, Some(X86IndirectAccess::OffsetIndexShift(-8, RSP, 0)))); // Swap guest_target_address and guest_current_pc
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x2b, REGISTER_INSTRUCTION_METER, REGISTER_MAP[0], 0, Some(X86IndirectAccess::OffsetIndexShift(8, REGISTER_SCRATCH, 3)))); // instruction_meter -= instruction_cost_prefix[guest_current_pc + 1];
+            self.emit_ins(X86Instruction::xchg(OperandSize::S64, REGISTER_SCRATCH, RSP, Some(X86IndirectAccess::OffsetIndexShift(-8, RSP, 0)))); // Swap back
+        }
         // Load host target_address from self.result.pc_section
         debug_assert_eq!(INSN_SIZE, 8); // Because the instruction size is also the slot size we do not need to shift the offset
-        self.emit_ins(X86Instruction::load_immediate(REGISTER_MAP[0], self.result.pc_section.as_ptr() as i64)); // host_target_address = self.result.pc_section;
+        self.emit_load_address(REGISTER_MAP[0], self.result.pc_section.as_ptr() as usize); // host_target_address = self.result.pc_section;
         self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x01, REGISTER_SCRATCH, REGISTER_MAP[0], 0, None)); // host_target_address += guest_target_address;
         self.emit_ins(X86Instruction::load(OperandSize::S64, REGISTER_MAP[0], REGISTER_MAP[0], X86IndirectAccess::Offset(0))); // host_target_address = self.result.pc_section[host_target_address / 8];
         // Calculate the guest_target_pc (dst / INSN_SIZE) to update REGISTER_INSTRUCTION_METER
@@ -1591,8 +2917,24 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
         let shift_amount = INSN_SIZE.trailing_zeros();
         debug_assert_eq!(INSN_SIZE, 1 << shift_amount);
         self.emit_ins(X86Instruction::alu(OperandSize::S64, 0xc1, 5, REGISTER_SCRATCH, shift_amount as i64, None)); // guest_target_pc /= INSN_SIZE;
-        // Second half of self.emit_profile_instruction_count(false, None);
-        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x01, REGISTER_SCRATCH, REGISTER_INSTRUCTION_METER, 0, None)); // instruction_meter += guest_target_pc;
+        if self.result.instruction_cost_prefix.is_empty() {
+            // Second half of self.emit_profile_instruction_count(false, None);
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x01, REGISTER_SCRATCH, REGISTER_INSTRUCTION_METER, 0, None)); // instruction_meter += guest_target_pc;
+        }
+        if self.reports_calls() {
+            let pc_access = X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * std::mem::size_of::<u64>() as i32);
+            if !self.config.enable_instruction_tracing {
+                // Without ANCHOR_TRACE registers[11] is not up to date, so save the guest current pc there
+                self.emit_ins(X86Instruction::xchg(OperandSize::S64, REGISTER_SCRATCH, RSP, Some(X86IndirectAccess::OffsetIndexShift(-8, RSP, 0)))); // Swap guest_target_pc and guest_current_pc
+                self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_SCRATCH, REGISTER_PTR_TO_VM, pc_access)); // registers[11] = guest_current_pc;
+                self.emit_ins(X86Instruction::xchg(OperandSize::S64, REGISTER_SCRATCH, RSP, Some(X86IndirectAccess::OffsetIndexShift(-8, RSP, 0)))); // Swap back
+            }
+            self.emit_ins(X86Instruction::call_immediate(self.relative_to_anchor(ANCHOR_TRACE_CALL, 5)));
+            // The call clobbered the guest current pc on the stack, restore it from registers[11]
+            self.emit_ins(X86Instruction::xchg(OperandSize::S64, REGISTER_SCRATCH, REGISTER_PTR_TO_VM, Some(pc_access))); // Swap guest_target_pc and registers[11]
+            self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_SCRATCH, RSP, X86IndirectAccess::OffsetIndexShift(-8, RSP, 0)));
+            self.emit_ins(X86Instruction::xchg(OperandSize::S64, REGISTER_SCRATCH, REGISTER_PTR_TO_VM, Some(pc_access))); // Swap back
+        }
         // Restore the clobbered REGISTER_MAP[0]
         self.emit_ins(X86Instruction::xchg(OperandSize::S64, REGISTER_MAP[0], RSP, Some(X86IndirectAccess::OffsetIndexShift(0, RSP, 0)))); // Swap REGISTER_MAP[0] and host_target_address
         self.emit_ins(X86Instruction::return_near()); // Tail call to host_target_address
@@ -1619,14 +2961,47 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
             (AccessType::Store, 8i32),
         ] {
             let target_offset = len.trailing_zeros() as usize + 4 * (*access_type as usize);
-            self.set_anchor(ANCHOR_TRANSLATE_MEMORY_ADDRESS + target_offset);
-            // call MemoryMapping::(load|store) storing the result in RuntimeEnvironmentSlot::ProgramResult
-            if *access_type == AccessType::Load {
+            if self.translates_aligned_addresses() {
+                self.set_anchor(ANCHOR_TRANSLATE_MEMORY_ADDRESS_SLOW + target_offset);
+                // Restore the registers of emit_aligned_address_translation()
+                self.emit_ins(X86Instruction::pop(REGISTER_MAP[1]));
+                self.emit_ins(X86Instruction::pop(REGISTER_MAP[0]));
+            } else {
+                self.set_anchor(ANCHOR_TRANSLATE_MEMORY_ADDRESS + target_offset);
+            }
+            // call MemoryMapping::handled_(load|store) storing the result in RuntimeEnvironmentSlot::ProgramResult
+            if self.config.enable_instruction_tracing || self.config.enable_execution_stats || self.config.enable_memory_watchpoints {
+                let (traced_access, arguments) = if *access_type == AccessType::Load {
+                    (match len {
+                        1 => traced_load::<C, u8> as *const u8 as i64,
+                        2 => traced_load::<C, u16> as *const u8 as i64,
+                        4 => traced_load::<C, u32> as *const u8 as i64,
+                        8 => traced_load::<C, u64> as *const u8 as i64,
+                        _ => unreachable!()
+                    }, vec![
+                        Argument { index: 1, value: Value::Register(REGISTER_SCRATCH) },
+                        Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+                    ])
+                } else {
+                    (match len {
+                        1 => traced_store::<C, u8> as *const u8 as i64,
+                        2 => traced_store::<C, u16> as *const u8 as i64,
+                        4 => traced_store::<C, u32> as *const u8 as i64,
+                        8 => traced_store::<C, u64> as *const u8 as i64,
+                        _ => unreachable!()
+                    }, vec![
+                        Argument { index: 2, value: Value::Register(REGISTER_SCRATCH) },
+                        Argument { index: 1, value: Value::RegisterIndirect(RSP, -8, false) },
+                        Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+                    ])
+                };
+                self.emit_rust_call(Value::Constant64(traced_access, false), &arguments, None);
+            } else if *access_type == AccessType::Load {
                 let load = match len {
-                    1 => MemoryMapping::load::<u8> as *const u8 as i64,
-                    2 => MemoryMapping::load::<u16> as *const u8 as i64,
-                    4 => MemoryMapping::load::<u32> as *const u8 as i64,
-                    8 => MemoryMapping::load::<u64> as *const u8 as i64,
+                    1 => MemoryMapping::handled_load::<u8> as *const u8 as i64,
+                    2 => MemoryMapping::handled_load::<u16> as *const u8 as i64,
+                    4 => MemoryMapping::handled_load::<u32> as *const u8 as i64,
+                    8 => MemoryMapping::handled_load::<u64> as *const u8 as i64,
                     _ => unreachable!()
                 };
                 self.emit_rust_call(Value::Constant64(load, false), &[
@@ -1637,10 +3012,10 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
                 ], None);
             } else {
                 let store = match len {
-                    1 => MemoryMapping::store::<u8> as *const u8 as i64,
-                    2 => MemoryMapping::store::<u16> as *const u8 as i64,
-                    4 => MemoryMapping::store::<u32> as *const u8 as i64,
-                    8 => MemoryMapping::store::<u64> as *const u8 as i64,
+                    1 => MemoryMapping::handled_store::<u8> as *const u8 as i64,
+                    2 => MemoryMapping::handled_store::<u16> as *const u8 as i64,
+                    4 => MemoryMapping::handled_store::<u32> as *const u8 as i64,
+                    8 => MemoryMapping::handled_store::<u64> as *const u8 as i64,
                     _ => unreachable!()
                 };
                 self.emit_rust_call(Value::Constant64(store, false), &[
@@ -1662,9 +3037,118 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
             self.emit_ins(X86Instruction::load(OperandSize::S64, REGISTER_PTR_TO_VM, REGISTER_SCRATCH, X86IndirectAccess::Offset(self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult) + std::mem::size_of::<u64>() as i32)));
 
             self.emit_ins(X86Instruction::return_near());
+
+            if self.translates_aligned_addresses() {
+                self.set_anchor(ANCHOR_TRANSLATE_MEMORY_ADDRESS + target_offset);
+                self.emit_aligned_address_translation(*access_type, *len, ANCHOR_TRANSLATE_MEMORY_ADDRESS_SLOW + target_offset, true);
+                if eliminates_bounds_checks(self.config) {
+                    self.set_anchor(ANCHOR_TRANSLATE_MEMORY_ADDRESS_UNCHECKED + target_offset);
+                    self.emit_aligned_address_translation(*access_type, *len, ANCHOR_TRANSLATE_MEMORY_ADDRESS_SLOW + target_offset, false);
+                }
+            }
         }
     }
 
+    /// Whether the address translation anchors have a fast path for aligned memory mappings
+    fn translates_aligned_addresses(&self) -> bool {
+        // Tracing, execution stats and watchpoints need to see every access, so they always take the slow path
+        self.config.enable_address_translation
+            && self.config.aligned_memory_mapping
+            && !self.config.enable_instruction_tracing
+            && !self.config.enable_execution_stats
+            && !self.config.enable_memory_watchpoints
+    }
+
+    /// Translates the vm address in REGISTER_SCRATCH and performs the access without calling into Rust
+    ///
+    /// This only works if the address is inside of a continuous region of a MemoryMapping::Aligned without protected
+    /// sub-ranges, a load goes to a region which is not write-only and a store goes to a writable region which does not
+    /// track dirty pages. Everything else (other mappings, gapped regions, CoW regions, protected sub-ranges, dirty page
+    /// tracking and errors) jumps to the slow path which calls MemoryMapping::handled_(load|store).
+    ///
+    /// If not `checked`, an earlier access already proved the bounds, the permission, the written range and the
+    /// dirty pages, see [BoundsCheckProof::RepeatedAccess]. Only that a load is permitted is checked again, because
+    /// the earlier access could have been a store to a write-only region.
+    fn emit_aligned_address_translation(&mut self, access_type: AccessType, len: i32, slow_path: usize, checked: bool) {
+        let region = REGISTER_MAP[0];
+        let offset = REGISTER_MAP[1];
+        let mapping = self.slot_in_vm(RuntimeEnvironmentSlot::MemoryMapping);
+        self.emit_ins(X86Instruction::push(region, None));
+        self.emit_ins(X86Instruction::push(offset, None));
+        self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S8, REGISTER_PTR_TO_VM, jit_layout::MAPPING_ALIGNED as i64, Some(X86IndirectAccess::Offset(mapping))));
+        self.emit_ins(X86Instruction::conditional_jump_immediate(0x85, self.relative_to_anchor(slow_path, 6)));
+        // region = &regions[vm_addr >> 32]
+        self.emit_ins(X86Instruction::mov(OperandSize::S64, REGISTER_SCRATCH, region));
+        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0xc1, 5, region, ebpf::VIRTUAL_ADDRESS_BITS as i64, None));
+        if checked {
+            self.emit_ins(X86Instruction::cmp(OperandSize::S64, region, REGISTER_PTR_TO_VM, Some(X86IndirectAccess::Offset(mapping + jit_layout::MAPPING_REGIONS_COUNT as i32))));
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x86, self.relative_to_anchor(slow_path, 6)));
+        }
+        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x69, region, region, jit_layout::REGION_SIZE as i64, None));
+        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x03, region, REGISTER_PTR_TO_VM, 0, Some(X86IndirectAccess::Offset(mapping + jit_layout::MAPPING_REGIONS_ADDRESS as i32))));
+        self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S8, region, jit_layout::CONTINUOUS as i64, Some(X86IndirectAccess::Offset(jit_layout::REGION_VM_GAP_SHIFT as i32))));
+        self.emit_ins(X86Instruction::conditional_jump_immediate(0x85, self.relative_to_anchor(slow_path, 6)));
+        if access_type == AccessType::Load {
+            self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S64, region, jit_layout::STATE_WRITE_ONLY as i64, Some(X86IndirectAccess::Offset(jit_layout::REGION_STATE as i32))));
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x84, self.relative_to_anchor(slow_path, 6)));
+        }
+        if checked {
+            // Protected sub-ranges are only checked by MemoryRegion::protected_range_violation()
+            self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S64, region, 0, Some(X86IndirectAccess::Offset(jit_layout::REGION_PROTECTED_RANGES_ADDRESS as i32))));
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x85, self.relative_to_anchor(slow_path, 6)));
+        }
+        if checked && access_type == AccessType::Store {
+            self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S64, region, jit_layout::STATE_WRITABLE as i64, Some(X86IndirectAccess::Offset(jit_layout::REGION_STATE as i32))));
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x85, self.relative_to_anchor(slow_path, 6)));
+            // Dirty pages are only recorded by MemoryRegion::mark_written()
+            self.emit_ins(X86Instruction::cmp_immediate(OperandSize::S64, region, 0, Some(X86IndirectAccess::Offset(jit_layout::REGION_DIRTY_PAGES_ADDRESS as i32))));
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x85, self.relative_to_anchor(slow_path, 6)));
+        }
+        // offset = vm_addr - region.vm_addr, the access must end inside of the region
+        self.emit_ins(X86Instruction::mov(OperandSize::S64, REGISTER_SCRATCH, offset));
+        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x2b, offset, region, 0, Some(X86IndirectAccess::Offset(jit_layout::REGION_VM_ADDR as i32))));
+        if checked {
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x82, self.relative_to_anchor(slow_path, 6)));
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 0, offset, len as i64, None));
+            self.emit_ins(X86Instruction::cmp(OperandSize::S64, offset, region, Some(X86IndirectAccess::Offset(jit_layout::REGION_LEN as i32))));
+            self.emit_ins(X86Instruction::conditional_jump_immediate(0x82, self.relative_to_anchor(slow_path, 6)));
+            if access_type == AccessType::Store {
+                // Extend the written range like MemoryRegion::mark_written(), even if the region does not track its writes
+                self.emit_ins(X86Instruction::load(OperandSize::S64, region, REGISTER_SCRATCH, X86IndirectAccess::Offset(jit_layout::REGION_WRITTEN_END as i32)));
+                self.emit_ins(X86Instruction::cmp(OperandSize::S64, offset, REGISTER_SCRATCH, None));
+                self.emit_ins(X86Instruction::cmov(OperandSize::S64, 0x42, offset, REGISTER_SCRATCH)); // written_end = max(written_end, offset + len)
+                self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_SCRATCH, region, X86IndirectAccess::Offset(jit_layout::REGION_WRITTEN_END as i32)));
+            }
+            self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x81, 5, offset, len as i64, None));
+            if access_type == AccessType::Store {
+                self.emit_ins(X86Instruction::load(OperandSize::S64, region, REGISTER_SCRATCH, X86IndirectAccess::Offset(jit_layout::REGION_WRITTEN_START as i32)));
+                self.emit_ins(X86Instruction::cmp(OperandSize::S64, offset, REGISTER_SCRATCH, None));
+                self.emit_ins(X86Instruction::cmov(OperandSize::S64, 0x47, offset, REGISTER_SCRATCH)); // written_start = min(written_start, offset)
+                self.emit_ins(X86Instruction::store(OperandSize::S64, REGISTER_SCRATCH, region, X86IndirectAccess::Offset(jit_layout::REGION_WRITTEN_START as i32)));
+            }
+        }
+        // host_addr = region.host_addr + offset
+        self.emit_ins(X86Instruction::alu(OperandSize::S64, 0x03, offset, region, 0, Some(X86IndirectAccess::Offset(jit_layout::REGION_HOST_ADDR as i32))));
+        let size = match len {
+            1 => OperandSize::S8,
+            2 => OperandSize::S16,
+            4 => OperandSize::S32,
+            8 => OperandSize::S64,
+            _ => unreachable!(),
+        };
+        if access_type == AccessType::Load {
+            self.emit_ins(X86Instruction::load(size, offset, REGISTER_SCRATCH, X86IndirectAccess::Offset(0)));
+        } else {
+            // The value to store is in the stack slot of emit_address_translation(), below the two saved registers,
+            // the return address and the pc
+            self.emit_ins(X86Instruction::load(OperandSize::S64, RSP, REGISTER_SCRATCH, X86IndirectAccess::OffsetIndexShift(-80, RSP, 0)));
+            self.emit_ins(X86Instruction::store(size, REGISTER_SCRATCH, offset, X86IndirectAccess::Offset(0)));
+        }
+        self.emit_ins(X86Instruction::pop(offset));
+        self.emit_ins(X86Instruction::pop(region));
+        self.emit_ins(X86Instruction::return_near_and_release(8)); // Also release the pc pushed by emit_address_translation()
+    }
+
     fn set_anchor(&mut self, anchor: usize) {
         self.anchors[anchor] = unsafe { self.result.text_section.as_ptr().add(self.offset_in_text_section) };
     }
@@ -1727,9 +3211,12 @@ impl<'a, C: ContextObject> JitCompiler<'a, C> {
 mod tests {
     use super::*;
     use crate::{
+        aligned_memory::AlignedMemory,
+        assembler::assemble,
+        memory_region::MemoryRegion,
         program::{BuiltinProgram, FunctionRegistry, SBPFVersion},
         syscalls,
-        vm::TestContextObject,
+        vm::{InstructionCosts, TestContextObject},
     };
     use byteorder::{ByteOrder, LittleEndian};
     use std::sync::Arc;
@@ -1766,6 +3253,8 @@ mod tests {
         check_slot!(env, due_insn_count, DueInsnCount);
         check_slot!(env, stopwatch_numerator, StopwatchNumerator);
         check_slot!(env, stopwatch_denominator, StopwatchDenominator);
+        check_slot!(env, cancellation_countdown, CancellationCountdown);
+        check_slot!(env, stack_host_address, StackHostAddress);
         check_slot!(env, registers, Registers);
         check_slot!(env, program_result, ProgramResult);
         check_slot!(env, memory_mapping, MemoryMapping);
@@ -1790,6 +3279,213 @@ mod tests {
         .unwrap()
     }
 
+    #[test]
+    fn test_serialize_compiled_program() {
+        let config = Config {
+            enable_instruction_tracing: true,
+            instruction_costs: InstructionCosts::default().with_opcode_cost(ebpf::CALL_IMM, 3),
+            ..Config::default()
+        };
+        let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+        loader
+            .register_function("gather_bytes", 1, syscalls::SyscallGatherBytes::vm)
+            .unwrap();
+        let mut executable = assemble::<TestContextObject>(
+            "
+            mov64 r1, 1
+            mov64 r2, 2
+            mov64 r3, 3
+            mov64 r4, 4
+            mov64 r5, 5
+            syscall 1
+            stxdw [r10-0x8], r0
+            call function_foo
+            mov64 r6, r0
+            ldxdw r0, [r10-0x8]
+            add64 r0, r6
+            exit
+            function_foo:
+            mov64 r0, 0x10
+            exit",
+            Arc::new(loader),
+        )
+        .unwrap();
+        let run = |executable: &Executable<TestContextObject>, interpreted: bool| {
+            let mut stack = AlignedMemory::<{ ebpf::HOST_ALIGN }>::zero_filled(
+                executable.get_config().stack_size(),
+            );
+            let stack_len = stack.len();
+            let memory_mapping = MemoryMapping::new(
+                vec![
+                    executable.get_ro_region(),
+                    MemoryRegion::new_writable(stack.as_slice_mut(), ebpf::MM_STACK_START),
+                ],
+                executable.get_config(),
+                executable.get_sbpf_version(),
+            )
+            .unwrap();
+            let mut context_object = TestContextObject::new(100);
+            let mut vm = EbpfVm::new(
+                executable.get_loader().clone(),
+                executable.get_sbpf_version(),
+                &mut context_object,
+                memory_mapping,
+                stack_len,
+            );
+            let (instruction_count, result) = vm.execute_program(executable, interpreted);
+            (instruction_count, result.unwrap())
+        };
+        let expected = run(&executable, true);
+        assert_eq!(expected, (16, 0x0102030405 + 0x10));
+
+        // Compile with a different runtime environment key to test its relocation
+        let mut jit = JitCompiler::<TestContextObject>::new(&executable).unwrap();
+        jit.runtime_environment_key = jit.runtime_environment_key.wrapping_add(0x1234);
+        let bytes = jit.compile().unwrap().serialize(&executable).unwrap();
+        executable.load_compiled_program(&bytes).unwrap();
+        assert_eq!(run(&executable, false), expected);
+        // Serializing the loaded program reproduces the relocations
+        let bytes = executable.serialize_compiled_program().unwrap();
+        executable.load_compiled_program(&bytes).unwrap();
+        assert_eq!(run(&executable, false), expected);
+
+        let mut corrupted = bytes.clone();
+        corrupted[0] ^= 1;
+        assert!(matches!(
+            executable.load_compiled_program(&corrupted),
+            Err(EbpfError::JitCacheError(_))
+        ));
+        // Any bit of the digest in the header must match
+        let mut corrupted = bytes.clone();
+        corrupted[SERIALIZED_PROGRAM_MAGIC.len() + 31] ^= 1;
+        assert!(matches!(
+            executable.load_compiled_program(&corrupted),
+            Err(EbpfError::JitCacheError(message)) if message == "key mismatch"
+        ));
+        // So must the checksum of the machine code and the relocations
+        for offset in [bytes.len() / 2, bytes.len() - 1] {
+            let mut corrupted = bytes.clone();
+            corrupted[offset] ^= 1;
+            assert!(matches!(
+                executable.load_compiled_program(&corrupted),
+                Err(EbpfError::JitCacheError(message)) if message == "checksum mismatch"
+            ));
+        }
+        assert!(matches!(
+            executable.load_compiled_program(&bytes[0..bytes.len() - 1]),
+            Err(EbpfError::JitCacheError(_))
+        ));
+        let other_executable = create_mockup_executable(Config::default(), &[]);
+        assert!(matches!(
+            JitProgram::deserialize(&other_executable, &bytes),
+            Err(EbpfError::JitCacheError(_))
+        ));
+    }
+
+    #[test]
+    fn test_disassemble_compiled_program() {
+        let mut executable = assemble::<TestContextObject>(
+            "
+            lddw r1, 0x100000000
+            stxdw [r10-0x8], r1
+            call function_foo
+            exit
+            function_foo:
+            mov64 r0, 0x10
+            exit",
+            Arc::new(BuiltinProgram::new_loader(
+                Config {
+                    enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V0,
+                    ..Config::default()
+                },
+                FunctionRegistry::default(),
+            )),
+        )
+        .unwrap();
+        executable.jit_compile().unwrap();
+        let disassemble = |compiled_program: &JitProgram| {
+            let mut output = Vec::new();
+            compiled_program
+                .disassemble(&mut output, |output, pc| writeln!(output, "pc {pc}"))
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let listing = disassemble(executable.get_compiled_program().unwrap());
+        for anchor in ["epilogue", "exit", "throw_exception", "translate_store_8"] {
+            assert!(listing.contains(&format!("\n<{anchor}>:\n")));
+        }
+        // Every pc is annotated once and in order, the second slot of lddw together with the first
+        let pcs = listing
+            .lines()
+            .filter_map(|line| line.strip_prefix("pc "))
+            .collect::<Vec<_>>();
+        assert_eq!(pcs, ["0", "1", "2", "3", "4", "5", "6"]);
+        assert!(listing.contains("pc 0\npc 1\n"));
+        assert!(!listing.contains(".byte"));
+
+        // The anchors survive serialization
+        let labels = |listing: &str| {
+            listing
+                .lines()
+                .filter(|line| !line.starts_with(' '))
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+        };
+        let bytes = executable.serialize_compiled_program().unwrap();
+        executable.load_compiled_program(&bytes).unwrap();
+        assert_eq!(
+            labels(&disassemble(executable.get_compiled_program().unwrap())),
+            labels(&listing)
+        );
+    }
+
+    #[test]
+    fn test_register_symbols() {
+        let mut executable = assemble::<TestContextObject>(
+            "
+            call function_profiled
+            exit
+            function_profiled:
+            mov64 r0, 0x10
+            exit",
+            Arc::new(BuiltinProgram::new_loader(
+                Config {
+                    enable_symbol_and_section_labels: true,
+                    enable_profiler_symbols: true,
+                    ..Config::default()
+                },
+                FunctionRegistry::default(),
+            )),
+        )
+        .unwrap();
+        executable.jit_compile().unwrap();
+        let compiled_program = executable.get_compiled_program().unwrap();
+        let perf_map_path = format!("/tmp/perf-{}.map", std::process::id());
+        let perf_map = std::fs::read_to_string(&perf_map_path).unwrap();
+        let function_start = compiled_program.pc_section[2];
+        let function_end =
+            compiled_program.text_section.as_ptr() as usize + compiled_program.text_section.len();
+        assert!(perf_map.contains(&format!(
+            "{:x} {:x} function_profiled\n",
+            function_start,
+            function_end - function_start,
+        )));
+        let epilogue = compiled_program.anchors[ANCHOR_EPILOGUE];
+        assert!(perf_map.contains(&format!("{epilogue:x} ")));
+        assert!(perf_map.contains(" solana_rbpf::epilogue\n"));
+
+        // Loading a cached program registers it again, the perf map is only appended to
+        let bytes = executable.serialize_compiled_program().unwrap();
+        executable.load_compiled_program(&bytes).unwrap();
+        let function_start = executable.get_compiled_program().unwrap().pc_section[2];
+        let perf_map = std::fs::read_to_string(&perf_map_path).unwrap();
+        assert_eq!(perf_map.matches("function_profiled").count(), 2);
+        assert!(perf_map.contains(&format!("{function_start:x} ")));
+        drop(executable);
+        let perf_map = std::fs::read_to_string(&perf_map_path).unwrap();
+        assert_eq!(perf_map.matches("function_profiled").count(), 2);
+    }
+
     #[test]
     fn test_code_length_estimate() {
         const INSTRUCTION_COUNT: usize = 256;
@@ -0,0 +1,2008 @@
+#![allow(clippy::arithmetic_side_effects)]
+// Only the x86_64 backend is used on x86_64 hosts, but this one is still compiled and tested there
+#![cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+// Copyright 2024 Solana Maintainers <maintainers@solana.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! AArch64 backend of the JIT compiler
+//!
+//! Mirrors the x86_64 backend in `jit.rs`: it uses the same anchors, runtime environment slots, host stack layout
+//! of BPF to BPF calls and instruction meter, so that `JitProgram` and `EbpfVm` work with either of them.
+
+#[cfg(not(feature = "shuttle-test"))]
+use rand::{thread_rng, Rng};
+
+#[cfg(feature = "shuttle-test")]
+use shuttle::rand::{thread_rng, Rng};
+
+use rand::{
+    distributions::{Distribution, Uniform},
+    rngs::SmallRng,
+    SeedableRng,
+};
+use std::{collections::BTreeMap, mem, ptr};
+
+use crate::{
+    aarch64::*,
+    ebpf::{self, FIRST_SCRATCH_REG, FRAME_PTR_REG, SCRATCH_REGS},
+    elf::Executable,
+    error::{EbpfError, ProgramResult},
+    jit::*,
+    memory_region::{jit_layout, AccessType, MemoryMapping},
+    program::BuiltinFunction,
+    static_analysis::{Analysis, BoundsCheckProof},
+    vm::{get_runtime_environment_key, Config, ContextObject},
+};
+
+const MAX_EMPTY_PROGRAM_MACHINE_CODE_LENGTH: usize = 8192;
+const MAX_MACHINE_CODE_LENGTH_PER_INSTRUCTION: usize = 256;
+const MACHINE_CODE_PER_INSTRUCTION_METER_CHECKPOINT: usize = 28;
+const MACHINE_CODE_PER_RESUMABLE_INSTRUCTION_METER_VALIDATION: usize = 16;
+const MACHINE_CODE_PER_CANCELLATION_CHECK: usize = 20;
+/// Unconditional branches and calls reach +/- 128 MiB
+const MAX_TEXT_SECTION_LENGTH: usize = 1 << 27;
+
+const REGISTER_MAP: [u8; 11] = [
+    CALLEE_SAVED_REGISTERS[0], // X19
+    ARGUMENT_REGISTERS[1],     // X1
+    ARGUMENT_REGISTERS[2],     // X2
+    ARGUMENT_REGISTERS[3],     // X3
+    ARGUMENT_REGISTERS[4],     // X4
+    ARGUMENT_REGISTERS[5],     // X5
+    CALLEE_SAVED_REGISTERS[1], // X20
+    CALLEE_SAVED_REGISTERS[2], // X21
+    CALLEE_SAVED_REGISTERS[3], // X22
+    CALLEE_SAVED_REGISTERS[4], // X23
+    CALLEE_SAVED_REGISTERS[5], // X24
+];
+
+/// X25: Used together with slot_in_vm()
+const REGISTER_PTR_TO_VM: u8 = CALLEE_SAVED_REGISTERS[6];
+/// X26: Program counter limit
+const REGISTER_INSTRUCTION_METER: u8 = CALLEE_SAVED_REGISTERS[7];
+/// X27: Scratch register, plays the role of R11 in the x86_64 backend
+const REGISTER_SCRATCH: u8 = CALLEE_SAVED_REGISTERS[8];
+/// X28: Second scratch register, holds immediate operands and the values to store
+const REGISTER_OTHER_SCRATCH: u8 = CALLEE_SAVED_REGISTERS[9];
+/// X16: Displacement of a slot in the RuntimeEnvironment, see emit_slot_displacement()
+const REGISTER_SLOT_DISPLACEMENT: u8 = X16;
+/// X17: Temporary for immediates which are not provided by the user
+const REGISTER_IMMEDIATE: u8 = X17;
+
+/// Size of the host stack area emit_rust_call() saves the caller saved registers in
+const RUST_CALL_SAVE_AREA: u16 = 48;
+/// Size of the host stack frame of a BPF to BPF call, see ANCHOR_INTERNAL_FUNCTION_CALL_PROLOGUE
+const CALL_FRAME_SIZE: u16 = 8 * (SCRATCH_REGS as u16 + 2);
+
+pub struct JitCompiler<'a, C: ContextObject> {
+    result: JitProgram,
+    text_section_jumps: Vec<Jump>,
+    anchors: [*const u8; ANCHOR_COUNT],
+    offset_in_text_section: usize,
+    executable: &'a Executable<C>,
+    program: &'a [u8],
+    program_vm_addr: u64,
+    config: &'a Config,
+    pc: usize,
+    last_instruction_meter_validation_pc: usize,
+    next_noop_insertion: u32,
+    noop_range: Uniform<u32>,
+    runtime_environment_key: i32,
+    diversification_rng: SmallRng,
+    bounds_check_proofs: BTreeMap<usize, BoundsCheckProof>,
+    instruction_meter_checkpoints: Vec<usize>,
+}
+
+#[rustfmt::skip]
+impl<'a, C: ContextObject> JitCompiler<'a, C> {
+    /// Constructs a new compiler and allocates memory for the compilation output
+    pub fn new(executable: &'a Executable<C>) -> Result<Self, EbpfError> {
+        let config = executable.get_config();
+        let (program_vm_addr, program) = executable.get_text_bytes();
+
+        // Scan through program to find actual number of instructions
+        let mut pc = 0;
+        if !executable.get_sbpf_version().disable_lddw() {
+            while (pc + 1) * ebpf::INSN_SIZE <= program.len() {
+                let insn = ebpf::get_insn_unchecked(program, pc);
+                pc += match insn.opc {
+                    ebpf::LD_DW_IMM => 2,
+                    _ => 1,
+                };
+            }
+        } else {
+            pc = program.len() / ebpf::INSN_SIZE;
+        }
+
+        let mut code_length_estimate = MAX_EMPTY_PROGRAM_MACHINE_CODE_LENGTH + MAX_START_PADDING_LENGTH + MAX_MACHINE_CODE_LENGTH_PER_INSTRUCTION * pc;
+        if config.noop_instruction_rate != 0 {
+            code_length_estimate += code_length_estimate / config.noop_instruction_rate as usize;
+        }
+        if let Some(checkpoint_count) = pc.checked_div(config.instruction_meter_checkpoint_distance) {
+            code_length_estimate += checkpoint_count * MACHINE_CODE_PER_INSTRUCTION_METER_CHECKPOINT;
+        }
+        if meters_exactly(config) {
+            // At most one checkpoint per instruction, `callx` is covered by MAX_MACHINE_CODE_LENGTH_PER_INSTRUCTION
+            code_length_estimate += pc * MACHINE_CODE_PER_INSTRUCTION_METER_CHECKPOINT;
+        }
+        if config.enable_resumable_execution || meters_exactly(config) {
+            code_length_estimate += pc * MACHINE_CODE_PER_RESUMABLE_INSTRUCTION_METER_VALIDATION;
+        }
+        if config.enable_cancellation_checks {
+            code_length_estimate += pc * MACHINE_CODE_PER_CANCELLATION_CHECK;
+        }
+        // Relative jump destinations limit the maximum output size
+        if code_length_estimate >= MAX_TEXT_SECTION_LENGTH {
+            return Err(EbpfError::ExhaustedTextSegment(pc));
+        }
+
+        let mut result = JitProgram::new(pc, code_length_estimate)?;
+        if !config.instruction_costs.is_uniform() {
+            // One more entry for the pc after the last instruction, which throws ExecutionOverrun
+            result.instruction_cost_prefix = Vec::with_capacity(pc + 2);
+            let mut instruction_cost_prefix = 0;
+            for slot in 0..=pc {
+                result.instruction_cost_prefix.push(instruction_cost_prefix);
+                instruction_cost_prefix += program.get(slot * ebpf::INSN_SIZE).map(|opc| config.instruction_costs.get(*opc)).unwrap_or(1);
+            }
+            result.instruction_cost_prefix.push(instruction_cost_prefix);
+            // Kept in the same range as in the x86_64 backend
+            if instruction_cost_prefix > i32::MAX as u64 {
+                return Err(EbpfError::ExhaustedTextSegment(pc));
+            }
+        }
+        let instruction_meter_checkpoints = if meters_exactly(config) {
+            exact_instruction_meter_checkpoints(executable, pc)
+        } else {
+            Vec::new()
+        };
+        result.max_checkpoint_interval_cost = max_checkpoint_interval_cost(&instruction_meter_checkpoints, &result.instruction_cost_prefix);
+        // Kept in the same range as in the x86_64 backend
+        if result.instruction_cost_prefix.last().copied().unwrap_or(pc as u64 + 1) + result.max_checkpoint_interval_cost > i32::MAX as u64 {
+            return Err(EbpfError::ExhaustedTextSegment(pc));
+        }
+
+        let runtime_environment_key = get_runtime_environment_key();
+        let mut diversification_rng = SmallRng::from_rng(thread_rng()).map_err(|_| EbpfError::JitNotCompiled)?;
+        let bounds_check_proofs = if eliminates_bounds_checks(config) {
+            Analysis::from_executable(executable)?.redundant_bounds_checks()
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            result,
+            text_section_jumps: vec![],
+            anchors: [std::ptr::null(); ANCHOR_COUNT],
+            offset_in_text_section: 0,
+            executable,
+            program_vm_addr,
+            program,
+            config,
+            pc: 0,
+            last_instruction_meter_validation_pc: 0,
+            next_noop_insertion: if config.noop_instruction_rate == 0 { u32::MAX } else { diversification_rng.gen_range(0..config.noop_instruction_rate * 2) },
+            noop_range: Uniform::new_inclusive(0, config.noop_instruction_rate * 2),
+            runtime_environment_key,
+            diversification_rng,
+            bounds_check_proofs,
+            instruction_meter_checkpoints,
+        })
+    }
+
+    /// Compiles the given executable, consuming the compiler
+    pub fn compile(mut self) -> Result<JitProgram, EbpfError> {
+        let text_section_base = self.result.text_section.as_ptr();
+
+        // Randomized padding at the start before random intervals begin
+        if self.config.noop_instruction_rate != 0 {
+            for _ in 0..self.diversification_rng.gen_range(0..MAX_START_PADDING_LENGTH / mem::size_of::<u32>()) {
+                ARM64Instruction::nop().emit(&mut self);
+            }
+        }
+
+        self.emit_subroutines();
+
+        while self.pc * ebpf::INSN_SIZE < self.program.len() {
+            if self.offset_in_text_section + MAX_MACHINE_CODE_LENGTH_PER_INSTRUCTION * 2 >= self.result.text_section.len() {
+                return Err(EbpfError::ExhaustedTextSegment(self.pc));
+            }
+            let mut insn = ebpf::get_insn_unchecked(self.program, self.pc);
+            self.result.pc_section[self.pc] = unsafe { text_section_base.add(self.offset_in_text_section) } as usize;
+
+            // Regular instruction meter checkpoints to prevent long linear runs from exceeding their budget
+            if self.last_instruction_meter_validation_pc + self.config.instruction_meter_checkpoint_distance <= self.pc
+                || self.instruction_meter_checkpoints.binary_search(&self.pc).is_ok() {
+                self.emit_validate_instruction_count(Some(self.pc));
+            }
+            if insn.opc == ebpf::CALL_REG && meters_exactly(self.config) && !self.executable.get_sbpf_version().static_syscalls() {
+                self.emit_validate_indirect_call_instruction_count();
+            }
+
+            if self.config.enable_instruction_tracing {
+                self.emit_load_immediate(REGISTER_SCRATCH, self.pc as i64);
+                self.emit_call_anchor(ANCHOR_TRACE);
+                self.emit_load_immediate(REGISTER_SCRATCH, 0);
+            }
+
+            let dst = REGISTER_MAP[insn.dst as usize];
+            let src = REGISTER_MAP[insn.src as usize];
+            let target_pc = (self.pc as isize + insn.off as isize + 1) as usize;
+
+            match insn.opc {
+                ebpf::LD_DW_IMM if !self.executable.get_sbpf_version().disable_lddw() => {
+                    self.emit_validate_and_profile_instruction_count(false, Some(self.pc + 2));
+                    self.pc += 1;
+                    self.result.pc_section[self.pc] = self.anchors[ANCHOR_CALL_UNSUPPORTED_INSTRUCTION] as usize;
+                    ebpf::augment_lddw_unchecked(self.program, &mut insn);
+                    self.emit_user_provided_immediate(dst, insn.imm);
+                },
+
+                // BPF_LDX class
+                ebpf::LD_B_REG  if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(Some(dst), Value::RegisterPlusConstant64(src, insn.off as i64, true), 1, None);
+                },
+                ebpf::LD_H_REG  if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(Some(dst), Value::RegisterPlusConstant64(src, insn.off as i64, true), 2, None);
+                },
+                ebpf::LD_W_REG  if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(Some(dst), Value::RegisterPlusConstant64(src, insn.off as i64, true), 4, None);
+                },
+                ebpf::LD_DW_REG if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(Some(dst), Value::RegisterPlusConstant64(src, insn.off as i64, true), 8, None);
+                },
+
+                // BPF_ST class
+                ebpf::ST_B_IMM  if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 1, Some(Value::Constant64(insn.imm, true)));
+                },
+                ebpf::ST_H_IMM  if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 2, Some(Value::Constant64(insn.imm, true)));
+                },
+                ebpf::ST_W_IMM  if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 4, Some(Value::Constant64(insn.imm, true)));
+                },
+                ebpf::ST_DW_IMM if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 8, Some(Value::Constant64(insn.imm, true)));
+                },
+
+                // BPF_STX class
+                ebpf::ST_B_REG  if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 1, Some(Value::Register(src)));
+                },
+                ebpf::ST_H_REG  if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 2, Some(Value::Register(src)));
+                },
+                ebpf::ST_W_REG  if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 4, Some(Value::Register(src)));
+                },
+                ebpf::ST_DW_REG if !self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 8, Some(Value::Register(src)));
+                },
+
+                // BPF_ALU32_LOAD class
+                ebpf::ADD32_IMM  => {
+                    self.emit_sanitized_alu(OperandSize::S32, alu_add, dst, insn.imm);
+                    if !self.executable.get_sbpf_version().explicit_sign_extension_of_results() {
+                        self.emit_ins(ARM64Instruction::sign_extend_to_i64(OperandSize::S32, dst, dst));
+                    }
+                },
+                ebpf::ADD32_REG  => {
+                    self.emit_ins(alu_add(OperandSize::S32, src, dst));
+                    if !self.executable.get_sbpf_version().explicit_sign_extension_of_results() {
+                        self.emit_ins(ARM64Instruction::sign_extend_to_i64(OperandSize::S32, dst, dst));
+                    }
+                },
+                ebpf::SUB32_IMM  => {
+                    if self.executable.get_sbpf_version().swap_sub_reg_imm_operands() {
+                        self.emit_ins(alu_neg(OperandSize::S32, dst));
+                        if insn.imm != 0 {
+                            self.emit_sanitized_alu(OperandSize::S32, alu_add, dst, insn.imm);
+                        }
+                    } else {
+                        self.emit_sanitized_alu(OperandSize::S32, alu_sub, dst, insn.imm);
+                    }
+                    if !self.executable.get_sbpf_version().explicit_sign_extension_of_results() {
+                        self.emit_ins(ARM64Instruction::sign_extend_to_i64(OperandSize::S32, dst, dst));
+                    }
+                },
+                ebpf::SUB32_REG  => {
+                    self.emit_ins(alu_sub(OperandSize::S32, src, dst));
+                    if !self.executable.get_sbpf_version().explicit_sign_extension_of_results() {
+                        self.emit_ins(ARM64Instruction::sign_extend_to_i64(OperandSize::S32, dst, dst));
+                    }
+                },
+                ebpf::MUL32_IMM | ebpf::DIV32_IMM | ebpf::MOD32_IMM if !self.executable.get_sbpf_version().enable_pqr() =>
+                    self.emit_product_quotient_remainder(
+                        OperandSize::S32,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) == ebpf::BPF_MOD,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) != ebpf::BPF_MUL,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) == ebpf::BPF_MUL,
+                        dst, dst, Some(insn.imm),
+                    ),
+                ebpf::LD_1B_REG  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(Some(dst), Value::RegisterPlusConstant64(src, insn.off as i64, true), 1, None);
+                },
+                ebpf::MUL32_REG | ebpf::DIV32_REG | ebpf::MOD32_REG if !self.executable.get_sbpf_version().enable_pqr() =>
+                    self.emit_product_quotient_remainder(
+                        OperandSize::S32,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) == ebpf::BPF_MOD,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) != ebpf::BPF_MUL,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) == ebpf::BPF_MUL,
+                        src, dst, None,
+                    ),
+                ebpf::LD_2B_REG  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(Some(dst), Value::RegisterPlusConstant64(src, insn.off as i64, true), 2, None);
+                },
+                ebpf::OR32_IMM   => self.emit_sanitized_alu(OperandSize::S32, ARM64Instruction::orr, dst, insn.imm),
+                ebpf::OR32_REG   => self.emit_ins(ARM64Instruction::orr(OperandSize::S32, src, dst)),
+                ebpf::AND32_IMM  => self.emit_sanitized_alu(OperandSize::S32, alu_and, dst, insn.imm),
+                ebpf::AND32_REG  => self.emit_ins(alu_and(OperandSize::S32, src, dst)),
+                ebpf::LSH32_IMM  => self.emit_shift(OperandSize::S32, ARM64Instruction::lsl_reg, REGISTER_OTHER_SCRATCH, dst, Some(insn.imm)),
+                ebpf::LSH32_REG  => self.emit_shift(OperandSize::S32, ARM64Instruction::lsl_reg, src, dst, None),
+                ebpf::RSH32_IMM  => self.emit_shift(OperandSize::S32, ARM64Instruction::lsr_reg, REGISTER_OTHER_SCRATCH, dst, Some(insn.imm)),
+                ebpf::RSH32_REG  => self.emit_shift(OperandSize::S32, ARM64Instruction::lsr_reg, src, dst, None),
+                ebpf::NEG32      if !self.executable.get_sbpf_version().disable_neg() => self.emit_ins(alu_neg(OperandSize::S32, dst)),
+                ebpf::LD_4B_REG  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(Some(dst), Value::RegisterPlusConstant64(src, insn.off as i64, true), 4, None);
+                },
+                ebpf::LD_8B_REG  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(Some(dst), Value::RegisterPlusConstant64(src, insn.off as i64, true), 8, None);
+                },
+                ebpf::XOR32_IMM  => self.emit_sanitized_alu(OperandSize::S32, ARM64Instruction::eor, dst, insn.imm),
+                ebpf::XOR32_REG  => self.emit_ins(ARM64Instruction::eor(OperandSize::S32, src, dst)),
+                ebpf::MOV32_IMM  => self.emit_user_provided_immediate(dst, insn.imm as u32 as u64 as i64),
+                ebpf::MOV32_REG  => {
+                    if self.executable.get_sbpf_version().explicit_sign_extension_of_results() {
+                        self.emit_ins(ARM64Instruction::sign_extend_to_i64(OperandSize::S32, src, dst));
+                    } else {
+                        self.emit_ins(ARM64Instruction::mov(OperandSize::S32, src, dst));
+                    }
+                }
+                ebpf::ARSH32_IMM => self.emit_shift(OperandSize::S32, ARM64Instruction::asr_reg, REGISTER_OTHER_SCRATCH, dst, Some(insn.imm)),
+                ebpf::ARSH32_REG => self.emit_shift(OperandSize::S32, ARM64Instruction::asr_reg, src, dst, None),
+                ebpf::LE if !self.executable.get_sbpf_version().disable_le() => {
+                    match insn.imm {
+                        16 => {
+                            self.emit_ins(ARM64Instruction::zero_extend_to_u64(OperandSize::S16, dst, dst)); // Mask to 16 bit
+                        }
+                        32 => {
+                            self.emit_ins(ARM64Instruction::zero_extend_to_u64(OperandSize::S32, dst, dst)); // Mask to 32 bit
+                        }
+                        64 => {}
+                        _ => {
+                            return Err(EbpfError::InvalidInstruction);
+                        }
+                    }
+                },
+                ebpf::BE         => {
+                    match insn.imm {
+                        16 => {
+                            self.emit_ins(ARM64Instruction::rev(OperandSize::S16, dst, dst));
+                            self.emit_ins(ARM64Instruction::zero_extend_to_u64(OperandSize::S16, dst, dst)); // Mask to 16 bit
+                        }
+                        32 => self.emit_ins(ARM64Instruction::rev(OperandSize::S32, dst, dst)),
+                        64 => self.emit_ins(ARM64Instruction::rev(OperandSize::S64, dst, dst)),
+                        _ => {
+                            return Err(EbpfError::InvalidInstruction);
+                        }
+                    }
+                },
+
+                // BPF_ALU64_STORE class
+                ebpf::ADD64_IMM  => self.emit_sanitized_alu(OperandSize::S64, alu_add, dst, insn.imm),
+                ebpf::ADD64_REG  => self.emit_ins(alu_add(OperandSize::S64, src, dst)),
+                ebpf::SUB64_IMM  => {
+                    if self.executable.get_sbpf_version().swap_sub_reg_imm_operands() {
+                        self.emit_ins(alu_neg(OperandSize::S64, dst));
+                        if insn.imm != 0 {
+                            self.emit_sanitized_alu(OperandSize::S64, alu_add, dst, insn.imm);
+                        }
+                    } else {
+                        self.emit_sanitized_alu(OperandSize::S64, alu_sub, dst, insn.imm);
+                    }
+                }
+                ebpf::SUB64_REG  => self.emit_ins(alu_sub(OperandSize::S64, src, dst)),
+                ebpf::MUL64_IMM | ebpf::DIV64_IMM | ebpf::MOD64_IMM if !self.executable.get_sbpf_version().enable_pqr() =>
+                    self.emit_product_quotient_remainder(
+                        OperandSize::S64,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) == ebpf::BPF_MOD,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) != ebpf::BPF_MUL,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) == ebpf::BPF_MUL,
+                        dst, dst, Some(insn.imm),
+                    ),
+                ebpf::ST_1B_IMM  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 1, Some(Value::Constant64(insn.imm, true)));
+                },
+                ebpf::ST_2B_IMM  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 2, Some(Value::Constant64(insn.imm, true)));
+                },
+                ebpf::MUL64_REG | ebpf::DIV64_REG | ebpf::MOD64_REG if !self.executable.get_sbpf_version().enable_pqr() =>
+                    self.emit_product_quotient_remainder(
+                        OperandSize::S64,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) == ebpf::BPF_MOD,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) != ebpf::BPF_MUL,
+                        (insn.opc & ebpf::BPF_ALU_OP_MASK) == ebpf::BPF_MUL,
+                        src, dst, None,
+                    ),
+                ebpf::ST_1B_REG  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 1, Some(Value::Register(src)));
+                },
+                ebpf::ST_2B_REG  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 2, Some(Value::Register(src)));
+                },
+                ebpf::OR64_IMM   => self.emit_sanitized_alu(OperandSize::S64, ARM64Instruction::orr, dst, insn.imm),
+                ebpf::OR64_REG   => self.emit_ins(ARM64Instruction::orr(OperandSize::S64, src, dst)),
+                ebpf::AND64_IMM  => self.emit_sanitized_alu(OperandSize::S64, alu_and, dst, insn.imm),
+                ebpf::AND64_REG  => self.emit_ins(alu_and(OperandSize::S64, src, dst)),
+                ebpf::LSH64_IMM  => self.emit_shift(OperandSize::S64, ARM64Instruction::lsl_reg, REGISTER_OTHER_SCRATCH, dst, Some(insn.imm)),
+                ebpf::LSH64_REG  => self.emit_shift(OperandSize::S64, ARM64Instruction::lsl_reg, src, dst, None),
+                ebpf::RSH64_IMM  => self.emit_shift(OperandSize::S64, ARM64Instruction::lsr_reg, REGISTER_OTHER_SCRATCH, dst, Some(insn.imm)),
+                ebpf::RSH64_REG  => self.emit_shift(OperandSize::S64, ARM64Instruction::lsr_reg, src, dst, None),
+                ebpf::ST_4B_IMM  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 4, Some(Value::Constant64(insn.imm, true)));
+                },
+                ebpf::NEG64      if !self.executable.get_sbpf_version().disable_neg() => self.emit_ins(alu_neg(OperandSize::S64, dst)),
+                ebpf::ST_4B_REG  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 4, Some(Value::Register(src)));
+                },
+                ebpf::ST_8B_IMM  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 8, Some(Value::Constant64(insn.imm, true)));
+                },
+                ebpf::ST_8B_REG  if self.executable.get_sbpf_version().move_memory_instruction_classes() => {
+                    self.emit_address_translation(None, Value::RegisterPlusConstant64(dst, insn.off as i64, true), 8, Some(Value::Register(src)));
+                },
+                ebpf::XOR64_IMM  => self.emit_sanitized_alu(OperandSize::S64, ARM64Instruction::eor, dst, insn.imm),
+                ebpf::XOR64_REG  => self.emit_ins(ARM64Instruction::eor(OperandSize::S64, src, dst)),
+                ebpf::MOV64_IMM  => self.emit_user_provided_immediate(dst, insn.imm),
+                ebpf::MOV64_REG  => self.emit_ins(ARM64Instruction::mov(OperandSize::S64, src, dst)),
+                ebpf::ARSH64_IMM => self.emit_shift(OperandSize::S64, ARM64Instruction::asr_reg, REGISTER_OTHER_SCRATCH, dst, Some(insn.imm)),
+                ebpf::ARSH64_REG => self.emit_shift(OperandSize::S64, ARM64Instruction::asr_reg, src, dst, None),
+                ebpf::HOR64_IMM if self.executable.get_sbpf_version().disable_lddw() => {
+                    self.emit_sanitized_alu(OperandSize::S64, ARM64Instruction::orr, dst, (insn.imm as u64).wrapping_shl(32) as i64);
+                }
+
+                // BPF_PQR class
+                ebpf::LMUL32_IMM | ebpf::LMUL64_IMM | ebpf::UHMUL64_IMM | ebpf::SHMUL64_IMM |
+                ebpf::UDIV32_IMM | ebpf::UDIV64_IMM | ebpf::UREM32_IMM | ebpf::UREM64_IMM |
+                ebpf::SDIV32_IMM | ebpf::SDIV64_IMM | ebpf::SREM32_IMM | ebpf::SREM64_IMM
+                if self.executable.get_sbpf_version().enable_pqr() => {
+                    let signed = insn.opc & (1 << 7) != 0;
+                    let mut imm = insn.imm;
+                    if !signed {
+                        imm &= u32::MAX as i64;
+                    }
+                    self.emit_product_quotient_remainder(
+                        if insn.opc & (1 << 4) != 0 { OperandSize::S64 } else { OperandSize::S32 },
+                        insn.opc & (1 << 5) != 0,
+                        insn.opc & (1 << 6) != 0,
+                        signed,
+                        dst, dst, Some(imm),
+                    )
+                }
+                ebpf::LMUL32_REG | ebpf::LMUL64_REG | ebpf::UHMUL64_REG | ebpf::SHMUL64_REG |
+                ebpf::UDIV32_REG | ebpf::UDIV64_REG | ebpf::UREM32_REG | ebpf::UREM64_REG |
+                ebpf::SDIV32_REG | ebpf::SDIV64_REG | ebpf::SREM32_REG | ebpf::SREM64_REG
+                if self.executable.get_sbpf_version().enable_pqr() =>
+                    self.emit_product_quotient_remainder(
+                        if insn.opc & (1 << 4) != 0 { OperandSize::S64 } else { OperandSize::S32 },
+                        insn.opc & (1 << 5) != 0,
+                        insn.opc & (1 << 6) != 0,
+                        insn.opc & (1 << 7) != 0,
+                        src, dst, None,
+                    ),
+
+                // BPF_JMP class
+                ebpf::JA         => {
+                    self.emit_validate_and_profile_instruction_count(true, Some(target_pc));
+                    self.emit_load_immediate(REGISTER_SCRATCH, target_pc as i64);
+                    self.emit_jump_to_target_pc(target_pc);
+                },
+                ebpf::JEQ_IMM    => self.emit_conditional_branch_imm(Condition::EQ, false, insn.imm, dst, target_pc),
+                ebpf::JEQ_REG    => self.emit_conditional_branch_reg(Condition::EQ, false, src, dst, target_pc),
+                ebpf::JGT_IMM    => self.emit_conditional_branch_imm(Condition::HI, false, insn.imm, dst, target_pc),
+                ebpf::JGT_REG    => self.emit_conditional_branch_reg(Condition::HI, false, src, dst, target_pc),
+                ebpf::JGE_IMM    => self.emit_conditional_branch_imm(Condition::HS, false, insn.imm, dst, target_pc),
+                ebpf::JGE_REG    => self.emit_conditional_branch_reg(Condition::HS, false, src, dst, target_pc),
+                ebpf::JLT_IMM    => self.emit_conditional_branch_imm(Condition::LO, false, insn.imm, dst, target_pc),
+                ebpf::JLT_REG    => self.emit_conditional_branch_reg(Condition::LO, false, src, dst, target_pc),
+                ebpf::JLE_IMM    => self.emit_conditional_branch_imm(Condition::LS, false, insn.imm, dst, target_pc),
+                ebpf::JLE_REG    => self.emit_conditional_branch_reg(Condition::LS, false, src, dst, target_pc),
+                ebpf::JSET_IMM   => self.emit_conditional_branch_imm(Condition::NE, true, insn.imm, dst, target_pc),
+                ebpf::JSET_REG   => self.emit_conditional_branch_reg(Condition::NE, true, src, dst, target_pc),
+                ebpf::JNE_IMM    => self.emit_conditional_branch_imm(Condition::NE, false, insn.imm, dst, target_pc),
+                ebpf::JNE_REG    => self.emit_conditional_branch_reg(Condition::NE, false, src, dst, target_pc),
+                ebpf::JSGT_IMM   => self.emit_conditional_branch_imm(Condition::GT, false, insn.imm, dst, target_pc),
+                ebpf::JSGT_REG   => self.emit_conditional_branch_reg(Condition::GT, false, src, dst, target_pc),
+                ebpf::JSGE_IMM   => self.emit_conditional_branch_imm(Condition::GE, false, insn.imm, dst, target_pc),
+                ebpf::JSGE_REG   => self.emit_conditional_branch_reg(Condition::GE, false, src, dst, target_pc),
+                ebpf::JSLT_IMM   => self.emit_conditional_branch_imm(Condition::LT, false, insn.imm, dst, target_pc),
+                ebpf::JSLT_REG   => self.emit_conditional_branch_reg(Condition::LT, false, src, dst, target_pc),
+                ebpf::JSLE_IMM   => self.emit_conditional_branch_imm(Condition::LE, false, insn.imm, dst, target_pc),
+                ebpf::JSLE_REG   => self.emit_conditional_branch_reg(Condition::LE, false, src, dst, target_pc),
+                ebpf::CALL_IMM => {
+                    // For JIT, external functions MUST be registered at compile time.
+                    if let (false, Some((_, function))) =
+                            (self.executable.get_sbpf_version().static_syscalls(),
+                                self.executable.get_loader().get_function_registry(self.executable.get_sbpf_version()).lookup_by_key(insn.imm as u32)) {
+                        // SBPFv0 syscall
+                        self.emit_syscall_dispatch(function);
+                    } else if let Some((_function_name, target_pc)) =
+                            self.executable
+                                .get_function_registry()
+                                .lookup_by_key(
+                                    self
+                                        .executable
+                                        .get_sbpf_version()
+                                        .calculate_call_imm_target_pc(self.pc, insn.imm)
+                            ) {
+                        // BPF to BPF call
+                        self.emit_internal_call(Value::Constant64(target_pc as i64, true));
+                    } else {
+                        self.emit_load_immediate(REGISTER_SCRATCH, self.pc as i64);
+                        self.emit_jump_to_anchor(ANCHOR_CALL_UNSUPPORTED_INSTRUCTION);
+                    }
+                },
+                ebpf::SYSCALL if self.executable.get_sbpf_version().static_syscalls() => {
+                    if let Some((_, function)) = self.executable.get_loader().get_function_registry(self.executable.get_sbpf_version()).lookup_by_key(insn.imm as u32) {
+                        self.emit_syscall_dispatch(function);
+                    } else {
+                        debug_assert!(false, "Invalid syscall should have been detected in the verifier.")
+                    }
+                },
+                ebpf::CALL_REG  => {
+                    let target_pc = if self.executable.get_sbpf_version().callx_uses_src_reg() {
+                        src
+                    } else {
+                        REGISTER_MAP[insn.imm as usize]
+                    };
+                    self.emit_internal_call(Value::Register(target_pc));
+                },
+                ebpf::RETURN
+                | ebpf::EXIT      => {
+                    if (insn.opc == ebpf::EXIT && self.executable.get_sbpf_version().static_syscalls())
+                        || (insn.opc == ebpf::RETURN && !self.executable.get_sbpf_version().static_syscalls()) {
+                        return Err(EbpfError::UnsupportedInstruction);
+                    }
+                    self.emit_validate_instruction_count(Some(self.pc));
+
+                    // If env.call_depth == 0, we've reached the exit instruction of the entry point
+                    self.emit_load_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::CallDepth));
+                    self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, X9, 0));
+                    self.emit_load_immediate(REGISTER_SCRATCH, self.pc as i64);
+                    // we're done
+                    self.emit_conditional_jump_to_anchor(Condition::EQ, ANCHOR_EXIT);
+
+                    // else decrement and update env.call_depth
+                    self.emit_ins(ARM64Instruction::sub_imm(OperandSize::S64, X9, 1, X9)); // env.call_depth -= 1;
+                    self.emit_store_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::CallDepth));
+                    if self.reports_calls() {
+                        self.emit_call_anchor(ANCHOR_TRACE_RETURN);
+                    }
+
+                    // and return to the address the caller stored in its frame
+                    self.emit_profile_instruction_count(false, Some(0));
+                    self.emit_ins(ARM64Instruction::load(OperandSize::S64, SP_XZR, ARM64MemoryOperand::OffsetScaled(0), LR));
+                    self.emit_ins(ARM64Instruction::ret());
+                },
+
+                _               => return Err(EbpfError::UnsupportedInstruction),
+            }
+
+            self.pc += 1;
+        }
+
+        // Bumper in case there was no final exit
+        if self.offset_in_text_section + MAX_MACHINE_CODE_LENGTH_PER_INSTRUCTION * 2 >= self.result.text_section.len() {
+            return Err(EbpfError::ExhaustedTextSegment(self.pc));
+        }
+        if self.config.enable_resumable_execution {
+            self.result.resume_addresses = self.result.pc_section.to_vec();
+            self.result.resume_addresses.push(unsafe { text_section_base.add(self.offset_in_text_section) } as usize);
+        }
+        self.emit_validate_and_profile_instruction_count(false, Some(self.pc + 1));
+        self.emit_load_immediate(REGISTER_SCRATCH, self.pc as i64); // Save pc
+        self.emit_set_exception_kind(EbpfError::ExecutionOverrun);
+        self.emit_jump_to_anchor(ANCHOR_THROW_EXCEPTION);
+
+        self.resolve_jumps();
+        self.result.runtime_environment_key = self.runtime_environment_key;
+        self.result.anchors = self.anchors.map(|anchor| anchor as usize);
+        self.result.seal(self.offset_in_text_section)?;
+        Ok(self.result)
+    }
+
+    #[inline]
+    fn should_sanitize_constant(&self, value: i64) -> bool {
+        if !self.config.sanitize_user_provided_values {
+            return false;
+        }
+
+        match value as u64 {
+            0xFFFF
+            | 0xFFFFFF
+            | 0xFFFFFFFF
+            | 0xFFFFFFFFFF
+            | 0xFFFFFFFFFFFF
+            | 0xFFFFFFFFFFFFFF
+            | 0xFFFFFFFFFFFFFFFF => false,
+            v if v <= 0xFF => false,
+            v if !v <= 0xFF => false,
+            _ => true
+        }
+    }
+
+    #[inline]
+    fn slot_in_vm(&self, slot: RuntimeEnvironmentSlot) -> i32 {
+        8 * (slot as i32 - self.runtime_environment_key)
+    }
+
+    #[inline]
+    pub(crate) fn emit<T>(&mut self, data: T) {
+        unsafe {
+            let ptr = self.result.text_section.as_ptr().add(self.offset_in_text_section);
+            #[allow(clippy::cast_ptr_alignment)]
+            ptr::write_unaligned(ptr as *mut T, data as T);
+        }
+        self.offset_in_text_section += mem::size_of::<T>();
+    }
+
+    // This function helps the optimizer to inline the machinecode emission while avoiding stack allocations
+    //
+    // Sequences which branch over a fixed number of instructions or literals use ARM64Instruction::emit() instead,
+    // so that no noop gets inserted in between.
+    #[inline(always)]
+    fn emit_ins(&mut self, instruction: ARM64Instruction) {
+        instruction.emit(self);
+        if self.next_noop_insertion == 0 {
+            self.next_noop_insertion = self.noop_range.sample(&mut self.diversification_rng);
+            ARM64Instruction::nop().emit(self);
+        } else {
+            self.next_noop_insertion -= 1;
+        }
+    }
+
+    /// Loads an absolute host address from a literal, which is relocated when the program is deserialized
+    #[inline]
+    fn emit_load_address(&mut self, destination: u8, address: usize) {
+        ARM64Instruction::ldr_literal(destination, 2).emit(self); // ldr destination, #8
+        ARM64Instruction::b(3).emit(self); // b #12, skip the literal
+        self.result.relocations.push(Relocation::Address(self.offset_in_text_section));
+        self.emit::<u64>(address as u64);
+    }
+
+    /// Loads the displacement of a slot relative to REGISTER_PTR_TO_VM into REGISTER_SLOT_DISPLACEMENT
+    ///
+    /// It comes from a literal, which is relocated when the program is deserialized, as it depends on the runtime
+    /// environment key.
+    #[inline]
+    fn emit_slot_displacement(&mut self, displacement: i32) {
+        ARM64Instruction::ldrsw_literal(REGISTER_SLOT_DISPLACEMENT, 2).emit(self); // ldrsw x16, #8
+        ARM64Instruction::b(2).emit(self); // b #8, skip the literal
+        self.result.relocations.push(Relocation::Environment(self.offset_in_text_section));
+        self.emit::<i32>(displacement);
+    }
+
+    #[inline]
+    fn emit_load_slot(&mut self, destination: u8, displacement: i32) {
+        self.emit_slot_displacement(displacement);
+        self.emit_ins(ARM64Instruction::load(OperandSize::S64, REGISTER_PTR_TO_VM, ARM64MemoryOperand::OffsetIndexShift(REGISTER_SLOT_DISPLACEMENT, false), destination));
+    }
+
+    #[inline]
+    fn emit_store_slot(&mut self, source: u8, displacement: i32) {
+        self.emit_slot_displacement(displacement);
+        self.emit_ins(ARM64Instruction::store(OperandSize::S64, source, REGISTER_PTR_TO_VM, ARM64MemoryOperand::OffsetIndexShift(REGISTER_SLOT_DISPLACEMENT, false)));
+    }
+
+    #[inline]
+    fn emit_slot_address(&mut self, destination: u8, displacement: i32) {
+        self.emit_slot_displacement(displacement);
+        self.emit_ins(ARM64Instruction::add(OperandSize::S64, REGISTER_PTR_TO_VM, REGISTER_SLOT_DISPLACEMENT, destination));
+    }
+
+    /// Loads a 64 bit immediate using as few MOVZ / MOVN / MOVK as possible
+    fn emit_load_immediate(&mut self, destination: u8, value: i64) {
+        let chunks = [value as u16, (value >> 16) as u16, (value >> 32) as u16, (value >> 48) as u16];
+        // Start from all ones if that leaves fewer chunks to fill in
+        let inverted = chunks.iter().filter(|chunk| **chunk == 0xFFFF).count() > chunks.iter().filter(|chunk| **chunk == 0).count();
+        let skipped_chunk = if inverted { 0xFFFF } else { 0 };
+        let mut is_first = true;
+        for (shift, chunk) in chunks.iter().enumerate() {
+            if *chunk == skipped_chunk {
+                continue;
+            }
+            if !is_first {
+                self.emit_ins(ARM64Instruction::movk(destination, shift as u8, *chunk));
+            } else if inverted {
+                self.emit_ins(ARM64Instruction::movn(destination, shift as u8, !*chunk));
+            } else {
+                self.emit_ins(ARM64Instruction::movz(destination, shift as u8, *chunk));
+            }
+            is_first = false;
+        }
+        if is_first {
+            // All chunks are zero or all are ones
+            if inverted {
+                self.emit_ins(ARM64Instruction::movn(destination, 0, 0));
+            } else {
+                self.emit_ins(ARM64Instruction::movz(destination, 0, 0));
+            }
+        }
+    }
+
+    #[inline]
+    fn emit_sanitized_load_immediate(&mut self, destination: u8, value: i64) {
+        debug_assert_ne!(destination, REGISTER_IMMEDIATE);
+        let key = self.diversification_rng.gen::<i64>();
+        self.emit_load_immediate(destination, value.wrapping_sub(key));
+        self.emit_load_immediate(REGISTER_IMMEDIATE, key);
+        self.emit_ins(ARM64Instruction::add(OperandSize::S64, destination, REGISTER_IMMEDIATE, destination)); // wrapping_add(key)
+    }
+
+    #[inline]
+    fn emit_user_provided_immediate(&mut self, destination: u8, value: i64) {
+        if self.should_sanitize_constant(value) {
+            self.emit_sanitized_load_immediate(destination, value);
+        } else {
+            self.emit_load_immediate(destination, value);
+        }
+    }
+
+    #[inline]
+    fn emit_sanitized_alu(&mut self, size: OperandSize, alu: fn(OperandSize, u8, u8) -> ARM64Instruction, destination: u8, immediate: i64) {
+        self.emit_user_provided_immediate(REGISTER_OTHER_SCRATCH, immediate);
+        self.emit_ins(alu(size, REGISTER_OTHER_SCRATCH, destination));
+    }
+
+    /// Adds an immediate which is not provided by the user to a 64 bit register
+    #[inline]
+    fn emit_add_immediate(&mut self, destination: u8, immediate: i64) {
+        if (0..1 << 12).contains(&immediate) {
+            self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, destination, immediate as u16, destination));
+        } else if (-(1 << 12) + 1..0).contains(&immediate) {
+            self.emit_ins(ARM64Instruction::sub_imm(OperandSize::S64, destination, (-immediate) as u16, destination));
+        } else {
+            self.emit_load_immediate(REGISTER_IMMEDIATE, immediate);
+            self.emit_ins(ARM64Instruction::add(OperandSize::S64, destination, REGISTER_IMMEDIATE, destination));
+        }
+    }
+
+    /// Compares a 64 bit register with an immediate which is not provided by the user
+    #[inline]
+    fn emit_cmp_immediate(&mut self, register: u8, immediate: i64) {
+        if (0..1 << 12).contains(&immediate) {
+            self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, register, immediate as u16));
+        } else {
+            self.emit_load_immediate(REGISTER_IMMEDIATE, immediate);
+            self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, REGISTER_IMMEDIATE, register));
+        }
+    }
+
+    /// Whether BPF to BPF calls and returns are reported for instruction tracing or execution statistics
+    #[inline]
+    fn reports_calls(&self) -> bool {
+        self.config.enable_instruction_tracing || self.config.enable_execution_stats
+    }
+
+    #[inline]
+    fn emit_validate_instruction_count(&mut self, pc: Option<usize>) {
+        if !self.config.enable_instruction_meter {
+            if let Some(pc) = pc {
+                self.last_instruction_meter_validation_pc = pc;
+                self.emit_poll_cancellation(pc);
+            }
+            return;
+        }
+        // Update `MACHINE_CODE_PER_INSTRUCTION_METER_CHECKPOINT` if you change the code generation here
+        if let Some(pc) = pc {
+            self.last_instruction_meter_validation_pc = pc;
+            if self.saves_state_on_exceeded_budget() {
+                // Save pc in case the execution gets suspended
+                self.emit_load_immediate(REGISTER_SCRATCH, pc as i64);
+            }
+            // instruction_meter >= self.instruction_cost_prefix(next_checkpoint) - 1
+            let next_checkpoint = self.next_instruction_meter_checkpoint(pc);
+            self.emit_cmp_immediate(REGISTER_INSTRUCTION_METER, self.instruction_cost_prefix(next_checkpoint) - 1);
+            self.emit_conditional_jump_to_anchor(Condition::LS, ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS);
+            self.emit_poll_cancellation(pc);
+            return;
+        } else if self.result.instruction_cost_prefix.is_empty() {
+            // instruction_meter >= scratch_register
+            self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, REGISTER_SCRATCH, REGISTER_INSTRUCTION_METER));
+        } else {
+            // instruction_meter >= instruction_cost_prefix[scratch_register + 1]
+            self.emit_load_address(X10, self.result.instruction_cost_prefix.as_ptr() as usize);
+            self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, X10, mem::size_of::<u64>() as u16, X10));
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, X10, ARM64MemoryOperand::OffsetIndexShift(REGISTER_SCRATCH, true), X11));
+            self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, REGISTER_INSTRUCTION_METER, X11));
+            self.emit_conditional_jump_to_anchor(Condition::HI, ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS);
+            return;
+        }
+        self.emit_conditional_jump_to_anchor(Condition::LS, ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS);
+    }
+
+    /// Without static syscalls the target of `callx` can be in the middle of a run,
+    /// so the budget has to cover the longest run after this instruction.
+    /// Happens before the instruction is traced, as the interpreter repeats it if the validation fails.
+    #[inline]
+    fn emit_validate_indirect_call_instruction_count(&mut self) {
+        self.emit_load_immediate(REGISTER_SCRATCH, self.pc as i64);
+        // instruction_meter >= self.instruction_cost_prefix(self.pc + 1) + max_checkpoint_interval_cost - 1
+        let threshold = self.instruction_cost_prefix(self.pc + 1) + self.result.max_checkpoint_interval_cost as i64 - 1;
+        self.emit_cmp_immediate(REGISTER_INSTRUCTION_METER, threshold);
+        self.emit_conditional_jump_to_anchor(Condition::LS, ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS);
+    }
+
+    #[inline]
+    fn emit_poll_cancellation(&mut self, pc: usize) {
+        if !self.config.enable_cancellation_checks {
+            return;
+        }
+        // Update `MACHINE_CODE_PER_CANCELLATION_CHECK` if you change the code generation here
+        if !self.saves_state_on_exceeded_budget() || !self.config.enable_instruction_meter {
+            self.emit_load_immediate(REGISTER_SCRATCH, pc as i64);
+        }
+        self.emit_call_anchor(ANCHOR_POLL_CANCELLATION);
+    }
+
+    /// Whether ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS saves the state to continue at the pc of the failed validation
+    ///
+    /// Either `EbpfVm::resume_program()` or the interpreter of exact instruction metering continues there.
+    #[inline]
+    fn saves_state_on_exceeded_budget(&self) -> bool {
+        self.config.enable_resumable_execution || meters_exactly(self.config)
+    }
+
+    /// The pc up to which a validation of the instruction meter at pc covers the instructions
+    #[inline]
+    fn next_instruction_meter_checkpoint(&self, pc: usize) -> usize {
+        let index = self.instruction_meter_checkpoints.partition_point(|checkpoint| *checkpoint <= pc);
+        self.instruction_meter_checkpoints.get(index).copied().unwrap_or(pc + 1)
+    }
+
+    /// Accumulated cost of all instructions before pc, which is pc itself if every instruction costs one unit
+    #[inline]
+    fn instruction_cost_prefix(&self, pc: usize) -> i64 {
+        self.result.instruction_cost_prefix.get(pc).map(|cost| *cost as i64).unwrap_or(pc as i64)
+    }
+
+    /// Adds or subtracts the accumulated cost of all instructions before (pc_reg + offset) to / from the instruction meter
+    fn emit_instruction_cost_prefix_alu(&mut self, add: bool, pc_reg: u8, offset: i64) {
+        let alu = if add { ARM64Instruction::add } else { ARM64Instruction::sub };
+        if self.result.instruction_cost_prefix.is_empty() {
+            self.emit_ins(alu(OperandSize::S64, REGISTER_INSTRUCTION_METER, pc_reg, REGISTER_INSTRUCTION_METER)); // instruction_meter +/-= pc_reg;
+            if offset != 0 {
+                self.emit_add_immediate(REGISTER_INSTRUCTION_METER, if add { offset } else { -offset }); // instruction_meter +/-= offset;
+            }
+        } else {
+            self.emit_load_address(X10, self.result.instruction_cost_prefix.as_ptr() as usize);
+            if offset != 0 {
+                self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, X10, (offset * mem::size_of::<u64>() as i64) as u16, X10));
+            }
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, X10, ARM64MemoryOperand::OffsetIndexShift(pc_reg, true), X11));
+            self.emit_ins(alu(OperandSize::S64, REGISTER_INSTRUCTION_METER, X11, REGISTER_INSTRUCTION_METER)); // instruction_meter +/-= instruction_cost_prefix[pc_reg + offset];
+        }
+    }
+
+    #[inline]
+    fn emit_profile_instruction_count(&mut self, user_provided: bool, target_pc: Option<usize>) {
+        if !self.config.enable_instruction_meter {
+            return;
+        }
+        match target_pc {
+            Some(target_pc) => {
+                // instruction_meter += target_pc - (self.pc + 1);
+                let immediate = self.instruction_cost_prefix(target_pc) - self.instruction_cost_prefix(self.pc + 1);
+                if user_provided && self.should_sanitize_constant(immediate) {
+                    self.emit_sanitized_alu(OperandSize::S64, alu_add, REGISTER_INSTRUCTION_METER, immediate);
+                } else {
+                    self.emit_add_immediate(REGISTER_INSTRUCTION_METER, immediate);
+                }
+            },
+            None => {
+                self.emit_add_immediate(REGISTER_INSTRUCTION_METER, -self.instruction_cost_prefix(self.pc + 1)); // instruction_meter -= self.pc + 1;
+                self.emit_instruction_cost_prefix_alu(true, REGISTER_SCRATCH, 0); // instruction_meter += target_pc;
+            },
+        }
+    }
+
+    #[inline]
+    fn emit_validate_and_profile_instruction_count(&mut self, user_provided: bool, target_pc: Option<usize>) {
+        self.emit_validate_instruction_count(Some(self.pc));
+        self.emit_profile_instruction_count(user_provided, target_pc);
+    }
+
+    #[inline]
+    fn emit_undo_profile_instruction_count(&mut self, target_pc: Value) {
+        if self.config.enable_instruction_meter {
+            match target_pc {
+                Value::Constant64(target_pc, _) => {
+                    let immediate = self.instruction_cost_prefix(self.pc + 1) - self.instruction_cost_prefix(target_pc as usize);
+                    self.emit_add_immediate(REGISTER_INSTRUCTION_METER, immediate); // instruction_meter += (self.pc + 1) - target_pc;
+                }
+                Value::Register(target_pc) => {
+                    self.emit_instruction_cost_prefix_alu(false, target_pc, 0); // instruction_meter -= guest_target_pc
+                    self.emit_instruction_cost_prefix_alu(true, REGISTER_SCRATCH, 1); // instruction_meter += self.pc + 1
+                }
+                _ => debug_assert!(false),
+            }
+        }
+    }
+
+    /// Calls a Rust function, argument index 8 is the indirect result location register (X8)
+    fn emit_rust_call(&mut self, target: Value, arguments: &[Argument], result_reg: Option<u8>) {
+        // Save the BPF registers which live in caller saved registers and the link register, SP stays 16 byte aligned
+        let saved_registers = [REGISTER_MAP[1], REGISTER_MAP[2], REGISTER_MAP[3], REGISTER_MAP[4], REGISTER_MAP[5], LR];
+        debug_assert_eq!(saved_registers.len() * mem::size_of::<u64>(), RUST_CALL_SAVE_AREA as usize);
+        debug_assert!(result_reg.map(|reg| !saved_registers.contains(&reg)).unwrap_or(true));
+        self.emit_ins(ARM64Instruction::sub_imm(OperandSize::S64, SP_XZR, RUST_CALL_SAVE_AREA, SP_XZR));
+        for (i, reg) in saved_registers.iter().enumerate() {
+            self.emit_ins(ARM64Instruction::store(OperandSize::S64, *reg, SP_XZR, ARM64MemoryOperand::OffsetScaled(i as u16)));
+        }
+
+        // Pass arguments
+        for argument in arguments {
+            let dst = if argument.index == 8 { XR } else { ARGUMENT_REGISTERS[argument.index] };
+            match argument.value {
+                Value::Register(reg) => {
+                    if reg != dst {
+                        self.emit_ins(ARM64Instruction::mov(OperandSize::S64, reg, dst));
+                    }
+                },
+                Value::RegisterIndirect(reg, offset, user_provided) => {
+                    debug_assert!(!user_provided && reg == REGISTER_PTR_TO_VM);
+                    self.emit_load_slot(dst, offset);
+                },
+                Value::RegisterPlusConstant32(reg, offset, user_provided) => {
+                    debug_assert!(!user_provided && reg == REGISTER_PTR_TO_VM);
+                    self.emit_slot_address(dst, offset);
+                },
+                Value::RegisterPlusConstant64(reg, offset, user_provided) => {
+                    debug_assert!(!user_provided);
+                    self.emit_load_immediate(dst, offset);
+                    self.emit_ins(ARM64Instruction::add(OperandSize::S64, dst, reg, dst));
+                },
+                Value::Constant64(value, user_provided) => {
+                    debug_assert!(!user_provided);
+                    self.emit_load_immediate(dst, value);
+                },
+                Value::Address(address) => {
+                    self.emit_load_address(dst, address);
+                },
+            }
+        }
+
+        match target {
+            Value::Register(reg) => {
+                self.emit_ins(ARM64Instruction::blr(reg));
+            },
+            Value::Constant64(value, user_provided) => {
+                debug_assert!(!user_provided);
+                self.emit_load_address(REGISTER_IMMEDIATE, value as usize);
+                self.emit_ins(ARM64Instruction::blr(REGISTER_IMMEDIATE));
+            },
+            _ => {
+                #[cfg(debug_assertions)]
+                unreachable!();
+            }
+        }
+
+        // Save returned value in result register
+        if let Some(reg) = result_reg {
+            self.emit_ins(ARM64Instruction::mov(OperandSize::S64, X0, reg));
+        }
+
+        // Restore registers from stack
+        for (i, reg) in saved_registers.iter().enumerate() {
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, SP_XZR, ARM64MemoryOperand::OffsetScaled(i as u16), *reg));
+        }
+        self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, SP_XZR, RUST_CALL_SAVE_AREA, SP_XZR));
+    }
+
+    #[inline]
+    fn emit_internal_call(&mut self, dst: Value) {
+        // Store PC in case the bounds check fails
+        self.emit_load_immediate(REGISTER_SCRATCH, self.pc as i64);
+        self.last_instruction_meter_validation_pc = self.pc;
+        self.emit_call_anchor(ANCHOR_INTERNAL_FUNCTION_CALL_PROLOGUE);
+
+        match dst {
+            Value::Register(reg) => {
+                // ANCHOR_INTERNAL_FUNCTION_CALL_REG expects the guest current pc in REGISTER_OTHER_SCRATCH
+                self.emit_ins(ARM64Instruction::mov(OperandSize::S64, REGISTER_SCRATCH, REGISTER_OTHER_SCRATCH));
+                // Move guest_target_address into REGISTER_SCRATCH
+                self.emit_ins(ARM64Instruction::mov(OperandSize::S64, reg, REGISTER_SCRATCH));
+                self.emit_call_with_return_address_in_frame(None);
+            },
+            Value::Constant64(target_pc, user_provided) => {
+                debug_assert!(user_provided);
+                self.emit_profile_instruction_count(user_provided, Some(target_pc as usize));
+                if user_provided && self.should_sanitize_constant(target_pc) {
+                    self.emit_sanitized_load_immediate(REGISTER_SCRATCH, target_pc);
+                } else {
+                    self.emit_load_immediate(REGISTER_SCRATCH, target_pc);
+                }
+                if self.reports_calls() {
+                    self.emit_call_anchor(ANCHOR_TRACE_CALL);
+                }
+                self.emit_call_with_return_address_in_frame(Some(target_pc as usize));
+            },
+            _ => {
+                #[cfg(debug_assertions)]
+                unreachable!();
+            }
+        }
+
+        self.emit_undo_profile_instruction_count(Value::Constant64(0, false));
+
+        // Restore the previous frame pointer and the scratch registers, then pop the frame
+        for (i, reg) in REGISTER_MAP.iter().skip(FIRST_SCRATCH_REG).take(SCRATCH_REGS + 1).rev().enumerate() {
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, SP_XZR, ARM64MemoryOperand::OffsetScaled(i as u16 + 1), *reg));
+        }
+        self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, SP_XZR, CALL_FRAME_SIZE, SP_XZR));
+    }
+
+    /// Stores the return address in the frame allocated by ANCHOR_INTERNAL_FUNCTION_CALL_PROLOGUE and jumps to the
+    /// target_pc or to ANCHOR_INTERNAL_FUNCTION_CALL_REG
+    #[inline]
+    fn emit_call_with_return_address_in_frame(&mut self, target_pc: Option<usize>) {
+        // adr + str + b
+        self.record_return_address(12);
+        ARM64Instruction::adr(X9, 12).emit(self);
+        ARM64Instruction::store(OperandSize::S64, X9, SP_XZR, ARM64MemoryOperand::OffsetScaled(0)).emit(self);
+        match target_pc {
+            Some(target_pc) => self.emit_jump_to_target_pc(target_pc),
+            None => self.emit_jump_to_anchor(ANCHOR_INTERNAL_FUNCTION_CALL_REG),
+        }
+    }
+
+    #[inline]
+    fn record_return_address(&mut self, instruction_length: usize) {
+        // Needed to rebuild the host stack from call_frames, see JitProgram::invoke()
+        if self.saves_state_on_exceeded_budget() || self.config.tiered_compilation_threshold != 0 {
+            let return_address = unsafe { self.result.text_section.as_ptr().add(self.offset_in_text_section).add(instruction_length) };
+            self.result.return_addresses.push((self.pc + 1, return_address as usize));
+        }
+    }
+
+    #[inline]
+    fn emit_syscall_dispatch(&mut self, function: BuiltinFunction<C>) {
+        self.emit_validate_and_profile_instruction_count(false, Some(0));
+        // Store the pc in case the syscall fails, ANCHOR_EXTERNAL_FUNCTION_CALL does not know it
+        self.emit_load_immediate(X9, self.pc as i64);
+        self.emit_store_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * mem::size_of::<u64>() as i32); // registers[11] = pc;
+        self.emit_load_address(REGISTER_SCRATCH, function as usize);
+        self.emit_call_anchor(ANCHOR_EXTERNAL_FUNCTION_CALL);
+        self.emit_undo_profile_instruction_count(Value::Constant64(0, false));
+    }
+
+    #[inline]
+    fn emit_address_translation(&mut self, dst: Option<u8>, vm_addr: Value, len: u64, value: Option<Value>) {
+        debug_assert_ne!(dst.is_some(), value.is_some());
+
+        match value {
+            Some(Value::Register(reg)) => {
+                self.emit_ins(ARM64Instruction::mov(OperandSize::S64, reg, REGISTER_OTHER_SCRATCH));
+            }
+            Some(Value::Constant64(constant, user_provided)) => {
+                if user_provided {
+                    self.emit_user_provided_immediate(REGISTER_OTHER_SCRATCH, constant);
+                } else {
+                    self.emit_load_immediate(REGISTER_OTHER_SCRATCH, constant);
+                }
+            }
+            _ => {}
+        }
+
+        let bounds_check_proof = self.bounds_check_proofs.get(&self.pc).cloned();
+        let stack_frame_access = self.config.enable_address_translation && bounds_check_proof == Some(BoundsCheckProof::StackFrame);
+        match vm_addr {
+            Value::RegisterPlusConstant64(_reg, constant, _user_provided) if stack_frame_access => {
+                self.emit_stack_frame_address(constant);
+            },
+            Value::RegisterPlusConstant64(reg, constant, user_provided) => {
+                if user_provided {
+                    self.emit_user_provided_immediate(REGISTER_SCRATCH, constant);
+                } else {
+                    self.emit_load_immediate(REGISTER_SCRATCH, constant);
+                }
+                self.emit_ins(ARM64Instruction::add(OperandSize::S64, REGISTER_SCRATCH, reg, REGISTER_SCRATCH));
+            },
+            Value::Constant64(constant, user_provided) => {
+                if user_provided {
+                    self.emit_user_provided_immediate(REGISTER_SCRATCH, constant);
+                } else {
+                    self.emit_load_immediate(REGISTER_SCRATCH, constant);
+                }
+            },
+            _ => {
+                #[cfg(debug_assertions)]
+                unreachable!();
+            },
+        }
+
+        let size = match len {
+            1 => OperandSize::S8,
+            2 => OperandSize::S16,
+            4 => OperandSize::S32,
+            8 => OperandSize::S64,
+            _ => unreachable!(),
+        };
+        if self.config.enable_address_translation && !stack_frame_access {
+            let access_type = if value.is_none() { AccessType::Load } else { AccessType::Store };
+            let anchor = if bounds_check_proof == Some(BoundsCheckProof::RepeatedAccess) && self.translates_aligned_addresses() {
+                ANCHOR_TRANSLATE_MEMORY_ADDRESS_UNCHECKED
+            } else {
+                ANCHOR_TRANSLATE_MEMORY_ADDRESS
+            } + len.trailing_zeros() as usize + 4 * (access_type as usize);
+            self.emit_load_immediate(X9, self.pc as i64);
+            if self.config.enable_memory_watchpoints && !self.config.enable_instruction_tracing {
+                // Watchpoints report the pc, which ANCHOR_TRACE does not keep up to date here
+                self.emit_store_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * mem::size_of::<u64>() as i32); // registers[11] = pc;
+            }
+            self.emit_ins(ARM64Instruction::push64(X9));
+            self.emit_call_anchor(anchor);
+            if let Some(dst) = dst {
+                self.emit_ins(ARM64Instruction::mov(OperandSize::S64, REGISTER_SCRATCH, dst));
+            }
+        } else if let Some(dst) = dst {
+            self.emit_ins(ARM64Instruction::load(size, REGISTER_SCRATCH, ARM64MemoryOperand::Offset(0), dst));
+        } else {
+            self.emit_ins(ARM64Instruction::store(size, REGISTER_OTHER_SCRATCH, REGISTER_SCRATCH, ARM64MemoryOperand::Offset(0)));
+        }
+    }
+
+    /// Translates the vm address `r10 + offset` to the host address in REGISTER_SCRATCH
+    ///
+    /// Same as in the x86_64 backend.
+    fn emit_stack_frame_address(&mut self, offset: i64) {
+        let constant = if self.config.enable_stack_frame_gaps {
+            offset * 2 + self.config.stack_frame_size as i64
+        } else {
+            offset
+        } - ebpf::MM_STACK_START as i64;
+        self.emit_user_provided_immediate(REGISTER_SCRATCH, constant);
+        self.emit_ins(ARM64Instruction::add(OperandSize::S64, REGISTER_SCRATCH, REGISTER_MAP[FRAME_PTR_REG], REGISTER_SCRATCH));
+        if self.config.enable_stack_frame_gaps {
+            self.emit_ins(ARM64Instruction::lsr_imm(REGISTER_SCRATCH, 1, REGISTER_SCRATCH));
+        }
+        self.emit_load_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::StackHostAddress));
+        self.emit_ins(ARM64Instruction::add(OperandSize::S64, REGISTER_SCRATCH, X9, REGISTER_SCRATCH));
+    }
+
+    #[inline]
+    fn emit_conditional_branch_reg(&mut self, condition: Condition, bitwise: bool, first_operand: u8, second_operand: u8, target_pc: usize) {
+        self.emit_validate_and_profile_instruction_count(true, Some(target_pc));
+        if bitwise { // Logical
+            self.emit_ins(ARM64Instruction::tst(OperandSize::S64, first_operand, second_operand));
+        } else { // Arithmetic
+            self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, first_operand, second_operand));
+        }
+        self.emit_load_immediate(REGISTER_SCRATCH, target_pc as i64);
+        self.emit_conditional_jump_to_target_pc(condition, target_pc);
+        self.emit_undo_profile_instruction_count(Value::Constant64(target_pc as i64, true));
+    }
+
+    #[inline]
+    fn emit_conditional_branch_imm(&mut self, condition: Condition, bitwise: bool, immediate: i64, second_operand: u8, target_pc: usize) {
+        self.emit_validate_and_profile_instruction_count(true, Some(target_pc));
+        if !bitwise && !self.should_sanitize_constant(immediate) && (0..1 << 12).contains(&immediate) {
+            self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, second_operand, immediate as u16));
+        } else {
+            self.emit_user_provided_immediate(REGISTER_OTHER_SCRATCH, immediate);
+            if bitwise { // Logical
+                self.emit_ins(ARM64Instruction::tst(OperandSize::S64, REGISTER_OTHER_SCRATCH, second_operand));
+            } else { // Arithmetic
+                self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, REGISTER_OTHER_SCRATCH, second_operand));
+            }
+        }
+        self.emit_load_immediate(REGISTER_SCRATCH, target_pc as i64);
+        self.emit_conditional_jump_to_target_pc(condition, target_pc);
+        self.emit_undo_profile_instruction_count(Value::Constant64(target_pc as i64, true));
+    }
+
+    /// Shifts by the register source, which also masks the shift amount like the interpreter does
+    fn emit_shift(&mut self, size: OperandSize, shift: fn(OperandSize, u8, u8, u8) -> ARM64Instruction, source: u8, destination: u8, immediate: Option<i64>) {
+        if let Some(immediate) = immediate {
+            self.emit_user_provided_immediate(source, immediate);
+        }
+        self.emit_ins(shift(size, destination, source, destination));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn emit_product_quotient_remainder(
+        &mut self,
+        size: OperandSize,
+        alt_dst: bool,
+        division: bool,
+        signed: bool,
+        src: u8,
+        dst: u8,
+        imm: Option<i64>,
+    ) {
+        //         LMUL UHMUL SHMUL UDIV SDIV UREM        SREM
+        // ALU     MUL  UMULH SMULH UDIV SDIV UDIV, MSUB  SDIV, MSUB
+
+        if division {
+            // Prevent division by zero
+            if imm.is_none() {
+                self.emit_load_immediate(REGISTER_SCRATCH, self.pc as i64); // Save pc
+                self.emit_ins(ARM64Instruction::cmp_imm(size, src, 0)); // src == 0
+                self.emit_conditional_jump_to_anchor(Condition::EQ, ANCHOR_DIV_BY_ZERO);
+            }
+
+            // Signed division overflows with MIN / -1.
+            // If we have an immediate and it's not -1, we can skip the following check.
+            if signed && imm.unwrap_or(-1) == -1 {
+                self.emit_load_immediate(REGISTER_IMMEDIATE, if let OperandSize::S64 = size { i64::MIN } else { i32::MIN as u32 as i64 });
+                self.emit_ins(ARM64Instruction::cmp(size, REGISTER_IMMEDIATE, dst)); // dst == MIN
+
+                if imm.is_none() {
+                    // The exception case is: dst == MIN && src == -1
+                    self.emit_ins(ARM64Instruction::ccmn_imm(size, src, 1, 0, Condition::EQ)); // if dst == MIN { src == -1 } else { false }
+                }
+
+                // MIN / -1, raise EbpfError::DivideOverflow
+                self.emit_load_immediate(REGISTER_SCRATCH, self.pc as i64);
+                self.emit_conditional_jump_to_anchor(Condition::EQ, ANCHOR_DIV_OVERFLOW);
+            }
+        }
+
+        let src = if let Some(imm) = imm {
+            self.emit_user_provided_immediate(REGISTER_OTHER_SCRATCH, imm);
+            REGISTER_OTHER_SCRATCH
+        } else {
+            src
+        };
+        match (division, alt_dst, signed) {
+            (false, false, _) => self.emit_ins(ARM64Instruction::mul(size, dst, src, dst)),
+            (false, true, false) => self.emit_ins(ARM64Instruction::umulh(dst, src, dst)),
+            (false, true, true) => self.emit_ins(ARM64Instruction::smulh(dst, src, dst)),
+            (true, false, false) => self.emit_ins(ARM64Instruction::udiv(size, dst, src, dst)),
+            (true, false, true) => self.emit_ins(ARM64Instruction::sdiv(size, dst, src, dst)),
+            (true, true, _) => {
+                if signed {
+                    self.emit_ins(ARM64Instruction::sdiv(size, dst, src, REGISTER_IMMEDIATE));
+                } else {
+                    self.emit_ins(ARM64Instruction::udiv(size, dst, src, REGISTER_IMMEDIATE));
+                }
+                self.emit_ins(ARM64Instruction::msub(size, REGISTER_IMMEDIATE, src, dst, dst)); // dst -= quotient * src;
+            },
+        }
+        if let OperandSize::S32 = size {
+            if signed && !self.executable.get_sbpf_version().explicit_sign_extension_of_results() {
+                self.emit_ins(ARM64Instruction::sign_extend_to_i64(OperandSize::S32, dst, dst)); // sign extend i32 to i64
+            }
+        }
+    }
+
+    fn emit_set_exception_kind(&mut self, err: EbpfError) {
+        let err_kind = unsafe { *std::ptr::addr_of!(err).cast::<u64>() };
+        let err_discriminant = ProgramResult::Err(err).discriminant();
+        let result_offset = self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult);
+        // Only clobbers X16 and X17, so that a suspended execution can be resumed from ANCHOR_THROW_EXCEPTION
+        self.emit_load_immediate(REGISTER_IMMEDIATE, err_discriminant as i64);
+        self.emit_store_slot(REGISTER_IMMEDIATE, result_offset); // result.discriminant = err_discriminant;
+        self.emit_load_immediate(REGISTER_IMMEDIATE, err_kind as i64);
+        self.emit_store_slot(REGISTER_IMMEDIATE, result_offset + mem::size_of::<u64>() as i32); // err.kind = err_kind;
+    }
+
+    /// Sets the flags so that Condition::NE means the ProgramResult is an error
+    fn emit_result_is_err(&mut self) {
+        let ok = ProgramResult::Ok(0);
+        let ok_discriminant = ok.discriminant();
+        self.emit_load_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult));
+        self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, X9, ok_discriminant as u16));
+    }
+
+    fn emit_subroutines(&mut self) {
+        // Routine for instruction tracing
+        if self.config.enable_instruction_tracing {
+            self.set_anchor(ANCHOR_TRACE);
+            self.emit_store_slot(REGISTER_SCRATCH, self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * mem::size_of::<u64>() as i32); // registers[11] = pc;
+            // Save registers on stack
+            let register_file_size = (REGISTER_MAP.len() + 1) * mem::size_of::<u64>();
+            self.emit_ins(ARM64Instruction::sub_imm(OperandSize::S64, SP_XZR, register_file_size as u16, SP_XZR));
+            for (i, reg) in REGISTER_MAP.iter().chain(std::iter::once(&REGISTER_SCRATCH)).enumerate() {
+                self.emit_ins(ARM64Instruction::store(OperandSize::S64, *reg, SP_XZR, ARM64MemoryOperand::OffsetScaled(i as u16)));
+            }
+            self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, SP_XZR, 0, X9)); // X9 = SP;
+            self.emit_rust_call(Value::Constant64(C::trace as *const u8 as i64, false), &[
+                Argument { index: 1, value: Value::Register(X9) }, // registers
+                Argument { index: 0, value: Value::RegisterIndirect(REGISTER_PTR_TO_VM, self.slot_in_vm(RuntimeEnvironmentSlot::ContextObjectPointer), false) },
+            ], None);
+            // Pop stack and return
+            self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, SP_XZR, register_file_size as u16, SP_XZR));
+            self.emit_ins(ARM64Instruction::ret());
+        }
+
+        if self.reports_calls() {
+            // Routine for tracing BPF to BPF calls, expects the target pc in REGISTER_SCRATCH
+            self.set_anchor(ANCHOR_TRACE_CALL);
+            if self.config.enable_instruction_meter {
+                self.emit_instruction_cost_prefix_alu(false, REGISTER_SCRATCH, 0); // instruction_meter -= target_pc;
+            }
+            self.emit_rust_call(Value::Constant64(trace_call::<C> as *const u8 as i64, false), &[
+                Argument { index: 2, value: Value::Register(REGISTER_INSTRUCTION_METER) },
+                Argument { index: 1, value: Value::Register(REGISTER_SCRATCH) },
+                Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+            ], None);
+            if self.config.enable_instruction_meter {
+                self.emit_instruction_cost_prefix_alu(true, REGISTER_SCRATCH, 0); // instruction_meter += target_pc;
+            }
+            self.emit_ins(ARM64Instruction::ret());
+
+            // Routine for tracing returns from BPF to BPF calls, expects the pc in REGISTER_SCRATCH
+            self.set_anchor(ANCHOR_TRACE_RETURN);
+            if self.config.enable_instruction_meter {
+                self.emit_instruction_cost_prefix_alu(false, REGISTER_SCRATCH, 1); // instruction_meter -= pc + 1;
+            }
+            self.emit_rust_call(Value::Constant64(trace_return::<C> as *const u8 as i64, false), &[
+                Argument { index: 1, value: Value::Register(REGISTER_INSTRUCTION_METER) },
+                Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+            ], None);
+            if self.config.enable_instruction_meter {
+                self.emit_instruction_cost_prefix_alu(true, REGISTER_SCRATCH, 1); // instruction_meter += pc + 1;
+            }
+            self.emit_ins(ARM64Instruction::ret());
+        }
+
+        // Epilogue
+        self.set_anchor(ANCHOR_EPILOGUE);
+        if self.config.enable_instruction_meter {
+            self.emit_instruction_cost_prefix_alu(false, REGISTER_SCRATCH, 1); // REGISTER_INSTRUCTION_METER -= pc + 1;
+            // *DueInsnCount = *PreviousInstructionMeter - REGISTER_INSTRUCTION_METER;
+            self.emit_load_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::PreviousInstructionMeter));
+            self.emit_ins(ARM64Instruction::sub(OperandSize::S64, X9, REGISTER_INSTRUCTION_METER, REGISTER_INSTRUCTION_METER)); // REGISTER_INSTRUCTION_METER = *PreviousInstructionMeter - REGISTER_INSTRUCTION_METER;
+            self.emit_store_slot(REGISTER_INSTRUCTION_METER, self.slot_in_vm(RuntimeEnvironmentSlot::DueInsnCount)); // *DueInsnCount = REGISTER_INSTRUCTION_METER;
+        }
+        // Restore stack pointer in case we did not exit gracefully and return to JitProgram::invoke()
+        self.emit_load_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::HostStackPointer));
+        self.emit_ins(ARM64Instruction::load(OperandSize::S64, X9, ARM64MemoryOperand::OffsetScaled(0), LR));
+        self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, X9, 16, SP_XZR));
+        self.emit_ins(ARM64Instruction::ret());
+
+        // Handler for EbpfError::ExceededMaxInstructions
+        self.set_anchor(ANCHOR_THROW_EXCEEDED_MAX_INSTRUCTIONS);
+        if self.saves_state_on_exceeded_budget() {
+            // Save the registers, the pc of the instruction which was not executed yet and the call frames for EbpfVm::resume_program()
+            // or for the interpreter to continue exactly, see EbpfVm::run_compiled_program()
+            for (i, reg) in REGISTER_MAP.iter().chain(std::iter::once(&REGISTER_SCRATCH)).enumerate() {
+                self.emit_store_slot(*reg, self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + (i * mem::size_of::<u64>()) as i32); // registers[i] = reg;
+            }
+            self.emit_rust_call(Value::Constant64(save_call_frames::<C> as *const u8 as i64, false), &[
+                Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+            ], None);
+            self.emit_set_exception_kind(EbpfError::ExecutionSuspended);
+            self.emit_ins(ARM64Instruction::sub_imm(OperandSize::S64, REGISTER_SCRATCH, 1, REGISTER_SCRATCH)); // REGISTER_SCRATCH -= 1; so that the epilogue does not count the instruction at pc
+            self.emit_jump_to_anchor(ANCHOR_EPILOGUE);
+        } else {
+            self.emit_set_exception_kind(EbpfError::ExceededMaxInstructions);
+            if self.result.instruction_cost_prefix.is_empty() {
+                self.emit_ins(ARM64Instruction::mov(OperandSize::S64, REGISTER_INSTRUCTION_METER, REGISTER_SCRATCH)); // REGISTER_SCRATCH = REGISTER_INSTRUCTION_METER;
+            } else {
+                self.emit_rust_call(Value::Constant64(pc_from_instruction_cost as *const u8 as i64, false), &[
+                    Argument { index: 2, value: Value::Register(REGISTER_INSTRUCTION_METER) },
+                    Argument { index: 1, value: Value::Constant64(self.result.instruction_cost_prefix.len() as i64, false) },
+                    Argument { index: 0, value: Value::Address(self.result.instruction_cost_prefix.as_ptr() as usize) },
+                ], Some(REGISTER_SCRATCH));
+            }
+            // Fall through
+        }
+
+        // Epilogue for errors
+        self.set_anchor(ANCHOR_THROW_EXCEPTION_UNCHECKED);
+        self.emit_store_slot(REGISTER_SCRATCH, self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * mem::size_of::<u64>() as i32); // registers[11] = pc;
+        self.emit_jump_to_anchor(ANCHOR_EPILOGUE);
+
+        // Quit gracefully
+        self.set_anchor(ANCHOR_EXIT);
+        // Keep the final register file, except for the frame pointer of the root frame
+        for (i, reg) in REGISTER_MAP.iter().take(FRAME_PTR_REG).enumerate() {
+            self.emit_store_slot(*reg, self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + (i * mem::size_of::<u64>()) as i32); // registers[i] = reg;
+        }
+        self.emit_store_slot(REGISTER_SCRATCH, self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * mem::size_of::<u64>() as i32); // registers[11] = pc;
+        // REGISTER_SCRATCH still holds the pc of the exit instruction, which the epilogue charges to the instruction meter
+        self.emit_store_slot(REGISTER_MAP[0], self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult) + mem::size_of::<u64>() as i32); // result.return_value = R0;
+        self.emit_jump_to_anchor(ANCHOR_EPILOGUE);
+
+        // Handler for exceptions which report their pc
+        self.set_anchor(ANCHOR_THROW_EXCEPTION);
+        // Validate that we did not reach the instruction meter limit before the exception occured
+        self.emit_validate_instruction_count(None);
+        self.emit_jump_to_anchor(ANCHOR_THROW_EXCEPTION_UNCHECKED);
+
+        // Handler for EbpfError::CallDepthExceeded
+        self.set_anchor(ANCHOR_CALL_DEPTH_EXCEEDED);
+        self.emit_set_exception_kind(EbpfError::CallDepthExceeded);
+        self.emit_jump_to_anchor(ANCHOR_THROW_EXCEPTION);
+
+        // Handler for EbpfError::CallOutsideTextSegment, expects the guest current pc in REGISTER_OTHER_SCRATCH
+        self.set_anchor(ANCHOR_CALL_OUTSIDE_TEXT_SEGMENT);
+        self.emit_set_exception_kind(EbpfError::CallOutsideTextSegment);
+        self.emit_ins(ARM64Instruction::mov(OperandSize::S64, REGISTER_OTHER_SCRATCH, REGISTER_SCRATCH));
+        self.emit_jump_to_anchor(ANCHOR_THROW_EXCEPTION);
+
+        // Handler for EbpfError::DivideByZero
+        self.set_anchor(ANCHOR_DIV_BY_ZERO);
+        self.emit_set_exception_kind(EbpfError::DivideByZero);
+        self.emit_jump_to_anchor(ANCHOR_THROW_EXCEPTION);
+
+        // Handler for EbpfError::DivideOverflow
+        self.set_anchor(ANCHOR_DIV_OVERFLOW);
+        self.emit_set_exception_kind(EbpfError::DivideOverflow);
+        self.emit_jump_to_anchor(ANCHOR_THROW_EXCEPTION);
+
+        // Handler for EbpfError::UnsupportedInstruction
+        self.set_anchor(ANCHOR_CALL_UNSUPPORTED_INSTRUCTION);
+        if self.config.enable_instruction_tracing {
+            self.emit_call_anchor(ANCHOR_TRACE);
+        }
+        self.emit_set_exception_kind(EbpfError::UnsupportedInstruction);
+        self.emit_jump_to_anchor(ANCHOR_THROW_EXCEPTION);
+
+        // Routine for polling the cancellation handle, expects the pc in REGISTER_SCRATCH
+        if self.config.enable_cancellation_checks {
+            self.set_anchor(ANCHOR_POLL_CANCELLATION_SLOW);
+            self.emit_rust_call(Value::Constant64(poll_cancellation::<C> as *const u8 as i64, false), &[
+                Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+            ], None);
+            self.emit_result_is_err();
+            self.emit_conditional_jump_to_anchor(Condition::NE, ANCHOR_THROW_EXCEPTION_UNCHECKED);
+            self.emit_ins(ARM64Instruction::ret());
+
+            self.set_anchor(ANCHOR_POLL_CANCELLATION);
+            self.emit_load_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::CancellationCountdown));
+            self.emit_ins(ARM64Instruction::sub_imm(OperandSize::S64, X9, 1, X9)); // cancellation_countdown -= 1;
+            self.emit_store_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::CancellationCountdown));
+            self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, X9, 0));
+            self.emit_conditional_jump_to_anchor(Condition::EQ, ANCHOR_POLL_CANCELLATION_SLOW); // Tail call
+            self.emit_ins(ARM64Instruction::ret());
+        }
+
+        // Routine for external functions
+        self.set_anchor(ANCHOR_EXTERNAL_FUNCTION_CALL);
+        if self.config.enable_instruction_meter {
+            self.emit_store_slot(REGISTER_INSTRUCTION_METER, self.slot_in_vm(RuntimeEnvironmentSlot::DueInsnCount)); // *DueInsnCount = REGISTER_INSTRUCTION_METER;
+        }
+        let (target, function_argument) = if self.config.enable_instruction_tracing || self.config.enable_execution_stats {
+            // Call traced_syscall() instead and pass the syscall as additional argument
+            (Value::Constant64(traced_syscall::<C> as *const u8 as i64, false), Some(Argument { index: 6, value: Value::Register(REGISTER_SCRATCH) }))
+        } else {
+            (Value::Register(REGISTER_SCRATCH), None)
+        };
+        let arguments = function_argument.into_iter().chain([
+            Argument { index: 5, value: Value::Register(ARGUMENT_REGISTERS[5]) },
+            Argument { index: 4, value: Value::Register(ARGUMENT_REGISTERS[4]) },
+            Argument { index: 3, value: Value::Register(ARGUMENT_REGISTERS[3]) },
+            Argument { index: 2, value: Value::Register(ARGUMENT_REGISTERS[2]) },
+            Argument { index: 1, value: Value::Register(ARGUMENT_REGISTERS[1]) },
+            Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+        ]).collect::<Vec<_>>();
+        self.emit_rust_call(target, &arguments, None);
+        if self.config.enable_instruction_meter {
+            self.emit_load_slot(REGISTER_INSTRUCTION_METER, self.slot_in_vm(RuntimeEnvironmentSlot::PreviousInstructionMeter)); // REGISTER_INSTRUCTION_METER = *PreviousInstructionMeter;
+        }
+
+        // Test if result indicates that an error occured
+        self.emit_result_is_err();
+        self.emit_ins(ARM64Instruction::movn(REGISTER_SCRATCH, 0, 0)); // Used as PC value in error case
+        self.emit_conditional_jump_to_anchor(Condition::NE, ANCHOR_EPILOGUE);
+        // Store Ok value in result register
+        self.emit_load_slot(REGISTER_MAP[0], self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult) + mem::size_of::<u64>() as i32);
+        self.emit_ins(ARM64Instruction::ret());
+
+        // Routine for prologue of emit_internal_call()
+        // Allocates the frame [return address, frame pointer, R9, R8, R7, R6], the caller stores the return address
+        self.set_anchor(ANCHOR_INTERNAL_FUNCTION_CALL_PROLOGUE);
+        self.emit_validate_instruction_count(None);
+        self.emit_ins(ARM64Instruction::sub_imm(OperandSize::S64, SP_XZR, CALL_FRAME_SIZE, SP_XZR)); // alloca
+        for (i, reg) in REGISTER_MAP.iter().skip(FIRST_SCRATCH_REG).take(SCRATCH_REGS + 1).rev().enumerate() {
+            self.emit_ins(ARM64Instruction::store(OperandSize::S64, *reg, SP_XZR, ARM64MemoryOperand::OffsetScaled(i as u16 + 1))); // Push SCRATCH_REG and the caller's frame pointer
+        }
+        // Increase env.call_depth
+        self.emit_load_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::CallDepth));
+        self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, X9, 1, X9)); // env.call_depth += 1;
+        self.emit_store_slot(X9, self.slot_in_vm(RuntimeEnvironmentSlot::CallDepth));
+        // If env.call_depth == self.config.max_call_depth, throw CallDepthExceeded
+        self.emit_cmp_immediate(X9, self.config.max_call_depth as i64);
+        self.emit_conditional_jump_to_anchor(Condition::HS, ANCHOR_CALL_DEPTH_EXCEEDED);
+        // Setup the frame pointer for the new frame. What we do depends on whether we're using dynamic or fixed frames.
+        if !self.executable.get_sbpf_version().dynamic_stack_frames() {
+            // With fixed frames we start the new frame at the next fixed offset
+            let stack_frame_size = self.config.stack_frame_size as i64 * if self.config.enable_stack_frame_gaps { 2 } else { 1 };
+            self.emit_add_immediate(REGISTER_MAP[FRAME_PTR_REG], stack_frame_size); // REGISTER_MAP[FRAME_PTR_REG] += stack_frame_size;
+        }
+        self.emit_ins(ARM64Instruction::ret());
+
+        // Routine for emit_internal_call(Value::Register())
+        // Inputs: Guest current pc in REGISTER_OTHER_SCRATCH, Guest target address in REGISTER_SCRATCH
+        // Outputs: Guest current pc in REGISTER_OTHER_SCRATCH, Guest target pc in REGISTER_SCRATCH, Host target address in PC
+        self.set_anchor(ANCHOR_INTERNAL_FUNCTION_CALL_REG);
+        // Calculate offset relative to program_vm_addr
+        self.emit_load_immediate(X9, self.program_vm_addr as i64);
+        self.emit_ins(ARM64Instruction::sub(OperandSize::S64, REGISTER_SCRATCH, X9, REGISTER_SCRATCH)); // guest_target_address -= self.program_vm_addr;
+        // Calculate the guest_target_pc (dst / INSN_SIZE), which also forces the alignment of guest_target_address
+        let shift_amount = ebpf::INSN_SIZE.trailing_zeros();
+        debug_assert_eq!(ebpf::INSN_SIZE, 1 << shift_amount);
+        self.emit_ins(ARM64Instruction::lsr_imm(REGISTER_SCRATCH, shift_amount as u8, REGISTER_SCRATCH)); // guest_target_pc = guest_target_address / INSN_SIZE;
+        // Bound check
+        // if(guest_target_pc >= number_of_instructions) throw CALL_OUTSIDE_TEXT_SEGMENT;
+        let number_of_instructions = self.result.pc_section.len();
+        self.emit_cmp_immediate(REGISTER_SCRATCH, number_of_instructions as i64);
+        self.emit_conditional_jump_to_anchor(Condition::HS, ANCHOR_CALL_OUTSIDE_TEXT_SEGMENT);
+        // self.emit_profile_instruction_count(false, None) with the guest current pc in REGISTER_OTHER_SCRATCH
+        if self.config.enable_instruction_meter {
+            self.emit_instruction_cost_prefix_alu(false, REGISTER_OTHER_SCRATCH, 1); // instruction_meter -= guest_current_pc + 1;
+            self.emit_instruction_cost_prefix_alu(true, REGISTER_SCRATCH, 0); // instruction_meter += guest_target_pc;
+        }
+        if self.reports_calls() {
+            if !self.config.enable_instruction_tracing {
+                // Without ANCHOR_TRACE registers[11] is not up to date, so save the guest current pc there
+                self.emit_store_slot(REGISTER_OTHER_SCRATCH, self.slot_in_vm(RuntimeEnvironmentSlot::Registers) + 11 * mem::size_of::<u64>() as i32); // registers[11] = guest_current_pc;
+            }
+            self.emit_call_anchor(ANCHOR_TRACE_CALL);
+        }
+        // Load host target_address from self.result.pc_section
+        self.emit_load_address(X9, self.result.pc_section.as_ptr() as usize); // host_target_address = self.result.pc_section;
+        self.emit_ins(ARM64Instruction::load(OperandSize::S64, X9, ARM64MemoryOperand::OffsetIndexShift(REGISTER_SCRATCH, true), X9)); // host_target_address = self.result.pc_section[guest_target_pc];
+        self.emit_ins(ARM64Instruction::br(X9)); // Tail call to host_target_address
+
+        // If callx lands in an invalid address, we must undo the changes in the instruction meter
+        // so that we can correctly calculate the number of executed instructions for error handling.
+        self.set_anchor(ANCHOR_CALL_REG_UNSUPPORTED_INSTRUCTION);
+        self.emit_ins(ARM64Instruction::mov(OperandSize::S64, REGISTER_SCRATCH, X9));
+        // Retrieve the current program counter, see ANCHOR_INTERNAL_FUNCTION_CALL_REG
+        self.emit_ins(ARM64Instruction::mov(OperandSize::S64, REGISTER_OTHER_SCRATCH, REGISTER_SCRATCH));
+        self.emit_undo_profile_instruction_count(Value::Register(X9));
+        self.emit_jump_to_anchor(ANCHOR_CALL_UNSUPPORTED_INSTRUCTION);
+
+        // Translates a vm memory address to a host memory address
+        // Inputs: vm_addr in REGISTER_SCRATCH, value to store in REGISTER_OTHER_SCRATCH, pc on the stack
+        // Outputs: loaded value in REGISTER_SCRATCH, pc popped from the stack
+        for (access_type, len) in &[
+            (AccessType::Load, 1i32),
+            (AccessType::Load, 2i32),
+            (AccessType::Load, 4i32),
+            (AccessType::Load, 8i32),
+            (AccessType::Store, 1i32),
+            (AccessType::Store, 2i32),
+            (AccessType::Store, 4i32),
+            (AccessType::Store, 8i32),
+        ] {
+            let target_offset = len.trailing_zeros() as usize + 4 * (*access_type as usize);
+            if self.translates_aligned_addresses() {
+                self.set_anchor(ANCHOR_TRANSLATE_MEMORY_ADDRESS_SLOW + target_offset);
+            } else {
+                self.set_anchor(ANCHOR_TRANSLATE_MEMORY_ADDRESS + target_offset);
+            }
+            // call MemoryMapping::handled_(load|store) storing the result in RuntimeEnvironmentSlot::ProgramResult
+            if self.config.enable_instruction_tracing || self.config.enable_execution_stats || self.config.enable_memory_watchpoints {
+                let (traced_access, arguments) = if *access_type == AccessType::Load {
+                    (match len {
+                        1 => traced_load::<C, u8> as *const u8 as i64,
+                        2 => traced_load::<C, u16> as *const u8 as i64,
+                        4 => traced_load::<C, u32> as *const u8 as i64,
+                        8 => traced_load::<C, u64> as *const u8 as i64,
+                        _ => unreachable!()
+                    }, vec![
+                        Argument { index: 1, value: Value::Register(REGISTER_SCRATCH) },
+                        Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+                    ])
+                } else {
+                    (match len {
+                        1 => traced_store::<C, u8> as *const u8 as i64,
+                        2 => traced_store::<C, u16> as *const u8 as i64,
+                        4 => traced_store::<C, u32> as *const u8 as i64,
+                        8 => traced_store::<C, u64> as *const u8 as i64,
+                        _ => unreachable!()
+                    }, vec![
+                        Argument { index: 2, value: Value::Register(REGISTER_SCRATCH) },
+                        Argument { index: 1, value: Value::Register(REGISTER_OTHER_SCRATCH) },
+                        Argument { index: 0, value: Value::Register(REGISTER_PTR_TO_VM) },
+                    ])
+                };
+                self.emit_rust_call(Value::Constant64(traced_access, false), &arguments, None);
+            } else if *access_type == AccessType::Load {
+                let load = match len {
+                    1 => MemoryMapping::handled_load::<u8> as *const u8 as i64,
+                    2 => MemoryMapping::handled_load::<u16> as *const u8 as i64,
+                    4 => MemoryMapping::handled_load::<u32> as *const u8 as i64,
+                    8 => MemoryMapping::handled_load::<u64> as *const u8 as i64,
+                    _ => unreachable!()
+                };
+                self.emit_rust_call(Value::Constant64(load, false), &[
+                    Argument { index: 1, value: Value::Register(REGISTER_SCRATCH) },
+                    Argument { index: 0, value: Value::RegisterPlusConstant32(REGISTER_PTR_TO_VM, self.slot_in_vm(RuntimeEnvironmentSlot::MemoryMapping), false) },
+                    Argument { index: 8, value: Value::RegisterPlusConstant32(REGISTER_PTR_TO_VM, self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult), false) },
+                ], None);
+            } else {
+                let store = match len {
+                    1 => MemoryMapping::handled_store::<u8> as *const u8 as i64,
+                    2 => MemoryMapping::handled_store::<u16> as *const u8 as i64,
+                    4 => MemoryMapping::handled_store::<u32> as *const u8 as i64,
+                    8 => MemoryMapping::handled_store::<u64> as *const u8 as i64,
+                    _ => unreachable!()
+                };
+                self.emit_rust_call(Value::Constant64(store, false), &[
+                    Argument { index: 2, value: Value::Register(REGISTER_SCRATCH) },
+                    Argument { index: 1, value: Value::Register(REGISTER_OTHER_SCRATCH) },
+                    Argument { index: 0, value: Value::RegisterPlusConstant32(REGISTER_PTR_TO_VM, self.slot_in_vm(RuntimeEnvironmentSlot::MemoryMapping), false) },
+                    Argument { index: 8, value: Value::RegisterPlusConstant32(REGISTER_PTR_TO_VM, self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult), false) },
+                ], None);
+            }
+
+            // Throw error if the result indicates one
+            self.emit_result_is_err();
+            self.emit_ins(ARM64Instruction::pop64(REGISTER_SCRATCH)); // REGISTER_SCRATCH = self.pc
+            self.emit_conditional_jump_to_anchor(Condition::NE, ANCHOR_THROW_EXCEPTION);
+
+            // unwrap() the result into REGISTER_SCRATCH
+            self.emit_load_slot(REGISTER_SCRATCH, self.slot_in_vm(RuntimeEnvironmentSlot::ProgramResult) + mem::size_of::<u64>() as i32);
+
+            self.emit_ins(ARM64Instruction::ret());
+
+            if self.translates_aligned_addresses() {
+                self.set_anchor(ANCHOR_TRANSLATE_MEMORY_ADDRESS + target_offset);
+                self.emit_aligned_address_translation(*access_type, *len, ANCHOR_TRANSLATE_MEMORY_ADDRESS_SLOW + target_offset, true);
+                if eliminates_bounds_checks(self.config) {
+                    self.set_anchor(ANCHOR_TRANSLATE_MEMORY_ADDRESS_UNCHECKED + target_offset);
+                    self.emit_aligned_address_translation(*access_type, *len, ANCHOR_TRANSLATE_MEMORY_ADDRESS_SLOW + target_offset, false);
+                }
+            }
+        }
+    }
+
+    /// Whether the address translation anchors have a fast path for aligned memory mappings
+    fn translates_aligned_addresses(&self) -> bool {
+        // Tracing, execution stats and watchpoints need to see every access, so they always take the slow path
+        self.config.enable_address_translation
+            && self.config.aligned_memory_mapping
+            && !self.config.enable_instruction_tracing
+            && !self.config.enable_execution_stats
+            && !self.config.enable_memory_watchpoints
+    }
+
+    /// Translates the vm address in REGISTER_SCRATCH and performs the access without calling into Rust
+    ///
+    /// Same as in the x86_64 backend, but the caller saved registers X9 to X12 are free to use here, so the slow path
+    /// does not need to restore anything.
+    fn emit_aligned_address_translation(&mut self, access_type: AccessType, len: i32, slow_path: usize, checked: bool) {
+        let mapping = X9;
+        let end = X9; // Reused once the mapping is not needed anymore
+        let region = X10;
+        let offset = X11;
+        let temp = X12;
+        self.emit_slot_address(mapping, self.slot_in_vm(RuntimeEnvironmentSlot::MemoryMapping));
+        self.emit_ins(ARM64Instruction::load(OperandSize::S8, mapping, ARM64MemoryOperand::Offset(0), temp));
+        self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, temp, jit_layout::MAPPING_ALIGNED as u16));
+        self.emit_conditional_jump_to_anchor(Condition::NE, slow_path);
+        // region = &regions[vm_addr >> 32]
+        self.emit_ins(ARM64Instruction::lsr_imm(REGISTER_SCRATCH, ebpf::VIRTUAL_ADDRESS_BITS as u8, region));
+        if checked {
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, mapping, ARM64MemoryOperand::Offset(jit_layout::MAPPING_REGIONS_COUNT as i16), temp));
+            self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, temp, region));
+            self.emit_conditional_jump_to_anchor(Condition::HS, slow_path);
+        }
+        self.emit_ins(ARM64Instruction::load(OperandSize::S64, mapping, ARM64MemoryOperand::Offset(jit_layout::MAPPING_REGIONS_ADDRESS as i16), offset));
+        self.emit_load_immediate(temp, jit_layout::REGION_SIZE as i64);
+        self.emit_ins(ARM64Instruction::madd(OperandSize::S64, region, temp, offset, region));
+        self.emit_ins(ARM64Instruction::load(OperandSize::S8, region, ARM64MemoryOperand::Offset(jit_layout::REGION_VM_GAP_SHIFT as i16), temp));
+        self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, temp, jit_layout::CONTINUOUS as u16));
+        self.emit_conditional_jump_to_anchor(Condition::NE, slow_path);
+        if access_type == AccessType::Load {
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, region, ARM64MemoryOperand::Offset(jit_layout::REGION_STATE as i16), temp));
+            self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, temp, jit_layout::STATE_WRITE_ONLY as u16));
+            self.emit_conditional_jump_to_anchor(Condition::EQ, slow_path);
+        }
+        if checked {
+            // Protected sub-ranges are only checked by MemoryRegion::protected_range_violation()
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, region, ARM64MemoryOperand::Offset(jit_layout::REGION_PROTECTED_RANGES_ADDRESS as i16), temp));
+            self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, temp, 0));
+            self.emit_conditional_jump_to_anchor(Condition::NE, slow_path);
+        }
+        if checked && access_type == AccessType::Store {
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, region, ARM64MemoryOperand::Offset(jit_layout::REGION_STATE as i16), temp));
+            self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, temp, jit_layout::STATE_WRITABLE as u16));
+            self.emit_conditional_jump_to_anchor(Condition::NE, slow_path);
+            // Dirty pages are only recorded by MemoryRegion::mark_written()
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, region, ARM64MemoryOperand::Offset(jit_layout::REGION_DIRTY_PAGES_ADDRESS as i16), temp));
+            self.emit_ins(ARM64Instruction::cmp_imm(OperandSize::S64, temp, 0));
+            self.emit_conditional_jump_to_anchor(Condition::NE, slow_path);
+        }
+        // offset = vm_addr - region.vm_addr, the access must end inside of the region
+        self.emit_ins(ARM64Instruction::load(OperandSize::S64, region, ARM64MemoryOperand::Offset(jit_layout::REGION_VM_ADDR as i16), temp));
+        if checked {
+            self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, temp, REGISTER_SCRATCH));
+            self.emit_conditional_jump_to_anchor(Condition::LO, slow_path);
+        }
+        self.emit_ins(ARM64Instruction::sub(OperandSize::S64, REGISTER_SCRATCH, temp, offset));
+        if checked {
+            self.emit_ins(ARM64Instruction::add_imm(OperandSize::S64, offset, len as u16, end));
+            self.emit_ins(ARM64Instruction::load(OperandSize::S64, region, ARM64MemoryOperand::Offset(jit_layout::REGION_LEN as i16), temp));
+            self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, temp, end));
+            self.emit_conditional_jump_to_anchor(Condition::HI, slow_path);
+            if access_type == AccessType::Store {
+                // Extend the written range like MemoryRegion::mark_written(), even if the region does not track its writes
+                self.emit_ins(ARM64Instruction::load(OperandSize::S64, region, ARM64MemoryOperand::Offset(jit_layout::REGION_WRITTEN_END as i16), temp));
+                self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, end, temp));
+                ARM64Instruction::b_cond(Condition::HS, 2).emit(self); // Not emit_ins(), a noop would break the skip
+                ARM64Instruction::store(OperandSize::S64, end, region, ARM64MemoryOperand::Offset(jit_layout::REGION_WRITTEN_END as i16)).emit(self); // written_end = max(written_end, offset + len)
+                self.emit_ins(ARM64Instruction::load(OperandSize::S64, region, ARM64MemoryOperand::Offset(jit_layout::REGION_WRITTEN_START as i16), temp));
+                self.emit_ins(ARM64Instruction::cmp(OperandSize::S64, offset, temp));
+                ARM64Instruction::b_cond(Condition::LS, 2).emit(self); // Not emit_ins(), a noop would break the skip
+                ARM64Instruction::store(OperandSize::S64, offset, region, ARM64MemoryOperand::Offset(jit_layout::REGION_WRITTEN_START as i16)).emit(self); // written_start = min(written_start, offset)
+            }
+        }
+        // host_addr = region.host_addr + offset
+        self.emit_ins(ARM64Instruction::load(OperandSize::S64, region, ARM64MemoryOperand::Offset(jit_layout::REGION_HOST_ADDR as i16), temp));
+        self.emit_ins(ARM64Instruction::add(OperandSize::S64, temp, offset, temp));
+        let size = match len {
+            1 => OperandSize::S8,
+            2 => OperandSize::S16,
+            4 => OperandSize::S32,
+            8 => OperandSize::S64,
+            _ => unreachable!(),
+        };
+        if access_type == AccessType::Load {
+            self.emit_ins(ARM64Instruction::load(size, temp, ARM64MemoryOperand::Offset(0), REGISTER_SCRATCH));
+        } else {
+            self.emit_ins(ARM64Instruction::store(size, REGISTER_OTHER_SCRATCH, temp, ARM64MemoryOperand::Offset(0)));
+        }
+        self.emit_ins(ARM64Instruction::pop64(temp)); // Release the pc pushed by emit_address_translation()
+        self.emit_ins(ARM64Instruction::ret());
+    }
+
+    fn set_anchor(&mut self, anchor: usize) {
+        self.anchors[anchor] = unsafe { self.result.text_section.as_ptr().add(self.offset_in_text_section) };
+    }
+
+    /// Distance from the next instruction to the anchor in instructions
+    #[inline]
+    fn relative_to_anchor(&self, anchor: usize) -> i32 {
+        let instruction = unsafe { self.result.text_section.as_ptr().add(self.offset_in_text_section) };
+        let destination = self.anchors[anchor];
+        debug_assert!(!destination.is_null());
+        (unsafe { destination.offset_from(instruction) } / mem::size_of::<u32>() as isize) as i32
+    }
+
+    #[inline]
+    fn emit_jump_to_anchor(&mut self, anchor: usize) {
+        let offset = self.relative_to_anchor(anchor);
+        self.emit_ins(ARM64Instruction::b(offset));
+    }
+
+    #[inline]
+    fn emit_call_anchor(&mut self, anchor: usize) {
+        let offset = self.relative_to_anchor(anchor);
+        self.emit_ins(ARM64Instruction::bl(offset));
+    }
+
+    /// B.cond only reaches +/- 1 MiB, so it branches over an unconditional branch instead
+    #[inline]
+    fn emit_conditional_jump_to_anchor(&mut self, condition: Condition, anchor: usize) {
+        ARM64Instruction::b_cond(condition.invert(), 2).emit(self);
+        self.emit_jump_to_anchor(anchor);
+    }
+
+    #[inline]
+    fn emit_jump_to_target_pc(&mut self, target_pc: usize) {
+        let instruction = unsafe { self.result.text_section.as_ptr().add(self.offset_in_text_section) };
+        let offset = if self.result.pc_section[target_pc] != 0 {
+            // Backward jump
+            let destination = self.result.pc_section[target_pc] as *const u8;
+            (unsafe { destination.offset_from(instruction) } / mem::size_of::<u32>() as isize) as i32
+        } else {
+            // Forward jump, needs relocation
+            self.text_section_jumps.push(Jump { location: instruction, target_pc });
+            0
+        };
+        self.emit_ins(ARM64Instruction::b(offset));
+    }
+
+    /// B.cond only reaches +/- 1 MiB, so it branches over an unconditional branch instead
+    #[inline]
+    fn emit_conditional_jump_to_target_pc(&mut self, condition: Condition, target_pc: usize) {
+        ARM64Instruction::b_cond(condition.invert(), 2).emit(self);
+        self.emit_jump_to_target_pc(target_pc);
+    }
+
+    fn resolve_jumps(&mut self) {
+        // Relocate forward jumps
+        for jump in &self.text_section_jumps {
+            let destination = self.result.pc_section[jump.target_pc] as *const u8;
+            let offset_value = (unsafe { destination.offset_from(jump.location) } / mem::size_of::<u32>() as isize) as i32; // Relative jump
+            let instruction = ARM64Instruction::b(offset_value).encode();
+            unsafe { ptr::write_unaligned(jump.location as *mut u32, instruction); }
+        }
+        // Patch addresses to which `callx` may raise an unsupported instruction error
+        let call_unsupported_instruction = self.anchors[ANCHOR_CALL_REG_UNSUPPORTED_INSTRUCTION] as usize;
+        if self.executable.get_sbpf_version().static_syscalls() {
+            let mut prev_pc = 0;
+            for current_pc in self.executable.get_function_registry().keys() {
+                if current_pc as usize >= self.result.pc_section.len() {
+                    break;
+                }
+                for pc in prev_pc..current_pc as usize {
+                    self.result.pc_section[pc] = call_unsupported_instruction;
+                }
+                prev_pc = current_pc as usize + 1;
+            }
+            for pc in prev_pc..self.result.pc_section.len() {
+                self.result.pc_section[pc] = call_unsupported_instruction;
+            }
+        }
+    }
+}
+
+fn alu_add(size: OperandSize, source: u8, destination: u8) -> ARM64Instruction {
+    ARM64Instruction::add(size, destination, source, destination)
+}
+
+fn alu_sub(size: OperandSize, source: u8, destination: u8) -> ARM64Instruction {
+    ARM64Instruction::sub(size, destination, source, destination)
+}
+
+fn alu_and(size: OperandSize, source: u8, destination: u8) -> ARM64Instruction {
+    ARM64Instruction::and(size, destination, source, destination)
+}
+
+fn alu_neg(size: OperandSize, destination: u8) -> ARM64Instruction {
+    ARM64Instruction::sub(size, SP_XZR, destination, destination)
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+    use crate::{
+        program::{BuiltinProgram, FunctionRegistry, SBPFVersion},
+        syscalls,
+        vm::TestContextObject,
+    };
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::sync::Arc;
+
+    fn create_mockup_executable(config: Config, program: &[u8]) -> Executable<TestContextObject> {
+        let sbpf_version = *config.enabled_sbpf_versions.end();
+        let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+        loader
+            .register_function("gather_bytes", 1, syscalls::SyscallGatherBytes::vm)
+            .unwrap();
+        let mut function_registry = FunctionRegistry::default();
+        function_registry
+            .register_function(8, *b"function_foo", 8)
+            .unwrap();
+        Executable::<TestContextObject>::from_text_bytes(
+            program,
+            Arc::new(loader),
+            sbpf_version,
+            function_registry,
+        )
+        .unwrap()
+    }
+
+    fn machine_code_length(executable: &Executable<TestContextObject>) -> Result<usize, EbpfError> {
+        Ok(JitCompiler::<TestContextObject>::new(executable)?
+            .compile()?
+            .machine_code_length())
+    }
+
+    #[test]
+    fn test_load_immediate() {
+        let executable = create_mockup_executable(Config::default(), &[]);
+        for (value, expected) in [
+            (0, vec![0xd2800000]),                            // movz x0, #0
+            (-1, vec![0x92800000]),                           // movn x0, #0
+            (0x12340000, vec![0xd2a24680]),                   // movz x0, #0x1234, lsl #16
+            (-0x10000, vec![0x929fffe0]),                     // movn x0, #0xffff
+            (0x1234_0000_5678, vec![0xd28acf00, 0xf2c24680]), // movz x0, #0x5678; movk x0, #0x1234, lsl #32
+        ] {
+            let mut jit = JitCompiler::<TestContextObject>::new(&executable).unwrap();
+            jit.next_noop_insertion = u32::MAX;
+            jit.emit_load_immediate(X0, value);
+            let machine_code = jit.result.text_section[0..jit.offset_in_text_section]
+                .chunks(4)
+                .map(LittleEndian::read_u32)
+                .collect::<Vec<_>>();
+            assert_eq!(machine_code, expected, "{:#x}", value);
+        }
+    }
+
+    #[test]
+    fn test_code_length_estimate() {
+        const INSTRUCTION_COUNT: usize = 256;
+        let mut prog = vec![0; ebpf::INSN_SIZE * INSTRUCTION_COUNT];
+
+        for sbpf_version in [SBPFVersion::V0, SBPFVersion::V3] {
+            let config = Config {
+                noop_instruction_rate: 0,
+                enabled_sbpf_versions: sbpf_version..=sbpf_version,
+                ..Config::default()
+            };
+            let empty_program_machine_code_length =
+                machine_code_length(&create_mockup_executable(config.clone(), &prog[0..0]))
+                    .unwrap();
+            assert!(empty_program_machine_code_length <= MAX_EMPTY_PROGRAM_MACHINE_CODE_LENGTH);
+
+            for mut opcode in 0x00..=0xFF {
+                let (registers, immediate) = match opcode {
+                    0x85 | 0x8D => (0x88, 8),
+                    0x86 => {
+                        // Put external function calls on a separate loop iteration
+                        opcode = 0x85;
+                        (0x00, 0x91020CDD)
+                    }
+                    0x87 => {
+                        // Put invalid function calls on a separate loop iteration
+                        opcode = 0x85;
+                        (0x88, 0x91020CDD)
+                    }
+                    0x95 => {
+                        // Put a valid syscall
+                        (0, 1)
+                    }
+                    0xD4 | 0xDC => (0x88, 16),
+                    _ => (0x88, 0xFFFFFFFF),
+                };
+                for pc in 0..INSTRUCTION_COUNT {
+                    prog[pc * ebpf::INSN_SIZE] = opcode;
+                    prog[pc * ebpf::INSN_SIZE + 1] = registers;
+                    prog[pc * ebpf::INSN_SIZE + 2] = 0xFF;
+                    prog[pc * ebpf::INSN_SIZE + 3] = 0xFF;
+                    LittleEndian::write_u32(&mut prog[pc * ebpf::INSN_SIZE + 4..], immediate);
+                }
+                let result = machine_code_length(&create_mockup_executable(config.clone(), &prog));
+                let machine_code_length = match result {
+                    Ok(machine_code_length) => {
+                        machine_code_length - empty_program_machine_code_length
+                    }
+                    Err(err) => {
+                        assert!(matches!(err, EbpfError::UnsupportedInstruction));
+                        continue;
+                    }
+                };
+                let instruction_count = if opcode == 0x18 {
+                    // LDDW takes two slots
+                    INSTRUCTION_COUNT / 2
+                } else {
+                    INSTRUCTION_COUNT
+                };
+                assert!(
+                    machine_code_length / instruction_count
+                        <= MAX_MACHINE_CODE_LENGTH_PER_INSTRUCTION,
+                    "opcode={:#04x}",
+                    opcode,
+                );
+            }
+        }
+
+        // The instruction meter checkpoints of a program with more than 4096 instructions need the longest sequence
+        const LONG_INSTRUCTION_COUNT: usize = 1 << 16;
+        let prog = [ebpf::ADD64_IMM, 0, 0, 0, 1, 0, 0, 0].repeat(LONG_INSTRUCTION_COUNT);
+        let mut machine_code_length_per_distance = [0; 2];
+        for (distance, machine_code_length_per_distance) in [1, LONG_INSTRUCTION_COUNT * 2]
+            .iter()
+            .zip(machine_code_length_per_distance.iter_mut())
+        {
+            let config = Config {
+                instruction_meter_checkpoint_distance: *distance,
+                noop_instruction_rate: 0,
+                enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V0,
+                ..Config::default()
+            };
+            *machine_code_length_per_distance =
+                machine_code_length(&create_mockup_executable(config, &prog)).unwrap();
+        }
+        let instruction_meter_checkpoint_machine_code_length =
+            (machine_code_length_per_distance[0] - machine_code_length_per_distance[1])
+                / LONG_INSTRUCTION_COUNT;
+        assert!(
+            instruction_meter_checkpoint_machine_code_length
+                <= MACHINE_CODE_PER_INSTRUCTION_METER_CHECKPOINT
+        );
+    }
+}
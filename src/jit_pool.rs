@@ -0,0 +1,328 @@
+// Copyright 2024 Solana Maintainers <maintainers@solana.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Pool of worker threads which JIT compile executables in the background
+//!
+//! A [JitCompilationPool] takes shared executables and returns a [JitCompilationHandle] right
+//! away. Until the compiled program is installed, the executable keeps running in the interpreter,
+//! even if the JIT was requested. The compiled program is installed atomically, so that every run
+//! either uses it from start to end or not at all.
+
+use crate::{elf::Executable, error::EbpfError, jit::JitProgram, vm::ContextObject};
+use std::{fmt::Debug, sync::OnceLock};
+
+#[cfg(not(feature = "shuttle-test"))]
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+#[cfg(feature = "shuttle-test")]
+use shuttle::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The result of a compilation, with the error converted to its message so that it can be sent between threads
+type CompilationResult = Result<(), String>;
+
+/// The compiled program an executable received from a [JitCompilationPool]
+#[derive(Debug, Default)]
+pub(crate) struct BackgroundCompilation {
+    /// Number of queued or running compilations of the executable
+    pending: AtomicUsize,
+    /// Installed by the first compilation which finishes
+    compiled_program: OnceLock<JitProgram>,
+}
+
+impl BackgroundCompilation {
+    /// Returns the compiled program once it is installed
+    pub(crate) fn get_compiled_program(&self) -> Option<&JitProgram> {
+        self.compiled_program.get()
+    }
+
+    /// Returns true while a compilation is pending and no compiled program is installed yet
+    pub(crate) fn is_pending(&self) -> bool {
+        // A compilation installs its program before it stops being pending, so look for the
+        // program after the counter
+        self.pending.load(Ordering::Acquire) != 0 && self.compiled_program.get().is_none()
+    }
+}
+
+impl PartialEq for BackgroundCompilation {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self as *const _, other as *const _)
+    }
+}
+
+/// The result of a background compilation, see [JitCompilationPool::compile]
+#[derive(Debug)]
+pub struct JitCompilationHandle {
+    result: Arc<(Mutex<Option<CompilationResult>>, Condvar)>,
+}
+
+impl JitCompilationHandle {
+    /// Returns true once the compilation finished, successfully or not
+    pub fn is_finished(&self) -> bool {
+        self.result.0.lock().unwrap().is_some()
+    }
+
+    /// Blocks until the compilation finished and returns its result
+    ///
+    /// A failed compilation is reported by the message of its [EbpfError], as not every
+    /// [EbpfError] can be sent between threads.
+    pub fn wait(self) -> Result<(), String> {
+        let (result, finished) = &*self.result;
+        let mut result = result.lock().unwrap();
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+            result = finished.wait(result).unwrap();
+        }
+    }
+}
+
+/// A fixed number of worker threads which JIT compile executables
+///
+/// Dropping the pool finishes all queued compilations and joins the worker threads.
+pub struct JitCompilationPool {
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl JitCompilationPool {
+    /// Starts `thread_count` worker threads, but at least one
+    pub fn new(thread_count: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..thread_count.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queues the JIT compilation of the executable
+    ///
+    /// If the executable already has a compiled program, nothing is compiled and the handle
+    /// finishes successfully.
+    pub fn compile<C: ContextObject + 'static>(
+        &self,
+        executable: &Arc<Executable<C>>,
+    ) -> JitCompilationHandle {
+        let executable = Arc::clone(executable);
+        let background_compilation = executable.get_background_compilation();
+        background_compilation
+            .pending
+            .fetch_add(1, Ordering::AcqRel);
+        self.spawn(move || {
+            let background_compilation = executable.get_background_compilation();
+            let compilation_result = if executable.get_compiled_program().is_some() {
+                Ok(())
+            } else {
+                executable.compile_program().map(|compiled_program| {
+                    // Another compilation of the same executable might have won the race
+                    let _ = background_compilation
+                        .compiled_program
+                        .set(compiled_program);
+                })
+            };
+            background_compilation
+                .pending
+                .fetch_sub(1, Ordering::AcqRel);
+            compilation_result
+        })
+    }
+
+    /// Queues a job which compiles something and returns a handle to its result
+    pub(crate) fn spawn<F: FnOnce() -> Result<(), EbpfError> + Send + 'static>(
+        &self,
+        compile: F,
+    ) -> JitCompilationHandle {
+        let result = Arc::new((Mutex::new(None), Condvar::new()));
+        let handle = JitCompilationHandle {
+            result: Arc::clone(&result),
+        };
+        let job = Box::new(move || {
+            let compilation_result = compile();
+            let (result, finished) = &*result;
+            *result.lock().unwrap() = Some(compilation_result.map_err(|error| error.to_string()));
+            finished.notify_all();
+        });
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(job);
+        }
+        handle
+    }
+
+    /// Returns the number of worker threads
+    pub fn thread_count(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+impl Debug for JitCompilationPool {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("JitCompilationPool")
+            .field("thread_count", &self.thread_count())
+            .finish()
+    }
+}
+
+impl Drop for JitCompilationPool {
+    fn drop(&mut self) {
+        // Closing the channel lets the workers exit once the queue is empty
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "shuttle-test"))]
+mod tests {
+    use super::*;
+    use crate::{
+        aligned_memory::AlignedMemory,
+        assembler::assemble,
+        ebpf,
+        memory_region::{MemoryMapping, MemoryRegion},
+        program::{BuiltinProgram, FunctionRegistry},
+        vm::{Config, EbpfVm, TestContextObject},
+    };
+
+    fn create_executable() -> Arc<Executable<TestContextObject>> {
+        let loader = BuiltinProgram::new_loader(Config::default(), FunctionRegistry::default());
+        let executable = assemble::<TestContextObject>(
+            "
+            mov64 r0, 0x10
+            call function_foo
+            exit
+            function_foo:
+            add64 r0, 1
+            exit",
+            Arc::new(loader),
+        )
+        .unwrap();
+        Arc::new(executable)
+    }
+
+    /// Runs the executable and returns the result and whether the JIT compiled program was used
+    fn run(executable: &Executable<TestContextObject>) -> (u64, bool) {
+        let mut stack = AlignedMemory::<{ ebpf::HOST_ALIGN }>::zero_filled(
+            executable.get_config().stack_size(),
+        );
+        let stack_len = stack.len();
+        let memory_mapping = MemoryMapping::new(
+            vec![
+                executable.get_ro_region(),
+                MemoryRegion::new_writable(stack.as_slice_mut(), ebpf::MM_STACK_START),
+            ],
+            executable.get_config(),
+            executable.get_sbpf_version(),
+        )
+        .unwrap();
+        let mut context_object = TestContextObject::new(100);
+        let mut vm = EbpfVm::new(
+            executable.get_loader().clone(),
+            executable.get_sbpf_version(),
+            &mut context_object,
+            memory_mapping,
+            stack_len,
+        );
+        let (_instruction_count, result) = vm.execute_program(executable, false);
+        // The host stack pointer is only set by the JIT compiled program
+        (result.unwrap(), !vm.host_stack_pointer.is_null())
+    }
+
+    #[test]
+    fn test_shuttle_switch_to_compiled_program() {
+        shuttle::check_random(
+            || {
+                let executable = create_executable();
+                let pool = JitCompilationPool::new(1);
+                let handle = pool.compile(&executable);
+                let runner = {
+                    let executable = Arc::clone(&executable);
+                    thread::spawn(move || {
+                        let compiling = executable.is_compiling_in_background();
+                        let (result, jit) = run(&executable);
+                        assert_eq!(result, 0x11);
+                        // Once the compiled program is installed it is used by every run
+                        assert!(jit || compiling);
+                    })
+                };
+                handle.wait().unwrap();
+                assert!(!executable.is_compiling_in_background());
+                assert!(executable.get_compiled_program().is_some());
+                runner.join().unwrap();
+                assert_eq!(run(&executable), (0x11, true));
+            },
+            100,
+        );
+    }
+
+    #[test]
+    fn test_shuttle_concurrent_compilations() {
+        shuttle::check_random(
+            || {
+                let executable = create_executable();
+                let pool = JitCompilationPool::new(2);
+                assert_eq!(pool.thread_count(), 2);
+                let handles = [pool.compile(&executable), pool.compile(&executable)];
+                let observer = thread::spawn({
+                    let executable = Arc::clone(&executable);
+                    move || {
+                        // Whichever compilation finishes first is installed and stays installed
+                        let first = executable
+                            .get_compiled_program()
+                            .map(|program| program as *const JitProgram as usize);
+                        let second = executable
+                            .get_compiled_program()
+                            .map(|program| program as *const JitProgram as usize);
+                        assert!(first.is_none() || first == second);
+                    }
+                });
+                for handle in handles {
+                    handle.wait().unwrap();
+                }
+                observer.join().unwrap();
+                assert!(!executable.is_compiling_in_background());
+                // Nothing is compiled if there already is a compiled program
+                let installed = executable.get_compiled_program().unwrap() as *const JitProgram;
+                let handle = pool.compile(&executable);
+                drop(pool);
+                assert!(handle.is_finished());
+                handle.wait().unwrap();
+                assert_eq!(
+                    executable.get_compiled_program().unwrap() as *const JitProgram,
+                    installed
+                );
+            },
+            100,
+        );
+    }
+}
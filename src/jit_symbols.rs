@@ -0,0 +1,324 @@
+#![allow(clippy::arithmetic_side_effects)]
+//! Symbolization of JIT compiled code for host profilers and debuggers
+//!
+//! The functions of a [JitProgram](crate::jit::JitProgram) are published in two ways:
+//! - The perf map file `/tmp/perf-<pid>.map`, which `perf report` reads
+//! - The GDB JIT compilation interface, which GDB reads from the process memory
+//!
+//! Other JIT compilers in the same process may use both as well. So the perf map is only ever
+//! appended to, which leaves the entries of dropped programs behind, and the GDB interface
+//! symbols are only exported with the `gdb-jit-interface` feature, as a process can only have
+//! one definition of them.
+
+use crate::elf_parser::{
+    consts::{
+        ELFCLASS64, ELFDATA2LSB, ELFMAG, ELFOSABI_NONE, EM_AARCH64, EM_X86_64, ET_EXEC, EV_CURRENT,
+        SHF_ALLOC, SHF_EXECINSTR, SHT_NOBITS, SHT_STRTAB, SHT_SYMTAB, STB_GLOBAL, STT_FUNC,
+    },
+    types::{Elf64Ehdr, Elf64Phdr, Elf64Shdr, Elf64Sym, ElfIdent},
+};
+use std::{fs::OpenOptions, io::Write, mem, ops::Range, ptr, sync::Mutex};
+
+/// A named range of machine code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct JitSymbol {
+    /// Name shown by the profiler
+    pub name: String,
+    /// Host addresses
+    pub range: Range<usize>,
+}
+
+/// Entry in the linked list of the GDB JIT compilation interface
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+/// Root of the GDB JIT compilation interface
+#[repr(C)]
+struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+/// GDB finds the list of symbol files by this name
+///
+/// Only exported with the `gdb-jit-interface` feature, so that it does not clash with another JIT
+/// compiler in the process which defines it as well. Unit tests link against the regular build of
+/// this crate as well, so the symbols are only exported by it.
+#[allow(non_upper_case_globals)]
+#[cfg_attr(all(feature = "gdb-jit-interface", not(test)), no_mangle)]
+static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: 0,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+/// GDB sets a breakpoint on this function to be notified of changes to `__jit_debug_descriptor`
+#[cfg_attr(all(feature = "gdb-jit-interface", not(test)), no_mangle)]
+#[inline(never)]
+extern "C" fn __jit_debug_register_code() {
+    // Prevents the calls from being optimized away
+    unsafe { ptr::read_volatile(&0u8) };
+}
+
+/// Guards `__jit_debug_descriptor` and the perf map
+static REGISTRATIONS: Mutex<()> = Mutex::new(());
+
+/// Appends lines to the perf map of this process
+fn append_to_perf_map(lines: &str) {
+    let path = format!("/tmp/perf-{}.map", std::process::id());
+    // Symbolization is best effort, a profiler which finds no map shows raw addresses
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = file.write_all(lines.as_bytes());
+    }
+}
+
+/// Appends the bytes of an ELF header type, which are `repr(C)` without padding
+fn push_elf_type<T>(bytes: &mut Vec<u8>, value: &T) {
+    bytes.extend_from_slice(unsafe {
+        std::slice::from_raw_parts((value as *const T).cast::<u8>(), mem::size_of::<T>())
+    });
+}
+
+/// Builds an in-memory ELF file which GDB loads as the symbol file of the machine code
+fn build_symbol_file(text_section: &Range<usize>, symbols: &[JitSymbol]) -> Vec<u8> {
+    let mut strtab = vec![0u8];
+    let mut symtab = Vec::new();
+    push_elf_type(&mut symtab, &unsafe { mem::zeroed::<Elf64Sym>() });
+    for symbol in symbols {
+        push_elf_type(
+            &mut symtab,
+            &Elf64Sym {
+                st_name: strtab.len() as u32,
+                st_info: (STB_GLOBAL << 4) | STT_FUNC,
+                st_other: 0,
+                st_shndx: 1,
+                st_value: symbol.range.start as u64,
+                st_size: symbol.range.len() as u64,
+            },
+        );
+        strtab.extend_from_slice(symbol.name.as_bytes());
+        strtab.push(0);
+    }
+    let shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+    let symtab_offset = mem::size_of::<Elf64Ehdr>();
+    let strtab_offset = symtab_offset + symtab.len();
+    let shstrtab_offset = strtab_offset + strtab.len();
+    let section_headers_offset = (shstrtab_offset + shstrtab.len() + 7) & !7;
+    let section_header = |sh_name: u32, sh_type: u32, sh_offset: usize, sh_size: usize| Elf64Shdr {
+        sh_name,
+        sh_type,
+        sh_flags: 0,
+        sh_addr: 0,
+        sh_offset: sh_offset as u64,
+        sh_size: sh_size as u64,
+        sh_link: 0,
+        sh_info: 0,
+        sh_addralign: 1,
+        sh_entsize: 0,
+    };
+    let section_headers = [
+        section_header(0, 0, 0, 0),
+        Elf64Shdr {
+            sh_flags: SHF_ALLOC | SHF_EXECINSTR,
+            sh_addr: text_section.start as u64,
+            sh_addralign: 16,
+            ..section_header(1, SHT_NOBITS, 0, text_section.len())
+        },
+        Elf64Shdr {
+            sh_link: 3,
+            sh_info: 1,
+            sh_addralign: 8,
+            sh_entsize: mem::size_of::<Elf64Sym>() as u64,
+            ..section_header(7, SHT_SYMTAB, symtab_offset, symtab.len())
+        },
+        section_header(15, SHT_STRTAB, strtab_offset, strtab.len()),
+        section_header(23, SHT_STRTAB, shstrtab_offset, shstrtab.len()),
+    ];
+    let mut bytes = Vec::new();
+    push_elf_type(
+        &mut bytes,
+        &Elf64Ehdr {
+            e_ident: ElfIdent {
+                ei_mag: ELFMAG,
+                ei_class: ELFCLASS64,
+                ei_data: ELFDATA2LSB,
+                ei_version: EV_CURRENT as u8,
+                ei_osabi: ELFOSABI_NONE,
+                ei_abiversion: 0,
+                ei_pad: [0; 7],
+            },
+            e_type: ET_EXEC,
+            e_machine: if cfg!(target_arch = "aarch64") {
+                EM_AARCH64
+            } else {
+                EM_X86_64
+            },
+            e_version: EV_CURRENT,
+            e_entry: 0,
+            e_phoff: 0,
+            e_shoff: section_headers_offset as u64,
+            e_flags: 0,
+            e_ehsize: mem::size_of::<Elf64Ehdr>() as u16,
+            e_phentsize: mem::size_of::<Elf64Phdr>() as u16,
+            e_phnum: 0,
+            e_shentsize: mem::size_of::<Elf64Shdr>() as u16,
+            e_shnum: section_headers.len() as u16,
+            e_shstrndx: 4,
+        },
+    );
+    bytes.extend_from_slice(&symtab);
+    bytes.extend_from_slice(&strtab);
+    bytes.extend_from_slice(shstrtab);
+    bytes.resize(section_headers_offset, 0);
+    for section_header in section_headers.iter() {
+        push_elf_type(&mut bytes, section_header);
+    }
+    bytes
+}
+
+/// Keeps the symbols of a JitProgram registered in the GDB interface until it is dropped
+pub(crate) struct JitSymbolRegistration {
+    /// Only read by GDB, through code_entry
+    #[allow(dead_code)]
+    symbol_file: Vec<u8>,
+    code_entry: Box<JitCodeEntry>,
+}
+
+impl JitSymbolRegistration {
+    /// Registers the `symbols` which are located in the `text_section`
+    pub fn new(text_section: Range<usize>, symbols: &[JitSymbol]) -> Self {
+        let symbol_file = build_symbol_file(&text_section, symbols);
+        let mut result = Self {
+            code_entry: Box::new(JitCodeEntry {
+                next_entry: ptr::null_mut(),
+                prev_entry: ptr::null_mut(),
+                symfile_addr: symbol_file.as_ptr(),
+                symfile_size: symbol_file.len() as u64,
+            }),
+            symbol_file,
+        };
+        let mut perf_map = String::new();
+        for symbol in symbols {
+            perf_map.push_str(&format!(
+                "{:x} {:x} {}\n",
+                symbol.range.start,
+                symbol.range.len(),
+                symbol.name
+            ));
+        }
+        let _guard = REGISTRATIONS.lock().unwrap();
+        append_to_perf_map(&perf_map);
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let code_entry = &mut *result.code_entry as *mut JitCodeEntry;
+            (*code_entry).next_entry = (*descriptor).first_entry;
+            if let Some(next_entry) = (*descriptor).first_entry.as_mut() {
+                next_entry.prev_entry = code_entry;
+            }
+            (*descriptor).first_entry = code_entry;
+            (*descriptor).relevant_entry = code_entry;
+            (*descriptor).action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+        }
+        result
+    }
+}
+
+impl Drop for JitSymbolRegistration {
+    fn drop(&mut self) {
+        let _guard = REGISTRATIONS.lock().unwrap();
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let code_entry = &mut *self.code_entry as *mut JitCodeEntry;
+            if let Some(prev_entry) = (*code_entry).prev_entry.as_mut() {
+                prev_entry.next_entry = (*code_entry).next_entry;
+            } else {
+                (*descriptor).first_entry = (*code_entry).next_entry;
+            }
+            if let Some(next_entry) = (*code_entry).next_entry.as_mut() {
+                next_entry.prev_entry = (*code_entry).prev_entry;
+            }
+            (*descriptor).relevant_entry = code_entry;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+        }
+    }
+}
+
+// The raw pointers are only accessed while holding the lock of REGISTRATIONS
+unsafe impl Send for JitSymbolRegistration {}
+unsafe impl Sync for JitSymbolRegistration {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{aligned_memory::AlignedMemory, ebpf, elf_parser::Elf64};
+
+    #[test]
+    fn test_registration() {
+        let text_section = 0x10000..0x10100;
+        let symbols = [
+            JitSymbol {
+                name: "test_registration_a".to_string(),
+                range: 0x10000..0x10080,
+            },
+            JitSymbol {
+                name: "test_registration_b".to_string(),
+                range: 0x10080..0x10100,
+            },
+        ];
+        // Entries of other JIT compilers are kept
+        let perf_map_path = format!("/tmp/perf-{}.map", std::process::id());
+        append_to_perf_map("20000 10 test_registration_other\n");
+        let registration = JitSymbolRegistration::new(text_section.clone(), &symbols);
+        let perf_map = std::fs::read_to_string(&perf_map_path).unwrap();
+        assert!(perf_map.contains("20000 10 test_registration_other\n"));
+        assert!(perf_map.contains("10000 80 test_registration_a\n10080 80 test_registration_b\n"));
+        let descriptor = unsafe { &*ptr::addr_of!(__jit_debug_descriptor) };
+        assert_eq!(descriptor.action_flag, JIT_REGISTER_FN);
+        assert!(ptr::eq(
+            descriptor.relevant_entry,
+            &*registration.code_entry
+        ));
+
+        let symbol_file =
+            AlignedMemory::<{ ebpf::HOST_ALIGN }>::from_slice(&registration.symbol_file);
+        let elf = Elf64::parse(symbol_file.as_slice()).unwrap();
+        let text = elf
+            .section_header_table()
+            .iter()
+            .find(|section_header| elf.section_name(section_header.sh_name) == Ok(b".text"))
+            .unwrap();
+        assert_eq!(text.sh_addr, text_section.start as u64);
+        assert_eq!(text.sh_size, text_section.len() as u64);
+        let parsed_symbols = elf
+            .symbol_table()
+            .unwrap()
+            .unwrap()
+            .iter()
+            .skip(1)
+            .map(|symbol| JitSymbol {
+                name: String::from_utf8(elf.symbol_name(symbol.st_name).unwrap().to_vec()).unwrap(),
+                range: symbol.st_value as usize..(symbol.st_value + symbol.st_size) as usize,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(parsed_symbols, symbols);
+
+        drop(registration);
+        let perf_map = std::fs::read_to_string(&perf_map_path).unwrap();
+        assert!(perf_map.contains("20000 10 test_registration_other\n"));
+        let descriptor = unsafe { &*ptr::addr_of!(__jit_debug_descriptor) };
+        assert_eq!(descriptor.action_flag, JIT_UNREGISTER_FN);
+    }
+}
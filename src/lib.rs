@@ -23,8 +23,18 @@ extern crate combine;
 extern crate hash32;
 extern crate log;
 extern crate rand;
+extern crate sha2;
 extern crate thiserror;
 
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
+mod aarch64;
 pub mod aligned_memory;
 mod asm_parser;
 pub mod assembler;
@@ -38,16 +48,77 @@ pub mod error;
 pub mod fuzz;
 pub mod insn_builder;
 pub mod interpreter;
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 mod jit;
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
+mod jit_aarch64;
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
+pub mod jit_pool;
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
+mod jit_symbols;
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
+pub mod lockstep;
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 mod memory_management;
 pub mod memory_region;
 pub mod program;
+pub mod snapshot;
 pub mod static_analysis;
 pub mod syscalls;
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
+mod tiered;
 pub mod verifier;
 pub mod vm;
+pub mod vm_pool;
 #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
 mod x86;
 
@@ -0,0 +1,298 @@
+// Copyright 2024 Solana Maintainers <maintainers@solana.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Lockstep execution: Run the interpreter alongside the JIT to catch miscompilations
+//!
+//! [execute_program_in_lockstep] executes the JIT compiled program on the given VM and the
+//! interpreter on a copy of its memory and its ContextObject. The JIT is stopped at every one of
+//! its instruction meter checkpoints, the interpreter then runs exactly as many instructions and
+//! the registers, call frames, memory contents and instruction meters of both are compared.
+//! The first difference is reported as a [LockstepDivergence] with the states of both.
+
+use crate::{
+    ebpf,
+    elf::Executable,
+    error::{EbpfError, ProgramResult},
+    jit::meters_exactly,
+    memory_region::MemoryState,
+    snapshot::{mapped_regions, SnapshotError, VmSnapshot},
+    vm::{ContextObject, EbpfVm},
+};
+
+/// Error definitions
+#[derive(Debug, thiserror::Error)]
+pub enum LockstepError {
+    /// The config of the executable does not allow lockstep execution
+    #[error("unsupported config: {0}")]
+    UnsupportedConfig(&'static str),
+    /// Failed to copy the VM for the interpreter
+    #[error("snapshot error: {0}")]
+    SnapshotError(#[from] SnapshotError),
+    /// The interpreter and the JIT reached different states
+    #[error(
+        "interpreter and JIT diverged between pc {} and pc {}",
+        .0.previous_pc,
+        .0.pc
+    )]
+    Divergence(Box<LockstepDivergence>),
+}
+
+/// State of an execution engine where the divergence was detected
+#[derive(Debug)]
+pub struct LockstepState {
+    /// Result of the last interval, `EbpfError::ExecutionSuspended` at a checkpoint
+    pub result: ProgramResult,
+    /// Instructions executed since the start
+    pub instruction_count: u64,
+    /// Registers, call frames, meter and memory contents
+    ///
+    /// Unless the execution was suspended, only the pc in the registers is meaningful.
+    pub snapshot: VmSnapshot,
+}
+
+/// The first difference between the interpreter and the JIT
+#[derive(Debug)]
+pub struct LockstepDivergence {
+    /// The last checkpoint at which both agreed, the divergent instruction follows it
+    pub previous_pc: u64,
+    /// The checkpoint (or the instruction where the JIT stopped) at which the states differ
+    pub pc: u64,
+    /// State of the interpreter
+    pub interpreter: LockstepState,
+    /// State of the JIT
+    pub jit: LockstepState,
+}
+
+/// Executes the program in the JIT and in the interpreter and compares them at every checkpoint
+///
+/// The JIT runs on `vm`, which holds the final state afterwards just like with
+/// [EbpfVm::execute_program]. Both `Config::enable_instruction_meter` and
+/// `Config::enable_resumable_execution` are required to stop at the checkpoints, tiered
+/// compilation is not supported. In the interval in which the budget runs out, the JIT only
+/// stops at the end of the interval unless `Config::enable_exact_instruction_meter` is set. So
+/// without it, only the results and the instruction meters are compared for that interval and
+/// the JIT may count more instructions. The interpreter has no cancellation handle, so when the JIT is cancelled
+/// the interpreter has to be suspended after as many instructions instead.
+pub fn execute_program_in_lockstep<C: ContextObject + Clone>(
+    vm: &mut EbpfVm<C>,
+    executable: &Executable<C>,
+) -> Result<(u64, ProgramResult), LockstepError> {
+    let config = executable.get_config();
+    if !config.enable_instruction_meter || !config.enable_resumable_execution {
+        return Err(LockstepError::UnsupportedConfig(
+            "lockstep execution requires the instruction meter and resumable execution",
+        ));
+    }
+    if config.tiered_compilation_threshold != 0 {
+        return Err(LockstepError::UnsupportedConfig(
+            "lockstep execution does not support tiered compilation",
+        ));
+    }
+    if executable.get_compiled_program().is_none() {
+        return Ok((0, ProgramResult::Err(EbpfError::JitNotCompiled)));
+    }
+    vm.enter_entrypoint(executable);
+    let mut snapshot = VmSnapshot::capture(vm, executable)?;
+    // The copy is private to the interpreter, so there is nothing to copy on write
+    for region in snapshot.regions.iter_mut() {
+        if let MemoryState::Cow(_) = region.state {
+            region.state = MemoryState::Writable;
+        }
+    }
+    let mut context_object = vm.context_object_pointer.clone();
+    let mut interpreter_vm = snapshot.create_vm(executable, &mut context_object, None)?;
+    let mut jit_instruction_count = 0u64;
+    let mut interpreter_instruction_count = 0u64;
+    loop {
+        let previous_pc = vm.registers[11];
+        // A budget of the cost of the instruction at pc passes its checkpoint but no other
+        let remaining = vm.context_object_pointer.get_remaining();
+        vm.instruction_meter_reserve =
+            remaining.saturating_sub(instruction_cost(executable, previous_pc));
+        let (interval_instruction_count, jit_result) = vm.resume_program(executable, false);
+        vm.instruction_meter_reserve = 0;
+        jit_instruction_count = jit_instruction_count.saturating_add(interval_instruction_count);
+        interpreter_vm.instruction_meter_reserve = interpreter_vm
+            .context_object_pointer
+            .get_remaining()
+            .saturating_sub(interval_instruction_count);
+        let (interpreter_interval_instruction_count, interpreter_result) =
+            interpreter_vm.resume_program(executable, true);
+        interpreter_vm.instruction_meter_reserve = 0;
+        interpreter_instruction_count =
+            interpreter_instruction_count.saturating_add(interpreter_interval_instruction_count);
+        let budget_exhausted = vm.context_object_pointer.get_remaining() == 0;
+        let cancelled = matches!(
+            jit_result,
+            ProgramResult::Err(EbpfError::ExecutionCancelled)
+        );
+        let mut counts_agree = jit_instruction_count == interpreter_instruction_count;
+        let agree = if cancelled {
+            // The interpreter has no cancellation handle, it is suspended after as many instructions
+            matches!(
+                interpreter_result,
+                ProgramResult::Err(EbpfError::ExecutionSuspended)
+            ) && states_agree(vm, &jit_result, &interpreter_vm, &jit_result)
+        } else if budget_exhausted && !meters_exactly(config) {
+            // The JIT runs past the budget up to its checkpoint and counts these instructions
+            counts_agree = jit_instruction_count >= interpreter_instruction_count;
+            results_agree(&jit_result, &interpreter_result)
+                && interpreter_vm.context_object_pointer.get_remaining() == 0
+        } else {
+            states_agree(vm, &jit_result, &interpreter_vm, &interpreter_result)
+        };
+        if !agree || !counts_agree {
+            return Err(LockstepError::Divergence(Box::new(LockstepDivergence {
+                previous_pc,
+                pc: vm.registers[11],
+                interpreter: LockstepState {
+                    result: interpreter_result,
+                    instruction_count: interpreter_instruction_count,
+                    snapshot: VmSnapshot::capture(&interpreter_vm, executable)?,
+                },
+                jit: LockstepState {
+                    result: jit_result,
+                    instruction_count: jit_instruction_count,
+                    snapshot: VmSnapshot::capture(vm, executable)?,
+                },
+            })));
+        }
+        let suspended = matches!(
+            jit_result,
+            ProgramResult::Err(EbpfError::ExecutionSuspended)
+        );
+        if !suspended || interval_instruction_count == 0 || budget_exhausted {
+            // Either the program terminated, was cancelled or ran out of budget
+            return Ok((jit_instruction_count, jit_result));
+        }
+    }
+}
+
+/// Cost of the instruction at `pc`, as charged by the instruction meter
+fn instruction_cost<C: ContextObject>(executable: &Executable<C>, pc: u64) -> u64 {
+    let (_text_section_vaddr, text_bytes) = executable.get_text_bytes();
+    (pc as usize)
+        .checked_mul(ebpf::INSN_SIZE)
+        .and_then(|offset| text_bytes.get(offset))
+        .map(|opc| executable.get_config().instruction_costs.get(*opc))
+        .unwrap_or(1)
+}
+
+/// Compares two results structurally
+fn results_agree(a: &ProgramResult, b: &ProgramResult) -> bool {
+    match (a, b) {
+        (ProgramResult::Ok(a), ProgramResult::Ok(b)) => a == b,
+        (ProgramResult::Err(a), ProgramResult::Err(b)) => errors_agree(a, b),
+        _ => false,
+    }
+}
+
+/// Compares two errors structurally
+fn errors_agree(a: &EbpfError, b: &EbpfError) -> bool {
+    match (a, b) {
+        (EbpfError::ElfError(a), EbpfError::ElfError(b)) => a == b,
+        (EbpfError::FunctionAlreadyRegistered(a), EbpfError::FunctionAlreadyRegistered(b)) => {
+            a == b
+        }
+        (EbpfError::FunctionNotRegistered(a), EbpfError::FunctionNotRegistered(b)) => a == b,
+        (EbpfError::InvalidVirtualAddress(a), EbpfError::InvalidVirtualAddress(b)) => a == b,
+        (EbpfError::InvalidMemoryRegion(a), EbpfError::InvalidMemoryRegion(b)) => a == b,
+        (
+            EbpfError::AccessViolation(a_type, a_addr, a_len, a_name),
+            EbpfError::AccessViolation(b_type, b_addr, b_len, b_name),
+        ) => (a_type, a_addr, a_len, a_name) == (b_type, b_addr, b_len, b_name),
+        (
+            EbpfError::StackAccessViolation(a_type, a_addr, a_len, a_frame),
+            EbpfError::StackAccessViolation(b_type, b_addr, b_len, b_frame),
+        ) => (a_type, a_addr, a_len, a_frame) == (b_type, b_addr, b_len, b_frame),
+        (EbpfError::ExhaustedTextSegment(a), EbpfError::ExhaustedTextSegment(b)) => a == b,
+        (
+            EbpfError::LibcInvocationFailed(a_name, a_args, a_code),
+            EbpfError::LibcInvocationFailed(b_name, b_args, b_code),
+        ) => (a_name, a_args, a_code) == (b_name, b_args, b_code),
+        (EbpfError::VerifierError(a), EbpfError::VerifierError(b)) => a == b,
+        // The errors of syscalls are opaque, so only their messages can be compared
+        (EbpfError::SyscallError(a), EbpfError::SyscallError(b)) => a.to_string() == b.to_string(),
+        (EbpfError::JitCacheError(a), EbpfError::JitCacheError(b)) => a == b,
+        (EbpfError::Fault(a, a_location), EbpfError::Fault(b, b_location)) => {
+            a_location == b_location && errors_agree(a, b)
+        }
+        // All remaining variants have no fields
+        (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+    }
+}
+
+/// Compares the result, meter, registers, call frames and memory contents of both VMs
+fn states_agree<C: ContextObject>(
+    jit_vm: &EbpfVm<C>,
+    jit_result: &ProgramResult,
+    interpreter_vm: &EbpfVm<C>,
+    interpreter_result: &ProgramResult,
+) -> bool {
+    if !results_agree(jit_result, interpreter_result)
+        || jit_vm.context_object_pointer.get_remaining()
+            != interpreter_vm.context_object_pointer.get_remaining()
+    {
+        return false;
+    }
+    match jit_result {
+        ProgramResult::Ok(_) => {
+            if jit_vm.registers != interpreter_vm.registers {
+                return false;
+            }
+        }
+        ProgramResult::Err(EbpfError::ExecutionSuspended | EbpfError::ExecutionCancelled) => {
+            // The JIT reports the branch at which it noticed the cancellation, which the
+            // interpreter has already passed
+            let registers = if matches!(
+                jit_result,
+                ProgramResult::Err(EbpfError::ExecutionCancelled)
+            ) {
+                0..11
+            } else {
+                0..12
+            };
+            let call_depth = jit_vm.call_depth as usize;
+            if jit_vm.registers[registers.clone()] != interpreter_vm.registers[registers]
+                || jit_vm.call_depth != interpreter_vm.call_depth
+                || jit_vm.call_frames.get(0..call_depth)
+                    != interpreter_vm.call_frames.get(0..call_depth)
+            {
+                return false;
+            }
+        }
+        // Errors only report the pc, the other registers are not written back
+        ProgramResult::Err(_) => {
+            if jit_vm.registers[11] != interpreter_vm.registers[11] {
+                return false;
+            }
+        }
+    }
+    match (
+        mapped_regions(&jit_vm.memory_mapping),
+        mapped_regions(&interpreter_vm.memory_mapping),
+    ) {
+        (Ok(jit_regions), Ok(interpreter_regions)) => {
+            jit_regions.len() == interpreter_regions.len()
+                && jit_regions.iter().zip(interpreter_regions.iter()).all(
+                    |(jit_region, interpreter_region)| {
+                        jit_region.vm_addr == interpreter_region.vm_addr
+                            && jit_region.len == interpreter_region.len
+                            && unsafe {
+                                std::slice::from_raw_parts(
+                                    jit_region.host_addr.get() as *const u8,
+                                    jit_region.len as usize,
+                                ) == std::slice::from_raw_parts(
+                                    interpreter_region.host_addr.get() as *const u8,
+                                    interpreter_region.len as usize,
+                                )
+                            }
+                    },
+                )
+        }
+        _ => false,
+    }
+}
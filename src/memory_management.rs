@@ -165,3 +165,32 @@ pub unsafe fn protect_pages(
     }
     Ok(())
 }
+
+/// Makes freshly written machine code visible to the instruction fetch of all cores
+///
+/// This is a no-op on x86, which keeps its instruction cache coherent.
+pub unsafe fn invalidate_instruction_cache(raw: *const u8, size_in_bytes: usize) {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let cache_type: u64;
+        std::arch::asm!("mrs {}, ctr_el0", out(reg) cache_type, options(nomem, nostack));
+        let data_cache_line_size = 4usize << (cache_type >> 16 & 0xf);
+        let instruction_cache_line_size = 4usize << (cache_type & 0xf);
+        let start = raw as usize;
+        let end = start.saturating_add(size_in_bytes);
+        let mut address = start & !data_cache_line_size.saturating_sub(1);
+        while address < end {
+            std::arch::asm!("dc cvau, {}", in(reg) address, options(nostack));
+            address = address.saturating_add(data_cache_line_size);
+        }
+        std::arch::asm!("dsb ish", options(nostack));
+        let mut address = start & !instruction_cache_line_size.saturating_sub(1);
+        while address < end {
+            std::arch::asm!("ic ivau, {}", in(reg) address, options(nostack));
+            address = address.saturating_add(instruction_cache_line_size);
+        }
+        std::arch::asm!("dsb ish", "isb", options(nostack));
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = (raw, size_in_bytes);
+}
@@ -9,6 +9,7 @@ use crate::{
 };
 use std::{
     array,
+    borrow::Cow,
     cell::{Cell, UnsafeCell},
     fmt, mem,
     ops::Range,
@@ -33,16 +34,41 @@ use std::{
 */
 
 /// The state of a memory region.
+///
+/// The tag is read by the JIT, see `jit_layout`.
 #[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[repr(u64)]
 pub enum MemoryState {
     /// The memory region is readable
     #[default]
-    Readable,
+    Readable = 0,
     /// The memory region is writable
-    Writable,
+    Writable = 1,
     /// The memory region is writable but must be copied before writing. The
     /// carried data can be used to uniquely identify the region.
-    Cow(u64),
+    Cow(u64) = 2,
+    /// The memory region is writable but not readable
+    WriteOnly = 3,
+}
+
+/// Permission of a protected sub-range of a [MemoryRegion], see [MemoryRegion::protect]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryProtection {
+    /// The sub-range is readable but not writable, even if the region is
+    Readable,
+    /// The sub-range is neither readable nor writable
+    Inaccessible,
+}
+
+/// A sub-range of a [MemoryRegion] with fewer permissions than the region itself
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProtectedRange {
+    /// Protected virtual addresses
+    pub vm_range: Range<u64>,
+    /// Whether the sub-range is readable or inaccessible
+    pub protection: MemoryProtection,
+    /// Reported as the section of an [EbpfError::AccessViolation] in the sub-range
+    pub name: Cow<'static, str>,
 }
 
 /// Callback executed when a CoW memory region is written to
@@ -60,14 +86,32 @@ pub struct MemoryRegion {
     pub vm_addr_end: u64,
     /// Length in bytes
     pub len: u64,
+    /// Length in bytes the region can be resized to, see [MemoryMapping::resize_region]
+    capacity: u64,
     /// Size of regular gaps as bit shift (63 means this region is continuous)
     pub vm_gap_shift: u8,
     /// Whether the region is readonly, writable or must be copied before writing
     pub state: Cell<MemoryState>,
+    /// Whether stores are recorded, see [MemoryRegion::enable_write_tracking]
+    tracks_writes: bool,
+    /// Start of the written range, as offset from host_addr
+    written_start: Cell<u64>,
+    /// End of the written range, as offset from host_addr
+    written_end: Cell<u64>,
+    /// One bit per page which was written to, see [MemoryRegion::enable_dirty_page_tracking]
+    dirty_pages: Box<[Cell<u64>]>,
+    /// Address of the first of the `dirty_pages` or 0 if they are not tracked, read by the JIT
+    dirty_pages_address: u64,
+    /// Size of the dirty pages as bit shift
+    dirty_page_shift: u8,
+    /// Sub-ranges with fewer permissions, see [MemoryRegion::protect]
+    protected_ranges: Box<[ProtectedRange]>,
+    /// Address of the first of the `protected_ranges` or 0 if there are none, read by the JIT
+    protected_ranges_address: u64,
 }
 
 impl MemoryRegion {
-    fn new(slice: &[u8], vm_addr: u64, vm_gap_size: u64, state: MemoryState) -> Self {
+    pub(crate) fn new(slice: &[u8], vm_addr: u64, vm_gap_size: u64, state: MemoryState) -> Self {
         let mut vm_addr_end = vm_addr.saturating_add(slice.len() as u64);
         let mut vm_gap_shift = (std::mem::size_of::<u64>() as u8)
             .saturating_mul(8)
@@ -82,8 +126,17 @@ impl MemoryRegion {
             vm_addr,
             vm_addr_end,
             len: slice.len() as u64,
+            capacity: slice.len() as u64,
             vm_gap_shift,
             state: Cell::new(state),
+            tracks_writes: false,
+            written_start: Cell::new(slice.len() as u64),
+            written_end: Cell::new(0),
+            dirty_pages: Box::default(),
+            dirty_pages_address: 0,
+            dirty_page_shift: 0,
+            protected_ranges: Box::default(),
+            protected_ranges_address: 0,
         }
     }
 
@@ -107,6 +160,11 @@ impl MemoryRegion {
         Self::new(&*slice, vm_addr, 0, MemoryState::Writable)
     }
 
+    /// Creates a new write-only MemoryRegion from a mutable slice
+    pub fn new_write_only(slice: &mut [u8], vm_addr: u64) -> Self {
+        Self::new(&*slice, vm_addr, 0, MemoryState::WriteOnly)
+    }
+
     /// Creates a new copy on write MemoryRegion.
     ///
     /// The region is made writable
@@ -119,6 +177,16 @@ impl MemoryRegion {
         Self::new(&*slice, vm_addr, vm_gap_size, MemoryState::Writable)
     }
 
+    /// Creates a new writable MemoryRegion which maps the first `len` bytes of a mutable slice
+    ///
+    /// The region can be resized up to the length of the slice, see [MemoryMapping::resize_region].
+    pub fn new_growable(slice: &mut [u8], vm_addr: u64, len: u64) -> Self {
+        let capacity = slice.len() as u64;
+        let mut region = Self::new_writable(&mut slice[..len.min(capacity) as usize], vm_addr);
+        region.capacity = capacity;
+        region
+    }
+
     /// Convert a virtual machine address into a host address
     pub fn vm_to_host(&self, vm_addr: u64, len: u64) -> ProgramResult {
         // This can happen if a region starts at an offset from the base region
@@ -144,6 +212,226 @@ impl MemoryRegion {
         }
         ProgramResult::Err(EbpfError::InvalidVirtualAddress(vm_addr))
     }
+
+    /// Starts recording which bytes are written to through a [MemoryMapping]
+    ///
+    /// Regions do not record their stores unless this or [MemoryRegion::enable_dirty_page_tracking]
+    /// was called, which keeps the store path of the interpreter cheap.
+    pub fn enable_write_tracking(&mut self) {
+        self.tracks_writes = true;
+        self.reset_written_range();
+    }
+
+    /// Whether [MemoryRegion::enable_write_tracking] was called
+    pub fn tracks_writes(&self) -> bool {
+        self.tracks_writes
+    }
+
+    /// Returns the range of host offsets which have been written to through a [MemoryMapping]
+    ///
+    /// The range is conservative: it covers every byte written since
+    /// [MemoryRegion::enable_write_tracking] or [MemoryRegion::reset_written_range] was called, but
+    /// may also cover bytes in between which were not written. Empty if the region does not track
+    /// its writes. With `Config::eliminate_redundant_bounds_checks` a JIT run marks the stack
+    /// region from the current stack frame to its end as written, as the JIT compiled program
+    /// accesses the stack frames directly.
+    pub fn written_range(&self) -> Range<u64> {
+        let start = self.written_start.get();
+        let end = self.written_end.get();
+        // JIT compiled programs extend the range unconditionally, as that is cheaper than a branch
+        if self.tracks_writes && start < end {
+            start..end
+        } else {
+            0..0
+        }
+    }
+
+    /// Forgets all previously recorded writes
+    pub fn reset_written_range(&self) {
+        self.written_start.set(self.len);
+        self.written_end.set(0);
+    }
+
+    /// Records a write of `len` bytes at the given host address
+    #[inline]
+    pub(crate) fn mark_written(&self, host_addr: u64, len: u64) {
+        if !self.tracks_writes {
+            return;
+        }
+        let offset = host_addr.saturating_sub(self.host_addr.get());
+        self.written_start.set(self.written_start.get().min(offset));
+        self.written_end
+            .set(self.written_end.get().max(offset.saturating_add(len)));
+        if self.dirty_pages_address != 0 && len > 0 {
+            self.mark_dirty_pages(offset, len);
+        }
+    }
+
+    /// Starts recording which pages of `page_size` bytes are written to through a [MemoryMapping]
+    ///
+    /// The page size is rounded up to a power of two. Previously recorded dirty pages are
+    /// forgotten. This also enables [MemoryRegion::enable_write_tracking]. JIT compiled programs
+    /// translate their stores to a region which tracks its dirty pages in Rust.
+    pub fn enable_dirty_page_tracking(&mut self, page_size: u64) {
+        if !self.tracks_writes {
+            self.enable_write_tracking();
+        }
+        self.dirty_page_shift = page_size
+            .checked_next_power_of_two()
+            .unwrap_or(1 << 63)
+            .trailing_zeros() as u8;
+        let page_count = self
+            .capacity
+            .saturating_add(
+                1u64.wrapping_shl(self.dirty_page_shift as u32)
+                    .saturating_sub(1),
+            )
+            .wrapping_shr(self.dirty_page_shift as u32);
+        self.dirty_pages = (0..page_count.div_ceil(u64::BITS as u64))
+            .map(|_| Cell::new(0))
+            .collect();
+        self.dirty_pages_address = self.dirty_pages.as_ptr() as u64;
+    }
+
+    /// Returns the ranges of host offsets of the pages which have been written to through a [MemoryMapping]
+    ///
+    /// Adjacent pages are merged and the last page ends at the capacity of the region, so pages
+    /// beyond the end of a shrunk region are kept. Empty unless
+    /// [MemoryRegion::enable_dirty_page_tracking] was called. Like [MemoryRegion::written_range],
+    /// this covers all pages of the stack region from the current stack frame on after a JIT run
+    /// with `Config::eliminate_redundant_bounds_checks`.
+    pub fn dirty_ranges(&self) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for (index, word) in self.dirty_pages.iter().enumerate() {
+            let mut bits = word.get();
+            while bits != 0 {
+                let page = (index as u64)
+                    .saturating_mul(u64::BITS as u64)
+                    .saturating_add(bits.trailing_zeros() as u64);
+                bits &= bits.wrapping_sub(1);
+                let start = page.wrapping_shl(self.dirty_page_shift as u32);
+                let end = page
+                    .saturating_add(1)
+                    .wrapping_shl(self.dirty_page_shift as u32)
+                    .min(self.capacity);
+                match ranges.last_mut() {
+                    Some(last) if last.end == start => last.end = end,
+                    _ => ranges.push(start..end),
+                }
+            }
+        }
+        ranges
+    }
+
+    /// Forgets all previously recorded dirty pages
+    pub fn reset_dirty_pages(&self) {
+        for word in self.dirty_pages.iter() {
+            word.set(0);
+        }
+    }
+
+    /// Restricts the accesses to the virtual addresses `vm_range` inside of this region
+    ///
+    /// Loads and stores which overlap the sub-range and are not permitted by `protection` fail
+    /// with an [EbpfError::AccessViolation] in the section `name`. This also applies to syscalls
+    /// which translate addresses through the [MemoryMapping]. Sub-ranges may overlap, the first
+    /// one added which forbids an access is reported.
+    pub fn protect(
+        &mut self,
+        vm_range: Range<u64>,
+        protection: MemoryProtection,
+        name: impl Into<Cow<'static, str>>,
+    ) -> Result<(), EbpfError> {
+        if vm_range.is_empty() || vm_range.start < self.vm_addr || vm_range.end > self.vm_addr_end {
+            return Err(EbpfError::InvalidVirtualAddress(vm_range.start));
+        }
+        let mut protected_ranges = mem::take(&mut self.protected_ranges).into_vec();
+        protected_ranges.push(ProtectedRange {
+            vm_range,
+            protection,
+            name: name.into(),
+        });
+        self.protected_ranges = protected_ranges.into_boxed_slice();
+        self.protected_ranges_address = self.protected_ranges.as_ptr() as u64;
+        Ok(())
+    }
+
+    /// Returns the sub-ranges with fewer permissions, see [MemoryRegion::protect]
+    pub fn protected_ranges(&self) -> &[ProtectedRange] {
+        &self.protected_ranges
+    }
+
+    /// Returns the name of the first protected sub-range which forbids the access, if any
+    #[inline]
+    fn protected_range_violation(
+        &self,
+        access_type: AccessType,
+        vm_addr: u64,
+        len: u64,
+    ) -> Option<Cow<'static, str>> {
+        let vm_addr_end = vm_addr.saturating_add(len);
+        self.protected_ranges
+            .iter()
+            .find(|protected_range| {
+                protected_range.vm_range.start < vm_addr_end
+                    && vm_addr < protected_range.vm_range.end
+                    && (access_type == AccessType::Store
+                        || protected_range.protection == MemoryProtection::Inaccessible)
+            })
+            .map(|protected_range| protected_range.name.clone())
+    }
+
+    /// Returns a copy of this continuous region with a length of `new_len` bytes
+    ///
+    /// The written range and the dirty pages are kept, even beyond the new end.
+    fn resized(&self, new_len: u64) -> Option<Self> {
+        if self.vm_gap_shift as u32 != u64::BITS.saturating_sub(1) || new_len > self.capacity {
+            return None;
+        }
+        let dirty_pages = self.dirty_pages.clone();
+        let protected_ranges = self.protected_ranges.clone();
+        Some(Self {
+            host_addr: self.host_addr.clone(),
+            vm_addr: self.vm_addr,
+            vm_addr_end: self.vm_addr.saturating_add(new_len),
+            len: new_len,
+            capacity: self.capacity,
+            vm_gap_shift: self.vm_gap_shift,
+            state: self.state.clone(),
+            tracks_writes: self.tracks_writes,
+            written_start: self.written_start.clone(),
+            written_end: self.written_end.clone(),
+            dirty_pages_address: if self.dirty_pages_address != 0 {
+                dirty_pages.as_ptr() as u64
+            } else {
+                0
+            },
+            dirty_pages,
+            dirty_page_shift: self.dirty_page_shift,
+            protected_ranges_address: if self.protected_ranges_address != 0 {
+                protected_ranges.as_ptr() as u64
+            } else {
+                0
+            },
+            protected_ranges,
+        })
+    }
+
+    /// Records a write of `len` bytes at the given host offset in the dirty pages
+    fn mark_dirty_pages(&self, offset: u64, len: u64) {
+        let first_page = offset.wrapping_shr(self.dirty_page_shift as u32);
+        let last_page = offset
+            .saturating_add(len.saturating_sub(1))
+            .wrapping_shr(self.dirty_page_shift as u32);
+        for page in first_page..=last_page {
+            if let Some(word) = self
+                .dirty_pages
+                .get(page.wrapping_shr(u64::BITS.trailing_zeros()) as usize)
+            {
+                word.set(word.get() | 1u64.wrapping_shl(page as u32 & (u64::BITS - 1)));
+            }
+        }
+    }
 }
 
 impl fmt::Debug for MemoryRegion {
@@ -179,6 +467,66 @@ pub enum AccessType {
     Store,
 }
 
+/// A load or store of the program which touched a watched range, see [MemoryMapping::add_watchpoint]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemoryAccess {
+    /// Instruction which accessed the memory
+    pub pc: u64,
+    /// Whether the memory was read or written
+    pub access_type: AccessType,
+    /// First virtual address of the access
+    pub vm_addr: u64,
+    /// Length of the access in bytes
+    pub len: u64,
+    /// The value which was read or written
+    pub value: u64,
+}
+
+/// Callback executed when a watched range is accessed, an error aborts the execution
+///
+/// Loads are reported after they were performed. Stores are reported before, so that an error
+/// prevents the write. A store which then fails, e.g. with an access violation, was reported anyway.
+pub type MemoryWatchCallback = Box<dyn Fn(&MemoryAccess) -> Result<(), EbpfError>>;
+
+/// A range of virtual addresses whose accesses are reported to a callback
+struct MemoryWatchpoint {
+    /// Watched virtual addresses
+    vm_range: Range<u64>,
+    /// Type of the reported accesses, all accesses if None
+    access_type: Option<AccessType>,
+    /// Callback which receives the accesses
+    callback: MemoryWatchCallback,
+}
+
+impl fmt::Debug for MemoryWatchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryWatchpoint")
+            .field("vm_range", &self.vm_range)
+            .field("access_type", &self.access_type)
+            .finish()
+    }
+}
+
+/// How an [AccessViolationHandler] resolves a failed access of the program
+#[derive(Debug)]
+pub enum AccessViolationResolution {
+    /// The access fails with the access violation
+    Fail,
+    /// Maps the region, which must not overlap any other region, and retries the access
+    MapRegion(MemoryRegion),
+    /// Makes the region containing the address writable at the given host address and retries the
+    /// access, like a [MemoryCowCallback]
+    MakeWritable(u64),
+}
+
+/// Handler executed when a load or store of the program is out of bounds or not permitted, see
+/// [MemoryMapping::set_access_violation_handler]
+///
+/// Receives the type, the virtual address and the length of the access as well as the region
+/// containing the address, if any.
+pub type AccessViolationHandler =
+    Box<dyn Fn(AccessType, u64, u64, Option<&MemoryRegion>) -> AccessViolationResolution>;
+
 /// Memory mapping based on eytzinger search.
 pub struct UnalignedMemoryMapping<'a> {
     /// Mapped memory regions
@@ -193,6 +541,10 @@ pub struct UnalignedMemoryMapping<'a> {
     sbpf_version: SBPFVersion,
     /// CoW callback
     cow_cb: Option<MemoryCowCallback>,
+    /// Watched ranges, see [MemoryMapping::add_watchpoint]
+    watchpoints: Vec<MemoryWatchpoint>,
+    /// Resolves failed accesses, see [MemoryMapping::set_access_violation_handler]
+    access_violation_handler: Option<AccessViolationHandler>,
 }
 
 impl fmt::Debug for UnalignedMemoryMapping<'_> {
@@ -210,6 +562,15 @@ impl fmt::Debug for UnalignedMemoryMapping<'_> {
                     .map(|cb| format!("Some({:p})", &cb))
                     .unwrap_or_else(|| "None".to_string()),
             )
+            .field("watchpoints", &self.watchpoints)
+            .field(
+                "access_violation_handler",
+                &self
+                    .access_violation_handler
+                    .as_ref()
+                    .map(|handler| format!("Some({:p})", &handler))
+                    .unwrap_or_else(|| "None".to_string()),
+            )
             .finish()
     }
 }
@@ -263,6 +624,8 @@ impl<'a> UnalignedMemoryMapping<'a> {
             config,
             sbpf_version,
             cow_cb,
+            watchpoints: Vec::new(),
+            access_violation_handler: None,
         };
         result.construct_eytzinger_order(&mut regions, 0, 0);
         Ok(result)
@@ -340,8 +703,19 @@ impl<'a> UnalignedMemoryMapping<'a> {
             }
         };
 
-        if access_type == AccessType::Load || ensure_writable_region(region, &self.cow_cb) {
+        if ensure_accessible_region(region, access_type, &self.cow_cb) {
             if let ProgramResult::Ok(host_addr) = region.vm_to_host(vm_addr, len) {
+                if let Some(name) = region.protected_range_violation(access_type, vm_addr, len) {
+                    return ProgramResult::Err(EbpfError::AccessViolation(
+                        access_type,
+                        vm_addr,
+                        len,
+                        name,
+                    ));
+                }
+                if access_type == AccessType::Store {
+                    region.mark_written(host_addr, len);
+                }
                 return ProgramResult::Ok(host_addr);
             }
         }
@@ -364,17 +738,22 @@ impl<'a> UnalignedMemoryMapping<'a> {
         let cache = unsafe { &mut *self.cache.get() };
 
         let mut region = match self.find_region(cache, vm_addr) {
-            Some(region) => {
+            Some(region) if region.state.get() != MemoryState::WriteOnly => {
                 if let ProgramResult::Ok(host_addr) = region.vm_to_host(vm_addr, len) {
-                    // fast path
-                    return ProgramResult::Ok(unsafe {
-                        ptr::read_unaligned::<T>(host_addr as *const _).into()
-                    });
+                    if region
+                        .protected_range_violation(AccessType::Load, vm_addr, len)
+                        .is_none()
+                    {
+                        // fast path
+                        return ProgramResult::Ok(unsafe {
+                            ptr::read_unaligned::<T>(host_addr as *const _).into()
+                        });
+                    }
                 }
 
                 region
             }
-            None => {
+            _ => {
                 return generate_access_violation(
                     self.config,
                     self.sbpf_version,
@@ -392,11 +771,25 @@ impl<'a> UnalignedMemoryMapping<'a> {
         let mut ptr = std::ptr::addr_of_mut!(value).cast::<u8>();
 
         while len > 0 {
+            if region.state.get() == MemoryState::WriteOnly {
+                break;
+            }
+
             let load_len = len.min(region.vm_addr_end.saturating_sub(vm_addr));
             if load_len == 0 {
                 break;
             }
             if let ProgramResult::Ok(host_addr) = region.vm_to_host(vm_addr, load_len) {
+                if let Some(name) =
+                    region.protected_range_violation(AccessType::Load, vm_addr, load_len)
+                {
+                    return ProgramResult::Err(EbpfError::AccessViolation(
+                        AccessType::Load,
+                        initial_vm_addr,
+                        initial_len,
+                        name,
+                    ));
+                }
                 // Safety:
                 // we debug_assert!(len <= mem::size_of::<u64>()) so we never
                 // overflow &value
@@ -446,11 +839,17 @@ impl<'a> UnalignedMemoryMapping<'a> {
             Some(region) if ensure_writable_region(region, &self.cow_cb) => {
                 // fast path
                 if let ProgramResult::Ok(host_addr) = region.vm_to_host(vm_addr, len) {
-                    // Safety:
-                    // vm_to_host() succeeded so we know there's enough space to
-                    // store `value`
-                    unsafe { ptr::write_unaligned(host_addr as *mut _, value) };
-                    return ProgramResult::Ok(host_addr);
+                    if region
+                        .protected_range_violation(AccessType::Store, vm_addr, len)
+                        .is_none()
+                    {
+                        // Safety:
+                        // vm_to_host() succeeded so we know there's enough space to
+                        // store `value`
+                        unsafe { ptr::write_unaligned(host_addr as *mut _, value) };
+                        region.mark_written(host_addr, len);
+                        return ProgramResult::Ok(host_addr);
+                    }
                 }
                 region
             }
@@ -479,9 +878,20 @@ impl<'a> UnalignedMemoryMapping<'a> {
                 break;
             }
             if let ProgramResult::Ok(host_addr) = region.vm_to_host(vm_addr, write_len) {
+                if let Some(name) =
+                    region.protected_range_violation(AccessType::Store, vm_addr, write_len)
+                {
+                    return ProgramResult::Err(EbpfError::AccessViolation(
+                        AccessType::Store,
+                        initial_vm_addr,
+                        initial_len,
+                        name,
+                    ));
+                }
                 // Safety:
                 // vm_to_host() succeeded so we have enough space for write_len
                 unsafe { copy_nonoverlapping(src, host_addr as *mut _, write_len as usize) };
+                region.mark_written(host_addr, write_len);
                 len = len.saturating_sub(write_len);
                 if len == 0 {
                     return ProgramResult::Ok(host_addr);
@@ -521,6 +931,10 @@ impl<'a> UnalignedMemoryMapping<'a> {
             if (region.vm_addr..region.vm_addr_end).contains(&vm_addr)
                 && (access_type == AccessType::Load || ensure_writable_region(region, &self.cow_cb))
             {
+                if access_type == AccessType::Store {
+                    // The caller may write anywhere inside of the region
+                    region.mark_written(region.host_addr.get(), region.len);
+                }
                 return Ok(region);
             }
         }
@@ -544,11 +958,36 @@ impl<'a> UnalignedMemoryMapping<'a> {
         self.cache.get_mut().flush();
         Ok(())
     }
+
+    /// Adds a `MemoryRegion` which must not overlap any other region
+    pub fn add_region(&mut self, region: MemoryRegion) -> Result<(), EbpfError> {
+        if let Some(index) = self.regions.iter().position(|other| {
+            other.vm_addr < region.vm_addr_end && region.vm_addr < other.vm_addr_end
+        }) {
+            return Err(EbpfError::InvalidMemoryRegion(index));
+        }
+        let mut regions = mem::take(&mut self.regions).into_vec();
+        regions.push(region);
+        regions.sort();
+        self.regions = (0..regions.len())
+            .map(|_| MemoryRegion::default())
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        self.region_addresses = vec![0; regions.len()].into_boxed_slice();
+        self.construct_eytzinger_order(&mut regions, 0, 0);
+        self.cache.get_mut().flush();
+        Ok(())
+    }
 }
 
 /// Memory mapping that uses the upper half of an address to identify the
 /// underlying memory region.
+#[repr(C)]
 pub struct AlignedMemoryMapping<'a> {
+    /// Address of the first of the `regions`, read by the JIT
+    regions_address: u64,
+    /// Number of `regions`, read by the JIT
+    regions_count: u64,
     /// Mapped memory regions
     regions: Box<[MemoryRegion]>,
     /// VM configuration
@@ -557,6 +996,10 @@ pub struct AlignedMemoryMapping<'a> {
     sbpf_version: SBPFVersion,
     /// CoW callback
     cow_cb: Option<MemoryCowCallback>,
+    /// Watched ranges, see [MemoryMapping::add_watchpoint]
+    watchpoints: Vec<MemoryWatchpoint>,
+    /// Resolves failed accesses, see [MemoryMapping::set_access_violation_handler]
+    access_violation_handler: Option<AccessViolationHandler>,
 }
 
 impl fmt::Debug for AlignedMemoryMapping<'_> {
@@ -572,6 +1015,15 @@ impl fmt::Debug for AlignedMemoryMapping<'_> {
                     .map(|cb| format!("Some({:p})", &cb))
                     .unwrap_or_else(|| "None".to_string()),
             )
+            .field("watchpoints", &self.watchpoints)
+            .field(
+                "access_violation_handler",
+                &self
+                    .access_violation_handler
+                    .as_ref()
+                    .map(|handler| format!("Some({:p})", &handler))
+                    .unwrap_or_else(|| "None".to_string()),
+            )
             .finish()
     }
 }
@@ -595,11 +1047,16 @@ impl<'a> AlignedMemoryMapping<'a> {
                 return Err(EbpfError::InvalidMemoryRegion(index));
             }
         }
+        let regions = regions.into_boxed_slice();
         Ok(Self {
-            regions: regions.into_boxed_slice(),
+            regions_address: regions.as_ptr() as u64,
+            regions_count: regions.len() as u64,
+            regions,
             config,
             sbpf_version,
             cow_cb,
+            watchpoints: Vec::new(),
+            access_violation_handler: None,
         })
     }
 
@@ -631,8 +1088,20 @@ impl<'a> AlignedMemoryMapping<'a> {
             .unwrap_or(0) as usize;
         if (1..self.regions.len()).contains(&index) {
             let region = &self.regions[index];
-            if access_type == AccessType::Load || ensure_writable_region(region, &self.cow_cb) {
+            if ensure_accessible_region(region, access_type, &self.cow_cb) {
                 if let ProgramResult::Ok(host_addr) = region.vm_to_host(vm_addr, len) {
+                    if let Some(name) = region.protected_range_violation(access_type, vm_addr, len)
+                    {
+                        return ProgramResult::Err(EbpfError::AccessViolation(
+                            access_type,
+                            vm_addr,
+                            len,
+                            name,
+                        ));
+                    }
+                    if access_type == AccessType::Store {
+                        region.mark_written(host_addr, len);
+                    }
                     return ProgramResult::Ok(host_addr);
                 }
             }
@@ -690,6 +1159,10 @@ impl<'a> AlignedMemoryMapping<'a> {
             if (region.vm_addr..region.vm_addr_end).contains(&vm_addr)
                 && (access_type == AccessType::Load || ensure_writable_region(region, &self.cow_cb))
             {
+                if access_type == AccessType::Store {
+                    // The caller may write anywhere inside of the region
+                    region.mark_written(region.host_addr.get(), region.len);
+                }
                 return Ok(region);
             }
         }
@@ -724,18 +1197,116 @@ impl<'a> AlignedMemoryMapping<'a> {
         self.regions[index] = region;
         Ok(())
     }
+
+    /// Adds a `MemoryRegion` in place of one without capacity or after the last one
+    ///
+    /// Skipped parts of the address space are filled with empty regions.
+    pub fn add_region(&mut self, region: MemoryRegion) -> Result<(), EbpfError> {
+        let index = region
+            .vm_addr
+            .checked_shr(ebpf::VIRTUAL_ADDRESS_BITS as u32)
+            .unwrap_or(0) as usize;
+        let end_index = region
+            .vm_addr
+            .saturating_add(region.len.saturating_sub(1))
+            .checked_shr(ebpf::VIRTUAL_ADDRESS_BITS as u32)
+            .unwrap_or(0) as usize;
+        if index == 0
+            || end_index != index
+            || self
+                .regions
+                .get(index)
+                .is_some_and(|other| other.capacity > 0)
+        {
+            return Err(EbpfError::InvalidMemoryRegion(index));
+        }
+        if index < self.regions.len() {
+            self.regions[index] = region;
+            return Ok(());
+        }
+        let mut regions = mem::take(&mut self.regions).into_vec();
+        while regions.len() < index {
+            let vm_addr = (regions.len() as u64)
+                .checked_shl(ebpf::VIRTUAL_ADDRESS_BITS as u32)
+                .unwrap_or(0);
+            regions.push(MemoryRegion::new_readonly(&[], vm_addr));
+        }
+        regions.push(region);
+        let regions = regions.into_boxed_slice();
+        self.regions_address = regions.as_ptr() as u64;
+        self.regions_count = regions.len() as u64;
+        self.regions = regions;
+        Ok(())
+    }
 }
 
 /// Maps virtual memory to host memory.
+///
+/// The tag is read by the JIT, see `jit_layout`.
 #[derive(Debug)]
+#[repr(u8)]
 pub enum MemoryMapping<'a> {
     /// Used when address translation is disabled
-    Identity,
+    Identity = 0,
     /// Aligned memory mapping which uses the upper half of an address to
     /// identify the underlying memory region.
-    Aligned(AlignedMemoryMapping<'a>),
+    Aligned(AlignedMemoryMapping<'a>) = 1,
     /// Memory mapping that allows mapping unaligned memory regions.
-    Unaligned(UnalignedMemoryMapping<'a>),
+    Unaligned(UnalignedMemoryMapping<'a>) = 2,
+}
+
+/// Layout of an aligned [MemoryMapping] and its [MemoryRegion]s
+///
+/// The JIT translates addresses of an aligned mapping in machine code. With `#[repr(u8)]` the
+/// tag of a [MemoryMapping] comes first and the fields of a variant follow at their alignment.
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
+pub(crate) mod jit_layout {
+    use super::{AlignedMemoryMapping, MemoryRegion};
+    use std::mem;
+
+    /// Tag of [MemoryMapping::Aligned](super::MemoryMapping::Aligned)
+    pub(crate) const MAPPING_ALIGNED: u8 = 1;
+    /// Offset of [AlignedMemoryMapping::regions_address] in a [MemoryMapping](super::MemoryMapping)
+    pub(crate) const MAPPING_REGIONS_ADDRESS: usize = mem::align_of::<AlignedMemoryMapping>()
+        + mem::offset_of!(AlignedMemoryMapping, regions_address);
+    /// Offset of [AlignedMemoryMapping::regions_count] in a [MemoryMapping](super::MemoryMapping)
+    pub(crate) const MAPPING_REGIONS_COUNT: usize = mem::align_of::<AlignedMemoryMapping>()
+        + mem::offset_of!(AlignedMemoryMapping, regions_count);
+    /// Size of a [MemoryRegion] including its padding
+    pub(crate) const REGION_SIZE: usize = mem::size_of::<MemoryRegion>();
+    /// Offset of [MemoryRegion::host_addr]
+    pub(crate) const REGION_HOST_ADDR: usize = mem::offset_of!(MemoryRegion, host_addr);
+    /// Offset of [MemoryRegion::vm_addr]
+    pub(crate) const REGION_VM_ADDR: usize = mem::offset_of!(MemoryRegion, vm_addr);
+    /// Offset of [MemoryRegion::len]
+    pub(crate) const REGION_LEN: usize = mem::offset_of!(MemoryRegion, len);
+    /// Offset of [MemoryRegion::vm_gap_shift]
+    pub(crate) const REGION_VM_GAP_SHIFT: usize = mem::offset_of!(MemoryRegion, vm_gap_shift);
+    /// Offset of [MemoryRegion::state]
+    pub(crate) const REGION_STATE: usize = mem::offset_of!(MemoryRegion, state);
+    /// Offset of [MemoryRegion::written_start]
+    pub(crate) const REGION_WRITTEN_START: usize = mem::offset_of!(MemoryRegion, written_start);
+    /// Offset of [MemoryRegion::written_end]
+    pub(crate) const REGION_WRITTEN_END: usize = mem::offset_of!(MemoryRegion, written_end);
+    /// Offset of [MemoryRegion::dirty_pages_address]
+    pub(crate) const REGION_DIRTY_PAGES_ADDRESS: usize =
+        mem::offset_of!(MemoryRegion, dirty_pages_address);
+    /// Offset of [MemoryRegion::protected_ranges_address]
+    pub(crate) const REGION_PROTECTED_RANGES_ADDRESS: usize =
+        mem::offset_of!(MemoryRegion, protected_ranges_address);
+    /// Tag of [MemoryState::Writable](super::MemoryState::Writable)
+    pub(crate) const STATE_WRITABLE: u64 = 1;
+    /// Tag of [MemoryState::WriteOnly](super::MemoryState::WriteOnly)
+    pub(crate) const STATE_WRITE_ONLY: u64 = 3;
+    /// Value of [MemoryRegion::vm_gap_shift] in a continuous region
+    pub(crate) const CONTINUOUS: u8 = 63;
 }
 
 impl<'a> MemoryMapping<'a> {
@@ -846,6 +1417,246 @@ impl<'a> MemoryMapping<'a> {
             MemoryMapping::Unaligned(m) => m.replace_region(index, region),
         }
     }
+
+    /// Adds a `MemoryRegion` which must not overlap any other region
+    ///
+    /// In an aligned mapping the upper half of the addresses of the region must be that of an empty
+    /// region or beyond the last region.
+    pub fn add_region(&mut self, region: MemoryRegion) -> Result<(), EbpfError> {
+        match self {
+            MemoryMapping::Identity => Err(EbpfError::InvalidMemoryRegion(0)),
+            MemoryMapping::Aligned(m) => m.add_region(region),
+            MemoryMapping::Unaligned(m) => m.add_region(region),
+        }
+    }
+
+    /// Resizes the `MemoryRegion` starting at `vm_addr` to `new_len` bytes in place
+    ///
+    /// The region must be continuous, must have been created with a capacity of at least `new_len`
+    /// bytes by [MemoryRegion::new_growable] and must not overlap another region afterwards. Its
+    /// host memory, state, written range and dirty pages are kept and the bytes which become mapped
+    /// keep their contents. JIT compiled programs read the bounds of the regions at runtime and see
+    /// the new length after returning from the syscall which resized the region.
+    pub fn resize_region(&mut self, vm_addr: u64, new_len: u64) -> Result<(), EbpfError> {
+        let regions = self.get_regions();
+        let index = regions
+            .iter()
+            .position(|region| region.vm_addr == vm_addr)
+            .ok_or(EbpfError::InvalidVirtualAddress(vm_addr))?;
+        let region = regions[index]
+            .resized(new_len)
+            .ok_or(EbpfError::InvalidMemoryRegion(index))?;
+        if regions.iter().enumerate().any(|(other_index, other)| {
+            other_index != index
+                && other.vm_addr < region.vm_addr_end
+                && region.vm_addr < other.vm_addr_end
+        }) {
+            return Err(EbpfError::InvalidMemoryRegion(index));
+        }
+        self.replace_region(index, region)
+    }
+
+    /// Restricts the accesses to the virtual addresses `vm_range` inside of the region containing them
+    ///
+    /// See [MemoryRegion::protect]. JIT compiled programs which access the stack frames directly
+    /// (see `Config::eliminate_redundant_bounds_checks`) only respect a protected sub-range of the
+    /// stack from their next execution on.
+    pub fn protect(
+        &mut self,
+        vm_range: Range<u64>,
+        protection: MemoryProtection,
+        name: impl Into<Cow<'static, str>>,
+    ) -> Result<(), EbpfError> {
+        let regions = match self {
+            MemoryMapping::Identity => return Err(EbpfError::InvalidMemoryRegion(0)),
+            MemoryMapping::Aligned(m) => m.regions.iter_mut(),
+            MemoryMapping::Unaligned(m) => m.regions.iter_mut(),
+        };
+        for region in regions {
+            if (region.vm_addr..region.vm_addr_end).contains(&vm_range.start) {
+                return region.protect(vm_range, protection, name);
+            }
+        }
+        Err(EbpfError::InvalidVirtualAddress(vm_range.start))
+    }
+
+    /// Starts tracking the dirty pages of all regions which are not readonly
+    ///
+    /// See [MemoryRegion::enable_dirty_page_tracking].
+    pub fn enable_dirty_page_tracking(&mut self, page_size: u64) {
+        let regions = match self {
+            MemoryMapping::Identity => return,
+            MemoryMapping::Aligned(m) => m.regions.iter_mut(),
+            MemoryMapping::Unaligned(m) => m.regions.iter_mut(),
+        };
+        for region in regions {
+            if region.state.get() != MemoryState::Readable {
+                region.enable_dirty_page_tracking(page_size);
+            }
+        }
+    }
+
+    /// Returns the dirty ranges of all regions as pairs of the `vm_addr` of the region and the range of host offsets
+    ///
+    /// See [MemoryRegion::dirty_ranges].
+    pub fn dirty_ranges(&self) -> Vec<(u64, Range<u64>)> {
+        let mut ranges = Vec::new();
+        for region in self.get_regions().iter() {
+            ranges.extend(
+                region
+                    .dirty_ranges()
+                    .into_iter()
+                    .map(|range| (region.vm_addr, range)),
+            );
+        }
+        ranges.sort_by_key(|(vm_addr, range)| (*vm_addr, range.start));
+        ranges
+    }
+
+    /// Forgets the recorded dirty pages of all regions
+    pub fn reset_dirty_pages(&self) {
+        for region in self.get_regions().iter() {
+            region.reset_dirty_pages();
+        }
+    }
+
+    /// Reports the loads and stores of the program which overlap `vm_range` to `callback`
+    ///
+    /// Only accesses of the given `access_type` are reported, or all if it is None. The callback can
+    /// abort the execution by returning an error, see [MemoryWatchCallback].
+    /// Accesses of syscalls are not reported. Requires `Config::enable_memory_watchpoints`.
+    pub fn add_watchpoint(
+        &mut self,
+        vm_range: Range<u64>,
+        access_type: Option<AccessType>,
+        callback: MemoryWatchCallback,
+    ) -> Result<(), EbpfError> {
+        let watchpoints = match self {
+            MemoryMapping::Identity => return Err(EbpfError::InvalidMemoryRegion(0)),
+            MemoryMapping::Aligned(m) => &mut m.watchpoints,
+            MemoryMapping::Unaligned(m) => &mut m.watchpoints,
+        };
+        watchpoints.push(MemoryWatchpoint {
+            vm_range,
+            access_type,
+            callback,
+        });
+        Ok(())
+    }
+
+    /// Removes all watchpoints
+    pub fn clear_watchpoints(&mut self) {
+        match self {
+            MemoryMapping::Identity => {}
+            MemoryMapping::Aligned(m) => m.watchpoints.clear(),
+            MemoryMapping::Unaligned(m) => m.watchpoints.clear(),
+        }
+    }
+
+    /// Sets the handler which resolves loads and stores of the program that are out of bounds or
+    /// not permitted, or removes it if None
+    ///
+    /// The handler can map a new region or make the accessed region writable, after which the access
+    /// is retried once. It runs in the interpreter and in JIT compiled programs, but not for the
+    /// accesses of syscalls.
+    pub fn set_access_violation_handler(
+        &mut self,
+        handler: Option<AccessViolationHandler>,
+    ) -> Result<(), EbpfError> {
+        match self {
+            MemoryMapping::Identity => return Err(EbpfError::InvalidMemoryRegion(0)),
+            MemoryMapping::Aligned(m) => m.access_violation_handler = handler,
+            MemoryMapping::Unaligned(m) => m.access_violation_handler = handler,
+        }
+        Ok(())
+    }
+
+    /// [MemoryMapping::load] which lets the access violation handler resolve a failed access
+    pub(crate) fn handled_load<T: Pod + Into<u64>>(&mut self, vm_addr: u64) -> ProgramResult {
+        let result = self.load::<T>(vm_addr);
+        if result.is_err()
+            && self.handle_access_violation(AccessType::Load, vm_addr, mem::size_of::<T>() as u64)
+        {
+            return self.load::<T>(vm_addr);
+        }
+        result
+    }
+
+    /// [MemoryMapping::store] which lets the access violation handler resolve a failed access
+    pub(crate) fn handled_store<T: Pod + Copy>(&mut self, value: T, vm_addr: u64) -> ProgramResult {
+        let result = self.store(value, vm_addr);
+        if result.is_err()
+            && self.handle_access_violation(AccessType::Store, vm_addr, mem::size_of::<T>() as u64)
+        {
+            return self.store(value, vm_addr);
+        }
+        result
+    }
+
+    /// Applies the resolution of the access violation handler, returns whether to retry the access
+    fn handle_access_violation(&mut self, access_type: AccessType, vm_addr: u64, len: u64) -> bool {
+        let handler = match &*self {
+            MemoryMapping::Identity => return false,
+            MemoryMapping::Aligned(m) => &m.access_violation_handler,
+            MemoryMapping::Unaligned(m) => &m.access_violation_handler,
+        };
+        let resolution = match handler {
+            Some(handler) => handler(
+                access_type,
+                vm_addr,
+                len,
+                self.region(AccessType::Load, vm_addr).ok(),
+            ),
+            None => return false,
+        };
+        match resolution {
+            AccessViolationResolution::Fail => false,
+            AccessViolationResolution::MapRegion(region) => self.add_region(region).is_ok(),
+            AccessViolationResolution::MakeWritable(host_addr) => {
+                match self.region(AccessType::Load, vm_addr) {
+                    Ok(region) => {
+                        region.host_addr.set(host_addr);
+                        region.state.set(MemoryState::Writable);
+                        true
+                    }
+                    Err(_) => false,
+                }
+            }
+        }
+    }
+
+    /// Invokes the callbacks of all watchpoints which the access overlaps
+    pub(crate) fn notify_watchpoints(&self, access: &MemoryAccess) -> Result<(), EbpfError> {
+        let watchpoints = match self {
+            MemoryMapping::Identity => return Ok(()),
+            MemoryMapping::Aligned(m) => &m.watchpoints,
+            MemoryMapping::Unaligned(m) => &m.watchpoints,
+        };
+        let access_end = access.vm_addr.saturating_add(access.len);
+        for watchpoint in watchpoints.iter() {
+            if watchpoint.access_type.unwrap_or(access.access_type) == access.access_type
+                && watchpoint.vm_range.start < access_end
+                && access.vm_addr < watchpoint.vm_range.end
+            {
+                (watchpoint.callback)(access)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Ensure that the given region permits the access.
+//
+// Stores go through ensure_writable_region().
+fn ensure_accessible_region(
+    region: &MemoryRegion,
+    access_type: AccessType,
+    cow_cb: &Option<MemoryCowCallback>,
+) -> bool {
+    match access_type {
+        AccessType::Load => region.state.get() != MemoryState::WriteOnly,
+        AccessType::Store => ensure_writable_region(region, cow_cb),
+    }
 }
 
 // Ensure that the given region is writable.
@@ -853,7 +1664,7 @@ impl<'a> MemoryMapping<'a> {
 // If the region is CoW, cow_cb is called to execute the CoW operation.
 fn ensure_writable_region(region: &MemoryRegion, cow_cb: &Option<MemoryCowCallback>) -> bool {
     match (region.state.get(), cow_cb) {
-        (MemoryState::Writable, _) => true,
+        (MemoryState::Writable | MemoryState::WriteOnly, _) => true,
         (MemoryState::Cow(cow_id), Some(cb)) => match cb(cow_id) {
             Ok(host_addr) => {
                 region.host_addr.replace(host_addr);
@@ -899,7 +1710,7 @@ fn generate_access_violation(
             access_type,
             vm_addr,
             len,
-            region_name,
+            Cow::Borrowed(region_name),
         ))
     }
 }
@@ -1526,6 +2337,485 @@ mod test {
         assert_error!(m.load::<u64>(ebpf::MM_INPUT_START + 1), "AccessViolation");
     }
 
+    #[test]
+    fn test_written_range() {
+        for aligned_memory_mapping in [false, true] {
+            let config = Config {
+                aligned_memory_mapping,
+                ..Config::default()
+            };
+            let mem1 = vec![0; 16];
+            let mut mem2 = vec![0; 16];
+            let mut mem3 = vec![0; 16];
+            let mut mem4 = vec![0; 16];
+            let mut regions = vec![
+                MemoryRegion::new_readonly(&mem1, ebpf::MM_RODATA_START),
+                MemoryRegion::new_writable(&mut mem2, ebpf::MM_STACK_START),
+                MemoryRegion::new_writable(&mut mem3, ebpf::MM_HEAP_START),
+                MemoryRegion::new_writable(&mut mem4, ebpf::MM_INPUT_START),
+            ];
+            regions[1].enable_write_tracking();
+            regions[2].enable_write_tracking();
+            let m = MemoryMapping::new(regions, &config, SBPFVersion::V3).unwrap();
+            let written_range =
+                |vm_addr| m.region(AccessType::Load, vm_addr).unwrap().written_range();
+            m.load::<u64>(ebpf::MM_STACK_START).unwrap();
+            assert_eq!(written_range(ebpf::MM_STACK_START), 0..0);
+            m.store(0x11u8, ebpf::MM_STACK_START + 8).unwrap();
+            assert_eq!(written_range(ebpf::MM_STACK_START), 8..9);
+            m.store(0x2233u16, ebpf::MM_STACK_START + 4).unwrap();
+            assert_eq!(written_range(ebpf::MM_STACK_START), 4..9);
+            m.map(AccessType::Store, ebpf::MM_STACK_START + 10, 4)
+                .unwrap();
+            assert_eq!(written_range(ebpf::MM_STACK_START), 4..14);
+            assert_eq!(written_range(ebpf::MM_HEAP_START), 0..0);
+            m.region(AccessType::Store, ebpf::MM_HEAP_START).unwrap();
+            assert_eq!(written_range(ebpf::MM_HEAP_START), 0..16);
+            m.region(AccessType::Load, ebpf::MM_STACK_START)
+                .unwrap()
+                .reset_written_range();
+            assert_eq!(written_range(ebpf::MM_STACK_START), 0..0);
+            // Regions which do not track their writes have no written range
+            m.store(0x11u8, ebpf::MM_INPUT_START).unwrap();
+            assert!(!m
+                .region(AccessType::Load, ebpf::MM_INPUT_START)
+                .unwrap()
+                .tracks_writes());
+            assert_eq!(written_range(ebpf::MM_INPUT_START), 0..0);
+        }
+    }
+
+    #[test]
+    fn test_dirty_pages() {
+        for aligned_memory_mapping in [false, true] {
+            let config = Config {
+                aligned_memory_mapping,
+                ..Config::default()
+            };
+            let mem1 = vec![0; 16];
+            let mut mem2 = vec![0; 40];
+            let mut mem3 = vec![0; 16];
+            let mut m = MemoryMapping::new(
+                vec![
+                    MemoryRegion::new_readonly(&mem1, ebpf::MM_RODATA_START),
+                    MemoryRegion::new_writable(&mut mem2, ebpf::MM_STACK_START),
+                    MemoryRegion::new_writable(&mut mem3, ebpf::MM_HEAP_START),
+                ],
+                &config,
+                SBPFVersion::V3,
+            )
+            .unwrap();
+            m.store(0x11u8, ebpf::MM_STACK_START).unwrap();
+            assert!(m.dirty_ranges().is_empty());
+            // Rounded up to 8 bytes
+            m.enable_dirty_page_tracking(6);
+            m.load::<u64>(ebpf::MM_STACK_START).unwrap();
+            assert!(m.dirty_ranges().is_empty());
+            m.store(0x11u8, ebpf::MM_STACK_START + 9).unwrap();
+            m.store(0x2233u32, ebpf::MM_STACK_START + 22).unwrap();
+            m.map(AccessType::Store, ebpf::MM_STACK_START + 39, 1)
+                .unwrap();
+            // Adjacent pages are merged
+            assert_eq!(m.dirty_ranges(), vec![(ebpf::MM_STACK_START, 8..40)]);
+            m.region(AccessType::Load, ebpf::MM_STACK_START)
+                .unwrap()
+                .reset_dirty_pages();
+            m.store(0x11u8, ebpf::MM_STACK_START + 9).unwrap();
+            m.store(0x2233u16, ebpf::MM_STACK_START + 31).unwrap();
+            assert_eq!(
+                m.dirty_ranges(),
+                vec![
+                    (ebpf::MM_STACK_START, 8..16),
+                    (ebpf::MM_STACK_START, 24..40)
+                ]
+            );
+            m.region(AccessType::Store, ebpf::MM_HEAP_START).unwrap();
+            assert_eq!(
+                m.dirty_ranges(),
+                vec![
+                    (ebpf::MM_STACK_START, 8..16),
+                    (ebpf::MM_STACK_START, 24..40),
+                    (ebpf::MM_HEAP_START, 0..16)
+                ]
+            );
+            // Readonly regions do not track their dirty pages
+            assert!(m
+                .region(AccessType::Load, ebpf::MM_RODATA_START)
+                .unwrap()
+                .dirty_ranges()
+                .is_empty());
+            m.reset_dirty_pages();
+            assert!(m.dirty_ranges().is_empty());
+            m.store(0x11u8, ebpf::MM_STACK_START + 1).unwrap();
+            assert_eq!(m.dirty_ranges(), vec![(ebpf::MM_STACK_START, 0..8)]);
+        }
+    }
+
+    #[test]
+    fn test_add_region() {
+        for aligned_memory_mapping in [false, true] {
+            let config = Config {
+                aligned_memory_mapping,
+                ..Config::default()
+            };
+            let mem1 = vec![11; 8];
+            let mem2 = vec![22; 8];
+            let mem3 = vec![33; 8];
+            let mut m = MemoryMapping::new(
+                vec![
+                    MemoryRegion::new_readonly(&mem1, ebpf::MM_RODATA_START),
+                    MemoryRegion::new_readonly(&[], ebpf::MM_STACK_START),
+                ],
+                &config,
+                SBPFVersion::V3,
+            )
+            .unwrap();
+            assert_error!(m.load::<u8>(ebpf::MM_INPUT_START), "AccessViolation");
+            m.add_region(MemoryRegion::new_readonly(&mem3, ebpf::MM_INPUT_START))
+                .unwrap();
+            assert_eq!(m.load::<u8>(ebpf::MM_INPUT_START).unwrap(), 33);
+            m.add_region(MemoryRegion::new_readonly(&mem2, ebpf::MM_STACK_START))
+                .unwrap();
+            assert_eq!(m.load::<u8>(ebpf::MM_STACK_START).unwrap(), 22);
+            assert_eq!(m.load::<u8>(ebpf::MM_INPUT_START).unwrap(), 33);
+            assert_eq!(m.load::<u8>(ebpf::MM_RODATA_START).unwrap(), 11);
+            assert_error!(m.load::<u8>(ebpf::MM_HEAP_START), "AccessViolation");
+            assert_error!(
+                m.add_region(MemoryRegion::new_readonly(&mem1, ebpf::MM_INPUT_START + 4)),
+                "InvalidMemoryRegion"
+            );
+        }
+
+        // Unaligned regions can share the upper half of their addresses
+        let config = Config {
+            aligned_memory_mapping: false,
+            ..Config::default()
+        };
+        let mem1 = vec![11; 8];
+        let mem2 = vec![22; 8];
+        let mut m = MemoryMapping::new(
+            vec![MemoryRegion::new_readonly(&mem1, ebpf::MM_INPUT_START)],
+            &config,
+            SBPFVersion::V3,
+        )
+        .unwrap();
+        m.add_region(MemoryRegion::new_readonly(&mem2, ebpf::MM_INPUT_START + 8))
+            .unwrap();
+        assert_eq!(
+            m.load::<u16>(ebpf::MM_INPUT_START + 7).unwrap(),
+            u16::from_le_bytes([11, 22]) as u64
+        );
+    }
+
+    #[test]
+    fn test_resize_region() {
+        for aligned_memory_mapping in [false, true] {
+            let config = Config {
+                aligned_memory_mapping,
+                ..Config::default()
+            };
+            let mem0 = vec![0; 16];
+            let mut mem1 = vec![0; 16];
+            let mut mem2 = vec![0; 64];
+            let mut m = MemoryMapping::new(
+                vec![
+                    MemoryRegion::new_readonly(&mem0, ebpf::MM_RODATA_START),
+                    MemoryRegion::new_writable_gapped(&mut mem1, ebpf::MM_STACK_START, 4),
+                    MemoryRegion::new_growable(&mut mem2, ebpf::MM_HEAP_START, 16),
+                ],
+                &config,
+                SBPFVersion::V3,
+            )
+            .unwrap();
+            m.enable_dirty_page_tracking(16);
+            m.store(0x11u64, ebpf::MM_HEAP_START + 8).unwrap();
+            assert_error!(m.load::<u8>(ebpf::MM_HEAP_START + 16), "AccessViolation");
+            m.resize_region(ebpf::MM_HEAP_START, 40).unwrap();
+            let heap = m.region(AccessType::Load, ebpf::MM_HEAP_START).unwrap();
+            assert_eq!(heap.len, 40);
+            assert_eq!(heap.vm_addr_end, ebpf::MM_HEAP_START + 40);
+            assert_eq!(heap.host_addr.get(), mem2.as_ptr() as u64);
+            m.store(0x22u64, ebpf::MM_HEAP_START + 32).unwrap();
+            assert_eq!(m.load::<u64>(ebpf::MM_HEAP_START + 8).unwrap(), 0x11);
+            assert_error!(m.load::<u8>(ebpf::MM_HEAP_START + 40), "AccessViolation");
+            assert_error!(
+                m.resize_region(ebpf::MM_HEAP_START, 65),
+                "InvalidMemoryRegion"
+            );
+            assert_error!(
+                m.resize_region(ebpf::MM_STACK_START, 8),
+                "InvalidMemoryRegion"
+            );
+            assert_error!(
+                m.resize_region(ebpf::MM_INPUT_START, 0),
+                "InvalidVirtualAddress"
+            );
+
+            // Shrinking keeps the written range and the dirty pages
+            m.resize_region(ebpf::MM_HEAP_START, 8).unwrap();
+            assert_error!(m.load::<u8>(ebpf::MM_HEAP_START + 8), "AccessViolation");
+            let heap = m.region(AccessType::Load, ebpf::MM_HEAP_START).unwrap();
+            assert_eq!(heap.written_range(), 8..40);
+            assert_eq!(heap.dirty_ranges(), vec![0..16, 32..48]);
+            m.resize_region(ebpf::MM_HEAP_START, 64).unwrap();
+            assert_eq!(m.load::<u64>(ebpf::MM_HEAP_START + 32).unwrap(), 0x22);
+        }
+
+        // A region must not grow into the next one
+        let config = Config {
+            aligned_memory_mapping: false,
+            ..Config::default()
+        };
+        let mut mem1 = vec![0; 16];
+        let mem2 = vec![0; 4];
+        let mut m = MemoryMapping::new(
+            vec![
+                MemoryRegion::new_growable(&mut mem1, ebpf::MM_INPUT_START, 8),
+                MemoryRegion::new_readonly(&mem2, ebpf::MM_INPUT_START + 12),
+            ],
+            &config,
+            SBPFVersion::V3,
+        )
+        .unwrap();
+        m.resize_region(ebpf::MM_INPUT_START, 12).unwrap();
+        assert_error!(
+            m.resize_region(ebpf::MM_INPUT_START, 13),
+            "InvalidMemoryRegion"
+        );
+        assert_eq!(
+            m.region(AccessType::Load, ebpf::MM_INPUT_START)
+                .unwrap()
+                .len,
+            12
+        );
+    }
+
+    #[test]
+    fn test_protected_ranges() {
+        for aligned_memory_mapping in [false, true] {
+            let config = Config {
+                aligned_memory_mapping,
+                ..Config::default()
+            };
+            let mut mem1 = vec![0; 16];
+            let mut mem2 = vec![0; 8];
+            let mut input = MemoryRegion::new_writable(&mut mem1, ebpf::MM_INPUT_START);
+            input.enable_write_tracking();
+            input
+                .protect(
+                    ebpf::MM_INPUT_START..ebpf::MM_INPUT_START + 4,
+                    MemoryProtection::Readable,
+                    "header",
+                )
+                .unwrap();
+            assert_error!(
+                input.protect(
+                    ebpf::MM_INPUT_START + 8..ebpf::MM_INPUT_START + 17,
+                    MemoryProtection::Readable,
+                    "header"
+                ),
+                "InvalidVirtualAddress"
+            );
+            let mut m = MemoryMapping::new(
+                vec![
+                    MemoryRegion::new_readonly(&[], ebpf::MM_RODATA_START),
+                    MemoryRegion::new_readonly(&[], ebpf::MM_STACK_START),
+                    MemoryRegion::new_write_only(&mut mem2, ebpf::MM_HEAP_START),
+                    input,
+                ],
+                &config,
+                SBPFVersion::V3,
+            )
+            .unwrap();
+            m.protect(
+                ebpf::MM_INPUT_START + 12..ebpf::MM_INPUT_START + 16,
+                MemoryProtection::Inaccessible,
+                "footer",
+            )
+            .unwrap();
+            assert_error!(
+                m.protect(
+                    ebpf::MM_STACK_START..ebpf::MM_STACK_START + 1,
+                    MemoryProtection::Inaccessible,
+                    "stack"
+                ),
+                "InvalidVirtualAddress"
+            );
+
+            m.store(0x11223344u32, ebpf::MM_INPUT_START + 4).unwrap();
+            assert_eq!(m.load::<u32>(ebpf::MM_INPUT_START).unwrap(), 0);
+            assert_error!(
+                m.store(0x55u16, ebpf::MM_INPUT_START + 3),
+                "AccessViolation(Store, {}, 2, \"header\")",
+                ebpf::MM_INPUT_START + 3
+            );
+            assert_error!(
+                m.map(AccessType::Store, ebpf::MM_INPUT_START, 1),
+                "AccessViolation(Store, {}, 1, \"header\")",
+                ebpf::MM_INPUT_START
+            );
+            assert_eq!(m.load::<u64>(ebpf::MM_INPUT_START + 4).unwrap(), 0x11223344);
+            assert_error!(
+                m.load::<u64>(ebpf::MM_INPUT_START + 8),
+                "AccessViolation(Load, {}, 8, \"footer\")",
+                ebpf::MM_INPUT_START + 8
+            );
+            assert_error!(
+                m.store(0u8, ebpf::MM_INPUT_START + 15),
+                "AccessViolation(Store, {}, 1, \"footer\")",
+                ebpf::MM_INPUT_START + 15
+            );
+            let input = m.region(AccessType::Load, ebpf::MM_INPUT_START).unwrap();
+            assert_eq!(input.written_range(), 4..8);
+            assert_eq!(input.protected_ranges().len(), 2);
+
+            // Write-only regions
+            m.store(0x66u64, ebpf::MM_HEAP_START).unwrap();
+            assert_error!(
+                m.load::<u8>(ebpf::MM_HEAP_START),
+                "AccessViolation(Load, {}, 1, \"heap\")",
+                ebpf::MM_HEAP_START
+            );
+            assert_error!(
+                m.map(AccessType::Load, ebpf::MM_HEAP_START, 8),
+                "AccessViolation"
+            );
+            assert_eq!(mem2, 0x66u64.to_le_bytes());
+        }
+
+        // Unaligned accesses across region boundaries
+        let config = Config {
+            aligned_memory_mapping: false,
+            ..Config::default()
+        };
+        let mut mem1 = vec![0; 8];
+        let mut mem2 = vec![0; 8];
+        let mut m = MemoryMapping::new(
+            vec![
+                MemoryRegion::new_writable(&mut mem1, ebpf::MM_INPUT_START),
+                MemoryRegion::new_write_only(&mut mem2, ebpf::MM_INPUT_START + 8),
+            ],
+            &config,
+            SBPFVersion::V3,
+        )
+        .unwrap();
+        m.protect(
+            ebpf::MM_INPUT_START..ebpf::MM_INPUT_START + 2,
+            MemoryProtection::Readable,
+            "header",
+        )
+        .unwrap();
+        m.store(0x1122334455667788u64, ebpf::MM_INPUT_START + 4)
+            .unwrap();
+        assert_error!(
+            m.load::<u64>(ebpf::MM_INPUT_START + 4),
+            "AccessViolation(Load, {}, 8, \"input\")",
+            ebpf::MM_INPUT_START + 4
+        );
+        assert_eq!(m.load::<u32>(ebpf::MM_INPUT_START + 4).unwrap(), 0x55667788);
+        assert_error!(
+            m.store(0u64, ebpf::MM_INPUT_START + 1),
+            "AccessViolation(Store, {}, 8, \"header\")",
+            ebpf::MM_INPUT_START + 1
+        );
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    fn test_jit_layout() {
+        let read = |base: *const u8, offset: usize| unsafe {
+            std::ptr::read_unaligned(base.add(offset).cast::<u64>())
+        };
+        let config = Config::default();
+        let mem1 = vec![0; 8];
+        let mut mem2 = vec![0; 16];
+        let mut mem3 = vec![0; 8];
+        let mut stack = MemoryRegion::new_writable_gapped(&mut mem2, ebpf::MM_STACK_START, 8);
+        stack.enable_write_tracking();
+        let mut m = MemoryMapping::new(
+            vec![
+                MemoryRegion::new_readonly(&mem1, ebpf::MM_RODATA_START),
+                stack,
+                MemoryRegion::new_write_only(&mut mem3, ebpf::MM_HEAP_START),
+            ],
+            &config,
+            SBPFVersion::V0,
+        )
+        .unwrap();
+        m.protect(
+            ebpf::MM_RODATA_START..ebpf::MM_RODATA_START + 4,
+            MemoryProtection::Inaccessible,
+            "header",
+        )
+        .unwrap();
+        m.store(0x11u8, ebpf::MM_STACK_START + 2).unwrap();
+        let mapping = (&m as *const MemoryMapping).cast::<u8>();
+        assert_eq!(unsafe { *mapping }, jit_layout::MAPPING_ALIGNED);
+        let regions = m.get_regions();
+        assert_eq!(
+            read(mapping, jit_layout::MAPPING_REGIONS_ADDRESS),
+            regions.as_ptr() as u64
+        );
+        assert_eq!(
+            read(mapping, jit_layout::MAPPING_REGIONS_COUNT),
+            regions.len() as u64
+        );
+        let stack = unsafe {
+            regions
+                .as_ptr()
+                .cast::<u8>()
+                .add(2 * jit_layout::REGION_SIZE)
+        };
+        assert_eq!(
+            read(stack, jit_layout::REGION_HOST_ADDR),
+            mem2.as_ptr() as u64
+        );
+        assert_eq!(
+            read(stack, jit_layout::REGION_VM_ADDR),
+            ebpf::MM_STACK_START
+        );
+        assert_eq!(read(stack, jit_layout::REGION_LEN), 16);
+        assert_eq!(unsafe { *stack.add(jit_layout::REGION_VM_GAP_SHIFT) }, 3);
+        assert_eq!(
+            read(stack, jit_layout::REGION_STATE),
+            jit_layout::STATE_WRITABLE
+        );
+        assert_eq!(read(stack, jit_layout::REGION_WRITTEN_START), 2);
+        assert_eq!(read(stack, jit_layout::REGION_WRITTEN_END), 3);
+        assert_eq!(read(stack, jit_layout::REGION_DIRTY_PAGES_ADDRESS), 0);
+        assert_eq!(read(stack, jit_layout::REGION_PROTECTED_RANGES_ADDRESS), 0);
+        let program = unsafe { regions.as_ptr().cast::<u8>().add(jit_layout::REGION_SIZE) };
+        assert_eq!(
+            unsafe { *program.add(jit_layout::REGION_VM_GAP_SHIFT) },
+            jit_layout::CONTINUOUS
+        );
+        assert_ne!(
+            read(program, jit_layout::REGION_STATE),
+            jit_layout::STATE_WRITABLE
+        );
+        assert_eq!(
+            read(program, jit_layout::REGION_PROTECTED_RANGES_ADDRESS),
+            regions[1].protected_ranges().as_ptr() as u64
+        );
+        let heap = unsafe {
+            regions
+                .as_ptr()
+                .cast::<u8>()
+                .add(3 * jit_layout::REGION_SIZE)
+        };
+        assert_eq!(
+            read(heap, jit_layout::REGION_STATE),
+            jit_layout::STATE_WRITE_ONLY
+        );
+    }
+
     #[test]
     #[should_panic(expected = "AccessViolation")]
     fn test_store_readonly() {
@@ -100,7 +100,7 @@ impl SBPFVersion {
 }
 
 /// Holds the function symbols of an Executable
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FunctionRegistry<T> {
     pub(crate) map: BTreeMap<u32, (Vec<u8>, T)>,
 }
@@ -396,6 +396,7 @@ macro_rules! declare_builtin_function {
                 $arg_d: u64,
                 $arg_e: u64,
             ) {
+                #[allow(unused_imports)]
                 use $crate::vm::ContextObject;
                 let vm = unsafe {
                     &mut *($vm.cast::<u64>().offset(-($crate::vm::get_runtime_environment_key() as isize)).cast::<$crate::vm::EbpfVm<$ContextObject>>())
@@ -409,7 +410,7 @@ macro_rules! declare_builtin_function {
                 ).map_err(|err| $crate::error::EbpfError::SyscallError(err)).into();
                 vm.program_result = converted_result;
                 if config.enable_instruction_meter {
-                    vm.previous_instruction_meter = vm.context_object_pointer.get_remaining();
+                    vm.previous_instruction_meter = vm.get_instruction_meter_budget();
                 }
             }
         }
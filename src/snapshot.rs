@@ -0,0 +1,559 @@
+// Copyright 2024 Solana Maintainers <maintainers@solana.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Portable snapshots of the machine state of an [EbpfVm]
+//!
+//! A [VmSnapshot] captures everything which is needed to rebuild an identical VM in another
+//! process: The registers, the call frames, the meter state and the layout and contents of all
+//! memory regions. The [Executable] itself is not part of the snapshot, it is only identified by
+//! [executable_hash] and has to be loaded separately by the process replaying the snapshot.
+
+use crate::{
+    aligned_memory::AlignedMemory,
+    ebpf::{self, HOST_ALIGN},
+    elf::Executable,
+    error::EbpfError,
+    memory_region::{
+        MemoryCowCallback, MemoryMapping, MemoryProtection, MemoryRegion, MemoryState,
+        ProtectedRange,
+    },
+    program::SBPFVersion,
+    vm::{CallFrame, ContextObject, EbpfVm},
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+};
+
+/// Identifies the file format
+const SNAPSHOT_MAGIC: [u8; 8] = *b"SBFSNAP\0";
+/// Incremented whenever the file format changes
+const SNAPSHOT_FORMAT_VERSION: u32 = 3;
+
+/// Error definitions
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    /// Failed to read or write the snapshot
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+    /// Not a snapshot
+    #[error("invalid magic number")]
+    InvalidMagic,
+    /// The snapshot was written in a format which is not supported
+    #[error("unsupported snapshot format version {0}")]
+    UnsupportedFormatVersion(u32),
+    /// A field of the snapshot contains an invalid value
+    #[error("invalid snapshot data: {0}")]
+    InvalidData(&'static str),
+    /// The snapshot was taken from a different executable
+    #[error(
+        "executable hash mismatch, snapshot has {} but executable has {}",
+        hex_string(.0),
+        hex_string(.1)
+    )]
+    ExecutableMismatch([u8; 32], [u8; 32]),
+    /// Address translation is disabled, so there are no memory regions to capture
+    #[error("can not capture an identity memory mapping")]
+    IdentityMemoryMapping,
+    /// Failed to rebuild the memory mapping
+    #[error("memory mapping error: {0}")]
+    MemoryMappingError(#[from] EbpfError),
+}
+
+/// Layout and contents of a captured [MemoryRegion]
+#[derive(Debug, PartialEq, Eq)]
+pub struct RegionSnapshot {
+    /// start virtual address
+    pub vm_addr: u64,
+    /// Size of regular gaps as bit shift (63 means this region is continuous)
+    pub vm_gap_shift: u8,
+    /// Whether the region is readonly, writable or must be copied before writing
+    pub state: MemoryState,
+    /// Contents of the region
+    pub data: AlignedMemory<{ HOST_ALIGN }>,
+    /// Sub-ranges with fewer permissions, see [MemoryRegion::protect]
+    pub protected_ranges: Vec<ProtectedRange>,
+}
+
+impl RegionSnapshot {
+    fn capture(region: &MemoryRegion) -> Self {
+        let data = unsafe {
+            std::slice::from_raw_parts(region.host_addr.get() as *const u8, region.len as usize)
+        };
+        Self {
+            vm_addr: region.vm_addr,
+            vm_gap_shift: region.vm_gap_shift,
+            state: region.state.get(),
+            data: AlignedMemory::from_slice(data),
+            protected_ranges: region.protected_ranges().to_vec(),
+        }
+    }
+
+    fn vm_gap_size(&self) -> u64 {
+        if self.vm_gap_shift as u32 == u64::BITS - 1 {
+            0
+        } else {
+            1u64.checked_shl(self.vm_gap_shift as u32).unwrap_or(0)
+        }
+    }
+}
+
+/// Machine state of an [EbpfVm]
+#[derive(Debug, PartialEq, Eq)]
+pub struct VmSnapshot {
+    /// [executable_hash] of the executable the VM was running
+    pub executable_hash: [u8; 32],
+    /// Registers including the pc in `registers[11]`
+    pub registers: [u64; 12],
+    /// The current call depth
+    pub call_depth: u64,
+    /// The active call frames, `call_frames.len() == call_depth`
+    pub call_frames: Vec<CallFrame>,
+    /// Last return value of instruction_meter.get_remaining()
+    pub previous_instruction_meter: u64,
+    /// Outstanding value to instruction_meter.consume()
+    pub due_insn_count: u64,
+    /// Return value of `ContextObject::get_remaining()` at the time of capture
+    pub remaining: u64,
+    /// All memory regions of the memory mapping
+    pub regions: Vec<RegionSnapshot>,
+}
+
+impl VmSnapshot {
+    /// Captures the state of a VM
+    ///
+    /// The JIT only writes the registers and call frames back to the VM when it suspends (see
+    /// [EbpfVm::resume_program]), so a capture is only complete before execution, after a
+    /// suspension or during an interpreted execution.
+    pub fn capture<C: ContextObject>(
+        vm: &EbpfVm<C>,
+        executable: &Executable<C>,
+    ) -> Result<Self, SnapshotError> {
+        let regions = mapped_regions(&vm.memory_mapping)?;
+        let call_depth = (vm.call_depth as usize).min(vm.call_frames.len());
+        Ok(Self {
+            executable_hash: executable_hash(executable),
+            registers: vm.registers,
+            call_depth: vm.call_depth,
+            call_frames: vm.call_frames[0..call_depth].to_vec(),
+            previous_instruction_meter: vm.previous_instruction_meter,
+            due_insn_count: vm.due_insn_count,
+            remaining: vm.context_object_pointer.get_remaining(),
+            regions: regions.iter().map(RegionSnapshot::capture).collect(),
+        })
+    }
+
+    /// Rebuilds a VM from the captured state
+    ///
+    /// The memory regions of the new VM point into this snapshot. The context object should be
+    /// created with the captured `remaining` meter value to replay an execution faithfully.
+    /// `cow_cb` is only needed if there are regions in the [MemoryState::Cow] state.
+    pub fn create_vm<'a, C: ContextObject>(
+        &'a mut self,
+        executable: &'a Executable<C>,
+        context_object: &'a mut C,
+        cow_cb: Option<MemoryCowCallback>,
+    ) -> Result<EbpfVm<'a, C>, SnapshotError> {
+        let expected_hash = executable_hash(executable);
+        if self.executable_hash != expected_hash {
+            return Err(SnapshotError::ExecutableMismatch(
+                self.executable_hash,
+                expected_hash,
+            ));
+        }
+        let config = executable.get_config();
+        if self.call_frames.len() > config.max_call_depth {
+            return Err(SnapshotError::InvalidData(
+                "call depth exceeds max_call_depth",
+            ));
+        }
+        // The stack length determines the frame pointer of the root frame
+        let stack_len = self
+            .regions
+            .iter()
+            .find(|region| region.vm_addr == ebpf::MM_STACK_START)
+            .map_or(0, |region| region.data.len());
+        let regions = self
+            .regions
+            .iter_mut()
+            .map(|region| {
+                let vm_gap_size = region.vm_gap_size();
+                let mut memory_region = MemoryRegion::new(
+                    region.data.as_slice_mut(),
+                    region.vm_addr,
+                    vm_gap_size,
+                    region.state,
+                );
+                for protected_range in region.protected_ranges.iter() {
+                    memory_region.protect(
+                        protected_range.vm_range.clone(),
+                        protected_range.protection,
+                        protected_range.name.clone(),
+                    )?;
+                }
+                Ok(memory_region)
+            })
+            .collect::<Result<_, EbpfError>>()?;
+        let memory_mapping = if let Some(cow_cb) = cow_cb {
+            MemoryMapping::new_with_cow(regions, cow_cb, config, executable.get_sbpf_version())?
+        } else {
+            MemoryMapping::new(regions, config, executable.get_sbpf_version())?
+        };
+        let mut vm = EbpfVm::new(
+            executable.get_loader().clone(),
+            executable.get_sbpf_version(),
+            context_object,
+            memory_mapping,
+            stack_len,
+        );
+        vm.registers = self.registers;
+        vm.call_depth = self.call_depth;
+        vm.call_frames[0..self.call_frames.len()].clone_from_slice(&self.call_frames);
+        vm.previous_instruction_meter = self.previous_instruction_meter;
+        vm.due_insn_count = self.due_insn_count;
+        Ok(vm)
+    }
+
+    /// Serializes the snapshot
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), SnapshotError> {
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_u32::<LittleEndian>(SNAPSHOT_FORMAT_VERSION)?;
+        writer.write_all(&self.executable_hash)?;
+        for register in self.registers.iter() {
+            writer.write_u64::<LittleEndian>(*register)?;
+        }
+        writer.write_u64::<LittleEndian>(self.call_depth)?;
+        writer.write_u64::<LittleEndian>(self.call_frames.len() as u64)?;
+        for frame in self.call_frames.iter() {
+            for register in frame.caller_saved_registers.iter() {
+                writer.write_u64::<LittleEndian>(*register)?;
+            }
+            writer.write_u64::<LittleEndian>(frame.frame_pointer)?;
+            writer.write_u64::<LittleEndian>(frame.target_pc)?;
+        }
+        writer.write_u64::<LittleEndian>(self.previous_instruction_meter)?;
+        writer.write_u64::<LittleEndian>(self.due_insn_count)?;
+        writer.write_u64::<LittleEndian>(self.remaining)?;
+        writer.write_u64::<LittleEndian>(self.regions.len() as u64)?;
+        for region in self.regions.iter() {
+            writer.write_u64::<LittleEndian>(region.vm_addr)?;
+            writer.write_u8(region.vm_gap_shift)?;
+            match region.state {
+                MemoryState::Readable => writer.write_u8(0)?,
+                MemoryState::Writable => writer.write_u8(1)?,
+                MemoryState::Cow(cow_id) => {
+                    writer.write_u8(2)?;
+                    writer.write_u64::<LittleEndian>(cow_id)?;
+                }
+                MemoryState::WriteOnly => writer.write_u8(3)?,
+            }
+            writer.write_u64::<LittleEndian>(region.data.len() as u64)?;
+            writer.write_all(region.data.as_slice())?;
+            writer.write_u64::<LittleEndian>(region.protected_ranges.len() as u64)?;
+            for protected_range in region.protected_ranges.iter() {
+                writer.write_u64::<LittleEndian>(protected_range.vm_range.start)?;
+                writer.write_u64::<LittleEndian>(protected_range.vm_range.end)?;
+                match protected_range.protection {
+                    MemoryProtection::Readable => writer.write_u8(0)?,
+                    MemoryProtection::Inaccessible => writer.write_u8(1)?,
+                }
+                writer.write_u64::<LittleEndian>(protected_range.name.len() as u64)?;
+                writer.write_all(protected_range.name.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deserializes a snapshot
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, SnapshotError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        let format_version = reader.read_u32::<LittleEndian>()?;
+        if format_version != SNAPSHOT_FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedFormatVersion(format_version));
+        }
+        let mut executable_hash = [0u8; 32];
+        reader.read_exact(&mut executable_hash)?;
+        let mut registers = [0u64; 12];
+        reader.read_u64_into::<LittleEndian>(&mut registers)?;
+        let call_depth = reader.read_u64::<LittleEndian>()?;
+        let call_frame_count = reader.read_u64::<LittleEndian>()?;
+        if call_frame_count != call_depth {
+            return Err(SnapshotError::InvalidData("call frame count"));
+        }
+        let mut call_frames = Vec::new();
+        for _ in 0..call_frame_count {
+            let mut frame = CallFrame::default();
+            reader.read_u64_into::<LittleEndian>(&mut frame.caller_saved_registers)?;
+            frame.frame_pointer = reader.read_u64::<LittleEndian>()?;
+            frame.target_pc = reader.read_u64::<LittleEndian>()?;
+            call_frames.push(frame);
+        }
+        let previous_instruction_meter = reader.read_u64::<LittleEndian>()?;
+        let due_insn_count = reader.read_u64::<LittleEndian>()?;
+        let remaining = reader.read_u64::<LittleEndian>()?;
+        let region_count = reader.read_u64::<LittleEndian>()?;
+        let mut regions = Vec::new();
+        for _ in 0..region_count {
+            let vm_addr = reader.read_u64::<LittleEndian>()?;
+            let vm_gap_shift = reader.read_u8()?;
+            if vm_gap_shift as u32 >= u64::BITS {
+                return Err(SnapshotError::InvalidData("region gap shift"));
+            }
+            let state = match reader.read_u8()? {
+                0 => MemoryState::Readable,
+                1 => MemoryState::Writable,
+                2 => MemoryState::Cow(reader.read_u64::<LittleEndian>()?),
+                3 => MemoryState::WriteOnly,
+                _ => return Err(SnapshotError::InvalidData("region state")),
+            };
+            let len = reader.read_u64::<LittleEndian>()?;
+            // Grows with the data actually read instead of trusting len for the allocation
+            let mut data = Vec::new();
+            reader.take(len).read_to_end(&mut data)?;
+            if data.len() as u64 != len {
+                return Err(SnapshotError::InvalidData("region length"));
+            }
+            let protected_range_count = reader.read_u64::<LittleEndian>()?;
+            let mut protected_ranges = Vec::new();
+            for _ in 0..protected_range_count {
+                let start = reader.read_u64::<LittleEndian>()?;
+                let end = reader.read_u64::<LittleEndian>()?;
+                let protection = match reader.read_u8()? {
+                    0 => MemoryProtection::Readable,
+                    1 => MemoryProtection::Inaccessible,
+                    _ => return Err(SnapshotError::InvalidData("protected range protection")),
+                };
+                let len = reader.read_u64::<LittleEndian>()?;
+                let mut name = Vec::new();
+                reader.take(len).read_to_end(&mut name)?;
+                if name.len() as u64 != len {
+                    return Err(SnapshotError::InvalidData("protected range name length"));
+                }
+                let name = String::from_utf8(name)
+                    .map_err(|_| SnapshotError::InvalidData("protected range name"))?;
+                protected_ranges.push(ProtectedRange {
+                    vm_range: start..end,
+                    protection,
+                    name: Cow::Owned(name),
+                });
+            }
+            regions.push(RegionSnapshot {
+                vm_addr,
+                vm_gap_shift,
+                state,
+                data: AlignedMemory::from_slice(&data),
+                protected_ranges,
+            });
+        }
+        Ok(Self {
+            executable_hash,
+            registers,
+            call_depth,
+            call_frames,
+            previous_instruction_meter,
+            due_insn_count,
+            remaining,
+            regions,
+        })
+    }
+}
+
+/// Returns the memory regions of a memory mapping, except for the null region
+pub(crate) fn mapped_regions<'a>(
+    memory_mapping: &'a MemoryMapping,
+) -> Result<&'a [MemoryRegion], SnapshotError> {
+    match memory_mapping {
+        MemoryMapping::Identity => Err(SnapshotError::IdentityMemoryMapping),
+        // Skip the null region, which AlignedMemoryMapping::new() inserts by itself
+        MemoryMapping::Aligned(memory_mapping) => Ok(&memory_mapping.get_regions()[1..]),
+        MemoryMapping::Unaligned(memory_mapping) => Ok(memory_mapping.get_regions()),
+    }
+}
+
+/// Identifies an executable by the SHA-256 of its SBPF version, entrypoint, functions and read-only sections
+pub fn executable_hash<C: ContextObject>(executable: &Executable<C>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    let sbpf_version: u8 = match executable.get_sbpf_version() {
+        SBPFVersion::V0 => 0,
+        SBPFVersion::V1 => 1,
+        SBPFVersion::V2 => 2,
+        SBPFVersion::V3 => 3,
+        SBPFVersion::Reserved => u8::MAX,
+    };
+    let (text_section_vaddr, text_bytes) = executable.get_text_bytes();
+    let ro_section = executable.get_ro_section();
+    hasher.update([sbpf_version]);
+    hasher.update((executable.get_entrypoint_instruction_offset() as u64).to_le_bytes());
+    hasher.update(text_section_vaddr.to_le_bytes());
+    // The lengths keep the boundaries between the sections unambiguous
+    hasher.update((text_bytes.len() as u64).to_le_bytes());
+    hasher.update(text_bytes);
+    hasher.update((ro_section.len() as u64).to_le_bytes());
+    hasher.update(ro_section);
+    for (key, (_name, pc)) in executable.get_function_registry().iter() {
+        hasher.update(key.to_le_bytes());
+        hasher.update((pc as u64).to_le_bytes());
+    }
+    hasher.finalize().into()
+}
+
+/// Formats a hash in lower case hex digits
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler::assemble,
+        error::ProgramResult,
+        memory_region::AccessType,
+        program::BuiltinProgram,
+        vm::{Config, TestContextObject},
+    };
+    use std::sync::Arc;
+
+    fn create_executable(source: &str) -> Executable<TestContextObject> {
+        let loader = Arc::new(BuiltinProgram::new_loader(
+            Config::default(),
+            crate::program::FunctionRegistry::default(),
+        ));
+        assemble::<TestContextObject>(source, loader).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let executable = create_executable("mov64 r0, 1\nexit");
+        let config = executable.get_config();
+        let mut stack = AlignedMemory::<{ HOST_ALIGN }>::zero_filled(config.stack_size());
+        let mut input = vec![0x11u8, 0x22, 0x33];
+        let mut input_region = MemoryRegion::new_cow(&input, ebpf::MM_INPUT_START, 7);
+        input_region
+            .protect(
+                ebpf::MM_INPUT_START + 1..ebpf::MM_INPUT_START + 3,
+                MemoryProtection::Inaccessible,
+                String::from("account data"),
+            )
+            .unwrap();
+        let regions = vec![
+            executable.get_ro_region(),
+            MemoryRegion::new_writable_gapped(
+                stack.as_slice_mut(),
+                ebpf::MM_STACK_START,
+                config.stack_frame_size as u64,
+            ),
+            MemoryRegion::new_write_only(&mut [], ebpf::MM_HEAP_START),
+            input_region,
+        ];
+        let memory_mapping =
+            MemoryMapping::new(regions, config, executable.get_sbpf_version()).unwrap();
+        let mut context_object = TestContextObject::new(42);
+        let mut vm = EbpfVm::new(
+            executable.get_loader().clone(),
+            executable.get_sbpf_version(),
+            &mut context_object,
+            memory_mapping,
+            stack.len(),
+        );
+        vm.registers[3] = 0xdead;
+        vm.call_depth = 1;
+        vm.call_frames[0].frame_pointer = 0x1234;
+        vm.call_frames[0].target_pc = 1;
+        vm.memory_mapping
+            .store(0xabu8, ebpf::MM_STACK_START + 1)
+            .unwrap();
+        let snapshot = VmSnapshot::capture(&vm, &executable).unwrap();
+        assert_eq!(snapshot.remaining, 42);
+        assert_eq!(snapshot.call_frames.len(), 1);
+        assert_eq!(snapshot.regions.len(), 4);
+        assert_eq!(snapshot.regions[2].state, MemoryState::WriteOnly);
+        assert_eq!(snapshot.regions[3].state, MemoryState::Cow(7));
+        assert_eq!(snapshot.regions[3].protected_ranges.len(), 1);
+        input[0] = 0;
+
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+        let mut restored = VmSnapshot::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(restored, snapshot);
+
+        let mut context_object = TestContextObject::new(restored.remaining);
+        let vm = restored
+            .create_vm(&executable, &mut context_object, None)
+            .unwrap();
+        assert_eq!(vm.registers, snapshot.registers);
+        assert_eq!(vm.call_depth, 1);
+        assert_eq!(vm.call_frames[0], snapshot.call_frames[0]);
+        assert_eq!(
+            vm.memory_mapping
+                .load::<u8>(ebpf::MM_STACK_START + 1)
+                .unwrap(),
+            0xab
+        );
+        assert_eq!(
+            vm.memory_mapping.load::<u8>(ebpf::MM_INPUT_START).unwrap(),
+            0x11
+        );
+        // The protected range is preserved
+        assert!(matches!(
+            vm.memory_mapping.load::<u8>(ebpf::MM_INPUT_START + 2),
+            ProgramResult::Err(EbpfError::AccessViolation(
+                AccessType::Load,
+                _,
+                1,
+                name,
+            )) if name == "account data"
+        ));
+        // The gap of the stack is preserved
+        assert!(vm
+            .memory_mapping
+            .load::<u8>(ebpf::MM_STACK_START + config.stack_frame_size as u64)
+            .is_err());
+    }
+
+    #[test]
+    fn test_snapshot_invalid() {
+        let executable = create_executable("mov64 r0, 1\nexit");
+        let other_executable = create_executable("mov64 r0, 2\nexit");
+        assert_ne!(
+            executable_hash(&executable),
+            executable_hash(&other_executable)
+        );
+        let mut snapshot = VmSnapshot {
+            executable_hash: executable_hash(&executable),
+            registers: [0; 12],
+            call_depth: 0,
+            call_frames: Vec::new(),
+            previous_instruction_meter: 0,
+            due_insn_count: 0,
+            remaining: 0,
+            regions: Vec::new(),
+        };
+        let mut context_object = TestContextObject::new(0);
+        assert!(matches!(
+            snapshot.create_vm(&other_executable, &mut context_object, None),
+            Err(SnapshotError::ExecutableMismatch(_, _))
+        ));
+
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+        assert!(matches!(
+            VmSnapshot::read(&mut &bytes[0..bytes.len() - 1]),
+            Err(SnapshotError::IoError(_))
+        ));
+        bytes[0] = 0;
+        assert!(matches!(
+            VmSnapshot::read(&mut bytes.as_slice()),
+            Err(SnapshotError::InvalidMagic)
+        ));
+    }
+}
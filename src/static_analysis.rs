@@ -6,11 +6,15 @@ use crate::{
     ebpf,
     elf::Executable,
     error::EbpfError,
+    memory_region::AccessType,
     program::SBPFVersion,
-    vm::{ContextObject, DynamicAnalysis, TestContextObject},
+    vm::{ContextObject, DynamicAnalysis, TestContextObject, TraceEvent},
 };
 use rustc_demangle::demangle;
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::Range,
+};
 
 /// Register state recorded after executing one instruction
 ///
@@ -110,6 +114,16 @@ pub struct DfgEdge {
     pub resource: DataResource,
 }
 
+/// The reason why a memory access does not need its bounds checked
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum BoundsCheckProof {
+    /// The access goes through `r10` and stays inside of the current (fixed size) stack frame
+    StackFrame,
+    /// An earlier access in the same basic block went through the same definition of the base register and covered
+    /// all bytes of this access
+    RepeatedAccess,
+}
+
 impl Default for CfgNode {
     fn default() -> Self {
         Self {
@@ -468,11 +482,57 @@ impl<'a> Analysis<'a> {
         Ok(())
     }
 
+    /// Generates a listing of the JIT compiled machine code, interleaved with the assembler code it was generated for
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    pub fn disassemble_jit<W: std::io::Write>(&self, output: &mut W) -> std::io::Result<()> {
+        let compiled_program = self.executable.get_compiled_program().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "executable is not JIT compiled",
+            )
+        })?;
+        let pc_to_insn_index = self
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(index, insn)| (insn.ptr, index))
+            .collect::<HashMap<usize, usize>>();
+        let mut last_basic_block = usize::MAX;
+        compiled_program.disassemble(output, |output, pc| {
+            // The second slot of lddw has no instruction of its own
+            if let Some(index) = pc_to_insn_index.get(&pc) {
+                let insn = &self.instructions[*index];
+                self.disassemble_label(output, *index == 0, pc, &mut last_basic_block)?;
+                writeln!(output, "    {}", self.disassemble_instruction(insn, *index))?;
+            }
+            Ok(())
+        })
+    }
+
     /// Use this method to print the trace log
     pub fn disassemble_trace_log<W: std::io::Write>(
         &self,
         output: &mut W,
         trace_log: &[TraceLogEntry],
+    ) -> Result<(), std::io::Error> {
+        self.disassemble_trace_log_with_events(output, trace_log, &[])
+    }
+
+    /// Use this method to print the trace log together with the trace events
+    ///
+    /// The trace events are printed below the instruction they belong to.
+    pub fn disassemble_trace_log_with_events<W: std::io::Write>(
+        &self,
+        output: &mut W,
+        trace_log: &[TraceLogEntry],
+        mut trace_events: &[(usize, TraceEvent)],
     ) -> Result<(), std::io::Error> {
         let mut pc_to_insn_index = vec![
             0usize;
@@ -496,6 +556,44 @@ impl<'a> Analysis<'a> {
                 pc,
                 self.disassemble_instruction(insn, pc),
             )?;
+            while let Some((_, event)) = trace_events.first().filter(|(i, _)| *i <= index) {
+                trace_events = &trace_events[1..];
+                match event {
+                    TraceEvent::MemoryAccess {
+                        access_type,
+                        vm_addr,
+                        len,
+                        value,
+                        region_vm_addr,
+                        ..
+                    } => writeln!(
+                        output,
+                        "{:>26}{:?} {} bytes at {:#X} (region {:#X}): {:#X}",
+                        "", access_type, len, vm_addr, region_vm_addr, value,
+                    )?,
+                    TraceEvent::SyscallEntry { arguments, .. } => {
+                        writeln!(output, "{:>26}syscall {:#X?}", "", arguments)?
+                    }
+                    TraceEvent::SyscallExit { result, .. } => {
+                        writeln!(output, "{:>26}syscall returned {:#X}", "", result)?
+                    }
+                    TraceEvent::Call {
+                        target_pc,
+                        call_depth,
+                        ..
+                    } => writeln!(
+                        output,
+                        "{:>26}call {} at depth {}",
+                        "", target_pc, call_depth
+                    )?,
+                    TraceEvent::Return { call_depth, .. } => {
+                        writeln!(output, "{:>26}return to depth {}", "", call_depth)?
+                    }
+                    TraceEvent::Exception { error, .. } => {
+                        writeln!(output, "{:>26}exception: {}", "", error)?
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -944,6 +1042,7 @@ impl<'a> Analysis<'a> {
                 state.2.insert(resource, insn.ptr);
             }
         }
+        let sbpf_version = self.executable.get_sbpf_version();
         let mut state = (0, BTreeMap::new(), HashMap::new());
         let data_dependencies = self
             .cfg_nodes
@@ -951,146 +1050,9 @@ impl<'a> Analysis<'a> {
             .map(|(basic_block_start, basic_block)| {
                 state.0 = *basic_block_start;
                 for insn in self.instructions[basic_block.instructions.clone()].iter() {
-                    match insn.opc {
-                        ebpf::LD_DW_IMM => {
-                            bind(&mut state, insn, true, DataResource::Register(insn.dst));
-                        }
-                        ebpf::LD_B_REG | ebpf::LD_H_REG | ebpf::LD_W_REG | ebpf::LD_DW_REG => {
-                            bind(&mut state, insn, false, DataResource::Memory);
-                            bind(&mut state, insn, false, DataResource::Register(insn.src));
-                            bind(&mut state, insn, true, DataResource::Register(insn.dst));
-                        }
-                        ebpf::ST_B_IMM | ebpf::ST_H_IMM | ebpf::ST_W_IMM | ebpf::ST_DW_IMM => {
-                            bind(&mut state, insn, false, DataResource::Register(insn.dst));
-                            bind(&mut state, insn, true, DataResource::Memory);
-                        }
-                        ebpf::ST_B_REG | ebpf::ST_H_REG | ebpf::ST_W_REG | ebpf::ST_DW_REG => {
-                            bind(&mut state, insn, false, DataResource::Register(insn.src));
-                            bind(&mut state, insn, false, DataResource::Register(insn.dst));
-                            bind(&mut state, insn, true, DataResource::Memory);
-                        }
-                        ebpf::ADD32_IMM
-                        | ebpf::SUB32_IMM
-                        | ebpf::MUL32_IMM
-                        | ebpf::DIV32_IMM
-                        | ebpf::SDIV32_IMM
-                        | ebpf::OR32_IMM
-                        | ebpf::AND32_IMM
-                        | ebpf::LSH32_IMM
-                        | ebpf::RSH32_IMM
-                        | ebpf::MOD32_IMM
-                        | ebpf::XOR32_IMM
-                        | ebpf::ARSH32_IMM
-                        | ebpf::ADD64_IMM
-                        | ebpf::SUB64_IMM
-                        | ebpf::MUL64_IMM
-                        | ebpf::DIV64_IMM
-                        | ebpf::SDIV64_IMM
-                        | ebpf::OR64_IMM
-                        | ebpf::AND64_IMM
-                        | ebpf::LSH64_IMM
-                        | ebpf::RSH64_IMM
-                        | ebpf::MOD64_IMM
-                        | ebpf::XOR64_IMM
-                        | ebpf::ARSH64_IMM
-                        | ebpf::HOR64_IMM
-                        | ebpf::NEG32
-                        | ebpf::NEG64
-                        | ebpf::LE
-                        | ebpf::BE => {
-                            bind(&mut state, insn, false, DataResource::Register(insn.dst));
-                            bind(&mut state, insn, true, DataResource::Register(insn.dst));
-                        }
-                        ebpf::MOV32_IMM | ebpf::MOV64_IMM => {
-                            bind(&mut state, insn, true, DataResource::Register(insn.dst));
-                        }
-                        ebpf::ADD32_REG
-                        | ebpf::SUB32_REG
-                        | ebpf::MUL32_REG
-                        | ebpf::DIV32_REG
-                        | ebpf::SDIV32_REG
-                        | ebpf::OR32_REG
-                        | ebpf::AND32_REG
-                        | ebpf::LSH32_REG
-                        | ebpf::RSH32_REG
-                        | ebpf::MOD32_REG
-                        | ebpf::XOR32_REG
-                        | ebpf::ARSH32_REG
-                        | ebpf::ADD64_REG
-                        | ebpf::SUB64_REG
-                        | ebpf::MUL64_REG
-                        | ebpf::DIV64_REG
-                        | ebpf::SDIV64_REG
-                        | ebpf::OR64_REG
-                        | ebpf::AND64_REG
-                        | ebpf::LSH64_REG
-                        | ebpf::RSH64_REG
-                        | ebpf::MOD64_REG
-                        | ebpf::XOR64_REG
-                        | ebpf::ARSH64_REG => {
-                            bind(&mut state, insn, false, DataResource::Register(insn.src));
-                            bind(&mut state, insn, false, DataResource::Register(insn.dst));
-                            bind(&mut state, insn, true, DataResource::Register(insn.dst));
-                        }
-                        ebpf::MOV32_REG | ebpf::MOV64_REG => {
-                            bind(&mut state, insn, false, DataResource::Register(insn.src));
-                            bind(&mut state, insn, true, DataResource::Register(insn.dst));
-                        }
-                        ebpf::JEQ_IMM
-                        | ebpf::JGT_IMM
-                        | ebpf::JGE_IMM
-                        | ebpf::JLT_IMM
-                        | ebpf::JLE_IMM
-                        | ebpf::JSET_IMM
-                        | ebpf::JNE_IMM
-                        | ebpf::JSGT_IMM
-                        | ebpf::JSGE_IMM
-                        | ebpf::JSLT_IMM
-                        | ebpf::JSLE_IMM => {
-                            bind(&mut state, insn, false, DataResource::Register(insn.dst));
-                        }
-                        ebpf::JEQ_REG
-                        | ebpf::JGT_REG
-                        | ebpf::JGE_REG
-                        | ebpf::JLT_REG
-                        | ebpf::JLE_REG
-                        | ebpf::JSET_REG
-                        | ebpf::JNE_REG
-                        | ebpf::JSGT_REG
-                        | ebpf::JSGE_REG
-                        | ebpf::JSLT_REG
-                        | ebpf::JSLE_REG => {
-                            bind(&mut state, insn, false, DataResource::Register(insn.src));
-                            bind(&mut state, insn, false, DataResource::Register(insn.dst));
-                        }
-                        ebpf::CALL_REG | ebpf::CALL_IMM => {
-                            if insn.opc == ebpf::CALL_REG
-                                && !(ebpf::FIRST_SCRATCH_REG
-                                    ..ebpf::FIRST_SCRATCH_REG + ebpf::SCRATCH_REGS)
-                                    .contains(&(insn.imm as usize))
-                            {
-                                bind(
-                                    &mut state,
-                                    insn,
-                                    false,
-                                    DataResource::Register(insn.imm as u8),
-                                );
-                            }
-                            bind(&mut state, insn, false, DataResource::Memory);
-                            bind(&mut state, insn, true, DataResource::Memory);
-                            for reg in (0..ebpf::FIRST_SCRATCH_REG).chain([10].iter().cloned()) {
-                                bind(&mut state, insn, false, DataResource::Register(reg as u8));
-                                bind(&mut state, insn, true, DataResource::Register(reg as u8));
-                            }
-                        }
-                        ebpf::EXIT => {
-                            bind(&mut state, insn, false, DataResource::Memory);
-                            for reg in (0..ebpf::FIRST_SCRATCH_REG).chain([10].iter().cloned()) {
-                                bind(&mut state, insn, false, DataResource::Register(reg as u8));
-                            }
-                        }
-                        _ => {}
-                    }
+                    instruction_data_flow(insn, sbpf_version, |is_output, resource| {
+                        bind(&mut state, insn, is_output, resource)
+                    });
                 }
                 let mut deps = HashMap::new();
                 std::mem::swap(&mut deps, &mut state.2);
@@ -1177,4 +1139,322 @@ impl<'a> Analysis<'a> {
             }
         }
     }
+
+    /// Finds the memory accesses which can skip the bounds checks of the address translation
+    ///
+    /// Stack frame accesses require fixed stack frames and that no instruction writes to `r10`.
+    /// Repeated accesses require that the execution can only enter a basic block at its start. This
+    /// does not hold if `callx` can jump to any instruction (without static syscalls) or if the
+    /// execution can be resumed at any instruction. The knowledge about earlier accesses is reset at
+    /// every call and syscall, as those can change the memory mapping. A repeated store needs an
+    /// earlier store, as a load does not prove that the memory is writable.
+    pub fn redundant_bounds_checks(&self) -> BTreeMap<usize, BoundsCheckProof> {
+        let config = self.executable.get_config();
+        let sbpf_version = self.executable.get_sbpf_version();
+        let frame_pointer_is_fixed = !sbpf_version.dynamic_stack_frames()
+            && !self.instructions.iter().any(|insn| {
+                let mut writes_frame_pointer = false;
+                if !is_call(insn, sbpf_version) {
+                    instruction_data_flow(insn, sbpf_version, |is_output, resource| {
+                        writes_frame_pointer |= is_output
+                            && resource == DataResource::Register(ebpf::FRAME_PTR_REG as u8);
+                    });
+                }
+                writes_frame_pointer
+            });
+        let repeated_accesses =
+            sbpf_version.static_syscalls() && !config.enable_resumable_execution;
+        let stack_frame_size = config.stack_frame_size as i64;
+        let mut proofs = BTreeMap::new();
+        for basic_block in self.cfg_nodes.values() {
+            let mut earlier_accesses: Vec<(u8, BTreeSet<DfgNode>, Range<i64>, AccessType)> =
+                Vec::new();
+            for insn in self.instructions[basic_block.instructions.clone()].iter() {
+                if let Some((base, range, access_type)) = memory_access(insn, sbpf_version) {
+                    // The definitions of the base register which reach this access
+                    let definitions = self
+                        .dfg_reverse_edges
+                        .get(&DfgNode::InstructionNode(insn.ptr))
+                        .map(|edges| {
+                            edges
+                                .iter()
+                                .filter(|edge| {
+                                    edge.kind == DfgEdgeKind::Filled
+                                        && edge.resource == DataResource::Register(base)
+                                })
+                                .map(|edge| edge.source.clone())
+                                .collect::<BTreeSet<_>>()
+                        })
+                        .unwrap_or_default();
+                    if frame_pointer_is_fixed
+                        && base == ebpf::FRAME_PTR_REG as u8
+                        && -stack_frame_size <= range.start
+                        && range.end <= 0
+                    {
+                        proofs.insert(insn.ptr, BoundsCheckProof::StackFrame);
+                    } else if repeated_accesses
+                        && earlier_accesses.iter().any(
+                            |(
+                                earlier_base,
+                                earlier_definitions,
+                                earlier_range,
+                                earlier_access_type,
+                            )| {
+                                *earlier_base == base
+                                    && *earlier_definitions == definitions
+                                    && earlier_range.start <= range.start
+                                    && range.end <= earlier_range.end
+                                    && (access_type == AccessType::Load
+                                        || *earlier_access_type == AccessType::Store)
+                            },
+                        )
+                    {
+                        proofs.insert(insn.ptr, BoundsCheckProof::RepeatedAccess);
+                    }
+                    earlier_accesses.push((base, definitions, range, access_type));
+                }
+                if is_call(insn, sbpf_version) {
+                    earlier_accesses.clear();
+                }
+            }
+        }
+        proofs
+    }
+}
+
+/// Reports which resources an instruction reads (`is_output == false`) and writes, in that order
+fn instruction_data_flow(
+    insn: &ebpf::Insn,
+    sbpf_version: SBPFVersion,
+    mut bind: impl FnMut(bool, DataResource),
+) {
+    match insn.opc {
+        ebpf::LD_DW_IMM => {
+            bind(true, DataResource::Register(insn.dst));
+        }
+        ebpf::LD_B_REG | ebpf::LD_H_REG | ebpf::LD_W_REG | ebpf::LD_DW_REG
+            if !sbpf_version.move_memory_instruction_classes() =>
+        {
+            bind(false, DataResource::Memory);
+            bind(false, DataResource::Register(insn.src));
+            bind(true, DataResource::Register(insn.dst));
+        }
+        ebpf::LD_1B_REG | ebpf::LD_2B_REG | ebpf::LD_4B_REG | ebpf::LD_8B_REG
+            if sbpf_version.move_memory_instruction_classes() =>
+        {
+            bind(false, DataResource::Memory);
+            bind(false, DataResource::Register(insn.src));
+            bind(true, DataResource::Register(insn.dst));
+        }
+        ebpf::ST_B_IMM | ebpf::ST_H_IMM | ebpf::ST_W_IMM | ebpf::ST_DW_IMM
+            if !sbpf_version.move_memory_instruction_classes() =>
+        {
+            bind(false, DataResource::Register(insn.dst));
+            bind(true, DataResource::Memory);
+        }
+        ebpf::ST_1B_IMM | ebpf::ST_2B_IMM | ebpf::ST_4B_IMM | ebpf::ST_8B_IMM
+            if sbpf_version.move_memory_instruction_classes() =>
+        {
+            bind(false, DataResource::Register(insn.dst));
+            bind(true, DataResource::Memory);
+        }
+        ebpf::ST_B_REG | ebpf::ST_H_REG | ebpf::ST_W_REG | ebpf::ST_DW_REG
+            if !sbpf_version.move_memory_instruction_classes() =>
+        {
+            bind(false, DataResource::Register(insn.src));
+            bind(false, DataResource::Register(insn.dst));
+            bind(true, DataResource::Memory);
+        }
+        ebpf::ST_1B_REG | ebpf::ST_2B_REG | ebpf::ST_4B_REG | ebpf::ST_8B_REG
+            if sbpf_version.move_memory_instruction_classes() =>
+        {
+            bind(false, DataResource::Register(insn.src));
+            bind(false, DataResource::Register(insn.dst));
+            bind(true, DataResource::Memory);
+        }
+        ebpf::ADD32_IMM
+        | ebpf::SUB32_IMM
+        | ebpf::MUL32_IMM
+        | ebpf::DIV32_IMM
+        | ebpf::SDIV32_IMM
+        | ebpf::OR32_IMM
+        | ebpf::AND32_IMM
+        | ebpf::LSH32_IMM
+        | ebpf::RSH32_IMM
+        | ebpf::MOD32_IMM
+        | ebpf::XOR32_IMM
+        | ebpf::ARSH32_IMM
+        | ebpf::LMUL32_IMM
+        | ebpf::UDIV32_IMM
+        | ebpf::UREM32_IMM
+        | ebpf::SREM32_IMM
+        | ebpf::ADD64_IMM
+        | ebpf::SUB64_IMM
+        | ebpf::MUL64_IMM
+        | ebpf::DIV64_IMM
+        | ebpf::SDIV64_IMM
+        | ebpf::OR64_IMM
+        | ebpf::AND64_IMM
+        | ebpf::LSH64_IMM
+        | ebpf::RSH64_IMM
+        | ebpf::MOD64_IMM
+        | ebpf::XOR64_IMM
+        | ebpf::ARSH64_IMM
+        | ebpf::HOR64_IMM
+        | ebpf::LMUL64_IMM
+        | ebpf::UHMUL64_IMM
+        | ebpf::UDIV64_IMM
+        | ebpf::UREM64_IMM
+        | ebpf::SHMUL64_IMM
+        | ebpf::SREM64_IMM
+        | ebpf::NEG32
+        | ebpf::NEG64
+        | ebpf::LE
+        | ebpf::BE => {
+            bind(false, DataResource::Register(insn.dst));
+            bind(true, DataResource::Register(insn.dst));
+        }
+        ebpf::MOV32_IMM | ebpf::MOV64_IMM => {
+            bind(true, DataResource::Register(insn.dst));
+        }
+        ebpf::ADD32_REG
+        | ebpf::SUB32_REG
+        | ebpf::MUL32_REG
+        | ebpf::DIV32_REG
+        | ebpf::SDIV32_REG
+        | ebpf::OR32_REG
+        | ebpf::AND32_REG
+        | ebpf::LSH32_REG
+        | ebpf::RSH32_REG
+        | ebpf::MOD32_REG
+        | ebpf::XOR32_REG
+        | ebpf::ARSH32_REG
+        | ebpf::LMUL32_REG
+        | ebpf::UDIV32_REG
+        | ebpf::UREM32_REG
+        | ebpf::SREM32_REG
+        | ebpf::ADD64_REG
+        | ebpf::SUB64_REG
+        | ebpf::MUL64_REG
+        | ebpf::DIV64_REG
+        | ebpf::SDIV64_REG
+        | ebpf::OR64_REG
+        | ebpf::AND64_REG
+        | ebpf::LSH64_REG
+        | ebpf::RSH64_REG
+        | ebpf::MOD64_REG
+        | ebpf::XOR64_REG
+        | ebpf::ARSH64_REG
+        | ebpf::LMUL64_REG
+        | ebpf::UHMUL64_REG
+        | ebpf::UDIV64_REG
+        | ebpf::UREM64_REG
+        | ebpf::SHMUL64_REG
+        | ebpf::SREM64_REG => {
+            bind(false, DataResource::Register(insn.src));
+            bind(false, DataResource::Register(insn.dst));
+            bind(true, DataResource::Register(insn.dst));
+        }
+        ebpf::MOV32_REG | ebpf::MOV64_REG => {
+            bind(false, DataResource::Register(insn.src));
+            bind(true, DataResource::Register(insn.dst));
+        }
+        ebpf::JEQ_IMM
+        | ebpf::JGT_IMM
+        | ebpf::JGE_IMM
+        | ebpf::JLT_IMM
+        | ebpf::JLE_IMM
+        | ebpf::JSET_IMM
+        | ebpf::JNE_IMM
+        | ebpf::JSGT_IMM
+        | ebpf::JSGE_IMM
+        | ebpf::JSLT_IMM
+        | ebpf::JSLE_IMM => {
+            bind(false, DataResource::Register(insn.dst));
+        }
+        ebpf::JEQ_REG
+        | ebpf::JGT_REG
+        | ebpf::JGE_REG
+        | ebpf::JLT_REG
+        | ebpf::JLE_REG
+        | ebpf::JSET_REG
+        | ebpf::JNE_REG
+        | ebpf::JSGT_REG
+        | ebpf::JSGE_REG
+        | ebpf::JSLT_REG
+        | ebpf::JSLE_REG => {
+            bind(false, DataResource::Register(insn.src));
+            bind(false, DataResource::Register(insn.dst));
+        }
+        _ if is_call(insn, sbpf_version) => {
+            if insn.opc == ebpf::CALL_REG {
+                let target = if sbpf_version.callx_uses_src_reg() {
+                    insn.src
+                } else {
+                    insn.imm as u8
+                };
+                if !(ebpf::FIRST_SCRATCH_REG..ebpf::FIRST_SCRATCH_REG + ebpf::SCRATCH_REGS)
+                    .contains(&(target as usize))
+                {
+                    bind(false, DataResource::Register(target));
+                }
+            }
+            bind(false, DataResource::Memory);
+            bind(true, DataResource::Memory);
+            for reg in (0..ebpf::FIRST_SCRATCH_REG).chain([10].iter().cloned()) {
+                bind(false, DataResource::Register(reg as u8));
+                bind(true, DataResource::Register(reg as u8));
+            }
+        }
+        ebpf::EXIT | ebpf::RETURN => {
+            bind(false, DataResource::Memory);
+            for reg in (0..ebpf::FIRST_SCRATCH_REG).chain([10].iter().cloned()) {
+                bind(false, DataResource::Register(reg as u8));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether the instruction leaves the current function and comes back, e.g. a function call or syscall
+fn is_call(insn: &ebpf::Insn, sbpf_version: SBPFVersion) -> bool {
+    match insn.opc {
+        ebpf::CALL_IMM | ebpf::CALL_REG => true,
+        ebpf::SYSCALL => sbpf_version.static_syscalls(),
+        _ => false,
+    }
+}
+
+/// Returns the base register, the range of offsets and the kind of a memory access
+fn memory_access(
+    insn: &ebpf::Insn,
+    sbpf_version: SBPFVersion,
+) -> Option<(u8, Range<i64>, AccessType)> {
+    let (base, len, access_type) = if sbpf_version.move_memory_instruction_classes() {
+        match insn.opc {
+            ebpf::LD_1B_REG => (insn.src, 1, AccessType::Load),
+            ebpf::LD_2B_REG => (insn.src, 2, AccessType::Load),
+            ebpf::LD_4B_REG => (insn.src, 4, AccessType::Load),
+            ebpf::LD_8B_REG => (insn.src, 8, AccessType::Load),
+            ebpf::ST_1B_IMM | ebpf::ST_1B_REG => (insn.dst, 1, AccessType::Store),
+            ebpf::ST_2B_IMM | ebpf::ST_2B_REG => (insn.dst, 2, AccessType::Store),
+            ebpf::ST_4B_IMM | ebpf::ST_4B_REG => (insn.dst, 4, AccessType::Store),
+            ebpf::ST_8B_IMM | ebpf::ST_8B_REG => (insn.dst, 8, AccessType::Store),
+            _ => return None,
+        }
+    } else {
+        match insn.opc {
+            ebpf::LD_B_REG => (insn.src, 1, AccessType::Load),
+            ebpf::LD_H_REG => (insn.src, 2, AccessType::Load),
+            ebpf::LD_W_REG => (insn.src, 4, AccessType::Load),
+            ebpf::LD_DW_REG => (insn.src, 8, AccessType::Load),
+            ebpf::ST_B_IMM | ebpf::ST_B_REG => (insn.dst, 1, AccessType::Store),
+            ebpf::ST_H_IMM | ebpf::ST_H_REG => (insn.dst, 2, AccessType::Store),
+            ebpf::ST_W_IMM | ebpf::ST_W_REG => (insn.dst, 4, AccessType::Store),
+            ebpf::ST_DW_IMM | ebpf::ST_DW_REG => (insn.dst, 8, AccessType::Store),
+            _ => return None,
+        }
+    };
+    let offset = insn.off as i64;
+    Some((base, offset..offset + len, access_type))
 }
@@ -22,12 +22,12 @@
 //! respect this convention.
 
 use crate::{
-    declare_builtin_function,
+    declare_builtin_function, ebpf,
     error::EbpfError,
     memory_region::{AccessType, MemoryMapping},
-    vm::TestContextObject,
+    vm::{ContextObject, TestContextObject},
 };
-use std::{slice::from_raw_parts, str::from_utf8};
+use std::{collections::BTreeMap, slice::from_raw_parts, str::from_utf8};
 
 declare_builtin_function!(
     /// Prints its **last three** arguments to standard output. The **first two** arguments are
@@ -189,3 +189,510 @@ declare_builtin_function!(
         Ok(0)
     }
 );
+
+/// Strategy of a [HeapAllocator]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapAllocatorStrategy {
+    /// Allocations are placed after each other, only the most recent one can be freed
+    Bump,
+    /// Freed blocks are reused (first fit) and merged with adjacent free blocks
+    FreeList,
+}
+
+/// Errors of the built-in heap syscalls
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum HeapAllocatorError {
+    /// The context object has no heap allocator
+    #[error("no heap allocator available")]
+    Unavailable,
+    /// The alignment is not a power of two
+    #[error("invalid alignment {0}")]
+    InvalidAlignment(u64),
+    /// The address was not returned by the allocator or has been freed already
+    #[error("invalid heap pointer {0:#x}")]
+    InvalidPointer(u64),
+    /// The context object has no heap limits
+    #[error("no heap limits available")]
+    LimitsUnavailable,
+}
+
+/// Allocator for the heap region mapped at `ebpf::MM_HEAP_START`
+///
+/// It is reached through [ContextObject::heap_allocator] by [SyscallHeapAlloc],
+/// [SyscallHeapFree] and [SyscallHeapRealloc]. Every allocation is aligned to at least
+/// [HeapAllocator::MIN_ALIGN] bytes.
+#[derive(Debug, Clone)]
+pub struct HeapAllocator {
+    strategy: HeapAllocatorStrategy,
+    heap_size: u64,
+    base_cost: u64,
+    byte_cost: u64,
+    /// Offset of the end of the bump region
+    top: u64,
+    /// Offset and size of the allocated blocks
+    allocations: BTreeMap<u64, u64>,
+    /// Offset and size of the free blocks, only used by [HeapAllocatorStrategy::FreeList]
+    free_blocks: BTreeMap<u64, u64>,
+}
+
+impl HeapAllocator {
+    /// Minimal alignment of every allocation
+    pub const MIN_ALIGN: u64 = 8;
+
+    /// Creates an allocator for a heap region of `heap_size` bytes
+    pub fn new(strategy: HeapAllocatorStrategy, heap_size: u64) -> Self {
+        let heap_size = heap_size & !(Self::MIN_ALIGN - 1);
+        let mut free_blocks = BTreeMap::new();
+        if strategy == HeapAllocatorStrategy::FreeList && heap_size > 0 {
+            free_blocks.insert(0, heap_size);
+        }
+        Self {
+            strategy,
+            heap_size,
+            base_cost: 0,
+            byte_cost: 0,
+            top: 0,
+            allocations: BTreeMap::new(),
+            free_blocks,
+        }
+    }
+
+    /// Sets the units consumed per syscall and per requested byte
+    pub fn with_cost(mut self, base_cost: u64, byte_cost: u64) -> Self {
+        self.base_cost = base_cost;
+        self.byte_cost = byte_cost;
+        self
+    }
+
+    /// Returns the strategy
+    pub fn get_strategy(&self) -> HeapAllocatorStrategy {
+        self.strategy
+    }
+
+    /// Returns the number of bytes currently allocated
+    pub fn allocated_bytes(&self) -> u64 {
+        self.allocations.values().sum()
+    }
+
+    /// Returns the units a syscall requesting `len` bytes consumes
+    pub fn cost(&self, len: u64) -> u64 {
+        self.base_cost
+            .saturating_add(self.byte_cost.saturating_mul(len))
+    }
+
+    /// Whether the heap can be resized to `heap_size` bytes without cutting off allocations
+    pub fn can_resize(&self, heap_size: u64) -> bool {
+        let end = match self.strategy {
+            HeapAllocatorStrategy::Bump => self.top,
+            HeapAllocatorStrategy::FreeList => self
+                .allocations
+                .last_key_value()
+                .map_or(0, |(offset, size)| offset + size),
+        };
+        end <= heap_size & !(Self::MIN_ALIGN - 1)
+    }
+
+    /// Adapts to the heap region being resized to `heap_size` bytes, see [SyscallHeapResize]
+    ///
+    /// Returns false and keeps the previous size if allocations extend beyond `heap_size`.
+    pub fn resize(&mut self, heap_size: u64) -> bool {
+        if !self.can_resize(heap_size) {
+            return false;
+        }
+        let heap_size = heap_size & !(Self::MIN_ALIGN - 1);
+        if self.strategy == HeapAllocatorStrategy::FreeList {
+            if heap_size > self.heap_size {
+                self.release(self.heap_size, heap_size - self.heap_size);
+            } else {
+                self.free_blocks.split_off(&heap_size);
+                if let Some((start, len)) = self.free_blocks.last_key_value() {
+                    if start + len > heap_size {
+                        self.free_blocks.insert(*start, heap_size - start);
+                    }
+                }
+            }
+        }
+        self.heap_size = heap_size;
+        true
+    }
+
+    /// Allocates `size` bytes aligned to `align` (0 for the minimal alignment)
+    ///
+    /// Returns the virtual address or 0 if the heap is exhausted or `size` is 0.
+    pub fn alloc(&mut self, size: u64, align: u64) -> Result<u64, HeapAllocatorError> {
+        let align = Self::check_align(align)?;
+        if size == 0 {
+            return Ok(0);
+        }
+        let size = match Self::round_size(size) {
+            Some(size) => size,
+            None => return Ok(0),
+        };
+        let offset = match self.strategy {
+            HeapAllocatorStrategy::Bump => self.alloc_bump(size, align),
+            HeapAllocatorStrategy::FreeList => self.alloc_free_list(size, align),
+        };
+        Ok(offset.map_or(0, |offset| {
+            self.allocations.insert(offset, size);
+            ebpf::MM_HEAP_START + offset
+        }))
+    }
+
+    /// Frees the allocation at `vm_addr`, 0 is ignored
+    pub fn free(&mut self, vm_addr: u64) -> Result<(), HeapAllocatorError> {
+        if vm_addr == 0 {
+            return Ok(());
+        }
+        let offset = self.offset(vm_addr)?;
+        let size = self.allocations.remove(&offset).unwrap();
+        self.release(offset, size);
+        Ok(())
+    }
+
+    /// Resizes the allocation at `vm_addr` to `new_size` bytes aligned to `align`
+    ///
+    /// Behaves like [HeapAllocator::alloc] if `vm_addr` is 0 and like [HeapAllocator::free] if
+    /// `new_size` is 0. If the allocation can not be resized in place its contents are copied to
+    /// a new allocation. Returns the virtual address or 0 if the heap is exhausted, in which case
+    /// the original allocation stays valid.
+    pub fn realloc(
+        &mut self,
+        vm_addr: u64,
+        new_size: u64,
+        align: u64,
+        memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, EbpfError> {
+        if vm_addr == 0 {
+            return self.alloc(new_size, align).map_err(heap_error);
+        }
+        let offset = self.offset(vm_addr).map_err(heap_error)?;
+        let align = Self::check_align(align).map_err(heap_error)?;
+        if new_size == 0 {
+            self.free(vm_addr).map_err(heap_error)?;
+            return Ok(0);
+        }
+        let new_size = match Self::round_size(new_size) {
+            Some(new_size) => new_size,
+            None => return Ok(0),
+        };
+        let old_size = self.allocations[&offset];
+        if vm_addr & (align - 1) == 0 && self.resize_in_place(offset, old_size, new_size) {
+            self.allocations.insert(offset, new_size);
+            return Ok(vm_addr);
+        }
+        let new_vm_addr = self.alloc(new_size, align).map_err(heap_error)?;
+        if new_vm_addr == 0 {
+            return Ok(0);
+        }
+        let len = old_size.min(new_size);
+        let src: Result<u64, EbpfError> = memory_mapping.map(AccessType::Load, vm_addr, len).into();
+        let dst: Result<u64, EbpfError> = memory_mapping
+            .map(AccessType::Store, new_vm_addr, len)
+            .into();
+        let (src, dst) = match (src, dst) {
+            (Ok(src), Ok(dst)) => (src, dst),
+            (Err(error), _) | (_, Err(error)) => {
+                self.free(new_vm_addr).map_err(heap_error)?;
+                return Err(error);
+            }
+        };
+        unsafe {
+            std::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, len as usize);
+        }
+        self.free(vm_addr).map_err(heap_error)?;
+        Ok(new_vm_addr)
+    }
+
+    fn check_align(align: u64) -> Result<u64, HeapAllocatorError> {
+        if align == 0 {
+            Ok(Self::MIN_ALIGN)
+        } else if align.is_power_of_two() {
+            Ok(align.max(Self::MIN_ALIGN))
+        } else {
+            Err(HeapAllocatorError::InvalidAlignment(align))
+        }
+    }
+
+    fn round_size(size: u64) -> Option<u64> {
+        Some(size.checked_add(Self::MIN_ALIGN - 1)? & !(Self::MIN_ALIGN - 1))
+    }
+
+    fn offset(&self, vm_addr: u64) -> Result<u64, HeapAllocatorError> {
+        vm_addr
+            .checked_sub(ebpf::MM_HEAP_START)
+            .filter(|offset| self.allocations.contains_key(offset))
+            .ok_or(HeapAllocatorError::InvalidPointer(vm_addr))
+    }
+
+    fn alloc_bump(&mut self, size: u64, align: u64) -> Option<u64> {
+        let offset = align_up(self.top, align)?;
+        let end = offset.checked_add(size)?;
+        if end > self.heap_size {
+            return None;
+        }
+        self.top = end;
+        Some(offset)
+    }
+
+    fn alloc_free_list(&mut self, size: u64, align: u64) -> Option<u64> {
+        let (block_start, block_len, offset) =
+            self.free_blocks.iter().find_map(|(start, len)| {
+                let offset = align_up(*start, align)?;
+                let end = offset.checked_add(size)?;
+                (end <= start + len).then_some((*start, *len, offset))
+            })?;
+        self.free_blocks.remove(&block_start);
+        if offset > block_start {
+            self.free_blocks.insert(block_start, offset - block_start);
+        }
+        let end = offset + size;
+        if end < block_start + block_len {
+            self.free_blocks.insert(end, block_start + block_len - end);
+        }
+        Some(offset)
+    }
+
+    fn resize_in_place(&mut self, offset: u64, old_size: u64, new_size: u64) -> bool {
+        let old_end = offset + old_size;
+        let new_end = offset + new_size;
+        match self.strategy {
+            HeapAllocatorStrategy::Bump => {
+                if old_end == self.top && new_end <= self.heap_size {
+                    self.top = new_end;
+                    true
+                } else {
+                    new_size <= old_size
+                }
+            }
+            HeapAllocatorStrategy::FreeList => {
+                if new_size <= old_size {
+                    if new_end < old_end {
+                        self.release(new_end, old_end - new_end);
+                    }
+                    return true;
+                }
+                match self.free_blocks.get(&old_end).copied() {
+                    Some(len) if new_end <= old_end + len => {
+                        self.free_blocks.remove(&old_end);
+                        if new_end < old_end + len {
+                            self.free_blocks.insert(new_end, old_end + len - new_end);
+                        }
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    fn release(&mut self, offset: u64, size: u64) {
+        match self.strategy {
+            HeapAllocatorStrategy::Bump => {
+                if offset + size == self.top {
+                    self.top = offset;
+                }
+            }
+            HeapAllocatorStrategy::FreeList => {
+                let mut start = offset;
+                let mut len = size;
+                if let Some(next_len) = self.free_blocks.remove(&(start + len)) {
+                    len += next_len;
+                }
+                if let Some((prev_start, prev_len)) = self
+                    .free_blocks
+                    .range(..start)
+                    .next_back()
+                    .map(|(start, len)| (*start, *len))
+                {
+                    if prev_start + prev_len == start {
+                        self.free_blocks.remove(&prev_start);
+                        start = prev_start;
+                        len += prev_len;
+                    }
+                }
+                self.free_blocks.insert(start, len);
+            }
+        }
+    }
+}
+
+/// Limits of the heap region mapped at `ebpf::MM_HEAP_START` when it is resized by
+/// [SyscallHeapResize]
+///
+/// It is reached through [ContextObject::heap_limits]. The heap region has to be created by
+/// [MemoryRegion::new_growable](crate::memory_region::MemoryRegion::new_growable) with a capacity
+/// of at least the maximal size. The [HeapAllocator], if any, is resized along with the heap
+/// region, which can not shrink below its allocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapLimits {
+    max_size: u64,
+    page_size: u64,
+    page_cost: u64,
+}
+
+impl HeapLimits {
+    /// Creates limits for a heap of up to `max_size` bytes which is resized in pages of
+    /// `page_size` bytes
+    pub fn new(max_size: u64, page_size: u64) -> Self {
+        Self {
+            max_size,
+            page_size: page_size.max(1),
+            page_cost: 0,
+        }
+    }
+
+    /// Sets the units consumed per page the heap grows by
+    pub fn with_cost(mut self, page_cost: u64) -> Self {
+        self.page_cost = page_cost;
+        self
+    }
+
+    /// Returns the maximal size in bytes
+    pub fn get_max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Returns the page size in bytes
+    pub fn get_page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Rounds `size` up to whole pages, returns None if that exceeds the maximal size
+    pub fn round_size(&self, size: u64) -> Option<u64> {
+        size.div_ceil(self.page_size)
+            .checked_mul(self.page_size)
+            .filter(|size| *size <= self.max_size)
+    }
+
+    /// Returns the units growing the heap from `old_size` to `new_size` bytes consumes
+    pub fn cost(&self, old_size: u64, new_size: u64) -> u64 {
+        let pages = new_size
+            .div_ceil(self.page_size)
+            .saturating_sub(old_size.div_ceil(self.page_size));
+        self.page_cost.saturating_mul(pages)
+    }
+}
+
+fn align_up(offset: u64, align: u64) -> Option<u64> {
+    Some(offset.checked_add(align - 1)? & !(align - 1))
+}
+
+fn heap_error(error: HeapAllocatorError) -> EbpfError {
+    EbpfError::SyscallError(Box::new(error))
+}
+
+/// Returns the heap allocator of the context object after consuming the cost of the syscall
+fn charged_heap_allocator<C: ContextObject>(
+    context_object: &mut C,
+    len: u64,
+) -> Result<&mut HeapAllocator, HeapAllocatorError> {
+    let cost = context_object
+        .heap_allocator()
+        .ok_or(HeapAllocatorError::Unavailable)?
+        .cost(len);
+    context_object.consume(cost);
+    Ok(context_object.heap_allocator().unwrap())
+}
+
+declare_builtin_function!(
+    /// Allocates `size` bytes on the heap, aligned to `align` (0 for the minimal alignment).
+    ///
+    /// Returns the address of the allocation or 0 if the heap is exhausted. See [HeapAllocator].
+    SyscallHeapAlloc<C: ContextObject>,
+    fn rust(
+        context_object: &mut C,
+        size: u64,
+        align: u64,
+        _arg3: u64,
+        _arg4: u64,
+        _arg5: u64,
+        _memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(charged_heap_allocator(context_object, size)?.alloc(size, align)?)
+    }
+);
+
+declare_builtin_function!(
+    /// Frees an allocation of [SyscallHeapAlloc] or [SyscallHeapRealloc]. Returns 0.
+    SyscallHeapFree<C: ContextObject>,
+    fn rust(
+        context_object: &mut C,
+        vm_addr: u64,
+        _arg2: u64,
+        _arg3: u64,
+        _arg4: u64,
+        _arg5: u64,
+        _memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        charged_heap_allocator(context_object, 0)?.free(vm_addr)?;
+        Ok(0)
+    }
+);
+
+declare_builtin_function!(
+    /// Resizes the allocation at `vm_addr` to `new_size` bytes, aligned to `align` (0 for the
+    /// minimal alignment).
+    ///
+    /// Returns the address of the allocation or 0 if the heap is exhausted. See
+    /// [HeapAllocator::realloc].
+    SyscallHeapRealloc<C: ContextObject>,
+    fn rust(
+        context_object: &mut C,
+        vm_addr: u64,
+        new_size: u64,
+        align: u64,
+        _arg4: u64,
+        _arg5: u64,
+        memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(charged_heap_allocator(context_object, new_size)?.realloc(
+            vm_addr,
+            new_size,
+            align,
+            memory_mapping,
+        )?)
+    }
+);
+
+declare_builtin_function!(
+    /// Resizes the heap region to `new_size` bytes, rounded up to whole pages.
+    ///
+    /// Returns the new size of the heap or its unchanged size if `new_size` exceeds the limit or
+    /// would cut off allocations of the [HeapAllocator]. Growing consumes the cost of the added
+    /// pages once the heap region was resized. See [HeapLimits].
+    SyscallHeapResize<C: ContextObject>,
+    fn rust(
+        context_object: &mut C,
+        new_size: u64,
+        _arg2: u64,
+        _arg3: u64,
+        _arg4: u64,
+        _arg5: u64,
+        memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let heap_limits = context_object
+            .heap_limits()
+            .ok_or(HeapAllocatorError::LimitsUnavailable)?;
+        let old_size = memory_mapping
+            .get_regions()
+            .iter()
+            .find(|region| region.vm_addr == ebpf::MM_HEAP_START)
+            .ok_or(EbpfError::InvalidVirtualAddress(ebpf::MM_HEAP_START))?
+            .len;
+        let new_size = match heap_limits.round_size(new_size) {
+            Some(new_size) => new_size,
+            None => return Ok(old_size),
+        };
+        if let Some(heap_allocator) = context_object.heap_allocator() {
+            if !heap_allocator.can_resize(new_size) {
+                return Ok(old_size);
+            }
+        }
+        memory_mapping.resize_region(ebpf::MM_HEAP_START, new_size)?;
+        if let Some(heap_allocator) = context_object.heap_allocator() {
+            heap_allocator.resize(new_size);
+        }
+        context_object.consume(heap_limits.cost(old_size, new_size));
+        Ok(new_size)
+    }
+);
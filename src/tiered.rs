@@ -0,0 +1,119 @@
+#![allow(clippy::arithmetic_side_effects)]
+// Copyright 2024 Solana Maintainers <maintainers@solana.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Tiered execution: Interpret first and JIT compile the whole program in the background once it is hot
+
+use crate::{
+    elf::Executable,
+    jit::JitProgram,
+    jit_pool::{JitCompilationHandle, JitCompilationPool},
+    vm::ContextObject,
+};
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+#[cfg(not(feature = "shuttle-test"))]
+use std::sync::Arc;
+
+#[cfg(feature = "shuttle-test")]
+use shuttle::sync::Arc;
+
+/// Queues the compilation of a copy of the executable in the pool, which installs the result in the given slot
+pub(crate) type SpawnCompilation<C> =
+    fn(&Executable<C>, &JitCompilationPool, Arc<OnceLock<JitProgram>>) -> JitCompilationHandle;
+
+/// Invocation counter and the compiled program of an executable in tiered execution
+///
+/// See [Executable::enable_tiered_compilation].
+pub(crate) struct TieredCompilation<C: ContextObject> {
+    /// Number of invocations of all functions together
+    invocation_count: AtomicU64,
+    /// Compiles the executable once it is hot
+    pool: Arc<JitCompilationPool>,
+    /// Set once the compilation was started, so that it only happens once
+    compilation_started: AtomicBool,
+    /// Handle of the started compilation until it is taken
+    compilation_handle: Mutex<Option<JitCompilationHandle>>,
+    /// Installed by the background compilation once it is done
+    compiled_program: Arc<OnceLock<JitProgram>>,
+    /// Starts the background compilation
+    spawn_compilation: SpawnCompilation<C>,
+}
+
+impl<C: ContextObject> TieredCompilation<C> {
+    /// Creates the counter, nothing is compiled yet
+    pub(crate) fn new(
+        pool: Arc<JitCompilationPool>,
+        spawn_compilation: SpawnCompilation<C>,
+    ) -> Self {
+        Self {
+            invocation_count: AtomicU64::new(0),
+            pool,
+            compilation_started: AtomicBool::new(false),
+            compilation_handle: Mutex::new(None),
+            compiled_program: Arc::new(OnceLock::new()),
+            spawn_compilation,
+        }
+    }
+
+    /// Returns the compiled program if the background compilation finished
+    pub(crate) fn get_compiled_program(&self) -> Option<&JitProgram> {
+        self.compiled_program.get()
+    }
+
+    /// Takes the handle of the background compilation once it was started
+    pub(crate) fn take_compilation_handle(&self) -> Option<JitCompilationHandle> {
+        self.compilation_handle.lock().unwrap().take()
+    }
+
+    /// Counts an invocation of a function
+    ///
+    /// Queues the compilation of a copy of the executable in the pool once the invocations of all
+    /// functions together reached the threshold and returns the compiled program once it is
+    /// installed. Only the invocations before the compilation is queued touch the counter.
+    pub(crate) fn enter_function(&self, executable: &Executable<C>) -> Option<&JitProgram> {
+        if let Some(compiled_program) = self.compiled_program.get() {
+            return Some(compiled_program);
+        }
+        if self.compilation_started.load(Ordering::Relaxed) {
+            return None;
+        }
+        let threshold = executable.get_config().tiered_compilation_threshold;
+        if threshold == 0 || self.invocation_count.fetch_add(1, Ordering::Relaxed) + 1 < threshold {
+            return None;
+        }
+        if !self.compilation_started.swap(true, Ordering::Relaxed) {
+            let handle = (self.spawn_compilation)(
+                executable,
+                &self.pool,
+                Arc::clone(&self.compiled_program),
+            );
+            *self.compilation_handle.lock().unwrap() = Some(handle);
+        }
+        None
+    }
+}
+
+impl<C: ContextObject> Debug for TieredCompilation<C> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("TieredCompilation")
+            .field("compilation_started", &self.compilation_started)
+            .field("compiled_program", &self.compiled_program.get())
+            .finish()
+    }
+}
+
+impl<C: ContextObject> PartialEq for TieredCompilation<C> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self as *const _, other as *const _)
+    }
+}
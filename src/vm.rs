@@ -17,22 +17,33 @@ use crate::{
     elf::Executable,
     error::{EbpfError, ProgramResult},
     interpreter::Interpreter,
-    memory_region::MemoryMapping,
+    memory_region::{AccessType, MemoryAccess, MemoryMapping, MemoryState},
     program::{BuiltinFunction, BuiltinProgram, FunctionRegistry, SBPFVersion},
     static_analysis::{Analysis, TraceLogEntry},
+    syscalls::{HeapAllocator, HeapLimits},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    time::{Duration, Instant},
 };
-use std::{collections::BTreeMap, fmt::Debug};
 
 #[cfg(not(feature = "shuttle-test"))]
 use {
     rand::{thread_rng, Rng},
-    std::sync::Arc,
+    std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 #[cfg(feature = "shuttle-test")]
 use shuttle::{
     rand::{thread_rng, Rng},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 /// Shift the RUNTIME_ENVIRONMENT_KEY by this many bits to the LSB
@@ -62,10 +73,37 @@ pub struct Config {
     pub instruction_meter_checkpoint_distance: usize,
     /// Enable instruction meter and limiting
     pub enable_instruction_meter: bool,
+    /// Units the instruction meter charges for each opcode
+    pub instruction_costs: InstructionCosts,
+    /// Suspend with EbpfError::ExecutionSuspended instead of failing when the instruction meter runs out
+    pub enable_resumable_execution: bool,
+    /// Let the JIT stop at the same instruction and report the same instruction count as the interpreter when the instruction meter runs out
+    ///
+    /// The JIT validates the instruction meter once for every run of instructions without branches
+    /// and hands the execution over to the interpreter if the budget does not cover the entire run.
+    /// The interpreter hands the execution back to the JIT at the end of that run.
+    pub enable_exact_instruction_meter: bool,
+    /// Poll the [CancellationHandle] of the EbpfVm and stop with EbpfError::ExecutionCancelled once it is cancelled
+    pub enable_cancellation_checks: bool,
     /// Enable instruction tracing
     pub enable_instruction_tracing: bool,
+    /// Collect [ExecutionStats], see [EbpfVm::execute_program_with_stats]
+    pub enable_execution_stats: bool,
+    /// Report the loads and stores of the program to the watchpoints of the MemoryMapping, see [MemoryMapping::add_watchpoint]
+    ///
+    /// Like tracing, this makes the JIT translate every address in Rust and keep all bounds checks.
+    pub enable_memory_watchpoints: bool,
     /// Enable dynamic string allocation for labels
     pub enable_symbol_and_section_labels: bool,
+    /// Publish the functions of JIT compiled programs to host profilers through a perf map and the GDB JIT interface
+    ///
+    /// Functions are named after the function registry, see `enable_symbol_and_section_labels`.
+    /// GDB only finds the functions if the crate is built with the `gdb-jit-interface` feature.
+    pub enable_profiler_symbols: bool,
+    /// Number of function invocations after which the whole program switches from the interpreter to JIT compiled code (0 = OFF)
+    ///
+    /// Only applies to executables prepared with [Executable::enable_tiered_compilation].
+    pub tiered_compilation_threshold: u64,
     /// Reject ELF files containing issues that the verifier did not catch before (up to v0.2.21)
     pub reject_broken_elfs: bool,
     /// Ratio of native host instructions per random no-op in JIT (0 = OFF)
@@ -76,6 +114,16 @@ pub struct Config {
     pub optimize_rodata: bool,
     /// Use aligned memory mapping
     pub aligned_memory_mapping: bool,
+    /// Let the JIT skip the bounds checks of memory accesses which static analysis proves to be safe
+    ///
+    /// These are accesses to the current stack frame through `r10` under fixed stack frames and
+    /// accesses which repeat an earlier access through the same unchanged register inside of a basic
+    /// block. Off by default as the analysis increases the compilation time. When the JIT accesses
+    /// the stack frames directly, it marks the stack region as written and its pages as dirty from the
+    /// current stack frame on, see [MemoryRegion::written_range](crate::memory_region::MemoryRegion::written_range).
+    /// JIT runs fail with `EbpfError::IncompatibleStackRegion` if the stack region does not have the
+    /// layout which the JIT compiled program expects, see [EbpfVm::execute_program].
+    pub eliminate_redundant_bounds_checks: bool,
     /// Allowed [SBPFVersion]s
     pub enabled_sbpf_versions: std::ops::RangeInclusive<SBPFVersion>,
 }
@@ -96,18 +144,75 @@ impl Default for Config {
             enable_stack_frame_gaps: true,
             instruction_meter_checkpoint_distance: 10000,
             enable_instruction_meter: true,
+            instruction_costs: InstructionCosts::default(),
+            enable_resumable_execution: false,
+            enable_exact_instruction_meter: false,
+            enable_cancellation_checks: false,
             enable_instruction_tracing: false,
+            enable_execution_stats: false,
+            enable_memory_watchpoints: false,
             enable_symbol_and_section_labels: false,
+            enable_profiler_symbols: false,
+            tiered_compilation_threshold: 0,
             reject_broken_elfs: false,
             noop_instruction_rate: 256,
             sanitize_user_provided_values: true,
             optimize_rodata: true,
             aligned_memory_mapping: true,
+            eliminate_redundant_bounds_checks: false,
             enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V3,
         }
     }
 }
 
+/// Cost model of the instruction meter
+///
+/// Maps every opcode to the number of units the instruction meter charges for executing it.
+/// By default every instruction costs one unit. Costs of zero are rejected, so that every
+/// instruction makes progress towards the limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionCosts([u32; 256]);
+
+impl Default for InstructionCosts {
+    fn default() -> Self {
+        Self([1; 256])
+    }
+}
+
+impl InstructionCosts {
+    /// Sets the cost of a single opcode
+    ///
+    /// Panics if `cost` is zero.
+    pub fn with_opcode_cost(mut self, opc: u8, cost: u32) -> Self {
+        assert!(cost > 0, "instructions must cost at least one unit");
+        self.0[opc as usize] = cost;
+        self
+    }
+
+    /// Sets the cost of all opcodes of an instruction class, e.g. `ebpf::BPF_PQR`
+    ///
+    /// Panics if `cost` is zero.
+    pub fn with_class_cost(mut self, class: u8, cost: u32) -> Self {
+        assert!(cost > 0, "instructions must cost at least one unit");
+        for (opc, opc_cost) in self.0.iter_mut().enumerate() {
+            if opc as u8 & 0x07 == class {
+                *opc_cost = cost;
+            }
+        }
+        self
+    }
+
+    /// Returns the cost of an opcode
+    pub fn get(&self, opc: u8) -> u64 {
+        self.0[opc as usize] as u64
+    }
+
+    /// Returns true if every instruction costs one unit
+    pub fn is_uniform(&self) -> bool {
+        self.0.iter().all(|cost| *cost == 1)
+    }
+}
+
 /// Static constructors for Executable
 impl<C: ContextObject> Executable<C> {
     /// Creates an executable from an ELF file
@@ -127,14 +232,304 @@ impl<C: ContextObject> Executable<C> {
     }
 }
 
+/// Event reported to [ContextObject::trace_event] when tracing is enabled
+///
+/// Every event carries the pc of the instruction which caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// A load or store which was translated successfully
+    ///
+    /// Only reported if address translation is enabled.
+    MemoryAccess {
+        /// Program counter
+        pc: u64,
+        /// Load or store
+        access_type: AccessType,
+        /// Virtual address of the access
+        vm_addr: u64,
+        /// Number of bytes accessed
+        len: u64,
+        /// Value which was loaded or stored, zero extended
+        value: u64,
+        /// Start address of the MemoryRegion which was accessed
+        region_vm_addr: u64,
+    },
+    /// A syscall is about to be invoked
+    SyscallEntry {
+        /// Program counter
+        pc: u64,
+        /// Contents of r1 to r5
+        arguments: [u64; 5],
+    },
+    /// A syscall returned without an error
+    SyscallExit {
+        /// Program counter
+        pc: u64,
+        /// Value which is written to r0
+        result: u64,
+    },
+    /// A BPF to BPF call entered a new frame
+    Call {
+        /// Program counter
+        pc: u64,
+        /// Program counter of the callee
+        target_pc: u64,
+        /// Call depth after entering the callee
+        call_depth: u64,
+    },
+    /// A BPF to BPF call returned to its caller
+    Return {
+        /// Program counter
+        pc: u64,
+        /// Call depth after leaving the callee
+        call_depth: u64,
+    },
+    /// The execution was aborted
+    Exception {
+        /// Program counter
+        pc: u64,
+        /// Description of the EbpfError
+        error: String,
+    },
+}
+
+/// Location of the instruction at which an execution failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultLocation {
+    /// Instruction index (pc) of the faulting instruction
+    pub pc: usize,
+    /// Virtual address of the faulting instruction
+    pub vm_addr: u64,
+    /// Name of the function containing the faulting instruction
+    pub function_name: String,
+}
+
+impl FaultLocation {
+    /// Locates the instruction at `pc` in the executable
+    pub fn new<C: ContextObject>(executable: &Executable<C>, pc: usize) -> Self {
+        let (text_vm_addr, _text_bytes) = executable.get_text_bytes();
+        let function_pc = executable
+            .get_function_registry()
+            .iter()
+            .map(|(_key, (_name, function_pc))| function_pc)
+            .filter(|function_pc| *function_pc <= pc)
+            .max()
+            .unwrap_or(0);
+        Self {
+            pc,
+            vm_addr: text_vm_addr
+                .saturating_add((pc as u64).saturating_mul(ebpf::INSN_SIZE as u64)),
+            function_name: function_name(executable, function_pc),
+        }
+    }
+}
+
+impl std::fmt::Display for FaultLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} ({:#x}) in {}",
+            self.pc, self.vm_addr, self.function_name
+        )
+    }
+}
+
+/// Name of the function starting at `function_pc`, falls back to `function_{pc}` if it has none
+fn function_name<C: ContextObject>(executable: &Executable<C>, function_pc: usize) -> String {
+    executable
+        .get_function_registry()
+        .iter()
+        .find(|(_key, (name, pc))| *pc == function_pc && !name.is_empty())
+        .map(|(_key, (name, _pc))| String::from_utf8_lossy(name).to_string())
+        .unwrap_or_else(|| format!("function_{}", function_pc))
+}
+
+/// Statistics about an execution, collected if `Config::enable_execution_stats` is set
+///
+/// Instruction counts are measured in the units of the instruction meter and are only
+/// collected if `Config::enable_instruction_meter` is set. Memory accesses are only
+/// collected if `Config::enable_address_translation` is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionStats {
+    /// Per function, keyed by the pc of the first instruction of the function
+    pub functions: BTreeMap<usize, FunctionStats>,
+    /// Maximum call depth reached
+    pub max_call_depth: u64,
+    /// Per MemoryRegion, keyed by the start address of the region
+    pub memory_regions: BTreeMap<u64, MemoryRegionStats>,
+    /// Per syscall, keyed by the name it was registered under
+    pub syscalls: BTreeMap<String, SyscallStats>,
+    /// Number of copy on write regions which were copied
+    pub cow_copies: u64,
+}
+
+/// Statistics about a function, see [ExecutionStats]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionStats {
+    /// Name of the function in the FunctionRegistry of the executable
+    pub name: String,
+    /// Number of times the function was entered
+    pub invocations: u64,
+    /// Instructions executed in the function itself, excluding its callees and syscalls
+    pub instruction_count: u64,
+}
+
+/// Statistics about a MemoryRegion, see [ExecutionStats]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryRegionStats {
+    /// Number of bytes loaded
+    pub bytes_read: u64,
+    /// Number of bytes stored
+    pub bytes_written: u64,
+}
+
+/// Statistics about a syscall, see [ExecutionStats]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyscallStats {
+    /// Number of invocations
+    pub invocations: u64,
+    /// Units the syscall consumed from the instruction meter
+    pub consumed_units: u64,
+}
+
+/// Accumulates the [ExecutionStats] while a program runs
+pub(crate) struct ExecutionStatsCollector {
+    stats: ExecutionStats,
+    /// Instruction meter at the start of the execution
+    initial_remaining: u64,
+    /// Instructions executed until the last call or return
+    last_instruction_count: u64,
+    /// Units consumed by syscalls since the last call or return
+    pending_syscall_units: u64,
+    /// Entry pcs of the active functions
+    function_stack: Vec<usize>,
+    /// Syscalls keyed by their function pointer, resolved to names in finish()
+    syscalls: BTreeMap<usize, SyscallStats>,
+    /// Number of regions which were not copied yet at the start of the execution
+    cow_regions: u64,
+}
+
+impl ExecutionStatsCollector {
+    pub(crate) fn new(root_pc: usize, initial_remaining: u64, cow_regions: u64) -> Self {
+        let mut collector = Self {
+            stats: ExecutionStats::default(),
+            initial_remaining,
+            last_instruction_count: 0,
+            pending_syscall_units: 0,
+            function_stack: Vec::new(),
+            syscalls: BTreeMap::new(),
+            cow_regions,
+        };
+        collector.enter_function(root_pc);
+        collector
+    }
+
+    fn enter_function(&mut self, pc: usize) {
+        self.function_stack.push(pc);
+        self.stats.functions.entry(pc).or_default().invocations += 1;
+        self.stats.max_call_depth = self
+            .stats
+            .max_call_depth
+            .max(self.function_stack.len().saturating_sub(1) as u64);
+    }
+
+    /// Attributes everything executed since the last call or return to the active function
+    fn attribute_instructions(&mut self, remaining: u64) {
+        let instruction_count = self.initial_remaining.saturating_sub(remaining);
+        let executed = instruction_count
+            .saturating_sub(self.last_instruction_count)
+            .saturating_sub(self.pending_syscall_units);
+        self.last_instruction_count = instruction_count;
+        self.pending_syscall_units = 0;
+        if let Some(pc) = self.function_stack.last() {
+            let function = self.stats.functions.entry(*pc).or_default();
+            function.instruction_count = function.instruction_count.saturating_add(executed);
+        }
+    }
+
+    /// Records a BPF to BPF call, `remaining` is the instruction meter after the call instruction
+    pub(crate) fn call(&mut self, target_pc: usize, remaining: u64) {
+        self.attribute_instructions(remaining);
+        self.enter_function(target_pc);
+    }
+
+    /// Records a return from a BPF to BPF call, `remaining` is the instruction meter after the exit instruction
+    pub(crate) fn ret(&mut self, remaining: u64) {
+        self.attribute_instructions(remaining);
+        self.function_stack.pop();
+    }
+
+    /// Records a syscall which consumed `consumed_units` from the instruction meter
+    pub(crate) fn syscall(&mut self, function: usize, consumed_units: u64) {
+        self.pending_syscall_units = self.pending_syscall_units.saturating_add(consumed_units);
+        let syscall = self.syscalls.entry(function).or_default();
+        syscall.invocations += 1;
+        syscall.consumed_units = syscall.consumed_units.saturating_add(consumed_units);
+    }
+
+    /// Records a successful load or store
+    pub(crate) fn memory_access(&mut self, region_vm_addr: u64, access_type: AccessType, len: u64) {
+        let region = self.stats.memory_regions.entry(region_vm_addr).or_default();
+        match access_type {
+            AccessType::Load => region.bytes_read = region.bytes_read.saturating_add(len),
+            AccessType::Store => region.bytes_written = region.bytes_written.saturating_add(len),
+        }
+    }
+
+    /// Resolves function and syscall names and returns the final statistics
+    pub(crate) fn finish<C: ContextObject>(
+        mut self,
+        executable: &Executable<C>,
+        remaining: u64,
+        cow_regions: u64,
+    ) -> ExecutionStats {
+        self.attribute_instructions(remaining);
+        for (pc, function) in self.stats.functions.iter_mut() {
+            function.name = function_name(executable, *pc);
+        }
+        let loader_registry = executable
+            .get_loader()
+            .get_function_registry(executable.get_sbpf_version());
+        for (function, syscall) in self.syscalls {
+            let name = loader_registry
+                .iter()
+                .find(|(_key, (_name, builtin))| *builtin as usize == function)
+                .map(|(_key, (name, _builtin))| String::from_utf8_lossy(name).to_string())
+                .unwrap_or_else(|| format!("{:#x}", function));
+            self.stats.syscalls.insert(name, syscall);
+        }
+        self.stats.cow_copies = self.cow_regions.saturating_sub(cow_regions);
+        self.stats
+    }
+}
+
+/// Number of copy on write regions which were not copied yet
+fn count_cow_regions(memory_mapping: &MemoryMapping) -> u64 {
+    memory_mapping
+        .get_regions()
+        .iter()
+        .filter(|region| matches!(region.state.get(), MemoryState::Cow(_)))
+        .count() as u64
+}
+
 /// Runtime context
 pub trait ContextObject {
     /// Called for every instruction executed when tracing is enabled
     fn trace(&mut self, state: [u64; 12]);
+    /// Called for every memory access, syscall, call, return and exception when tracing is enabled
+    fn trace_event(&mut self, _event: &TraceEvent) {}
     /// Consume instructions from meter
     fn consume(&mut self, amount: u64);
     /// Get the number of remaining instructions allowed
     fn get_remaining(&self) -> u64;
+    /// Allocator used by the built-in heap syscalls, if any
+    fn heap_allocator(&mut self) -> Option<&mut HeapAllocator> {
+        None
+    }
+    /// Limits of the heap when it is resized by the built-in heap syscall, if any
+    fn heap_limits(&self) -> Option<HeapLimits> {
+        None
+    }
 }
 
 /// Simple instruction meter for testing
@@ -142,8 +537,14 @@ pub trait ContextObject {
 pub struct TestContextObject {
     /// Contains the register state at every instruction in order of execution
     pub trace_log: Vec<TraceLogEntry>,
+    /// Contains the trace events, each with the index of the trace_log entry it belongs to
+    pub event_log: Vec<(usize, TraceEvent)>,
     /// Maximal amount of instructions which still can be executed
     pub remaining: u64,
+    /// Allocator used by the built-in heap syscalls
+    pub heap_allocator: Option<HeapAllocator>,
+    /// Limits of the heap when it is resized by the built-in heap syscall
+    pub heap_limits: Option<HeapLimits>,
 }
 
 impl ContextObject for TestContextObject {
@@ -151,6 +552,11 @@ impl ContextObject for TestContextObject {
         self.trace_log.push(state);
     }
 
+    fn trace_event(&mut self, event: &TraceEvent) {
+        self.event_log
+            .push((self.trace_log.len().saturating_sub(1), event.clone()));
+    }
+
     fn consume(&mut self, amount: u64) {
         self.remaining = self.remaining.saturating_sub(amount);
     }
@@ -158,6 +564,14 @@ impl ContextObject for TestContextObject {
     fn get_remaining(&self) -> u64 {
         self.remaining
     }
+
+    fn heap_allocator(&mut self) -> Option<&mut HeapAllocator> {
+        self.heap_allocator.as_mut()
+    }
+
+    fn heap_limits(&self) -> Option<HeapLimits> {
+        self.heap_limits
+    }
 }
 
 impl TestContextObject {
@@ -165,20 +579,35 @@ impl TestContextObject {
     pub fn new(remaining: u64) -> Self {
         Self {
             trace_log: Vec::new(),
+            event_log: Vec::new(),
             remaining,
+            heap_allocator: None,
+            heap_limits: None,
         }
     }
 
     /// Compares an interpreter trace and a JIT trace.
     ///
     /// The log of the JIT can be longer because it only validates the instruction meter at branches.
+    /// Exceptions are not compared as the JIT can report them at a later pc for the same reason.
     pub fn compare_trace_log(interpreter: &Self, jit: &Self) -> bool {
-        let interpreter = interpreter.trace_log.as_slice();
-        let mut jit = jit.trace_log.as_slice();
-        if jit.len() > interpreter.len() {
-            jit = &jit[0..interpreter.len()];
+        let trace_log_len = interpreter.trace_log.len();
+        let events = |context_object: &Self| {
+            context_object
+                .event_log
+                .iter()
+                .filter(|(index, event)| {
+                    *index < trace_log_len && !matches!(event, TraceEvent::Exception { .. })
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let interpreter_trace_log = interpreter.trace_log.as_slice();
+        let mut jit_trace_log = jit.trace_log.as_slice();
+        if jit_trace_log.len() > trace_log_len {
+            jit_trace_log = &jit_trace_log[0..trace_log_len];
         }
-        interpreter == jit
+        interpreter_trace_log == jit_trace_log && events(interpreter) == events(jit)
     }
 }
 
@@ -216,8 +645,70 @@ impl DynamicAnalysis {
     }
 }
 
+/// Number of instructions (Interpreter) or instruction meter checkpoints (JIT) between two polls of the [CancellationHandle]
+const CANCELLATION_POLL_INTERVAL: u64 = 64;
+
+/// Stops a running program from another thread
+///
+/// All clones share the same cancellation flag. The VM polls the handle when
+/// `Config::enable_cancellation_checks` is set and ends the execution with
+/// `EbpfError::ExecutionCancelled` once the handle was cancelled or its deadline passed.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationHandle {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationHandle {
+    /// Creates a handle which is only cancelled by [CancellationHandle::cancel]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a handle which is cancelled automatically once the deadline passed
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self {
+            cancelled: Arc::default(),
+            deadline: Some(deadline),
+        }
+    }
+
+    /// Creates a handle which is cancelled automatically once the timeout elapsed
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self::with_deadline(Instant::now() + timeout)
+    }
+
+    /// Requests all executions polling this handle to stop
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns the deadline, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns true if the handle was cancelled or its deadline passed
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self
+                .deadline
+                .map(|deadline| Instant::now() >= deadline)
+                .unwrap_or(false)
+    }
+}
+
+/// Selects a function from the FunctionRegistry of an Executable, see [EbpfVm::execute_function]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionSelector<'a> {
+    /// The key the function was registered under
+    Key(u32),
+    /// The name the function was registered under
+    Name(&'a [u8]),
+}
+
 /// A call frame used for function calls inside the Interpreter
-#[derive(Clone, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct CallFrame {
     /// The caller saved registers
     pub caller_saved_registers: [u64; ebpf::SCRATCH_REGS],
@@ -297,6 +788,10 @@ pub struct EbpfVm<'a, C: ContextObject> {
     pub stopwatch_numerator: u64,
     /// Number of times the stop watch was used
     pub stopwatch_denominator: u64,
+    /// Remaining polls until the cancellation_handle is checked next
+    pub cancellation_countdown: u64,
+    /// Host address of the stack region, see `Config::eliminate_redundant_bounds_checks`
+    pub(crate) stack_host_address: u64,
     /// Registers inlined
     pub registers: [u64; 12],
     /// ProgramResult inlined
@@ -307,6 +802,21 @@ pub struct EbpfVm<'a, C: ContextObject> {
     pub call_frames: Vec<CallFrame>,
     /// Loader built-in program
     pub loader: Arc<BuiltinProgram<C>>,
+    /// Allows stopping the execution from another thread, see `Config::enable_cancellation_checks`
+    pub cancellation_handle: Option<CancellationHandle>,
+    /// Statistics of the current execution, see `Config::enable_execution_stats`
+    pub(crate) execution_stats: Option<ExecutionStatsCollector>,
+    /// Part of the remaining budget of the ContextObject which the instruction meter must not use
+    ///
+    /// Lets [crate::lockstep] stop the execution at the next JIT meter checkpoint.
+    pub(crate) instruction_meter_reserve: u64,
+    /// Units of the current execution which exceeded the remaining budget of the ContextObject
+    ///
+    /// The JIT only notices an exhausted budget at its checkpoints, so it can run past it. These
+    /// units are still part of the reported instruction count.
+    pub(crate) uncovered_insn_count: u64,
+    /// Frame pointer of the root frame, which every execution starts with
+    pub(crate) initial_frame_pointer: u64,
     /// TCP port for the debugger interface
     #[cfg(feature = "debugger")]
     pub debug_port: Option<u16>,
@@ -315,13 +825,34 @@ pub struct EbpfVm<'a, C: ContextObject> {
 impl<'a, C: ContextObject> EbpfVm<'a, C> {
     /// Creates a new virtual machine instance.
     pub fn new(
+        loader: Arc<BuiltinProgram<C>>,
+        sbpf_version: SBPFVersion,
+        context_object: &'a mut C,
+        memory_mapping: MemoryMapping<'a>,
+        stack_len: usize,
+    ) -> Self {
+        let call_frames = vec![CallFrame::default(); loader.get_config().max_call_depth];
+        Self::new_with_call_frames(
+            loader,
+            sbpf_version,
+            context_object,
+            memory_mapping,
+            stack_len,
+            call_frames,
+        )
+    }
+
+    /// Creates a new virtual machine instance which reuses the given call frames
+    pub(crate) fn new_with_call_frames(
         loader: Arc<BuiltinProgram<C>>,
         sbpf_version: SBPFVersion,
         context_object: &'a mut C,
         mut memory_mapping: MemoryMapping<'a>,
         stack_len: usize,
+        call_frames: Vec<CallFrame>,
     ) -> Self {
         let config = loader.get_config();
+        debug_assert!(call_frames.len() >= config.max_call_depth);
         let mut registers = [0u64; 12];
         registers[ebpf::FRAME_PTR_REG] =
             ebpf::MM_STACK_START.saturating_add(if sbpf_version.dynamic_stack_frames() {
@@ -342,11 +873,18 @@ impl<'a, C: ContextObject> EbpfVm<'a, C> {
             due_insn_count: 0,
             stopwatch_numerator: 0,
             stopwatch_denominator: 0,
+            cancellation_countdown: 0,
+            stack_host_address: 0,
             registers,
             program_result: ProgramResult::Ok(0),
             memory_mapping,
-            call_frames: vec![CallFrame::default(); config.max_call_depth],
+            call_frames,
             loader,
+            cancellation_handle: None,
+            execution_stats: None,
+            instruction_meter_reserve: 0,
+            uncovered_insn_count: 0,
+            initial_frame_pointer: registers[ebpf::FRAME_PTR_REG],
             #[cfg(feature = "debugger")]
             debug_port: None,
         }
@@ -354,38 +892,207 @@ impl<'a, C: ContextObject> EbpfVm<'a, C> {
 
     /// Execute the program
     ///
-    /// If interpreted = `false` then the JIT compiled executable is used.
+    /// If interpreted = `false` then the JIT compiled executable is used. With
+    /// `Config::eliminate_redundant_bounds_checks` this fails with
+    /// `EbpfError::IncompatibleStackRegion` if the stack region does not fit the JIT compiled program.
     pub fn execute_program(
         &mut self,
         executable: &Executable<C>,
         interpreted: bool,
     ) -> (u64, ProgramResult) {
         debug_assert!(Arc::ptr_eq(&self.loader, executable.get_loader()));
-        self.registers[1] = ebpf::MM_INPUT_START;
+        self.enter_entrypoint(executable);
+        self.run(executable, interpreted)
+    }
+
+    /// Sets up the registers and the call depth for an execution starting at the entrypoint
+    pub(crate) fn enter_entrypoint(&mut self, executable: &Executable<C>) {
+        self.reset_registers([ebpf::MM_INPUT_START, 0, 0, 0, 0]);
         self.registers[11] = executable.get_entrypoint_instruction_offset() as u64;
+        self.call_depth = 0;
+    }
+
+    /// Execute the program and return the [ExecutionStats] alongside the result
+    ///
+    /// The stats are only collected if `Config::enable_execution_stats` is set.
+    /// If interpreted = `false` then the JIT compiled executable is used.
+    pub fn execute_program_with_stats(
+        &mut self,
+        executable: &Executable<C>,
+        interpreted: bool,
+    ) -> (u64, ProgramResult, Option<ExecutionStats>) {
+        let (instruction_count, result) = self.execute_program(executable, interpreted);
+        let remaining = if executable.get_config().enable_instruction_meter {
+            self.context_object_pointer.get_remaining()
+        } else {
+            0
+        };
+        let execution_stats = self.execution_stats.take().map(|execution_stats| {
+            execution_stats.finish(
+                executable,
+                remaining,
+                count_cow_regions(&self.memory_mapping),
+            )
+        });
+        (instruction_count, result, execution_stats)
+    }
+
+    /// Invoke a function of the executable with the given arguments in r1 to r5
+    ///
+    /// The function is looked up in the FunctionRegistry of the executable by its key or name.
+    /// Functions of an ELF only have names if `Config::enable_symbol_and_section_labels` is set.
+    /// If interpreted = `false` then the JIT compiled executable is used.
+    /// Returns the instruction count, the result (r0 on success) and the final register file.
+    pub fn execute_function(
+        &mut self,
+        executable: &Executable<C>,
+        function: FunctionSelector,
+        arguments: [u64; 5],
+        interpreted: bool,
+    ) -> (u64, ProgramResult, [u64; 12]) {
+        debug_assert!(Arc::ptr_eq(&self.loader, executable.get_loader()));
+        let function_registry = executable.get_function_registry();
+        let target_pc = match function {
+            FunctionSelector::Key(key) => function_registry.lookup_by_key(key),
+            FunctionSelector::Name(name) => function_registry.lookup_by_name(name),
+        };
+        let Some((_name, target_pc)) = target_pc else {
+            let error = EbpfError::FunctionNotRegistered(match function {
+                FunctionSelector::Key(key) => format!("#{}", key),
+                FunctionSelector::Name(name) => String::from_utf8_lossy(name).to_string(),
+            });
+            return (0, ProgramResult::Err(error), self.registers);
+        };
+        self.reset_registers(arguments);
+        self.registers[11] = target_pc as u64;
+        self.call_depth = 0;
+        let (instruction_count, result) = self.run(executable, interpreted);
+        (instruction_count, result, self.registers)
+    }
+
+    /// Clears the registers left over by a previous execution and points the frame pointer to the root frame
+    ///
+    /// A suspended execution leaves the frame pointer of a deeper frame behind.
+    fn reset_registers(&mut self, arguments: [u64; 5]) {
+        self.registers[0] = 0;
+        self.registers[1..6].copy_from_slice(&arguments);
+        self.registers[6..ebpf::FRAME_PTR_REG].fill(0);
+        self.registers[ebpf::FRAME_PTR_REG] = self.initial_frame_pointer;
+    }
+
+    /// Resume a program which was suspended with `EbpfError::ExecutionSuspended`
+    ///
+    /// Continues at the pc in `registers[11]` with the `call_frames` of the suspended execution
+    /// and whatever budget the ContextObject has remaining now. The suspended state can be
+    /// resumed in the interpreter and in the JIT, regardless of which one suspended it.
+    /// The JIT only suspends at its checkpoints, so it can run past the budget. The returned
+    /// instruction count includes these instructions.
+    pub fn resume_program(
+        &mut self,
+        executable: &Executable<C>,
+        interpreted: bool,
+    ) -> (u64, ProgramResult) {
+        debug_assert!(Arc::ptr_eq(&self.loader, executable.get_loader()));
+        debug_assert!(executable.get_config().enable_resumable_execution);
+        self.run(executable, interpreted)
+    }
+
+    fn run(&mut self, executable: &Executable<C>, interpreted: bool) -> (u64, ProgramResult) {
         let config = executable.get_config();
         let initial_insn_count = if config.enable_instruction_meter {
             self.context_object_pointer.get_remaining()
         } else {
             0
         };
-        self.previous_instruction_meter = initial_insn_count;
+        self.previous_instruction_meter =
+            initial_insn_count.saturating_sub(self.instruction_meter_reserve);
         self.due_insn_count = 0;
+        // Poll the cancellation handle right at the first opportunity
+        self.cancellation_countdown = 1;
         self.program_result = ProgramResult::Ok(0);
+        self.uncovered_insn_count = 0;
+        self.execution_stats = config.enable_execution_stats.then(|| {
+            ExecutionStatsCollector::new(
+                self.registers[11] as usize,
+                initial_insn_count,
+                count_cow_regions(&self.memory_mapping),
+            )
+        });
+        #[cfg(all(
+            feature = "jit",
+            not(target_os = "windows"),
+            any(
+                target_arch = "x86_64",
+                all(target_arch = "aarch64", feature = "jit-aarch64")
+            )
+        ))]
+        let may_tier_up = !interpreted;
+        #[cfg(all(
+            feature = "jit",
+            not(target_os = "windows"),
+            any(
+                target_arch = "x86_64",
+                all(target_arch = "aarch64", feature = "jit-aarch64")
+            )
+        ))]
+        if may_tier_up && !self.prepare_stack_frames(executable) {
+            return (0, ProgramResult::Err(EbpfError::IncompatibleStackRegion));
+        }
+        #[cfg(all(
+            feature = "jit",
+            not(target_os = "windows"),
+            any(
+                target_arch = "x86_64",
+                all(target_arch = "aarch64", feature = "jit-aarch64")
+            )
+        ))]
+        let interpreted = interpreted
+            || executable.is_compiling_in_background()
+            || executable.awaits_tiered_compilation();
         if interpreted {
             #[cfg(feature = "debugger")]
             let debug_port = self.debug_port.clone();
             let mut interpreter = Interpreter::new(self, executable, self.registers);
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
+            {
+                interpreter.may_tier_up = may_tier_up;
+            }
+            interpreter.enter_function();
             #[cfg(feature = "debugger")]
             if let Some(debug_port) = debug_port {
                 crate::debugger::execute(&mut interpreter, debug_port);
             } else {
-                while interpreter.step() {}
+                while !interpreter.tier_up && interpreter.step() {}
             }
             #[cfg(not(feature = "debugger"))]
-            while interpreter.step() {}
+            while !interpreter.tier_up && interpreter.step() {}
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
+            if interpreter.tier_up {
+                self.tier_up(executable);
+            }
         } else {
-            #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+            #[cfg(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            ))]
             {
                 let compiled_program = match executable
                     .get_compiled_program()
@@ -394,24 +1101,333 @@ impl<'a, C: ContextObject> EbpfVm<'a, C> {
                     Ok(compiled_program) => compiled_program,
                     Err(error) => return (0, ProgramResult::Err(error)),
                 };
-                compiled_program.invoke(config, self, self.registers);
+                self.run_compiled_program(executable, compiled_program);
             }
-            #[cfg(not(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64")))]
+            #[cfg(not(all(
+                feature = "jit",
+                not(target_os = "windows"),
+                any(
+                    target_arch = "x86_64",
+                    all(target_arch = "aarch64", feature = "jit-aarch64")
+                )
+            )))]
             {
                 return (0, ProgramResult::Err(EbpfError::JitNotCompiled));
             }
         };
+        // The JIT does not write back the frame pointer when it exits the root frame, so it could
+        // still be the one of a deeper frame at which the interpreter handed over
+        if self.program_result.is_ok() {
+            self.registers[ebpf::FRAME_PTR_REG] = self.initial_frame_pointer;
+        }
         let instruction_count = if config.enable_instruction_meter {
-            self.context_object_pointer.consume(self.due_insn_count);
-            initial_insn_count.saturating_sub(self.context_object_pointer.get_remaining())
+            self.charge_due_insn_count();
+            // Running out of budget consumes all of it, but not more
+            if matches!(
+                self.program_result,
+                ProgramResult::Err(EbpfError::ExceededMaxInstructions)
+            ) {
+                self.uncovered_insn_count = 0;
+            }
+            initial_insn_count
+                .saturating_sub(self.context_object_pointer.get_remaining())
+                .saturating_add(self.uncovered_insn_count)
         } else {
             0
         };
+        if config.enable_instruction_tracing {
+            if let ProgramResult::Err(error) = &self.program_result {
+                let event = TraceEvent::Exception {
+                    pc: self.registers[11],
+                    error: error.to_string(),
+                };
+                self.context_object_pointer.trace_event(&event);
+            }
+        }
         let mut result = ProgramResult::Ok(0);
         std::mem::swap(&mut result, &mut self.program_result);
+        // Suspension and cancellation are not faults of the program
+        if let ProgramResult::Err(error) = result {
+            result = ProgramResult::Err(match error {
+                EbpfError::ExecutionSuspended | EbpfError::ExecutionCancelled => error,
+                error => EbpfError::Fault(
+                    Box::new(error),
+                    FaultLocation::new(executable, self.registers[11] as usize),
+                ),
+            });
+        }
         (instruction_count, result)
     }
 
+    /// Continues an interpreted execution in the JIT compiled program
+    ///
+    /// The call frames of the interpreter are taken over by the JIT, see [Executable::enable_tiered_compilation].
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    fn tier_up(&mut self, executable: &Executable<C>) {
+        // The JIT starts metering from the remaining budget
+        self.settle_instruction_meter(executable.get_config());
+        if let Some(compiled_program) = executable.get_compiled_program() {
+            self.run_compiled_program(executable, compiled_program);
+        }
+    }
+
+    /// Continues the execution at `registers[11]` in the JIT compiled program
+    ///
+    /// With `Config::enable_exact_instruction_meter` the JIT stops at the start of the run of
+    /// instructions which the budget does not cover entirely and the interpreter executes that run
+    /// instruction by instruction. The same happens if the budget might not cover the rest of the
+    /// run at which the execution is resumed. The JIT takes over again after the next branch.
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    fn run_compiled_program(
+        &mut self,
+        executable: &Executable<C>,
+        compiled_program: &crate::jit::JitProgram,
+    ) {
+        let config = executable.get_config();
+        let meters_exactly = crate::jit::meters_exactly(config);
+        let mut interpreted = meters_exactly
+            && self.previous_instruction_meter < compiled_program.max_checkpoint_interval_cost;
+        loop {
+            if !interpreted {
+                compiled_program.invoke(config, self, self.registers);
+                if !meters_exactly
+                    || !matches!(
+                        self.program_result,
+                        ProgramResult::Err(EbpfError::ExecutionSuspended)
+                    )
+                {
+                    return;
+                }
+                // The interpreter starts metering from the remaining budget
+                self.settle_instruction_meter(config);
+                self.program_result = ProgramResult::Ok(0);
+            }
+            let mut interpreter = Interpreter::new(self, executable, self.registers);
+            interpreter.may_tier_up = true;
+            interpreter.tier_up_at_checkpoint = meters_exactly;
+            while !interpreter.tier_up && interpreter.step() {}
+            if !interpreter.tier_up {
+                return;
+            }
+            self.settle_instruction_meter(config);
+            interpreted = false;
+        }
+    }
+
+    /// Charges the due instructions, so that the next execution engine meters from the remaining budget
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    fn settle_instruction_meter(&mut self, config: &Config) {
+        if config.enable_instruction_meter {
+            self.charge_due_insn_count();
+            self.previous_instruction_meter = self.get_instruction_meter_budget();
+        }
+        self.due_insn_count = 0;
+    }
+
+    /// Consumes the due instructions and remembers the part which exceeded the remaining budget
+    fn charge_due_insn_count(&mut self) {
+        let remaining = self.context_object_pointer.get_remaining();
+        self.uncovered_insn_count = self
+            .uncovered_insn_count
+            .saturating_add(self.due_insn_count.saturating_sub(remaining));
+        self.context_object_pointer.consume(self.due_insn_count);
+    }
+
+    /// Locates the stack for JIT compiled code which accesses the current stack frame directly
+    ///
+    /// Returns false if the JIT compiled code can not run because the stack region or `r10` do not
+    /// look like the JIT expects or the stack has protected sub-ranges.
+    /// As stores to the stack do not record which bytes they write, the stack region is marked as
+    /// written and its pages as dirty from the current stack frame on.
+    #[cfg(all(
+        feature = "jit",
+        not(target_os = "windows"),
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "jit-aarch64")
+        )
+    ))]
+    fn prepare_stack_frames(&mut self, executable: &Executable<C>) -> bool {
+        let config = executable.get_config();
+        self.stack_host_address = 0;
+        if !crate::jit::accesses_stack_frames_directly(config, executable.get_sbpf_version()) {
+            return true;
+        }
+        let region = match self
+            .memory_mapping
+            .region(AccessType::Load, ebpf::MM_STACK_START)
+        {
+            Ok(region) => region,
+            Err(_) => return false,
+        };
+        let stack_frame_size = config.stack_frame_size as u64;
+        let (vm_gap_shift, frame_distance) = if config.enable_stack_frame_gaps {
+            (
+                stack_frame_size.trailing_zeros() as u8,
+                stack_frame_size * 2,
+            )
+        } else {
+            (63, stack_frame_size)
+        };
+        // r10 has to point to the end of the stack frame of the current call depth
+        let frame_pointer = ebpf::MM_STACK_START
+            .saturating_add(stack_frame_size)
+            .saturating_add(self.call_depth.saturating_mul(frame_distance));
+        if region.vm_addr != ebpf::MM_STACK_START
+            || region.len < config.stack_size() as u64
+            || region.vm_gap_shift != vm_gap_shift
+            || (config.enable_stack_frame_gaps && !stack_frame_size.is_power_of_two())
+            || region.state.get() != MemoryState::Writable
+            || !region.protected_ranges().is_empty()
+            || self.registers[ebpf::FRAME_PTR_REG] != frame_pointer
+        {
+            return false;
+        }
+        // Frames of the callers are only reached through pointers, which are not accessed directly
+        let current_frame_offset = self.call_depth.saturating_mul(stack_frame_size);
+        region.mark_written(
+            region.host_addr.get().saturating_add(current_frame_offset),
+            region.len.saturating_sub(current_frame_offset),
+        );
+        self.stack_host_address = region.host_addr.get();
+        true
+    }
+
+    /// Returns true if the execution should stop because the cancellation handle was cancelled
+    pub(crate) fn poll_cancellation(&mut self) -> bool {
+        self.cancellation_countdown = CANCELLATION_POLL_INTERVAL;
+        self.cancellation_handle
+            .as_ref()
+            .map(|cancellation_handle| cancellation_handle.is_cancelled())
+            .unwrap_or(false)
+    }
+
+    /// Reports a successful memory access to the ContextObject, the ExecutionStats and the watchpoints
+    ///
+    /// Returns the error of a watchpoint which aborts the execution. Stores were already reported to
+    /// the watchpoints by [Self::watch_store].
+    pub(crate) fn trace_memory_access(
+        &mut self,
+        pc: u64,
+        access_type: AccessType,
+        vm_addr: u64,
+        len: u64,
+        value: u64,
+    ) -> Result<(), EbpfError> {
+        let region_vm_addr = self
+            .memory_mapping
+            .region(AccessType::Load, vm_addr)
+            .map(|region| region.vm_addr)
+            .unwrap_or(0);
+        if let Some(execution_stats) = self.execution_stats.as_mut() {
+            execution_stats.memory_access(region_vm_addr, access_type, len);
+        }
+        if self.loader.get_config().enable_instruction_tracing {
+            self.context_object_pointer
+                .trace_event(&TraceEvent::MemoryAccess {
+                    pc,
+                    access_type,
+                    vm_addr,
+                    len,
+                    value,
+                    region_vm_addr,
+                });
+        }
+        if self.loader.get_config().enable_memory_watchpoints && access_type == AccessType::Load {
+            self.memory_mapping.notify_watchpoints(&MemoryAccess {
+                pc,
+                access_type,
+                vm_addr,
+                len,
+                value,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Reports a store to the watchpoints before it is performed
+    ///
+    /// Returns the error of a watchpoint which prevents the store and aborts the execution.
+    pub(crate) fn watch_store(
+        &self,
+        pc: u64,
+        vm_addr: u64,
+        len: u64,
+        value: u64,
+    ) -> Result<(), EbpfError> {
+        if !self.loader.get_config().enable_memory_watchpoints {
+            return Ok(());
+        }
+        self.memory_mapping.notify_watchpoints(&MemoryAccess {
+            pc,
+            access_type: AccessType::Store,
+            vm_addr,
+            len,
+            value,
+        })
+    }
+
+    /// Remaining budget of the ContextObject which the instruction meter may use
+    ///
+    /// Built-in functions reload `previous_instruction_meter` from this after consuming units.
+    pub fn get_instruction_meter_budget(&self) -> u64 {
+        self.context_object_pointer
+            .get_remaining()
+            .saturating_sub(self.instruction_meter_reserve)
+    }
+
+    /// Instruction meter before a syscall is invoked, excluding the instructions it will consume on behalf of the program
+    ///
+    /// Returns None if no ExecutionStats are collected.
+    pub(crate) fn remaining_before_syscall(&self) -> Option<u64> {
+        self.execution_stats.as_ref()?;
+        let remaining = self.context_object_pointer.get_remaining();
+        Some(if self.loader.get_config().enable_instruction_meter {
+            remaining.saturating_sub(
+                self.previous_instruction_meter
+                    .saturating_sub(self.due_insn_count),
+            )
+        } else {
+            remaining
+        })
+    }
+
+    /// Records the units a syscall consumed in the ExecutionStats
+    pub(crate) fn record_syscall(
+        &mut self,
+        function: BuiltinFunction<C>,
+        remaining_before: Option<u64>,
+    ) {
+        if let (Some(remaining_before), Some(execution_stats)) =
+            (remaining_before, self.execution_stats.as_mut())
+        {
+            let consumed_units =
+                remaining_before.saturating_sub(self.context_object_pointer.get_remaining());
+            execution_stats.syscall(function as usize, consumed_units);
+        }
+    }
+
     /// Invokes a built-in function
     pub fn invoke_function(&mut self, function: BuiltinFunction<C>) {
         function(
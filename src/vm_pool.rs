@@ -0,0 +1,232 @@
+// Copyright 2024 Solana Maintainers <maintainers@solana.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Pool of pre-allocated virtual machines and a parallel batch executor
+//!
+//! A [VmPool] keeps the stacks, heaps and call frames of idle VMs for one [Executable] and
+//! builds the [MemoryMapping] around them for every run. When a run is finished only the bytes
+//! which were written to (see [MemoryRegion::written_range]) are zeroed before the memory is
+//! handed out again.
+
+use crate::{
+    aligned_memory::AlignedMemory,
+    ebpf,
+    elf::Executable,
+    error::{EbpfError, ProgramResult},
+    memory_region::{MemoryMapping, MemoryRegion},
+    vm::{CallFrame, ContextObject, EbpfVm},
+};
+use std::{ops::Range, sync::Mutex};
+
+/// The memory of an idle VM
+struct VmMemory {
+    stack: AlignedMemory<{ ebpf::HOST_ALIGN }>,
+    heap: AlignedMemory<{ ebpf::HOST_ALIGN }>,
+    call_frames: Vec<CallFrame>,
+}
+
+/// A pool of pre-allocated virtual machines for one executable
+pub struct VmPool<'a, C: ContextObject> {
+    executable: &'a Executable<C>,
+    heap_size: usize,
+    idle: Mutex<Vec<VmMemory>>,
+}
+
+impl<'a, C: ContextObject> VmPool<'a, C> {
+    /// Creates a new pool and pre-allocates `capacity` VMs with a heap of `heap_size` bytes each
+    ///
+    /// More VMs are allocated on demand if all of them are in use.
+    pub fn new(executable: &'a Executable<C>, heap_size: usize, capacity: usize) -> Self {
+        let pool = Self {
+            executable,
+            heap_size,
+            idle: Mutex::new(Vec::with_capacity(capacity)),
+        };
+        {
+            let mut idle = pool.idle.lock().unwrap();
+            for _ in 0..capacity {
+                idle.push(pool.allocate());
+            }
+        }
+        pool
+    }
+
+    /// Returns the executable the VMs of this pool run
+    pub fn get_executable(&self) -> &'a Executable<C> {
+        self.executable
+    }
+
+    /// Returns the number of idle VMs
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn allocate(&self) -> VmMemory {
+        let config = self.executable.get_config();
+        VmMemory {
+            stack: AlignedMemory::zero_filled(config.stack_size()),
+            heap: AlignedMemory::zero_filled(self.heap_size),
+            call_frames: vec![CallFrame::default(); config.max_call_depth],
+        }
+    }
+
+    /// Takes a VM from the pool, passes it to `f` and puts it back afterwards
+    ///
+    /// The `input` is mapped writable at `ebpf::MM_INPUT_START`. The VM must not be reused by
+    /// `f` after it changed its memory mapping.
+    pub fn with_vm<R>(
+        &self,
+        context_object: &mut C,
+        input: &mut [u8],
+        f: impl FnOnce(&mut EbpfVm<C>) -> R,
+    ) -> Result<R, EbpfError> {
+        let mut memory = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| self.allocate());
+        let config = self.executable.get_config();
+        let sbpf_version = self.executable.get_sbpf_version();
+        let stack_len = memory.stack.len();
+        let mut stack_region = MemoryRegion::new_writable_gapped(
+            memory.stack.as_slice_mut(),
+            ebpf::MM_STACK_START,
+            if !sbpf_version.dynamic_stack_frames() && config.enable_stack_frame_gaps {
+                config.stack_frame_size as u64
+            } else {
+                0
+            },
+        );
+        stack_region.enable_write_tracking();
+        let mut heap_region =
+            MemoryRegion::new_writable(memory.heap.as_slice_mut(), ebpf::MM_HEAP_START);
+        heap_region.enable_write_tracking();
+        let regions = vec![
+            self.executable.get_ro_region(),
+            stack_region,
+            heap_region,
+            MemoryRegion::new_writable(input, ebpf::MM_INPUT_START),
+        ];
+        let memory_mapping = match MemoryMapping::new(regions, config, sbpf_version) {
+            Ok(memory_mapping) => memory_mapping,
+            Err(error) => {
+                self.idle.lock().unwrap().push(memory);
+                return Err(error);
+            }
+        };
+        let mut vm = EbpfVm::new_with_call_frames(
+            self.executable.get_loader().clone(),
+            sbpf_version,
+            context_object,
+            memory_mapping,
+            stack_len,
+            std::mem::take(&mut memory.call_frames),
+        );
+        let result = f(&mut vm);
+        let stack_written = written_range(&vm.memory_mapping, ebpf::MM_STACK_START, stack_len);
+        let heap_written =
+            written_range(&vm.memory_mapping, ebpf::MM_HEAP_START, memory.heap.len());
+        memory.call_frames = std::mem::take(&mut vm.call_frames);
+        drop(vm);
+        memory.stack.as_slice_mut()[stack_written].fill(0);
+        memory.heap.as_slice_mut()[heap_written].fill(0);
+        self.idle.lock().unwrap().push(memory);
+        Ok(result)
+    }
+
+    /// Runs the program on the given input with a VM from the pool
+    ///
+    /// If interpreted = `false` then the JIT compiled executable is used.
+    pub fn execute_program(
+        &self,
+        context_object: &mut C,
+        input: &mut [u8],
+        interpreted: bool,
+    ) -> (u64, ProgramResult) {
+        self.with_vm(context_object, input, |vm| {
+            vm.execute_program(self.executable, interpreted)
+        })
+        .unwrap_or_else(|error| (0, ProgramResult::Err(error)))
+    }
+}
+
+impl<'a, C: ContextObject> VmPool<'a, C>
+where
+    Executable<C>: Sync,
+{
+    /// Runs the program once for every input, distributed over `num_threads` worker threads
+    ///
+    /// The context object of each run is created by `create_context_object` from the index of the
+    /// input. Once a run is finished `collect` turns the index of the input, the VM, the
+    /// instruction count and the result into the entry of the returned vector.
+    /// Panics if the memory mapping of a VM can not be created.
+    /// If interpreted = `false` then the JIT compiled executable is used.
+    pub fn execute_batch<I, R>(
+        &self,
+        inputs: &mut [I],
+        num_threads: usize,
+        create_context_object: impl Fn(usize) -> C + Sync,
+        collect: impl Fn(usize, &mut EbpfVm<C>, u64, ProgramResult) -> R + Sync,
+        interpreted: bool,
+    ) -> Vec<R>
+    where
+        I: AsMut<[u8]> + Send,
+        R: Send,
+    {
+        if inputs.is_empty() {
+            return Vec::new();
+        }
+        let chunk_size = inputs.len().div_ceil(num_threads.max(1));
+        let create_context_object = &create_context_object;
+        let collect = &collect;
+        std::thread::scope(|scope| {
+            let workers = inputs
+                .chunks_mut(chunk_size)
+                .enumerate()
+                .map(|(chunk_index, chunk)| {
+                    scope.spawn(move || {
+                        let first_index = chunk_index.saturating_mul(chunk_size);
+                        chunk
+                            .iter_mut()
+                            .enumerate()
+                            .map(|(offset, input)| {
+                                let index = first_index.saturating_add(offset);
+                                let mut context_object = create_context_object(index);
+                                self.with_vm(&mut context_object, input.as_mut(), |vm| {
+                                    let (instruction_count, result) =
+                                        vm.execute_program(self.executable, interpreted);
+                                    collect(index, vm, instruction_count, result)
+                                })
+                                .unwrap()
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        })
+    }
+}
+
+/// Returns the host offsets written to in the region at `vm_addr`, or everything if it is not mapped
+/// or does not track its writes
+fn written_range(memory_mapping: &MemoryMapping, vm_addr: u64, len: usize) -> Range<usize> {
+    memory_mapping
+        .get_regions()
+        .iter()
+        .find(|region| {
+            region.vm_addr == vm_addr && region.len == len as u64 && region.tracks_writes()
+        })
+        .map(|region| {
+            let range = region.written_range();
+            range.start as usize..range.end as usize
+        })
+        .unwrap_or(0..len)
+}
@@ -1,6 +1,6 @@
 #![allow(clippy::arithmetic_side_effects)]
 use crate::{
-    jit::{JitCompiler, OperandSize},
+    jit::{JitCompiler, OperandSize, REGISTER_PTR_TO_VM},
     vm::ContextObject,
 };
 
@@ -121,12 +121,15 @@ impl X86Instruction {
         };
         let mut displacement_size = OperandSize::S0;
         let mut displacement = 0;
+        // Displacements relative to the runtime environment depend on its key and are relocated
+        let relocatable_displacement =
+            self.modrm && self.indirect.is_some() && self.second_operand == REGISTER_PTR_TO_VM;
         if self.modrm {
             match self.indirect {
                 Some(X86IndirectAccess::Offset(offset)) => {
                     displacement = offset;
                     debug_assert_ne!(self.second_operand & 0b111, RSP); // Reserved for SIB addressing
-                    if (-128..=127).contains(&displacement)
+                    if !relocatable_displacement && (-128..=127).contains(&displacement)
                         || (displacement == 0 && self.second_operand & 0b111 == RBP)
                     {
                         displacement_size = OperandSize::S8;
@@ -172,6 +175,9 @@ impl X86Instruction {
             if sib != 0 {
                 jit.emit::<u8>(sib);
             }
+            if relocatable_displacement {
+                jit.record_environment_relocation();
+            }
             jit.emit_variable_length(displacement_size, displacement as u64);
         }
         jit.emit_variable_length(self.immediate_size, self.immediate as u64);
@@ -195,7 +201,7 @@ impl X86Instruction {
             second_operand: destination,
             immediate_size: match opcode {
                 0xc1 => OperandSize::S8,
-                0x81 => OperandSize::S32,
+                0x69 | 0x81 => OperandSize::S32,
                 0xf7 if source == 0 => OperandSize::S32,
                 _ => OperandSize::S0,
             },
@@ -483,6 +489,20 @@ impl X86Instruction {
         }
     }
 
+    /// Load destination from a 64 bit immediate which can be relocated
+    #[inline]
+    pub const fn load_address(destination: u8, address: i64) -> Self {
+        Self {
+            size: OperandSize::S64,
+            opcode: 0xb8 | (destination & 0b111),
+            modrm: false,
+            second_operand: destination,
+            immediate_size: OperandSize::S64,
+            immediate: address,
+            ..Self::DEFAULT
+        }
+    }
+
     /// Load destination from immediate
     #[inline]
     pub const fn load_immediate(destination: u8, immediate: i64) -> Self {
@@ -678,6 +698,19 @@ impl X86Instruction {
         }
     }
 
+    /// Pop RIP and release the given number of bytes from the stack
+    #[inline]
+    pub const fn return_near_and_release(bytes: u16) -> Self {
+        Self {
+            size: OperandSize::S32,
+            opcode: 0xc2,
+            modrm: false,
+            immediate_size: OperandSize::S16,
+            immediate: bytes as i64,
+            ..Self::DEFAULT
+        }
+    }
+
     /// No operation
     #[allow(dead_code)]
     #[inline]
@@ -738,3 +771,495 @@ impl X86Instruction {
         }
     }
 }
+
+const REGISTER_NAMES: [[&str; 16]; 4] = [
+    [
+        "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+        "r13b", "r14b", "r15b",
+    ],
+    [
+        "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w",
+        "r13w", "r14w", "r15w",
+    ],
+    [
+        "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d",
+        "r12d", "r13d", "r14d", "r15d",
+    ],
+    [
+        "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14", "r15",
+    ],
+];
+
+const CONDITION_NAMES: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+/// The r/m operand of a ModRM byte
+enum X86Operand {
+    Register(u8),
+    Memory(String),
+}
+
+/// Decodes the subset of x86-64 which `X86Instruction` emits
+struct X86Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    rex: u8,
+    operand_size_override: bool,
+}
+
+impl<'a> X86Decoder<'a> {
+    fn read_u8(&mut self) -> Option<u8> {
+        let value = *self.bytes.get(self.offset)?;
+        self.offset += 1;
+        Some(value)
+    }
+
+    fn read_immediate(&mut self, size: OperandSize) -> Option<i64> {
+        let length = size as usize / 8;
+        let bytes = self.bytes.get(self.offset..self.offset + length)?;
+        self.offset += length;
+        let mut value = 0u64;
+        for (index, byte) in bytes.iter().enumerate() {
+            value |= (*byte as u64) << (index * 8);
+        }
+        // Sign extend
+        let shift = 64 - size as u32;
+        Some(((value << shift) as i64) >> shift)
+    }
+
+    /// Operand size selected by the REX.W bit and the 0x66 prefix
+    fn operand_size(&self) -> OperandSize {
+        if self.rex & 0b1000 != 0 {
+            OperandSize::S64
+        } else if self.operand_size_override {
+            OperandSize::S16
+        } else {
+            OperandSize::S32
+        }
+    }
+
+    fn register(&self, size: OperandSize, register: u8) -> &'static str {
+        match size {
+            // Without a REX prefix the encodings of spl, bpl, sil and dil refer to ah, ch, dh and bh
+            OperandSize::S8 if self.rex == 0 && (4..8).contains(&register) => {
+                ["ah", "ch", "dh", "bh"][register as usize - 4]
+            }
+            OperandSize::S8 => REGISTER_NAMES[0][register as usize],
+            OperandSize::S16 => REGISTER_NAMES[1][register as usize],
+            OperandSize::S32 => REGISTER_NAMES[2][register as usize],
+            _ => REGISTER_NAMES[3][register as usize],
+        }
+    }
+
+    /// Reads a ModRM byte and returns its reg field and its r/m operand
+    fn modrm(&mut self) -> Option<(u8, X86Operand)> {
+        let modrm = self.read_u8()?;
+        let mode = modrm >> 6;
+        let r = ((modrm >> 3) & 0b111) | ((self.rex & 0b100) << 1);
+        let m = modrm & 0b111;
+        if mode == 3 {
+            return Some((r, X86Operand::Register(m | ((self.rex & 0b1) << 3))));
+        }
+        let (base, index) = if m == RSP {
+            let sib = self.read_u8()?;
+            let index = ((sib >> 3) & 0b111) | ((self.rex & 0b10) << 2);
+            let base = sib & 0b111;
+            (
+                if mode == 0 && base == RBP {
+                    None
+                } else {
+                    Some(base | ((self.rex & 0b1) << 3))
+                },
+                if index == RSP {
+                    None
+                } else {
+                    Some((index, 1 << (sib >> 6)))
+                },
+            )
+        } else if mode == 0 && m == RBP {
+            // RIP relative addressing is never emitted
+            return None;
+        } else {
+            (Some(m | ((self.rex & 0b1) << 3)), None)
+        };
+        let displacement = match mode {
+            0 if base.is_none() => self.read_immediate(OperandSize::S32)?,
+            0 => 0,
+            1 => self.read_immediate(OperandSize::S8)?,
+            _ => self.read_immediate(OperandSize::S32)?,
+        };
+        let mut address = Vec::new();
+        if let Some(base) = base {
+            address.push(REGISTER_NAMES[3][base as usize].to_string());
+        }
+        if let Some((index, scale)) = index {
+            address.push(format!("{}*{}", REGISTER_NAMES[3][index as usize], scale));
+        }
+        let mut address = address.join(" + ");
+        if address.is_empty() {
+            address = format_immediate(displacement);
+        } else if displacement < 0 {
+            address = format!("{} - {:#x}", address, displacement.unsigned_abs());
+        } else if displacement > 0 {
+            address = format!("{address} + {displacement:#x}");
+        }
+        Some((r, X86Operand::Memory(format!("[{address}]"))))
+    }
+
+    fn operand(&self, size: OperandSize, operand: &X86Operand) -> String {
+        match operand {
+            X86Operand::Register(register) => self.register(size, *register).to_string(),
+            X86Operand::Memory(address) => {
+                let pointer_size = match size {
+                    OperandSize::S8 => "byte",
+                    OperandSize::S16 => "word",
+                    OperandSize::S32 => "dword",
+                    _ => "qword",
+                };
+                format!("{pointer_size} ptr {address}")
+            }
+        }
+    }
+
+    /// Decodes an instruction which is located at `address`
+    fn decode(&mut self, address: usize) -> Option<String> {
+        let mut opcode = self.read_u8()?;
+        if opcode == 0x66 {
+            self.operand_size_override = true;
+            opcode = self.read_u8()?;
+        }
+        if opcode & 0xf0 == 0x40 {
+            self.rex = opcode;
+            opcode = self.read_u8()?;
+        }
+        let size = self.operand_size();
+        let relative_target = |decoder: &mut Self, size: OperandSize| {
+            let relative_destination = decoder.read_immediate(size)?;
+            Some(format!(
+                "{:#x}",
+                (address + decoder.offset).wrapping_add(relative_destination as usize)
+            ))
+        };
+        Some(match opcode {
+            0x0f => {
+                let opcode = self.read_u8()?;
+                match opcode {
+                    0x31 => "rdtsc".to_string(),
+                    0x40..=0x4f | 0xaf | 0xb6 | 0xb7 => {
+                        let (r, m) = self.modrm()?;
+                        let (name, source_size) = match opcode {
+                            0xaf => ("imul".to_string(), size),
+                            0xb6 => ("movzx".to_string(), OperandSize::S8),
+                            0xb7 => ("movzx".to_string(), OperandSize::S16),
+                            _ => (
+                                format!("cmov{}", CONDITION_NAMES[opcode as usize & 0xf]),
+                                size,
+                            ),
+                        };
+                        format!(
+                            "{} {}, {}",
+                            name,
+                            self.register(size, r),
+                            self.operand(source_size, &m)
+                        )
+                    }
+                    0x80..=0x8f => format!(
+                        "j{} {}",
+                        CONDITION_NAMES[opcode as usize & 0xf],
+                        relative_target(self, OperandSize::S32)?
+                    ),
+                    0xae => match self.modrm()? {
+                        (5, X86Operand::Register(_)) => "lfence".to_string(),
+                        (6, X86Operand::Register(_)) => "mfence".to_string(),
+                        (7, X86Operand::Register(_)) => "sfence".to_string(),
+                        _ => return None,
+                    },
+                    0xc8..=0xcf => format!(
+                        "bswap {}",
+                        self.register(size, (opcode & 0b111) | ((self.rex & 0b1) << 3))
+                    ),
+                    _ => return None,
+                }
+            }
+            0x01 | 0x09 | 0x21 | 0x29 | 0x31 | 0x39 | 0x85 | 0x87 | 0x89 | 0x38 | 0x84 | 0x88 => {
+                let size = if opcode & 0b1 == 0 {
+                    OperandSize::S8
+                } else {
+                    size
+                };
+                let name = match opcode {
+                    0x01 => "add",
+                    0x09 => "or",
+                    0x21 => "and",
+                    0x29 => "sub",
+                    0x31 => "xor",
+                    0x38 | 0x39 => "cmp",
+                    0x84 | 0x85 => "test",
+                    0x87 => "xchg",
+                    _ => "mov",
+                };
+                let (r, m) = self.modrm()?;
+                format!(
+                    "{} {}, {}",
+                    name,
+                    self.operand(size, &m),
+                    self.register(size, r)
+                )
+            }
+            0x03 | 0x2b | 0x63 | 0x8b | 0x8d => {
+                let (r, m) = self.modrm()?;
+                let (name, source) = match opcode {
+                    0x03 => ("add", self.operand(size, &m)),
+                    0x2b => ("sub", self.operand(size, &m)),
+                    0x63 => ("movsxd", self.operand(OperandSize::S32, &m)),
+                    0x8b => ("mov", self.operand(size, &m)),
+                    _ => match m {
+                        X86Operand::Memory(address) => ("lea", address),
+                        X86Operand::Register(_) => return None,
+                    },
+                };
+                format!("{} {}, {}", name, self.register(size, r), source)
+            }
+            0x50..=0x5f => format!(
+                "{} {}",
+                if opcode < 0x58 { "push" } else { "pop" },
+                self.register(OperandSize::S64, (opcode & 0b111) | ((self.rex & 0b1) << 3))
+            ),
+            0x68 => format!(
+                "push {}",
+                format_immediate(self.read_immediate(OperandSize::S32)?)
+            ),
+            0x6a => format!(
+                "push {}",
+                format_immediate(self.read_immediate(OperandSize::S8)?)
+            ),
+            0x80 | 0x81 | 0xc1 | 0xc6 | 0xc7 | 0xd3 | 0xf6 | 0xf7 | 0xff => {
+                let size = if opcode & 0b1 == 0 {
+                    OperandSize::S8
+                } else {
+                    size
+                };
+                let (extension, m) = self.modrm()?;
+                let extension = extension & 0b111;
+                let name = match (opcode, extension) {
+                    (0x80 | 0x81, extension) => {
+                        ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"][extension as usize]
+                    }
+                    (0xc1 | 0xd3, extension) => {
+                        ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"][extension as usize]
+                    }
+                    (0xc6 | 0xc7, 0) => "mov",
+                    (0xf6 | 0xf7, 0) => "test",
+                    (0xf6 | 0xf7, 1) => return None,
+                    (0xf6 | 0xf7, extension) => {
+                        ["", "", "not", "neg", "mul", "imul", "div", "idiv"][extension as usize]
+                    }
+                    (0xff, 0) => "inc",
+                    (0xff, 1) => "dec",
+                    (0xff, 2) => "call",
+                    (0xff, 4) => "jmp",
+                    (0xff, 6) => "push",
+                    _ => return None,
+                };
+                let immediate_size = match (opcode, extension) {
+                    (0x80 | 0xc1 | 0xc6 | 0xf6, _) => OperandSize::S8,
+                    (0x81 | 0xc7, _) | (0xf7, 0) if matches!(size, OperandSize::S16) => {
+                        OperandSize::S16
+                    }
+                    (0x81 | 0xc7, _) | (0xf7, 0) => OperandSize::S32,
+                    _ => OperandSize::S0,
+                };
+                let size = if opcode == 0xff && extension >= 2 {
+                    OperandSize::S64
+                } else {
+                    size
+                };
+                let destination = self.operand(size, &m);
+                if opcode == 0xd3 {
+                    format!("{name} {destination}, cl")
+                } else if let OperandSize::S0 = immediate_size {
+                    format!("{name} {destination}")
+                } else {
+                    let immediate = self.read_immediate(immediate_size)?;
+                    format!("{} {}, {}", name, destination, format_immediate(immediate))
+                }
+            }
+            0x90 => "nop".to_string(),
+            0x99 => match size {
+                OperandSize::S64 => "cqo",
+                OperandSize::S16 => "cwd",
+                _ => "cdq",
+            }
+            .to_string(),
+            0xb8..=0xbf => {
+                let register = (opcode & 0b111) | ((self.rex & 0b1) << 3);
+                let immediate = match size {
+                    OperandSize::S64 => self.read_immediate(OperandSize::S64)?,
+                    // Zero extended
+                    OperandSize::S16 => self.read_immediate(OperandSize::S16)? & 0xffff,
+                    _ => self.read_immediate(OperandSize::S32)? & 0xffff_ffff,
+                };
+                format!(
+                    "{} {}, {}",
+                    if let OperandSize::S64 = size {
+                        "movabs"
+                    } else {
+                        "mov"
+                    },
+                    self.register(size, register),
+                    format_immediate(immediate)
+                )
+            }
+            0x69 => {
+                let (r, m) = self.modrm()?;
+                let source = self.operand(size, &m);
+                let immediate = self.read_immediate(OperandSize::S32)?;
+                format!(
+                    "imul {}, {}, {}",
+                    self.register(size, r),
+                    source,
+                    format_immediate(immediate)
+                )
+            }
+            0xc2 => format!(
+                "ret {}",
+                format_immediate(self.read_immediate(OperandSize::S16)? as u16 as i64)
+            ),
+            0xc3 => "ret".to_string(),
+            0xcc => "int3".to_string(),
+            0xcd => format!("int {:#x}", self.read_u8()?),
+            0xe8 => format!("call {}", relative_target(self, OperandSize::S32)?),
+            0xe9 => format!("jmp {}", relative_target(self, OperandSize::S32)?),
+            _ => return None,
+        })
+    }
+}
+
+fn format_immediate(immediate: i64) -> String {
+    if immediate < 0 {
+        format!("-{:#x}", immediate.unsigned_abs())
+    } else {
+        format!("{immediate:#x}")
+    }
+}
+
+/// Decodes the instruction at the start of `bytes` in Intel syntax
+///
+/// Only understands the subset of x86-64 which `X86Instruction` can emit.
+/// `address` is the location of the instruction, which relative jumps and calls are resolved against.
+/// Returns the length of the instruction in bytes and its text,
+/// or `None` if the bytes are truncated or not recognized.
+pub fn decode(bytes: &[u8], address: usize) -> Option<(usize, String)> {
+    let mut decoder = X86Decoder {
+        bytes,
+        offset: 0,
+        rex: 0,
+        operand_size_override: false,
+    };
+    let text = decoder.decode(address)?;
+    Some((decoder.offset, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        for (bytes, expected) in [
+            (&[0x49, 0x01, 0xc3][..], "add r11, rax"),
+            (&[0x03, 0x47, 0x10], "add eax, dword ptr [rdi + 0x10]"),
+            (&[0x48, 0x2b, 0x77, 0xf8], "sub rsi, qword ptr [rdi - 0x8]"),
+            (&[0x45, 0x21, 0xd1], "and r9d, r10d"),
+            (&[0x48, 0x31, 0xc0], "xor rax, rax"),
+            (
+                &[0x4c, 0x39, 0x9f, 0x00, 0x02, 0x00, 0x00],
+                "cmp qword ptr [rdi + 0x200], r11",
+            ),
+            (&[0x4d, 0x85, 0xe0], "test r8, r12"),
+            (&[0x48, 0x87, 0x1c, 0x24], "xchg qword ptr [rsp], rbx"),
+            (
+                &[0x41, 0x88, 0x74, 0x03, 0x07],
+                "mov byte ptr [r11 + rax*1 + 0x7], sil",
+            ),
+            (&[0x88, 0x67, 0x01], "mov byte ptr [rdi + 0x1], ah"),
+            (&[0x66, 0x89, 0x47, 0x10], "mov word ptr [rdi + 0x10], ax"),
+            (
+                &[0x4a, 0x8b, 0x84, 0xdf, 0x00, 0x01, 0x00, 0x00],
+                "mov rax, qword ptr [rdi + r11*8 + 0x100]",
+            ),
+            (&[0x49, 0x63, 0xc3], "movsxd rax, r11d"),
+            (&[0x48, 0x8d, 0x64, 0x24, 0xd0], "lea rsp, [rsp - 0x30]"),
+            (&[0x41, 0x57], "push r15"),
+            (&[0x5b], "pop rbx"),
+            (&[0x6a, 0x7f], "push 0x7f"),
+            (&[0x68, 0x00, 0x00, 0x00, 0x80], "push -0x80000000"),
+            (&[0x80, 0x38, 0x01], "cmp byte ptr [rax], 0x1"),
+            (&[0x49, 0x81, 0xc2, 0xff, 0xff, 0xff, 0xff], "add r10, -0x1"),
+            (
+                &[0x41, 0x81, 0xe3, 0xff, 0xff, 0xff, 0x7f],
+                "and r11d, 0x7fffffff",
+            ),
+            (&[0x49, 0xc1, 0xe3, 0x3f], "shl r11, 0x3f"),
+            (&[0x41, 0xd3, 0xfb], "sar r11d, cl"),
+            (&[0x66, 0xc1, 0xc6, 0x08], "rol si, 0x8"),
+            (
+                &[0xc7, 0x47, 0x08, 0xff, 0xff, 0xff, 0xff],
+                "mov dword ptr [rdi + 0x8], -0x1",
+            ),
+            (&[0xc6, 0x07, 0x03], "mov byte ptr [rdi], 0x3"),
+            (
+                &[0x41, 0xf7, 0xc3, 0x10, 0x00, 0x00, 0x00],
+                "test r11d, 0x10",
+            ),
+            (&[0x48, 0xf7, 0xd8], "neg rax"),
+            (&[0x49, 0xf7, 0xe3], "mul r11"),
+            (&[0x41, 0xf7, 0xfb], "idiv r11d"),
+            (&[0xff, 0x57, 0x40], "call qword ptr [rdi + 0x40]"),
+            (&[0x41, 0xff, 0xe3], "jmp r11"),
+            (&[0x41, 0xff, 0x33], "push qword ptr [r11]"),
+            (&[0x90], "nop"),
+            (&[0x48, 0x99], "cqo"),
+            (&[0x99], "cdq"),
+            (
+                &[0x41, 0xbb, 0xff, 0xff, 0xff, 0xff],
+                "mov r11d, 0xffffffff",
+            ),
+            (
+                &[0x49, 0xbb, 0x90, 0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00],
+                "movabs r11, 0x1234567890",
+            ),
+            (&[0x49, 0xc7, 0xc3, 0xfe, 0xff, 0xff, 0xff], "mov r11, -0x2"),
+            (&[0xc3], "ret"),
+            (&[0xc2, 0x08, 0x00], "ret 0x8"),
+            (
+                &[0x48, 0x69, 0xc0, 0x60, 0x00, 0x00, 0x00],
+                "imul rax, rax, 0x60",
+            ),
+            (&[0xcc], "int3"),
+            (&[0x0f, 0x31], "rdtsc"),
+            (&[0x0f, 0xae, 0xe8], "lfence"),
+            (&[0x0f, 0xae, 0xf0], "mfence"),
+            (&[0x49, 0x0f, 0xcc], "bswap r12"),
+            (&[0x0f, 0xc8], "bswap eax"),
+            (&[0x49, 0x0f, 0x45, 0xc3], "cmovne rax, r11"),
+            (&[0x0f, 0xb6, 0x46, 0x03], "movzx eax, byte ptr [rsi + 0x3]"),
+            (&[0x45, 0x0f, 0xb7, 0x1b], "movzx r11d, word ptr [r11]"),
+            (&[0x48, 0x0f, 0xaf, 0xc1], "imul rax, rcx"),
+            (&[0xe8, 0x10, 0x00, 0x00, 0x00], "call 0x1015"),
+            (&[0xe9, 0xfb, 0xff, 0xff, 0xff], "jmp 0x1000"),
+            (&[0x0f, 0x84, 0x00, 0x01, 0x00, 0x00], "je 0x1106"),
+        ] {
+            assert_eq!(
+                decode(bytes, 0x1000),
+                Some((bytes.len(), expected.to_string())),
+            );
+        }
+        // Truncated
+        assert_eq!(decode(&[0x49, 0xbb, 0x90, 0x78], 0), None);
+        // Not emitted by X86Instruction
+        assert_eq!(decode(&[0x0f, 0x0b], 0), None);
+    }
+}
@@ -1,5 +1,12 @@
 #![allow(clippy::arithmetic_side_effects)]
-#![cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#![cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 // Copyright 2020 Solana Maintainers <maintainers@solana.com>
 //
 // Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
@@ -13,27 +20,66 @@ extern crate test_utils;
 extern crate thiserror;
 
 use byteorder::{ByteOrder, LittleEndian};
-#[cfg(all(not(windows), target_arch = "x86_64"))]
+#[cfg(all(
+    not(windows),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 use rand::{rngs::SmallRng, RngCore, SeedableRng};
 use solana_rbpf::{
+    aligned_memory::AlignedMemory,
     assembler::assemble,
     declare_builtin_function, ebpf,
     elf::Executable,
     error::{EbpfError, ProgramResult},
-    memory_region::{AccessType, MemoryMapping, MemoryRegion},
+    jit_pool::JitCompilationPool,
+    lockstep::{execute_program_in_lockstep, LockstepError},
+    memory_region::{
+        AccessType, AccessViolationResolution, MemoryAccess, MemoryMapping, MemoryProtection,
+        MemoryRegion, MemoryState,
+    },
     program::{BuiltinFunction, BuiltinProgram, FunctionRegistry, SBPFVersion},
-    static_analysis::Analysis,
-    syscalls,
+    snapshot::VmSnapshot,
+    static_analysis::{Analysis, BoundsCheckProof},
+    syscalls::{self, HeapAllocator, HeapAllocatorError, HeapAllocatorStrategy, HeapLimits},
     verifier::RequisiteVerifier,
-    vm::{Config, ContextObject, TestContextObject},
+    vm::{
+        CancellationHandle, Config, ContextObject, EbpfVm, ExecutionStats, FaultLocation,
+        FunctionSelector, FunctionStats, InstructionCosts, MemoryRegionStats, SyscallStats,
+        TestContextObject, TraceEvent,
+    },
+    vm_pool::VmPool,
+};
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::Read,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
-use std::{fs::File, io::Read, sync::Arc};
 use test_utils::{
     assert_error, create_vm, PROG_TCP_PORT_80, TCP_SACK_ASM, TCP_SACK_MATCH, TCP_SACK_NOMATCH,
 };
 
 const INSTRUCTION_METER_BUDGET: u64 = 1024;
 
+/// Splits the location off a fault, so that the result and the location can be compared on their own
+fn split_fault_location(result: ProgramResult) -> (ProgramResult, Option<FaultLocation>) {
+    match result {
+        ProgramResult::Ok(value) => (ProgramResult::Ok(value), None),
+        ProgramResult::Err(error) => {
+            let (error, location) = error.split_location();
+            (ProgramResult::Err(error), location)
+        }
+    }
+}
+
 macro_rules! test_interpreter_and_jit {
     (register, $function_registry:expr, $location:expr => $syscall_function:expr) => {
         $function_registry
@@ -85,6 +131,7 @@ macro_rules! test_interpreter_and_jit {
                 None
             );
             let (instruction_count_interpreter, result) = vm.execute_program(&$executable, true);
+            let (result, fault_location) = split_fault_location(result);
             assert_eq!(
                 format!("{:?}", result),
                 expected_result,
@@ -92,11 +139,18 @@ macro_rules! test_interpreter_and_jit {
             );
             (
                 instruction_count_interpreter,
-                vm.registers[11],
+                (vm.registers[11], fault_location),
                 vm.context_object_pointer.clone(),
             )
         };
-        #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+        #[cfg(all(
+            feature = "jit",
+            not(target_os = "windows"),
+            any(
+                target_arch = "x86_64",
+                all(target_arch = "aarch64", feature = "jit-aarch64")
+            )
+        ))]
         {
             #[allow(unused_mut)]
             let compilation_result = $executable.jit_compile();
@@ -119,18 +173,24 @@ macro_rules! test_interpreter_and_jit {
                 ),
                 Ok(()) => {
                     let (instruction_count_jit, result) = vm.execute_program(&$executable, false);
+                    let (result, fault_location) = split_fault_location(result);
                     let tracer_jit = &vm.context_object_pointer;
                     if !TestContextObject::compare_trace_log(&_tracer_interpreter, tracer_jit) {
                         let analysis = Analysis::from_executable(&$executable).unwrap();
                         let stdout = std::io::stdout();
                         analysis
-                            .disassemble_trace_log(
+                            .disassemble_trace_log_with_events(
                                 &mut stdout.lock(),
                                 &_tracer_interpreter.trace_log,
+                                &_tracer_interpreter.event_log,
                             )
                             .unwrap();
                         analysis
-                            .disassemble_trace_log(&mut stdout.lock(), &tracer_jit.trace_log)
+                            .disassemble_trace_log_with_events(
+                                &mut stdout.lock(),
+                                &tracer_jit.trace_log,
+                                &tracer_jit.event_log,
+                            )
                             .unwrap();
                         panic!();
                     }
@@ -144,7 +204,8 @@ macro_rules! test_interpreter_and_jit {
                         "Interpreter and JIT instruction meter diverged",
                     );
                     assert_eq!(
-                        interpreter_final_pc, vm.registers[11],
+                        interpreter_final_pc,
+                        (vm.registers[11], fault_location),
                         "Interpreter and JIT instruction final PC diverged",
                     );
                 }
@@ -1144,7 +1205,7 @@ fn test_err_ldxdw_oob() {
             AccessType::Load,
             0x400000006,
             8,
-            "input"
+            "input".into()
         )),
     );
 }
@@ -1161,7 +1222,7 @@ fn test_err_ldxdw_nomem() {
             AccessType::Load,
             0x400000006,
             8,
-            "input"
+            "input".into()
         )),
     );
 }
@@ -1440,6 +1501,796 @@ fn test_stxb_chain() {
     );
 }
 
+#[test]
+fn test_aligned_address_translation() {
+    // Without tracing the JIT translates addresses of aligned mappings in machine code
+    fn execute(
+        executable: &Executable<TestContextObject>,
+        interpreted: bool,
+        mem: &mut [u8],
+        track_writes: bool,
+    ) -> (u64, String, Option<FaultLocation>, std::ops::Range<u64>) {
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        let mut input_region = MemoryRegion::new_writable(mem, ebpf::MM_INPUT_START);
+        if track_writes {
+            input_region.enable_write_tracking();
+        }
+        create_vm!(
+            vm,
+            executable,
+            &mut context_object,
+            stack,
+            heap,
+            vec![input_region],
+            None
+        );
+        let (instruction_count, result) = vm.execute_program(executable, interpreted);
+        let (result, fault_location) = split_fault_location(result);
+        let written_range = vm
+            .memory_mapping
+            .region(AccessType::Load, ebpf::MM_INPUT_START)
+            .unwrap()
+            .written_range();
+        (
+            instruction_count,
+            format!("{result:?}"),
+            fault_location,
+            written_range,
+        )
+    }
+
+    for (source, expected_result, expected_written_range) in [
+        (
+            "
+            mov32 r2, 0x55667788
+            stxdw [r1+8], r2
+            stxw [r1+16], r2
+            stxh [r1+20], r2
+            stxb [r1+23], r2
+            ldxdw r0, [r1+8]
+            ldxw r3, [r1+16]
+            add64 r0, r3
+            ldxh r3, [r1+20]
+            add64 r0, r3
+            ldxb r3, [r1+23]
+            add64 r0, r3
+            ldxh r3, [r1+30]
+            add64 r0, r3
+            exit",
+            ProgramResult::Ok(0xaacd863e),
+            8..24,
+        ),
+        (
+            "
+            mov32 r2, 0x11223344
+            stxdw [r10-8], r2
+            ldxdw r0, [r10-8]
+            exit",
+            ProgramResult::Ok(0x11223344),
+            0..0,
+        ),
+        (
+            "
+            stb [r1+31], 1
+            ldxdw r0, [r1+28]
+            exit",
+            ProgramResult::Err(EbpfError::AccessViolation(
+                AccessType::Load,
+                0x40000001c,
+                8,
+                "input".into(),
+            )),
+            31..32,
+        ),
+        (
+            "
+            stw [r1+30], 1
+            exit",
+            ProgramResult::Err(EbpfError::AccessViolation(
+                AccessType::Store,
+                0x40000001e,
+                4,
+                "input".into(),
+            )),
+            0..0,
+        ),
+        (
+            "
+            mov64 r5, 1
+            lsh64 r5, 32
+            stb [r5+0], 1
+            exit",
+            ProgramResult::Err(EbpfError::AccessViolation(
+                AccessType::Store,
+                0x100000000,
+                1,
+                "program".into(),
+            )),
+            0..0,
+        ),
+        (
+            "
+            mov64 r5, 7
+            lsh64 r5, 32
+            ldxb r0, [r5+0]
+            exit",
+            ProgramResult::Err(EbpfError::AccessViolation(
+                AccessType::Load,
+                0x700000000,
+                1,
+                "unknown".into(),
+            )),
+            0..0,
+        ),
+        (
+            "
+            mov64 r5, 0
+            sth [r5+8], 1
+            exit",
+            ProgramResult::Err(EbpfError::AccessViolation(
+                AccessType::Store,
+                0x8,
+                2,
+                "unknown".into(),
+            )),
+            0..0,
+        ),
+    ] {
+        for sbpf_version in [SBPFVersion::V0, SBPFVersion::V3] {
+            let config = Config {
+                enabled_sbpf_versions: sbpf_version..=sbpf_version,
+                ..Config::default()
+            };
+            let loader = Arc::new(BuiltinProgram::new_loader(
+                config,
+                FunctionRegistry::default(),
+            ));
+            let mut executable = assemble::<TestContextObject>(source, loader).unwrap();
+            executable.verify::<RequisiteVerifier>().unwrap();
+            executable.jit_compile().unwrap();
+            for track_writes in [false, true] {
+                let mut mem_interpreter = (0..32).collect::<Vec<u8>>();
+                let mut mem_jit = mem_interpreter.clone();
+                let interpreter = execute(&executable, true, &mut mem_interpreter, track_writes);
+                let jit = execute(&executable, false, &mut mem_jit, track_writes);
+                assert_eq!(interpreter.1, format!("{expected_result:?}"));
+                // Regions only record their writes if asked to
+                if track_writes {
+                    assert_eq!(interpreter.3, expected_written_range);
+                } else {
+                    assert_eq!(interpreter.3, 0..0);
+                }
+                assert_eq!(interpreter, jit);
+                assert_eq!(mem_interpreter, mem_jit);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_aligned_address_translation_cow() {
+    let config = Config::default();
+    let loader = Arc::new(BuiltinProgram::new_loader(
+        config,
+        FunctionRegistry::default(),
+    ));
+    let mut executable = assemble::<TestContextObject>(
+        "
+        ldxb r0, [r1+1]
+        stb [r1+1], 7
+        ldxb r2, [r1+1]
+        add64 r0, r2
+        stb [r1+2], 8
+        ldxb r2, [r1+2]
+        add64 r0, r2
+        exit",
+        loader,
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    for interpreted in [true, false] {
+        let input = [1u8, 2, 3, 4];
+        let mut input_copy = input;
+        let input_copy_addr = input_copy.as_mut_ptr() as u64;
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        let mut input_region = MemoryRegion::new_cow(&input, ebpf::MM_INPUT_START, 0);
+        input_region.enable_write_tracking();
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            vec![input_region],
+            Some(Box::new(move |_| Ok(input_copy_addr)))
+        );
+        let (instruction_count, result) = vm.execute_program(&executable, interpreted);
+        assert_eq!(result.unwrap(), 2 + 7 + 8);
+        assert_eq!(instruction_count, 8);
+        assert_eq!(
+            vm.memory_mapping
+                .region(AccessType::Load, ebpf::MM_INPUT_START)
+                .unwrap()
+                .written_range(),
+            1..3
+        );
+        assert_eq!(input, [1, 2, 3, 4]);
+        assert_eq!(input_copy, [1, 7, 8, 4]);
+    }
+}
+
+#[test]
+fn test_dirty_page_tracking() {
+    for aligned_memory_mapping in [true, false] {
+        for sbpf_version in [SBPFVersion::V0, SBPFVersion::V3] {
+            let config = Config {
+                aligned_memory_mapping,
+                eliminate_redundant_bounds_checks: true,
+                enabled_sbpf_versions: sbpf_version..=sbpf_version,
+                ..Config::default()
+            };
+            let loader = Arc::new(BuiltinProgram::new_loader(
+                config,
+                FunctionRegistry::default(),
+            ));
+            let mut executable = assemble::<TestContextObject>(
+                "
+                mov32 r2, 0x55667788
+                stxb [r1+1], r2
+                stxdw [r1+46], r2
+                stxh [r1+70], r2
+                stxh [r1+70], r2
+                stxdw [r10-8], r2
+                ldxdw r0, [r1+56]
+                exit",
+                loader,
+            )
+            .unwrap();
+            executable.verify::<RequisiteVerifier>().unwrap();
+            executable.jit_compile().unwrap();
+            for interpreted in [true, false] {
+                let mut mem = [0u8; 72];
+                let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+                create_vm!(
+                    vm,
+                    &executable,
+                    &mut context_object,
+                    stack,
+                    heap,
+                    vec![MemoryRegion::new_writable(&mut mem, ebpf::MM_INPUT_START)],
+                    None
+                );
+                vm.memory_mapping.enable_dirty_page_tracking(16);
+                let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+                assert_eq!(result.unwrap(), 0);
+                let dirty_ranges = vm.memory_mapping.dirty_ranges();
+                let input_ranges = dirty_ranges
+                    .iter()
+                    .filter(|(vm_addr, _range)| *vm_addr == ebpf::MM_INPUT_START)
+                    .map(|(_vm_addr, range)| range.clone())
+                    .collect::<Vec<_>>();
+                assert_eq!(input_ranges, vec![0..16, 32..72]);
+                // Direct accesses to the stack frames mark the stack from the current frame on as dirty
+                let stack_region = vm
+                    .memory_mapping
+                    .region(AccessType::Load, ebpf::MM_STACK_START)
+                    .unwrap();
+                let stack_offset = unsafe {
+                    std::slice::from_raw_parts(
+                        stack_region.host_addr.get() as *const u8,
+                        stack_region.len as usize,
+                    )
+                }
+                .windows(8)
+                .position(|bytes| bytes == 0x55667788u64.to_le_bytes())
+                .unwrap() as u64;
+                let stack_ranges = dirty_ranges
+                    .iter()
+                    .filter(|(vm_addr, _range)| *vm_addr == ebpf::MM_STACK_START)
+                    .map(|(_vm_addr, range)| range.clone())
+                    .collect::<Vec<_>>();
+                if !interpreted && sbpf_version == SBPFVersion::V0 {
+                    assert_eq!(stack_ranges, vec![0..stack_region.len]);
+                } else {
+                    let page_start = stack_offset & !15;
+                    assert_eq!(stack_ranges, vec![page_start..page_start + 16]);
+                }
+                vm.memory_mapping.reset_dirty_pages();
+                assert!(vm.memory_mapping.dirty_ranges().is_empty());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_access_violation_handler() {
+    for aligned_memory_mapping in [true, false] {
+        for sbpf_version in [SBPFVersion::V0, SBPFVersion::V3] {
+            let config = Config {
+                aligned_memory_mapping,
+                eliminate_redundant_bounds_checks: true,
+                enabled_sbpf_versions: sbpf_version..=sbpf_version,
+                ..Config::default()
+            };
+            let loader = Arc::new(BuiltinProgram::new_loader(
+                config,
+                FunctionRegistry::default(),
+            ));
+            // Loads from the unmapped input twice and stores to the readonly heap
+            let mut executable = assemble::<TestContextObject>(
+                "
+                mov64 r2, 4
+                lsh64 r2, 32
+                ldxdw r0, [r2+0x1008]
+                ldxw r3, [r2+0x100c]
+                add64 r0, r3
+                mov64 r4, 3
+                lsh64 r4, 32
+                stxdw [r4+8], r0
+                exit",
+                loader.clone(),
+            )
+            .unwrap();
+            executable.verify::<RequisiteVerifier>().unwrap();
+            executable.jit_compile().unwrap();
+            let mut out_of_bounds_executable = assemble::<TestContextObject>(
+                "
+                mov64 r2, 4
+                lsh64 r2, 32
+                ldxb r0, [r2+0x4000]
+                exit",
+                loader,
+            )
+            .unwrap();
+            out_of_bounds_executable
+                .verify::<RequisiteVerifier>()
+                .unwrap();
+            out_of_bounds_executable.jit_compile().unwrap();
+            for interpreted in [true, false] {
+                for executable in [&executable, &out_of_bounds_executable] {
+                    let mut heap_mem = [0u8; 16];
+                    let calls = Rc::new(RefCell::new(Vec::new()));
+                    let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+                    create_vm!(
+                        vm,
+                        executable,
+                        &mut context_object,
+                        stack,
+                        heap,
+                        Vec::new(),
+                        None
+                    );
+                    let index = vm
+                        .memory_mapping
+                        .get_regions()
+                        .iter()
+                        .position(|region| region.vm_addr == ebpf::MM_HEAP_START)
+                        .unwrap();
+                    let heap_region =
+                        MemoryRegion::new_writable(&mut heap_mem, ebpf::MM_HEAP_START);
+                    heap_region.state.set(MemoryState::Readable);
+                    vm.memory_mapping
+                        .replace_region(index, heap_region)
+                        .unwrap();
+                    // The sparse input is mapped on demand, page by page in an unaligned mapping
+                    let input = (0..0x4000u64).map(|i| i as u8).collect::<Vec<u8>>();
+                    let handler_calls = calls.clone();
+                    vm.memory_mapping
+                        .set_access_violation_handler(Some(Box::new(
+                            move |access_type, vm_addr, len, region| {
+                                handler_calls.borrow_mut().push((access_type, vm_addr, len));
+                                match region {
+                                    Some(region) if access_type == AccessType::Store => {
+                                        AccessViolationResolution::MakeWritable(
+                                            region.host_addr.get(),
+                                        )
+                                    }
+                                    None if (ebpf::MM_INPUT_START
+                                        ..ebpf::MM_INPUT_START + input.len() as u64)
+                                        .contains(&vm_addr) =>
+                                    {
+                                        let range = if aligned_memory_mapping {
+                                            0..input.len()
+                                        } else {
+                                            let page =
+                                                (vm_addr - ebpf::MM_INPUT_START) as usize & !0xfff;
+                                            page..page + 0x1000
+                                        };
+                                        AccessViolationResolution::MapRegion(
+                                            MemoryRegion::new_readonly(
+                                                &input[range.clone()],
+                                                ebpf::MM_INPUT_START + range.start as u64,
+                                            ),
+                                        )
+                                    }
+                                    _ => AccessViolationResolution::Fail,
+                                }
+                            },
+                        )))
+                        .unwrap();
+                    let (_instruction_count, result) = vm.execute_program(executable, interpreted);
+                    if std::ptr::eq(executable, &out_of_bounds_executable) {
+                        assert_error!(
+                            result,
+                            "AccessViolation(Load, {}, 1, \"input\")",
+                            ebpf::MM_INPUT_START + 0x4000
+                        );
+                        assert_eq!(
+                            *calls.borrow(),
+                            vec![(AccessType::Load, ebpf::MM_INPUT_START + 0x4000, 1)]
+                        );
+                        continue;
+                    }
+                    let expected = 0x0f0e0d0c0b0a0908u64 + 0x0f0e0d0c;
+                    assert_eq!(result.unwrap(), expected);
+                    assert_eq!(heap_mem[8..], expected.to_le_bytes());
+                    assert_eq!(
+                        *calls.borrow(),
+                        vec![
+                            (AccessType::Load, ebpf::MM_INPUT_START + 0x1008, 8),
+                            (AccessType::Store, ebpf::MM_HEAP_START + 8, 8),
+                        ]
+                    );
+                    assert_eq!(
+                        vm.memory_mapping
+                            .region(AccessType::Load, ebpf::MM_HEAP_START)
+                            .unwrap()
+                            .state
+                            .get(),
+                        MemoryState::Writable
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_protected_ranges() {
+    for aligned_memory_mapping in [true, false] {
+        for sbpf_version in [SBPFVersion::V0, SBPFVersion::V3] {
+            let config = Config {
+                aligned_memory_mapping,
+                eliminate_redundant_bounds_checks: true,
+                enabled_sbpf_versions: sbpf_version..=sbpf_version,
+                ..Config::default()
+            };
+            let loader = Arc::new(BuiltinProgram::new_loader(
+                config,
+                FunctionRegistry::default(),
+            ));
+            // The input starts with a readonly header followed by writable data and inaccessible
+            // padding, the heap is write-only
+            let prologue = "
+                mov64 r2, 4
+                lsh64 r2, 32
+                mov64 r4, 3
+                lsh64 r4, 32";
+            let cases = [
+                (
+                    "
+                    ldxdw r0, [r2]
+                    stxdw [r2+8], r0
+                    ldxdw r0, [r2+8]
+                    stxdw [r4], r0
+                    stxdw [r4], r0
+                    exit",
+                    ProgramResult::Ok(0x0706050403020100),
+                ),
+                (
+                    "
+                    stxw [r2+4], r0
+                    exit",
+                    ProgramResult::Err(EbpfError::AccessViolation(
+                        AccessType::Store,
+                        ebpf::MM_INPUT_START + 4,
+                        4,
+                        "account header".into(),
+                    )),
+                ),
+                (
+                    "
+                    ldxdw r0, [r2+12]
+                    exit",
+                    ProgramResult::Err(EbpfError::AccessViolation(
+                        AccessType::Load,
+                        ebpf::MM_INPUT_START + 12,
+                        8,
+                        "padding".into(),
+                    )),
+                ),
+                (
+                    "
+                    stxdw [r4], r0
+                    ldxdw r0, [r4]
+                    exit",
+                    ProgramResult::Err(EbpfError::AccessViolation(
+                        AccessType::Load,
+                        ebpf::MM_HEAP_START,
+                        8,
+                        "heap".into(),
+                    )),
+                ),
+            ];
+            for (source, expected_result) in cases {
+                let mut executable =
+                    assemble::<TestContextObject>(&format!("{prologue}{source}"), loader.clone())
+                        .unwrap();
+                executable.verify::<RequisiteVerifier>().unwrap();
+                executable.jit_compile().unwrap();
+                for interpreted in [true, false] {
+                    let mut input = (0..24u8).collect::<Vec<u8>>();
+                    let mut heap_mem = [0u8; 8];
+                    let mut input_region =
+                        MemoryRegion::new_writable(&mut input, ebpf::MM_INPUT_START);
+                    input_region
+                        .protect(
+                            ebpf::MM_INPUT_START..ebpf::MM_INPUT_START + 8,
+                            MemoryProtection::Readable,
+                            "account header",
+                        )
+                        .unwrap();
+                    let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+                    create_vm!(
+                        vm,
+                        &executable,
+                        &mut context_object,
+                        stack,
+                        heap,
+                        vec![input_region],
+                        None
+                    );
+                    vm.memory_mapping
+                        .protect(
+                            ebpf::MM_INPUT_START + 16..ebpf::MM_INPUT_START + 24,
+                            MemoryProtection::Inaccessible,
+                            "padding",
+                        )
+                        .unwrap();
+                    let index = vm
+                        .memory_mapping
+                        .get_regions()
+                        .iter()
+                        .position(|region| region.vm_addr == ebpf::MM_HEAP_START)
+                        .unwrap();
+                    vm.memory_mapping
+                        .replace_region(
+                            index,
+                            MemoryRegion::new_write_only(&mut heap_mem, ebpf::MM_HEAP_START),
+                        )
+                        .unwrap();
+                    let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+                    let (result, _fault_location) = split_fault_location(result);
+                    assert_eq!(
+                        format!("{result:?}"),
+                        format!("{expected_result:?}"),
+                        "{source} interpreted: {interpreted}"
+                    );
+                    if result.is_ok() {
+                        assert_eq!(input[8..16], input[0..8]);
+                        assert_eq!(heap_mem, input[0..8]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_eliminate_redundant_bounds_checks() {
+    fn execute(
+        executable: &Executable<TestContextObject>,
+        interpreted: bool,
+        mem: &mut [u8],
+    ) -> (u64, String, Option<FaultLocation>, Vec<u8>) {
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        create_vm!(
+            vm,
+            executable,
+            &mut context_object,
+            stack,
+            heap,
+            vec![MemoryRegion::new_writable(mem, ebpf::MM_INPUT_START)],
+            None
+        );
+        let (instruction_count, result) = vm.execute_program(executable, interpreted);
+        let (result, fault_location) = split_fault_location(result);
+        (
+            instruction_count,
+            format!("{result:?}"),
+            fault_location,
+            stack.as_slice().to_vec(),
+        )
+    }
+
+    // Pairs of pc and the expected proofs for SBPFv0 and SBPFv3
+    let stack_frame = Some(BoundsCheckProof::StackFrame);
+    let repeated_access = Some(BoundsCheckProof::RepeatedAccess);
+    for (source, expected_proofs) in [
+        (
+            "
+            mov64 r1, 0x11
+            stxdw [r10-8], r1
+            call function_foo
+            ldxdw r0, [r10-8]
+            add64 r0, r2
+            exit
+            function_foo:
+            stdw [r10-4096], 0x22
+            ldxdw r2, [r10-4096]
+            stxb [r10-1], r2
+            ldxb r3, [r10-1]
+            add64 r2, r3
+            exit",
+            vec![
+                (1, stack_frame, None),
+                (3, stack_frame, None),
+                (6, stack_frame, None),
+                (7, stack_frame, repeated_access),
+                (8, stack_frame, None),
+                (9, stack_frame, repeated_access),
+            ],
+        ),
+        (
+            "
+            ldxdw r2, [r1+0]
+            ldxw r3, [r1+4]
+            stxw [r1+8], r3
+            stxb [r1+9], r2
+            ldxdw r0, [r1+8]
+            add64 r0, r3
+            exit",
+            vec![
+                (0, None, None),
+                (1, None, repeated_access),
+                (2, None, None),
+                (3, None, repeated_access),
+                (4, None, None),
+            ],
+        ),
+        (
+            "
+            ldxdw r2, [r1+28]
+            ldxb r0, [r1+30]
+            exit",
+            vec![(0, None, None), (1, None, repeated_access)],
+        ),
+        (
+            "
+            mov64 r5, 1
+            lsh64 r5, 32
+            ldxb r0, [r5+0]
+            stb [r5+0], 1
+            ldxb r0, [r5+0]
+            exit",
+            vec![(2, None, None), (3, None, None), (4, None, repeated_access)],
+        ),
+        (
+            "
+            ldxb r2, [r1+0]
+            mov64 r1, r10
+            ldxb r0, [r1+0]
+            stxdw [r10+8], r2
+            ldxw r0, [r10-4]
+            exit",
+            vec![
+                (0, None, None),
+                (2, None, None),
+                (3, None, None),
+                (4, stack_frame, None),
+            ],
+        ),
+    ] {
+        for (sbpf_version, enable_stack_frame_gaps) in [
+            (SBPFVersion::V0, true),
+            (SBPFVersion::V0, false),
+            (SBPFVersion::V3, true),
+        ] {
+            let config = Config {
+                enabled_sbpf_versions: sbpf_version..=sbpf_version,
+                enable_stack_frame_gaps,
+                eliminate_redundant_bounds_checks: true,
+                ..Config::default()
+            };
+            let loader = Arc::new(BuiltinProgram::new_loader(
+                config,
+                FunctionRegistry::default(),
+            ));
+            let mut executable = assemble::<TestContextObject>(source, loader).unwrap();
+            executable.verify::<RequisiteVerifier>().unwrap();
+            let proofs = Analysis::from_executable(&executable)
+                .unwrap()
+                .redundant_bounds_checks();
+            for (pc, expected_proof_v0, expected_proof_v3) in expected_proofs.iter() {
+                let expected_proof = if sbpf_version == SBPFVersion::V0 {
+                    expected_proof_v0
+                } else {
+                    expected_proof_v3
+                };
+                assert_eq!(proofs.get(pc), expected_proof.as_ref(), "pc {pc}");
+            }
+            executable.jit_compile().unwrap();
+            let mut mem_interpreter = (0..32).collect::<Vec<u8>>();
+            let mut mem_jit = mem_interpreter.clone();
+            let interpreter = execute(&executable, true, &mut mem_interpreter);
+            let jit = execute(&executable, false, &mut mem_jit);
+            assert_eq!(interpreter, jit);
+            assert_eq!(mem_interpreter, mem_jit);
+        }
+    }
+}
+
+#[test]
+fn test_eliminate_redundant_bounds_checks_invalid_stack() {
+    // The JIT only accesses stack frames directly if the stack region has the expected layout,
+    // otherwise it refuses to run instead of falling back to the interpreter
+    let config = Config {
+        enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V0,
+        eliminate_redundant_bounds_checks: true,
+        ..Config::default()
+    };
+    let loader = Arc::new(BuiltinProgram::new_loader(
+        config.clone(),
+        FunctionRegistry::default(),
+    ));
+    let mut executable = assemble::<TestContextObject>(
+        "
+        mov64 r1, 40
+        call function_foo
+        exit
+        function_foo:
+        stxdw [r10-8], r1
+        sub64 r1, 1
+        jeq r1, 0, +1
+        call function_foo
+        exit",
+        loader,
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    for interpreted in [true, false] {
+        let mut stack = AlignedMemory::<{ ebpf::HOST_ALIGN }>::zero_filled(config.stack_size() / 2);
+        let stack_len = stack.len();
+        let memory_mapping = MemoryMapping::new(
+            vec![
+                executable.get_ro_region(),
+                MemoryRegion::new_writable_gapped(
+                    stack.as_slice_mut(),
+                    ebpf::MM_STACK_START,
+                    config.stack_frame_size as u64,
+                ),
+            ],
+            &config,
+            SBPFVersion::V0,
+        )
+        .unwrap();
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        let mut vm = EbpfVm::new(
+            executable.get_loader().clone(),
+            SBPFVersion::V0,
+            &mut context_object,
+            memory_mapping,
+            stack_len,
+        );
+        let (instruction_count, result) = vm.execute_program(&executable, interpreted);
+        let (result, _fault_location) = split_fault_location(result);
+        if interpreted {
+            assert!(matches!(
+                result,
+                ProgramResult::Err(EbpfError::StackAccessViolation(AccessType::Store, _, 8, _))
+            ));
+        } else {
+            assert_eq!(instruction_count, 0);
+            assert!(matches!(
+                result,
+                ProgramResult::Err(EbpfError::IncompatibleStackRegion)
+            ));
+        }
+    }
+}
+
 // BPF_JMP : Branches
 
 #[test]
@@ -2071,7 +2922,7 @@ fn test_err_dynamic_stack_out_of_bound() {
             AccessType::Store,
             ebpf::MM_STACK_START - 1,
             1,
-            "program"
+            "program".into()
         )),
     );
 
@@ -2087,7 +2938,7 @@ fn test_err_dynamic_stack_out_of_bound() {
             AccessType::Store,
             ebpf::MM_STACK_START + config.stack_size() as u64,
             1,
-            "stack"
+            "stack".into()
         )),
     );
 }
@@ -2115,7 +2966,7 @@ fn test_err_dynamic_stack_ptr_overflow() {
             AccessType::Store,
             u64::MAX - 63,
             1,
-            "unknown"
+            "unknown".into()
         )),
     );
 }
@@ -2299,7 +3150,7 @@ fn test_err_mem_access_out_of_bound() {
                 AccessType::Store,
                 address,
                 1,
-                "unknown"
+                "unknown".into()
             )),
         );
     }
@@ -2324,504 +3175,2622 @@ fn test_relative_call() {
 }
 
 #[test]
-fn test_bpf_to_bpf_scratch_registers() {
-    test_interpreter_and_jit_asm!(
+fn test_execute_function() {
+    let loader = Arc::new(BuiltinProgram::new_loader(
+        Config::default(),
+        FunctionRegistry::default(),
+    ));
+    let mut executable = assemble::<TestContextObject>(
         "
-        mov64 r6, 0x11
-        mov64 r7, 0x22
-        mov64 r8, 0x44
-        mov64 r9, 0x88
-        call function_foo
-        mov64 r0, r6
-        add64 r0, r7
-        add64 r0, r8
-        add64 r0, r9
+        entrypoint:
+        mov64 r1, 0x2
+        mov64 r2, 0x3
+        mov64 r3, 0x4
+        call function_mul_add
         exit
-        function_foo:
-        mov64 r6, 0x00
-        mov64 r7, 0x00
-        mov64 r8, 0x00
-        mov64 r9, 0x00
+        function_mul_add:
+        mov64 r0, r1
+        lmul64 r0, r2
+        add64 r0, r3
+        mov64 r6, 0x7
         exit",
-        [],
-        TestContextObject::new(15),
-        ProgramResult::Ok(0xFF),
-    );
-}
+        loader,
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    let mut final_registers = Vec::new();
+    for interpreted in [true, false] {
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        // Like a suspended execution, leave the frame pointer of a deeper frame behind
+        let frame_pointer = vm.registers[ebpf::FRAME_PTR_REG];
+        vm.registers[ebpf::FRAME_PTR_REG] += 0x2000;
+        let (instruction_count, result, registers) = vm.execute_function(
+            &executable,
+            FunctionSelector::Name(b"function_mul_add"),
+            [4, 5, 6, 0, 0],
+            interpreted,
+        );
+        assert_eq!(result.unwrap(), 26);
+        assert_eq!(instruction_count, 5);
+        assert_eq!(registers[0..7], [26, 4, 5, 6, 0, 0, 7]);
+        assert_eq!(registers[ebpf::FRAME_PTR_REG], frame_pointer);
+        assert_eq!(registers[11], 9);
+        final_registers.push(registers);
+
+        let (_instruction_count, result, _registers) = vm.execute_function(
+            &executable,
+            FunctionSelector::Key(5),
+            [1, 1, 1, 0, 0],
+            interpreted,
+        );
+        assert_eq!(result.unwrap(), 2);
 
-#[test]
-fn test_syscall_parameter_on_stack() {
-    test_syscall_asm!(
-        "
-        mov64 r1, r10
-        add64 r1, -0x100
-        mov64 r2, 0x1
-        syscall {}
-        mov64 r0, 0x0
-        exit",
-        [],
-        (
-            1 => "bpf_syscall_string" => syscalls::SyscallString::vm,
-        ),
-        TestContextObject::new(6),
-        ProgramResult::Ok(0),
-    );
-}
+        let (instruction_count, result, _registers) = vm.execute_function(
+            &executable,
+            FunctionSelector::Name(b"function_foo"),
+            [0, 0, 0, 0, 0],
+            interpreted,
+        );
+        assert_error!(result, "FunctionNotRegistered(\"function_foo\")");
+        assert_eq!(instruction_count, 0);
 
-#[test]
-fn test_callx() {
-    test_interpreter_and_jit_asm!(
-        "
-        mov64 r0, 0x0
-        or64 r8, 0x20
-        callx r8
-        exit
-        function_foo:
-        mov64 r0, 0x2A
-        exit",
-        [],
-        TestContextObject::new(6),
-        ProgramResult::Ok(42),
-    );
+        // The registers of the previous executions do not leak into the next one
+        let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+        assert_eq!(result.unwrap(), 10);
+        assert_eq!(vm.registers[6], 0);
+    }
+    assert_eq!(final_registers[0], final_registers[1]);
 }
 
 #[test]
-fn test_err_callx_unregistered() {
+fn test_execute_function_in_elf() {
+    let mut file = File::open("tests/elfs/relative_call_sbpfv0.so").unwrap();
+    let mut elf = Vec::new();
+    file.read_to_end(&mut elf).unwrap();
     let config = Config {
         enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V0,
+        enable_symbol_and_section_labels: true,
         ..Config::default()
     };
+    let loader = Arc::new(BuiltinProgram::new_loader(
+        config,
+        FunctionRegistry::default(),
+    ));
+    let mut executable = Executable::<TestContextObject>::from_elf(&elf, loader).unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    for interpreted in [true, false] {
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        // function_sum() of tests/elfs/relative_call.rs
+        let (_instruction_count, result, _registers) = vm.execute_function(
+            &executable,
+            FunctionSelector::Name(b"function_13"),
+            [20, 22, 0, 0, 0],
+            interpreted,
+        );
+        assert_eq!(result.unwrap(), 42);
+    }
+}
 
-    // Callx jumps to `mov64 r0, 0x2A`
-    test_interpreter_and_jit_asm!(
+#[test]
+fn test_vm_pool() {
+    // Returns the input plus whatever a previous run left in the heap and on the stack
+    let mut executable = assemble::<TestContextObject>(
         "
-        mov64 r0, 0x0
-        lddw r8, 0x100000028
-        callx r8
-        exit
-        mov64 r0, 0x2A
+        mov64 r2, 0x0
+        hor64 r2, 0x3
+        ldxdw r0, [r2]
+        ldxdw r4, [r10-0x8]
+        add64 r0, r4
+        ldxdw r3, [r1]
+        add64 r0, r3
+        stxdw [r2], r3
+        stxdw [r10-0x8], r3
         exit",
-        config,
-        [],
-        TestContextObject::new(6),
-        ProgramResult::Ok(42),
-    );
+        Arc::new(BuiltinProgram::new_mock()),
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    let pool = VmPool::new(&executable, 0x100, 1);
+    for interpreted in [true, false] {
+        for value in [5u64, 7] {
+            let mut input = value.to_le_bytes();
+            let mut context_object = TestContextObject::new(10);
+            let (instruction_count, result) = pool
+                .with_vm(&mut context_object, &mut input, |vm| {
+                    let (instruction_count, result) = vm.execute_program(&executable, interpreted);
+                    assert_eq!(
+                        vm.memory_mapping.load::<u64>(ebpf::MM_HEAP_START).unwrap(),
+                        value
+                    );
+                    (instruction_count, result)
+                })
+                .unwrap();
+            assert_eq!(instruction_count, 10);
+            assert_eq!(result.unwrap(), value);
+            assert_eq!(pool.idle_count(), 1);
+        }
+    }
 
-    let config = Config {
-        enabled_sbpf_versions: SBPFVersion::V3..=SBPFVersion::V3,
-        ..Config::default()
-    };
+    let mut inputs = (0..32u64).map(u64::to_le_bytes).collect::<Vec<_>>();
+    for interpreted in [true, false] {
+        let results = pool.execute_batch(
+            &mut inputs,
+            4,
+            |_index| TestContextObject::new(10),
+            |index, vm, instruction_count, result| {
+                (
+                    index,
+                    instruction_count,
+                    result.unwrap(),
+                    vm.context_object_pointer.remaining,
+                )
+            },
+            interpreted,
+        );
+        assert_eq!(
+            results,
+            (0..32)
+                .map(|index| (index as usize, 10, index, 0))
+                .collect::<Vec<_>>()
+        );
+        assert!(pool.idle_count() <= 4);
+    }
+}
 
-    // Callx jumps to `mov64 r0, 0x2A`
-    test_interpreter_and_jit_asm!(
+#[test]
+fn test_fault_location() {
+    let mut loader = BuiltinProgram::new_loader_with_dense_registration(Config::default());
+    loader
+        .register_function("bpf_syscall_string", 1, syscalls::SyscallString::vm)
+        .unwrap();
+    let mut executable = assemble::<TestContextObject>(
         "
-        mov64 r0, 0x0
-        or64 r8, 0x28
-        callx r8
+        entrypoint:
+        mov64 r6, r1
+        call function_foo
+        mov64 r1, 0x0
+        mov64 r2, 0x1
+        syscall 1
         exit
-        mov64 r0, 0x2A
+        function_foo:
+        mov64 r0, 0x1
+        jeq r6, 0x0, +1
+        udiv64 r0, r7
         exit",
-        config,
-        [],
-        TestContextObject::new(3),
-        ProgramResult::Err(EbpfError::UnsupportedInstruction),
-    );
-
-    let versions = [SBPFVersion::V0, SBPFVersion::V3];
-    let expected_errors = [
-        EbpfError::CallOutsideTextSegment,
-        EbpfError::UnsupportedInstruction,
-    ];
-
-    // We execute three instructions when callx errors out.
-    for (version, error) in versions.iter().zip(expected_errors) {
-        let config = Config {
-            enabled_sbpf_versions: *version..=*version,
-            ..Config::default()
-        };
-
-        // Callx jumps to a location outside text segment
-        test_interpreter_and_jit_asm!(
-            "
-            mov64 r0, 0x0
-            or64 r8, 0x20
-            callx r8
-            exit
-            mov64 r0, 0x2A
-            exit",
-            config,
-            [],
-            TestContextObject::new(3),
-            ProgramResult::Err(error),
+        Arc::new(loader),
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    let (text_vm_addr, _text_bytes) = executable.get_text_bytes();
+    for interpreted in [true, false] {
+        for (input_start, expected_result, expected_location) in [
+            (
+                ebpf::MM_INPUT_START,
+                ProgramResult::Err(EbpfError::DivideByZero),
+                (8, "function_foo"),
+            ),
+            (
+                0,
+                ProgramResult::Err(EbpfError::SyscallError(Box::new(
+                    EbpfError::AccessViolation(AccessType::Load, 0, 1, "unknown".into()),
+                ))),
+                (4, "entrypoint"),
+            ),
+        ] {
+            let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+            create_vm!(
+                vm,
+                &executable,
+                &mut context_object,
+                stack,
+                heap,
+                Vec::new(),
+                None
+            );
+            let (_instruction_count, result, _registers) = vm.execute_function(
+                &executable,
+                FunctionSelector::Name(b"entrypoint"),
+                [input_start, 0, 0, 0, 0],
+                interpreted,
+            );
+            let (result, location) = split_fault_location(result);
+            assert_eq!(format!("{:?}", result), format!("{:?}", expected_result));
+            let location = location.unwrap();
+            assert_eq!(
+                location,
+                FaultLocation {
+                    pc: expected_location.0,
+                    vm_addr: text_vm_addr + expected_location.0 as u64 * ebpf::INSN_SIZE as u64,
+                    function_name: expected_location.1.to_string(),
+                }
+            );
+            assert_eq!(
+                location.to_string(),
+                format!(
+                    "#{} ({:#x}) in {}",
+                    location.pc, location.vm_addr, location.function_name
+                )
+            );
+        }
+        // The location reaches the users of the VmPool as well
+        let pool = VmPool::new(&executable, 0, 1);
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        let (_instruction_count, result) =
+            pool.execute_program(&mut context_object, &mut [], interpreted);
+        let error = result.unwrap_err();
+        assert_eq!(error.fault_location().unwrap().pc, 8);
+        assert!(matches!(error.without_location(), EbpfError::DivideByZero));
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "divide by zero at BPF instruction (instruction #8 ({:#x}) in function_foo)",
+                text_vm_addr + 8 * ebpf::INSN_SIZE as u64
+            )
         );
     }
-}
 
-#[test]
-fn test_err_callx_oob_low() {
+    // A suspension is not a fault
     let config = Config {
-        enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V0,
+        enable_resumable_execution: true,
         ..Config::default()
     };
-    test_interpreter_and_jit_asm!(
+    let mut executable = assemble::<TestContextObject>(
         "
-        mov64 r0, 0x3
-        callx r0
+        mov64 r0, 0x1
+        ja -2
         exit",
-        config,
-        [],
-        TestContextObject::new(2),
-        ProgramResult::Err(EbpfError::CallOutsideTextSegment),
-    );
+        Arc::new(BuiltinProgram::new_loader(
+            config,
+            FunctionRegistry::default(),
+        )),
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    for interpreted in [true, false] {
+        let mut context_object = TestContextObject::new(10);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+        let error = result.unwrap_err();
+        assert!(matches!(error, EbpfError::ExecutionSuspended));
+        assert_eq!(error.fault_location(), None);
+    }
 }
 
 #[test]
-fn test_err_callx_oob_high() {
-    test_interpreter_and_jit_asm!(
+fn test_heap_allocator() {
+    let mut loader = BuiltinProgram::new_loader_with_dense_registration(Config::default());
+    loader
+        .register_function("bpf_heap_alloc", 1, syscalls::SyscallHeapAlloc::vm)
+        .unwrap();
+    loader
+        .register_function("bpf_heap_free", 2, syscalls::SyscallHeapFree::vm)
+        .unwrap();
+    loader
+        .register_function("bpf_heap_realloc", 3, syscalls::SyscallHeapRealloc::vm)
+        .unwrap();
+    // Allocates a, writes to it, allocates b, grows a (moving it to c), frees b and allocates d.
+    // Returns the address of d plus the value read from c.
+    let mut executable = assemble::<TestContextObject>(
         "
-        mov64 r0, -0x1
-        lsh64 r0, 0x20
-        or64 r0, 0x3
-        callx r0
+        mov64 r1, 24
+        mov64 r2, 0
+        syscall 1
+        mov64 r6, r0
+        mov64 r1, 0x11
+        stxdw [r6], r1
+        mov64 r1, 64
+        mov64 r2, 64
+        syscall 1
+        mov64 r7, r0
+        mov64 r1, r6
+        mov64 r2, 128
+        mov64 r3, 0
+        syscall 3
+        mov64 r8, r0
+        mov64 r1, r7
+        syscall 2
+        mov64 r1, 16
+        mov64 r2, 0
+        syscall 1
+        ldxdw r1, [r8]
+        add64 r0, r1
         exit",
-        [],
-        TestContextObject::new(4),
-        ProgramResult::Err(EbpfError::CallOutsideTextSegment),
-    );
-}
+        Arc::new(loader),
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    let pool = VmPool::new(&executable, 0x400, 1);
+    for interpreted in [true, false] {
+        for (strategy, expected_offset) in [
+            (HeapAllocatorStrategy::Bump, 256),
+            (HeapAllocatorStrategy::FreeList, 0),
+        ] {
+            let mut remaining = Vec::new();
+            for (base_cost, byte_cost) in [(0, 0), (10, 1)] {
+                let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+                context_object.heap_allocator =
+                    Some(HeapAllocator::new(strategy, 0x400).with_cost(base_cost, byte_cost));
+                let (_instruction_count, result) =
+                    pool.execute_program(&mut context_object, &mut [], interpreted);
+                assert_eq!(
+                    result.unwrap(),
+                    ebpf::MM_HEAP_START + expected_offset + 0x11
+                );
+                let heap_allocator = context_object.heap_allocator.as_ref().unwrap();
+                assert_eq!(heap_allocator.allocated_bytes(), 128 + 16);
+                remaining.push(context_object.remaining);
+            }
+            // Five syscalls requesting 24 + 64 + 128 + 0 + 16 bytes
+            assert_eq!(remaining[0] - remaining[1], 5 * 10 + 232);
+        }
 
-#[test]
-fn test_err_callx_oob_max() {
-    test_interpreter_and_jit_asm!(
-        "
-        mov64 r0, -0x8
-        hor64 r0, -0x1
-        callx r0
-        exit",
-        [],
-        TestContextObject::new(3),
-        ProgramResult::Err(EbpfError::CallOutsideTextSegment),
-    );
-}
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        let (_instruction_count, result) =
+            pool.execute_program(&mut context_object, &mut [], interpreted);
+        assert_error!(result, "Unavailable");
+    }
 
-#[test]
-fn test_callx_unaligned_text_section() {
-    test_interpreter_and_jit_elf!(
-        "tests/elfs/callx_unaligned.so",
-        [],
-        (),
-        TestContextObject::new(129),
-        ProgramResult::Err(EbpfError::CallDepthExceeded),
-    );
+    let mut heap_allocator = HeapAllocator::new(HeapAllocatorStrategy::FreeList, 0x100);
+    assert_eq!(
+        heap_allocator.alloc(8, 3),
+        Err(HeapAllocatorError::InvalidAlignment(3))
+    );
+    let vm_addr = heap_allocator.alloc(0x100, 0).unwrap();
+    assert_eq!(vm_addr, ebpf::MM_HEAP_START);
+    assert_eq!(heap_allocator.alloc(1, 0), Ok(0));
+    heap_allocator.free(vm_addr).unwrap();
+    assert_eq!(
+        heap_allocator.free(vm_addr),
+        Err(HeapAllocatorError::InvalidPointer(vm_addr))
+    );
+
+    // Resizing the heap can not cut off allocations
+    for strategy in [HeapAllocatorStrategy::Bump, HeapAllocatorStrategy::FreeList] {
+        let mut heap_allocator = HeapAllocator::new(strategy, 0x40);
+        assert_eq!(heap_allocator.alloc(0x20, 0), Ok(ebpf::MM_HEAP_START));
+        assert_eq!(heap_allocator.alloc(0x40, 0), Ok(0));
+        assert!(heap_allocator.resize(0x80));
+        let vm_addr = heap_allocator.alloc(0x40, 0).unwrap();
+        assert_eq!(vm_addr, ebpf::MM_HEAP_START + 0x20);
+        assert!(!heap_allocator.can_resize(0x40));
+        assert!(!heap_allocator.resize(0x40));
+        heap_allocator.free(vm_addr).unwrap();
+        assert!(heap_allocator.resize(0x20));
+        assert_eq!(heap_allocator.alloc(8, 0), Ok(0));
+    }
 }
 
 #[test]
-fn test_bpf_to_bpf_depth() {
-    for max_call_depth in [20usize, Config::default().max_call_depth] {
+fn test_heap_resize() {
+    for aligned_memory_mapping in [true, false] {
         let config = Config {
-            max_call_depth,
+            aligned_memory_mapping,
+            eliminate_redundant_bounds_checks: true,
             ..Config::default()
         };
-        test_interpreter_and_jit_asm!(
+        let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+        loader
+            .register_function("bpf_heap_resize", 1, syscalls::SyscallHeapResize::vm)
+            .unwrap();
+        loader
+            .register_function("bpf_heap_alloc", 2, syscalls::SyscallHeapAlloc::vm)
+            .unwrap();
+        let loader = Arc::new(loader);
+        // Grows the heap to 100 bytes (two pages), writes to its end, fails to grow it beyond the
+        // limit and shrinks it to one page. Returns the sizes plus the value read back.
+        let mut executable = assemble::<TestContextObject>(
             "
-            ldxb r1, [r1]
-            add64 r1, -2
-            call function_foo
-            exit
-            function_foo:
-            jeq r1, 0, +2
-            add64 r1, -1
-            call function_foo
+            mov64 r1, 100
+            syscall 1
+            mov64 r6, r0
+            mov64 r7, 3
+            lsh64 r7, 32
+            mov64 r1, 0x11
+            stxdw [r7+120], r1
+            mov64 r1, 0x1000
+            syscall 1
+            add64 r6, r0
+            ldxdw r0, [r7+120]
+            add64 r6, r0
+            mov64 r1, 64
+            syscall 1
+            add64 r0, r6
             exit",
-            config.clone(),
-            [max_call_depth as u8],
-            TestContextObject::new(max_call_depth as u64 * 4 - 2),
-            ProgramResult::Ok(0),
-        );
-        // The instruction count is lower here because all the `exit`s never run
-        test_interpreter_and_jit_asm!(
+            loader.clone(),
+        )
+        .unwrap();
+        executable.verify::<RequisiteVerifier>().unwrap();
+        executable.jit_compile().unwrap();
+        // The same store is repeated after shrinking the heap
+        let mut shrinking_executable = assemble::<TestContextObject>(
             "
-            ldxb r1, [r1]
-            add64 r1, -2
-            call function_foo
-            exit
-            function_foo:
-            jeq r1, 0, +2
-            add64 r1, -1
-            call function_foo
+            mov64 r1, 128
+            syscall 1
+            mov64 r7, 3
+            lsh64 r7, 32
+            stxdw [r7+120], r1
+            mov64 r1, 64
+            syscall 1
+            stxdw [r7+120], r1
             exit",
-            config,
-            [max_call_depth as u8 + 1],
-            TestContextObject::new(max_call_depth as u64 * 3),
-            ProgramResult::Err(EbpfError::CallDepthExceeded),
-        );
-    }
-}
-
-#[test]
-fn test_err_reg_stack_depth() {
-    for max_call_depth in [20usize, Config::default().max_call_depth] {
-        let config = Config {
-            max_call_depth,
-            ..Config::default()
-        };
-        test_interpreter_and_jit_asm!(
+            loader.clone(),
+        )
+        .unwrap();
+        shrinking_executable.verify::<RequisiteVerifier>().unwrap();
+        shrinking_executable.jit_compile().unwrap();
+        // Allocates in the grown heap and fails to shrink it below the allocation
+        let mut allocating_executable = assemble::<TestContextObject>(
             "
-            callx r0
+            mov64 r1, 128
+            syscall 1
+            mov64 r1, 100
+            mov64 r2, 0
+            syscall 2
+            mov64 r6, r0
+            mov64 r1, 64
+            syscall 1
+            add64 r0, r6
             exit",
-            config,
-            [],
-            TestContextObject::new(max_call_depth as u64),
-            ProgramResult::Err(EbpfError::CallDepthExceeded),
-        );
+            loader,
+        )
+        .unwrap();
+        allocating_executable.verify::<RequisiteVerifier>().unwrap();
+        allocating_executable.jit_compile().unwrap();
+        for interpreted in [true, false] {
+            let mut remaining = Vec::new();
+            for page_cost in [0, 5] {
+                let mut heap_mem = [0u8; 0x100];
+                let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+                context_object.heap_limits = Some(HeapLimits::new(0x100, 64).with_cost(page_cost));
+                create_vm!(
+                    vm,
+                    &executable,
+                    &mut context_object,
+                    stack,
+                    heap,
+                    Vec::new(),
+                    None
+                );
+                let index = vm
+                    .memory_mapping
+                    .get_regions()
+                    .iter()
+                    .position(|region| region.vm_addr == ebpf::MM_HEAP_START)
+                    .unwrap();
+                vm.memory_mapping
+                    .replace_region(
+                        index,
+                        MemoryRegion::new_growable(&mut heap_mem, ebpf::MM_HEAP_START, 0),
+                    )
+                    .unwrap();
+                let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+                assert_eq!(result.unwrap(), 128 + 128 + 0x11 + 64);
+                assert_eq!(vm.memory_mapping.get_regions()[index].len, 64);
+                remaining.push(vm.context_object_pointer.remaining);
+                assert_eq!(heap_mem[120], 0x11);
+            }
+            // Only growing to two pages consumes units
+            assert_eq!(remaining[0] - remaining[1], 2 * 5);
+
+            let mut heap_mem = [0u8; 0x100];
+            let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+            context_object.heap_limits = Some(HeapLimits::new(0x100, 64));
+            create_vm!(
+                vm,
+                &shrinking_executable,
+                &mut context_object,
+                stack,
+                heap,
+                Vec::new(),
+                None
+            );
+            let index = vm
+                .memory_mapping
+                .get_regions()
+                .iter()
+                .position(|region| region.vm_addr == ebpf::MM_HEAP_START)
+                .unwrap();
+            vm.memory_mapping
+                .replace_region(
+                    index,
+                    MemoryRegion::new_growable(&mut heap_mem, ebpf::MM_HEAP_START, 0),
+                )
+                .unwrap();
+            let (_instruction_count, result) =
+                vm.execute_program(&shrinking_executable, interpreted);
+            assert_error!(
+                result,
+                "AccessViolation(Store, {}, 8, \"heap\")",
+                ebpf::MM_HEAP_START + 120
+            );
+
+            // Without limits, or without a growable heap region
+            let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+            create_vm!(
+                vm,
+                &executable,
+                &mut context_object,
+                stack,
+                heap,
+                Vec::new(),
+                None
+            );
+            let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+            assert_error!(result, "LimitsUnavailable");
+            // A failed resize consumes no units for the pages
+            vm.context_object_pointer.heap_limits = Some(HeapLimits::new(0x100, 64).with_cost(5));
+            vm.context_object_pointer.remaining = INSTRUCTION_METER_BUDGET;
+            let (instruction_count, result) = vm.execute_program(&executable, interpreted);
+            assert_error!(result, "InvalidMemoryRegion");
+            assert_eq!(
+                vm.context_object_pointer.remaining,
+                INSTRUCTION_METER_BUDGET - instruction_count
+            );
+
+            // The heap allocator is resized along with the heap
+            let mut heap_mem = [0u8; 0x100];
+            let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+            context_object.heap_limits = Some(HeapLimits::new(0x100, 64));
+            context_object.heap_allocator =
+                Some(HeapAllocator::new(HeapAllocatorStrategy::FreeList, 0));
+            create_vm!(
+                vm,
+                &allocating_executable,
+                &mut context_object,
+                stack,
+                heap,
+                Vec::new(),
+                None
+            );
+            let index = vm
+                .memory_mapping
+                .get_regions()
+                .iter()
+                .position(|region| region.vm_addr == ebpf::MM_HEAP_START)
+                .unwrap();
+            vm.memory_mapping
+                .replace_region(
+                    index,
+                    MemoryRegion::new_growable(&mut heap_mem, ebpf::MM_HEAP_START, 0),
+                )
+                .unwrap();
+            let (_instruction_count, result) =
+                vm.execute_program(&allocating_executable, interpreted);
+            assert_eq!(result.unwrap(), ebpf::MM_HEAP_START + 128);
+            assert_eq!(vm.memory_mapping.get_regions()[index].len, 128);
+        }
     }
-}
 
-// CALL_IMM : Syscalls
+    let heap_limits = HeapLimits::new(0x100, 64).with_cost(3);
+    assert_eq!(heap_limits.round_size(0), Some(0));
+    assert_eq!(heap_limits.round_size(65), Some(128));
+    assert_eq!(heap_limits.round_size(0x101), None);
+    assert_eq!(heap_limits.round_size(u64::MAX), None);
+    assert_eq!(heap_limits.cost(1, 0x100), 3 * 3);
+    assert_eq!(heap_limits.cost(0x100, 0), 0);
+}
 
-/* TODO: syscalls::trash_registers needs asm!().
-// https://github.com/rust-lang/rust/issues/72016
 #[test]
-fn test_call_save() {
-    test_interpreter_and_jit_asm!(
-        "
-        mov64 r6, 0x1
-        mov64 r7, 0x20
-        mov64 r8, 0x300
-        mov64 r9, 0x4000
-        call 0
+fn test_jit_cache() {
+    let directory = std::env::temp_dir().join(format!("rbpf_jit_cache_{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let source = "
         mov64 r0, 0x0
-        or64 r0, r6
-        or64 r0, r7
-        or64 r0, r8
-        or64 r0, r9
-        exit",
-        [],
-        (
-            0 => syscalls::trash_registers,
-        ),
-        { |_vm, res: ProgramResult| { res.unwrap() == 0 } }
+        add64 r0, 0x2
+        ldxdw r1, [r1]
+        add64 r0, r1
+        exit";
+    let create_executable = || {
+        let executable = assemble::<TestContextObject>(
+            source,
+            Arc::new(BuiltinProgram::new_loader(
+                Config::default(),
+                FunctionRegistry::default(),
+            )),
+        )
+        .unwrap();
+        executable.verify::<RequisiteVerifier>().unwrap();
+        executable
+    };
+    let mut executable = create_executable();
+    assert!(matches!(
+        executable.serialize_compiled_program(),
+        Err(EbpfError::JitNotCompiled)
+    ));
+    let key = executable
+        .get_jit_cache_key()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    let path = directory.join(format!("{key}.jit"));
+    // A corrupted entry is replaced
+    std::fs::write(&path, b"corrupted").unwrap();
+    executable.jit_compile_cached(&directory).unwrap();
+    assert_eq!(
+        std::fs::read(&path).unwrap(),
+        executable.serialize_compiled_program().unwrap()
     );
-}*/
+    test_interpreter_and_jit!(
+        executable,
+        [0x03, 0, 0, 0, 0, 0, 0, 0],
+        TestContextObject::new(5),
+        ProgramResult::Ok(0x5),
+    );
+
+    // Another executable with the same text and config loads the entry
+    let mut executable = create_executable();
+    let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+    executable.jit_compile_cached(&directory).unwrap();
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().modified().unwrap(),
+        modified
+    );
+    let mut mem = [0x04, 0, 0, 0, 0, 0, 0, 0];
+    let mem_region = MemoryRegion::new_writable(&mut mem, ebpf::MM_INPUT_START);
+    let mut context_object = TestContextObject::new(5);
+    create_vm!(
+        vm,
+        &executable,
+        &mut context_object,
+        stack,
+        heap,
+        vec![mem_region],
+        None
+    );
+    let (instruction_count, result) = vm.execute_program(&executable, false);
+    assert_eq!(instruction_count, 5);
+    assert_eq!(result.unwrap(), 0x6);
+
+    // Concurrent writers in one process do not share a temporary file
+    std::fs::remove_file(&path).unwrap();
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| create_executable().jit_compile_cached(&directory).unwrap());
+        }
+    });
+    assert_eq!(
+        std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>(),
+        [path.clone()]
+    );
+    executable
+        .load_compiled_program(&std::fs::read(&path).unwrap())
+        .unwrap();
+
+    // A different config results in a different key
+    let other_executable = assemble::<TestContextObject>(
+        source,
+        Arc::new(BuiltinProgram::new_loader(
+            Config {
+                enable_instruction_tracing: true,
+                ..Config::default()
+            },
+            FunctionRegistry::default(),
+        )),
+    )
+    .unwrap();
+    assert_ne!(
+        other_executable.get_jit_cache_key(),
+        executable.get_jit_cache_key()
+    );
+    std::fs::remove_dir_all(&directory).unwrap();
+}
 
 #[test]
-fn test_err_syscall_string() {
-    test_syscall_asm!(
+fn test_bpf_to_bpf_scratch_registers() {
+    test_interpreter_and_jit_asm!(
         "
-        mov64 r1, 0x0
-        syscall {}
-        mov64 r0, 0x0
+        mov64 r6, 0x11
+        mov64 r7, 0x22
+        mov64 r8, 0x44
+        mov64 r9, 0x88
+        call function_foo
+        mov64 r0, r6
+        add64 r0, r7
+        add64 r0, r8
+        add64 r0, r9
+        exit
+        function_foo:
+        mov64 r6, 0x00
+        mov64 r7, 0x00
+        mov64 r8, 0x00
+        mov64 r9, 0x00
         exit",
-        [72, 101, 108, 108, 111],
-        (
-            2 => "bpf_syscall_string" => syscalls::SyscallString::vm,
-        ),
-        TestContextObject::new(2),
-        ProgramResult::Err(EbpfError::SyscallError(Box::new(EbpfError::AccessViolation(AccessType::Load, 0, 0, "unknown")))),
+        [],
+        TestContextObject::new(15),
+        ProgramResult::Ok(0xFF),
     );
 }
 
 #[test]
-fn test_syscall_string() {
+fn test_syscall_parameter_on_stack() {
     test_syscall_asm!(
         "
-        mov64 r2, 0x5
+        mov64 r1, r10
+        add64 r1, -0x100
+        mov64 r2, 0x1
         syscall {}
         mov64 r0, 0x0
         exit",
-        [72, 101, 108, 108, 111],
+        [],
         (
             1 => "bpf_syscall_string" => syscalls::SyscallString::vm,
         ),
-        TestContextObject::new(4),
+        TestContextObject::new(6),
         ProgramResult::Ok(0),
     );
 }
 
 #[test]
-fn test_syscall() {
-    test_syscall_asm!(
+fn test_callx() {
+    test_interpreter_and_jit_asm!(
         "
-        mov64 r1, 0xAA
-        mov64 r2, 0xBB
-        mov64 r3, 0xCC
-        mov64 r4, 0xDD
-        mov64 r5, 0xEE
-        syscall {}
         mov64 r0, 0x0
+        or64 r8, 0x20
+        callx r8
+        exit
+        function_foo:
+        mov64 r0, 0x2A
         exit",
         [],
-        (
-            3 => "bpf_syscall_u64" => syscalls::SyscallU64::vm,
-        ),
-        TestContextObject::new(8),
-        ProgramResult::Ok(0),
+        TestContextObject::new(6),
+        ProgramResult::Ok(42),
     );
 }
 
 #[test]
-fn test_call_gather_bytes() {
-    test_syscall_asm!(
+fn test_err_callx_unregistered() {
+    let config = Config {
+        enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V0,
+        ..Config::default()
+    };
+
+    // Callx jumps to `mov64 r0, 0x2A`
+    test_interpreter_and_jit_asm!(
         "
-        mov r1, 1
-        mov r2, 2
-        mov r3, 3
-        mov r4, 4
-        mov r5, 5
-        syscall {}
+        mov64 r0, 0x0
+        lddw r8, 0x100000028
+        callx r8
+        exit
+        mov64 r0, 0x2A
         exit",
+        config,
         [],
-        (
-            1 => "bpf_gather_bytes" => syscalls::SyscallGatherBytes::vm,
-        ),
-        TestContextObject::new(7),
-        ProgramResult::Ok(0x0102030405),
+        TestContextObject::new(6),
+        ProgramResult::Ok(42),
     );
-}
 
-#[test]
-fn test_call_memfrob() {
-    test_syscall_asm!(
+    let config = Config {
+        enabled_sbpf_versions: SBPFVersion::V3..=SBPFVersion::V3,
+        ..Config::default()
+    };
+
+    // Callx jumps to `mov64 r0, 0x2A`
+    test_interpreter_and_jit_asm!(
         "
-        mov r6, r1
-        add r1, 2
-        mov r2, 4
-        syscall {}
-        ldxdw r0, [r6]
-        be64 r0
-        exit",
-        [
+        mov64 r0, 0x0
+        or64 r8, 0x28
+        callx r8
+        exit
+        mov64 r0, 0x2A
+        exit",
+        config,
+        [],
+        TestContextObject::new(3),
+        ProgramResult::Err(EbpfError::UnsupportedInstruction),
+    );
+
+    let versions = [SBPFVersion::V0, SBPFVersion::V3];
+    let expected_errors = [
+        EbpfError::CallOutsideTextSegment,
+        EbpfError::UnsupportedInstruction,
+    ];
+
+    // We execute three instructions when callx errors out.
+    for (version, error) in versions.iter().zip(expected_errors) {
+        let config = Config {
+            enabled_sbpf_versions: *version..=*version,
+            ..Config::default()
+        };
+
+        // Callx jumps to a location outside text segment
+        test_interpreter_and_jit_asm!(
+            "
+            mov64 r0, 0x0
+            or64 r8, 0x20
+            callx r8
+            exit
+            mov64 r0, 0x2A
+            exit",
+            config,
+            [],
+            TestContextObject::new(3),
+            ProgramResult::Err(error),
+        );
+    }
+}
+
+#[test]
+fn test_err_callx_oob_low() {
+    let config = Config {
+        enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V0,
+        ..Config::default()
+    };
+    test_interpreter_and_jit_asm!(
+        "
+        mov64 r0, 0x3
+        callx r0
+        exit",
+        config,
+        [],
+        TestContextObject::new(2),
+        ProgramResult::Err(EbpfError::CallOutsideTextSegment),
+    );
+}
+
+#[test]
+fn test_err_callx_oob_high() {
+    test_interpreter_and_jit_asm!(
+        "
+        mov64 r0, -0x1
+        lsh64 r0, 0x20
+        or64 r0, 0x3
+        callx r0
+        exit",
+        [],
+        TestContextObject::new(4),
+        ProgramResult::Err(EbpfError::CallOutsideTextSegment),
+    );
+}
+
+#[test]
+fn test_err_callx_oob_max() {
+    test_interpreter_and_jit_asm!(
+        "
+        mov64 r0, -0x8
+        hor64 r0, -0x1
+        callx r0
+        exit",
+        [],
+        TestContextObject::new(3),
+        ProgramResult::Err(EbpfError::CallOutsideTextSegment),
+    );
+}
+
+#[test]
+fn test_callx_unaligned_text_section() {
+    test_interpreter_and_jit_elf!(
+        "tests/elfs/callx_unaligned.so",
+        [],
+        (),
+        TestContextObject::new(129),
+        ProgramResult::Err(EbpfError::CallDepthExceeded),
+    );
+}
+
+#[test]
+fn test_bpf_to_bpf_depth() {
+    for max_call_depth in [20usize, Config::default().max_call_depth] {
+        let config = Config {
+            max_call_depth,
+            ..Config::default()
+        };
+        test_interpreter_and_jit_asm!(
+            "
+            ldxb r1, [r1]
+            add64 r1, -2
+            call function_foo
+            exit
+            function_foo:
+            jeq r1, 0, +2
+            add64 r1, -1
+            call function_foo
+            exit",
+            config.clone(),
+            [max_call_depth as u8],
+            TestContextObject::new(max_call_depth as u64 * 4 - 2),
+            ProgramResult::Ok(0),
+        );
+        // The instruction count is lower here because all the `exit`s never run
+        test_interpreter_and_jit_asm!(
+            "
+            ldxb r1, [r1]
+            add64 r1, -2
+            call function_foo
+            exit
+            function_foo:
+            jeq r1, 0, +2
+            add64 r1, -1
+            call function_foo
+            exit",
+            config,
+            [max_call_depth as u8 + 1],
+            TestContextObject::new(max_call_depth as u64 * 3),
+            ProgramResult::Err(EbpfError::CallDepthExceeded),
+        );
+    }
+}
+
+#[test]
+fn test_err_reg_stack_depth() {
+    for max_call_depth in [20usize, Config::default().max_call_depth] {
+        let config = Config {
+            max_call_depth,
+            ..Config::default()
+        };
+        test_interpreter_and_jit_asm!(
+            "
+            callx r0
+            exit",
+            config,
+            [],
+            TestContextObject::new(max_call_depth as u64),
+            ProgramResult::Err(EbpfError::CallDepthExceeded),
+        );
+    }
+}
+
+// CALL_IMM : Syscalls
+
+/* TODO: syscalls::trash_registers needs asm!().
+// https://github.com/rust-lang/rust/issues/72016
+#[test]
+fn test_call_save() {
+    test_interpreter_and_jit_asm!(
+        "
+        mov64 r6, 0x1
+        mov64 r7, 0x20
+        mov64 r8, 0x300
+        mov64 r9, 0x4000
+        call 0
+        mov64 r0, 0x0
+        or64 r0, r6
+        or64 r0, r7
+        or64 r0, r8
+        or64 r0, r9
+        exit",
+        [],
+        (
+            0 => syscalls::trash_registers,
+        ),
+        { |_vm, res: ProgramResult| { res.unwrap() == 0 } }
+    );
+}*/
+
+#[test]
+fn test_err_syscall_string() {
+    test_syscall_asm!(
+        "
+        mov64 r1, 0x0
+        syscall {}
+        mov64 r0, 0x0
+        exit",
+        [72, 101, 108, 108, 111],
+        (
+            2 => "bpf_syscall_string" => syscalls::SyscallString::vm,
+        ),
+        TestContextObject::new(2),
+        ProgramResult::Err(EbpfError::SyscallError(Box::new(EbpfError::AccessViolation(AccessType::Load, 0, 0, "unknown".into())))),
+    );
+}
+
+#[test]
+fn test_syscall_string() {
+    test_syscall_asm!(
+        "
+        mov64 r2, 0x5
+        syscall {}
+        mov64 r0, 0x0
+        exit",
+        [72, 101, 108, 108, 111],
+        (
+            1 => "bpf_syscall_string" => syscalls::SyscallString::vm,
+        ),
+        TestContextObject::new(4),
+        ProgramResult::Ok(0),
+    );
+}
+
+#[test]
+fn test_syscall() {
+    test_syscall_asm!(
+        "
+        mov64 r1, 0xAA
+        mov64 r2, 0xBB
+        mov64 r3, 0xCC
+        mov64 r4, 0xDD
+        mov64 r5, 0xEE
+        syscall {}
+        mov64 r0, 0x0
+        exit",
+        [],
+        (
+            3 => "bpf_syscall_u64" => syscalls::SyscallU64::vm,
+        ),
+        TestContextObject::new(8),
+        ProgramResult::Ok(0),
+    );
+}
+
+#[test]
+fn test_call_gather_bytes() {
+    test_syscall_asm!(
+        "
+        mov r1, 1
+        mov r2, 2
+        mov r3, 3
+        mov r4, 4
+        mov r5, 5
+        syscall {}
+        exit",
+        [],
+        (
+            1 => "bpf_gather_bytes" => syscalls::SyscallGatherBytes::vm,
+        ),
+        TestContextObject::new(7),
+        ProgramResult::Ok(0x0102030405),
+    );
+}
+
+#[test]
+fn test_call_memfrob() {
+    test_syscall_asm!(
+        "
+        mov r6, r1
+        add r1, 2
+        mov r2, 4
+        syscall {}
+        ldxdw r0, [r6]
+        be64 r0
+        exit",
+        [
             0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, //
         ],
         (
-            2 => "bpf_mem_frob" => syscalls::SyscallMemFrob::vm,
+            2 => "bpf_mem_frob" => syscalls::SyscallMemFrob::vm,
+        ),
+        TestContextObject::new(7),
+        ProgramResult::Ok(0x102292e2f2c0708),
+    );
+}
+
+declare_builtin_function!(
+    /// For test_nested_vm_syscall()
+    SyscallNestedVm,
+    fn rust(
+        _context_object: &mut TestContextObject,
+        depth: u64,
+        throw: u64,
+        version: u64,
+        _arg4: u64,
+        _arg5: u64,
+        _memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let (result, expected_result): (Result<u64, Box<dyn std::error::Error>>, ProgramResult) =
+            if throw == 0 {
+                (Result::Ok(42), ProgramResult::Ok(42))
+            } else {
+                (
+                    Result::Err(Box::new(EbpfError::CallDepthExceeded)),
+                    ProgramResult::Err(EbpfError::SyscallError(Box::new(
+                        EbpfError::CallDepthExceeded,
+                    ))),
+                )
+            };
+        #[allow(unused_mut)]
+        if depth > 0 {
+            let mut config = Config::default();
+            let syscall_name = if version == 1 {
+                config.enabled_sbpf_versions = SBPFVersion::V0..=SBPFVersion::V0;
+                "nested_vm_syscall"
+            } else {
+                config.enabled_sbpf_versions = SBPFVersion::V3..=SBPFVersion::V3;
+                "1"
+            };
+            let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+            loader.register_function("nested_vm_syscall", 1, SyscallNestedVm::vm).unwrap();
+            let source_code = format!(
+                "
+                ldxb r2, [r1+1]
+                ldxb r1, [r1]
+                syscall {}
+                exit",
+                syscall_name
+            );
+            let mem = [depth as u8 - 1, throw as u8];
+            let mut executable = assemble::<TestContextObject>(
+                source_code.as_str(),
+                Arc::new(loader),
+            )
+            .unwrap();
+            test_interpreter_and_jit!(
+                executable,
+                mem,
+                TestContextObject::new(if throw == 0 { 4 } else { 3 }),
+                expected_result,
+            );
+        }
+        result
+    }
+);
+
+#[test]
+fn test_nested_vm_syscall() {
+    let config = Config::default();
+    let mut context_object = TestContextObject::default();
+    let mut memory_mapping = MemoryMapping::new(vec![], &config, SBPFVersion::V3).unwrap();
+
+    // SBPFv0
+    let result = SyscallNestedVm::rust(&mut context_object, 1, 0, 1, 0, 0, &mut memory_mapping);
+    assert_eq!(result.unwrap(), 42);
+    let result = SyscallNestedVm::rust(&mut context_object, 1, 1, 1, 0, 0, &mut memory_mapping);
+    assert_error!(result, "CallDepthExceeded");
+
+    // SBPFv3
+    let result = SyscallNestedVm::rust(&mut context_object, 1, 0, 2, 0, 0, &mut memory_mapping);
+    assert_eq!(result.unwrap(), 42);
+    let result = SyscallNestedVm::rust(&mut context_object, 1, 1, 2, 0, 0, &mut memory_mapping);
+    assert_error!(result, "CallDepthExceeded");
+}
+
+#[test]
+fn test_trace_events() {
+    let config = Config {
+        enable_instruction_tracing: true,
+        ..Config::default()
+    };
+    let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+    loader
+        .register_function("bpf_syscall_u64", 1, syscalls::SyscallU64::vm)
+        .unwrap();
+    let loader = Arc::new(loader);
+    let mut executable = assemble::<TestContextObject>(
+        "
+        ldxb r2, [r1+1]
+        stxdw [r10-8], r2
+        call function_foo
+        ldxw r0, [r1+0x10]
+        exit
+        function_foo:
+        mov64 r1, 5
+        syscall 1
+        exit",
+        loader,
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    let stack_top = ebpf::MM_STACK_START + executable.get_config().stack_size() as u64;
+    let expected_event_log = vec![
+        (
+            0,
+            TraceEvent::MemoryAccess {
+                pc: 0,
+                access_type: AccessType::Load,
+                vm_addr: ebpf::MM_INPUT_START + 1,
+                len: 1,
+                value: 0xbb,
+                region_vm_addr: ebpf::MM_INPUT_START,
+            },
+        ),
+        (
+            1,
+            TraceEvent::MemoryAccess {
+                pc: 1,
+                access_type: AccessType::Store,
+                vm_addr: stack_top - 8,
+                len: 8,
+                value: 0xbb,
+                region_vm_addr: ebpf::MM_STACK_START,
+            },
+        ),
+        (
+            2,
+            TraceEvent::Call {
+                pc: 2,
+                target_pc: 5,
+                call_depth: 1,
+            },
+        ),
+        (
+            4,
+            TraceEvent::SyscallEntry {
+                pc: 6,
+                arguments: [5, 0xbb, 0, 0, 0],
+            },
+        ),
+        (4, TraceEvent::SyscallExit { pc: 6, result: 0 }),
+        (
+            5,
+            TraceEvent::Return {
+                pc: 7,
+                call_depth: 0,
+            },
+        ),
+        (
+            6,
+            TraceEvent::Exception {
+                pc: 3,
+                error: "Access violation in unknown section at address 0x15 of size 4".to_string(),
+            },
+        ),
+    ];
+    for interpreted in [true, false] {
+        let mut mem = [0xaa, 0xbb];
+        let mut context_object = TestContextObject::new(100);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            vec![MemoryRegion::new_writable(&mut mem, ebpf::MM_INPUT_START)],
+            None
+        );
+        let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+        let (result, _fault_location) = split_fault_location(result);
+        assert!(matches!(
+            result,
+            ProgramResult::Err(EbpfError::AccessViolation(..))
+        ));
+        assert_eq!(vm.context_object_pointer.event_log, expected_event_log);
+    }
+}
+
+// Instruction Meter Limit
+
+#[test]
+fn test_tight_infinite_loop_conditional() {
+    test_interpreter_and_jit_asm!(
+        "
+        jsge r0, r0, -1
+        exit",
+        [],
+        TestContextObject::new(4),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+}
+
+#[test]
+fn test_tight_infinite_loop_unconditional() {
+    test_interpreter_and_jit_asm!(
+        "
+        ja -1
+        exit",
+        [],
+        TestContextObject::new(4),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+}
+
+#[test]
+fn test_tight_infinite_recursion() {
+    test_interpreter_and_jit_asm!(
+        "
+        entrypoint:
+        mov64 r3, 0x41414141
+        call entrypoint
+        exit",
+        [],
+        TestContextObject::new(4),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+}
+
+#[test]
+fn test_tight_infinite_recursion_callx() {
+    test_interpreter_and_jit_asm!(
+        "
+        or64 r8, 0x18
+        call function_foo
+        exit
+        function_foo:
+        mov64 r3, 0x41414141
+        callx r8
+        exit",
+        [],
+        TestContextObject::new(6),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+}
+
+#[test]
+fn test_instruction_count_syscall() {
+    test_syscall_asm!(
+        "
+        mov64 r2, 0x5
+        syscall {}
+        mov64 r0, 0x0
+        exit",
+        [72, 101, 108, 108, 111],
+        (
+            1 => "bpf_syscall_string" => syscalls::SyscallString::vm,
+        ),
+        TestContextObject::new(4),
+        ProgramResult::Ok(0),
+    );
+}
+
+#[test]
+fn test_err_instruction_count_syscall_capped() {
+    test_syscall_asm!(
+        "
+        mov64 r2, 0x5
+        syscall {}
+        mov64 r0, 0x0
+        exit",
+        [72, 101, 108, 108, 111],
+        (
+            1 => "bpf_syscall_string" => syscalls::SyscallString::vm,
         ),
+        TestContextObject::new(3),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+}
+
+#[test]
+fn test_err_non_terminate_capped() {
+    test_interpreter_and_jit_asm!(
+        "
+        mov64 r6, 0x0
+        mov64 r1, 0x0
+        mov64 r2, 0x0
+        mov64 r3, 0x0
+        mov64 r4, 0x0
+        mov64 r5, r6
+        add64 r6, 0x1
+        ja -0x8
+        exit",
+        [],
         TestContextObject::new(7),
-        ProgramResult::Ok(0x102292e2f2c0708),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+    test_interpreter_and_jit_asm!(
+        "
+        mov64 r6, 0x0
+        mov64 r1, 0x0
+        mov64 r2, 0x0
+        mov64 r3, 0x0
+        mov64 r4, 0x0
+        mov64 r5, r6
+        add64 r6, 0x1
+        ja -0x8
+        exit",
+        [],
+        TestContextObject::new(1000),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+}
+
+#[test]
+fn test_err_capped_before_exception() {
+    test_interpreter_and_jit_asm!(
+        "
+        mov64 r1, 0x0
+        mov64 r2, 0x0
+        udiv64 r1, r2
+        mov64 r0, 0x0
+        exit",
+        [],
+        TestContextObject::new(2),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+
+    test_interpreter_and_jit_asm!(
+        "
+        mov64 r1, 0x0
+        mov64 r2, 0x0
+        callx r2
+        mov64 r0, 0x0
+        exit",
+        [],
+        TestContextObject::new(2),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+}
+
+#[test]
+fn test_err_exit_capped() {
+    test_interpreter_and_jit_asm!(
+        "
+        or64 r0, 0x18
+        callx r0
+        exit
+        function_foo:
+        exit
+        ",
+        [],
+        TestContextObject::new(3),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+    test_interpreter_and_jit_asm!(
+        "
+        or64 r0, 0x18
+        callx r0
+        exit
+        function_foo:
+        mov r0, r0
+        exit
+        ",
+        [],
+        TestContextObject::new(4),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+    );
+    test_interpreter_and_jit_asm!(
+        "
+        call function_foo
+        exit
+        function_foo:
+        mov r0, r0
+        exit
+        ",
+        [],
+        TestContextObject::new(3),
+        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
     );
 }
 
+const RESUMABLE_PROGRAM: &str = "
+    mov64 r6, 0
+    mov64 r0, 0
+    call function_sum
+    add64 r6, 1
+    jlt r6, 5, -3
+    exit
+    function_sum:
+    mov64 r7, 0
+    add64 r0, r7
+    add64 r7, 1
+    jlt r7, 10, -3
+    exit";
+
+#[test]
+fn test_resume_suspended_execution() {
+    for sbpf_version in [SBPFVersion::V0, SBPFVersion::V3] {
+        let config = Config {
+            enable_resumable_execution: true,
+            instruction_meter_checkpoint_distance: 3,
+            enabled_sbpf_versions: sbpf_version..=sbpf_version,
+            ..Config::default()
+        };
+        let loader = Arc::new(BuiltinProgram::new_loader(
+            config,
+            FunctionRegistry::default(),
+        ));
+        let mut executable = assemble::<TestContextObject>(RESUMABLE_PROGRAM, loader).unwrap();
+        executable.verify::<RequisiteVerifier>().unwrap();
+        executable.jit_compile().unwrap();
+        let expected_instruction_count = {
+            let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+            create_vm!(
+                vm,
+                &executable,
+                &mut context_object,
+                stack,
+                heap,
+                Vec::new(),
+                None
+            );
+            let (instruction_count, result) = vm.execute_program(&executable, true);
+            assert_eq!(result.unwrap(), 225);
+            instruction_count
+        };
+        for (suspend_interpreted, resume_interpreted) in
+            [(true, true), (true, false), (false, true), (false, false)]
+        {
+            let mut context_object = TestContextObject::new(7);
+            create_vm!(
+                vm,
+                &executable,
+                &mut context_object,
+                stack,
+                heap,
+                Vec::new(),
+                None
+            );
+            let (_instruction_count, result) = vm.execute_program(&executable, suspend_interpreted);
+            assert_error!(result, "ExecutionSuspended");
+            let suspended_state = (vm.call_depth, vm.registers);
+            assert_eq!(suspended_state.0, 1);
+            // A new execution starts in the root frame again, even if the last one was suspended in a call
+            vm.context_object_pointer.remaining = 7;
+            let (mut instruction_count, mut result) =
+                vm.execute_program(&executable, suspend_interpreted);
+            assert_eq!((vm.call_depth, vm.registers), suspended_state);
+            let mut suspensions = 0;
+            while let ProgramResult::Err(EbpfError::ExecutionSuspended) = result {
+                suspensions += 1;
+                vm.context_object_pointer.remaining = 7;
+                let (slice_instruction_count, slice_result) =
+                    vm.resume_program(&executable, resume_interpreted);
+                instruction_count += slice_instruction_count;
+                result = slice_result;
+            }
+            assert!(suspensions > 1);
+            assert_eq!(result.unwrap(), 225);
+            // The JIT only suspends at its checkpoints, but reports the instructions it ran over budget
+            assert_eq!(instruction_count, expected_instruction_count);
+        }
+    }
+}
+
+#[test]
+fn test_suspended_state() {
+    for sbpf_version in [SBPFVersion::V0, SBPFVersion::V3] {
+        // With a checkpoint at every instruction the JIT suspends at exactly the same pc as the interpreter
+        let config = Config {
+            enable_resumable_execution: true,
+            instruction_meter_checkpoint_distance: 1,
+            enabled_sbpf_versions: sbpf_version..=sbpf_version,
+            ..Config::default()
+        };
+        let loader = Arc::new(BuiltinProgram::new_loader(
+            config,
+            FunctionRegistry::default(),
+        ));
+        let mut executable = assemble::<TestContextObject>(RESUMABLE_PROGRAM, loader).unwrap();
+        executable.verify::<RequisiteVerifier>().unwrap();
+        executable.jit_compile().unwrap();
+        for budget in 1..64 {
+            let mut suspended_states = Vec::new();
+            for interpreted in [true, false] {
+                let mut context_object = TestContextObject::new(budget);
+                create_vm!(
+                    vm,
+                    &executable,
+                    &mut context_object,
+                    stack,
+                    heap,
+                    Vec::new(),
+                    None
+                );
+                let (instruction_count, result) = vm.execute_program(&executable, interpreted);
+                assert_eq!(
+                    format!("{:?}", result),
+                    format!("{:?}", ProgramResult::Err(EbpfError::ExecutionSuspended)),
+                );
+                assert_eq!(instruction_count, budget);
+                suspended_states.push((
+                    vm.registers,
+                    vm.call_depth,
+                    vm.call_frames[0..vm.call_depth as usize].to_vec(),
+                ));
+            }
+            assert_eq!(suspended_states[0], suspended_states[1]);
+        }
+    }
+}
+
+#[test]
+fn test_suspend_before_exception() {
+    let config = Config {
+        enable_resumable_execution: true,
+        ..Config::default()
+    };
+    let loader = Arc::new(BuiltinProgram::new_loader(
+        config,
+        FunctionRegistry::default(),
+    ));
+    let mut executable = assemble::<TestContextObject>(
+        "
+        mov64 r0, 0x1
+        mov64 r2, 0x0
+        udiv64 r0, r2
+        exit",
+        loader,
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    for interpreted in [true, false] {
+        let mut context_object = TestContextObject::new(2);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        let (instruction_count, result) = vm.execute_program(&executable, interpreted);
+        assert_error!(result, "ExecutionSuspended");
+        assert_eq!(instruction_count, 2);
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[11], 2);
+        vm.context_object_pointer.remaining = 2;
+        let (instruction_count, result) = vm.resume_program(&executable, interpreted);
+        assert_error!(result, "DivideByZero");
+        assert_eq!(instruction_count, 1);
+    }
+}
+
+#[test]
+fn test_replay_snapshot_of_suspended_execution() {
+    for aligned_memory_mapping in [true, false] {
+        let config = Config {
+            enable_resumable_execution: true,
+            aligned_memory_mapping,
+            ..Config::default()
+        };
+        let loader = Arc::new(BuiltinProgram::new_loader(
+            config,
+            FunctionRegistry::default(),
+        ));
+        let mut executable = assemble::<TestContextObject>(
+            "
+            ldxdw r2, [r1]
+            stxdw [r10-8], r2
+            mov64 r0, 0
+            add64 r0, 1
+            jlt r0, 20, -2
+            ldxdw r0, [r10-8]
+            exit",
+            loader,
+        )
+        .unwrap();
+        executable.verify::<RequisiteVerifier>().unwrap();
+        executable.jit_compile().unwrap();
+        for interpreted in [true, false] {
+            let mut bytes = Vec::new();
+            {
+                let mut mem = 0x1122334455667788u64.to_le_bytes();
+                let mut context_object = TestContextObject::new(10);
+                create_vm!(
+                    vm,
+                    &executable,
+                    &mut context_object,
+                    stack,
+                    heap,
+                    vec![MemoryRegion::new_writable(&mut mem, ebpf::MM_INPUT_START)],
+                    None
+                );
+                let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+                assert_error!(result, "ExecutionSuspended");
+                VmSnapshot::capture(&vm, &executable)
+                    .unwrap()
+                    .write(&mut bytes)
+                    .unwrap();
+            }
+            for resume_interpreted in [true, false] {
+                let mut snapshot = VmSnapshot::read(&mut bytes.as_slice()).unwrap();
+                let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+                let mut vm = snapshot
+                    .create_vm(&executable, &mut context_object, None)
+                    .unwrap();
+                let (_instruction_count, result) =
+                    vm.resume_program(&executable, resume_interpreted);
+                assert_eq!(result.unwrap(), 0x1122334455667788);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_lockstep_execution() {
+    let programs: [(&str, ProgramResult); 3] = [
+        (RESUMABLE_PROGRAM, ProgramResult::Ok(225)),
+        (
+            "
+            ldxdw r2, [r1]
+            stxdw [r10-8], r2
+            mov64 r0, 0
+            add64 r0, 1
+            stxb [r1+8], r0
+            jlt r0, 20, -3
+            ldxdw r0, [r10-8]
+            exit",
+            ProgramResult::Ok(0x1122334455667788),
+        ),
+        (
+            "
+            mov64 r0, 0
+            add64 r0, 1
+            jlt r0, 5, -2
+            mov64 r2, 0
+            ldxdw r0, [r2]
+            exit",
+            ProgramResult::Err(EbpfError::AccessViolation(
+                AccessType::Load,
+                0,
+                8,
+                "unknown".into(),
+            )),
+        ),
+    ];
+    for (sbpf_version, enable_exact_instruction_meter) in [
+        (SBPFVersion::V0, false),
+        (SBPFVersion::V3, false),
+        (SBPFVersion::V3, true),
+    ] {
+        for instruction_meter_checkpoint_distance in [1, 3, 10000] {
+            // With the exact instruction meter the interval in which the budget runs out is
+            // compared entirely
+            let config = Config {
+                enable_resumable_execution: true,
+                enable_exact_instruction_meter,
+                instruction_meter_checkpoint_distance,
+                enabled_sbpf_versions: sbpf_version..=sbpf_version,
+                ..Config::default()
+            };
+            let loader = Arc::new(BuiltinProgram::new_loader(
+                config,
+                FunctionRegistry::default(),
+            ));
+            for (source, expected_result) in programs.iter() {
+                let mut executable = assemble::<TestContextObject>(source, loader.clone()).unwrap();
+                executable.verify::<RequisiteVerifier>().unwrap();
+                executable.jit_compile().unwrap();
+                for budget in [INSTRUCTION_METER_BUDGET, 7] {
+                    let mut results = Vec::new();
+                    for lockstep in [false, true] {
+                        let mut mem = [0u8; 16];
+                        mem[0..8].copy_from_slice(&0x1122334455667788u64.to_le_bytes());
+                        let mut context_object = TestContextObject::new(budget);
+                        create_vm!(
+                            vm,
+                            &executable,
+                            &mut context_object,
+                            stack,
+                            heap,
+                            vec![MemoryRegion::new_writable(&mut mem, ebpf::MM_INPUT_START)],
+                            None
+                        );
+                        let (instruction_count, result) = if lockstep {
+                            execute_program_in_lockstep(&mut vm, &executable).unwrap()
+                        } else {
+                            vm.execute_program(&executable, false)
+                        };
+                        let (result, fault_location) = split_fault_location(result);
+                        // The host stack pointer is only set by the JIT compiled program
+                        assert!(!vm.host_stack_pointer.is_null());
+                        if budget == INSTRUCTION_METER_BUDGET {
+                            assert_eq!(format!("{result:?}"), format!("{expected_result:?}"));
+                        } else {
+                            assert_error!(result, "ExecutionSuspended");
+                        }
+                        results.push((
+                            instruction_count,
+                            format!("{result:?}"),
+                            fault_location,
+                            vm.registers[11],
+                            mem,
+                        ));
+                    }
+                    assert_eq!(results[0], results[1]);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_lockstep_execution_with_syscalls() {
+    let config = Config {
+        enable_resumable_execution: true,
+        ..Config::default()
+    };
+    let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+    loader
+        .register_function("syscall_consume", 1, SyscallConsume::vm)
+        .unwrap();
+    let mut executable = assemble::<TestContextObject>(
+        "
+        mov64 r6, 0
+        mov64 r1, 5
+        syscall 1
+        add64 r6, 1
+        jlt r6, 4, -4
+        mov64 r0, r6
+        exit",
+        Arc::new(loader),
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    for lockstep in [false, true] {
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        let (instruction_count, result) = if lockstep {
+            execute_program_in_lockstep(&mut vm, &executable).unwrap()
+        } else {
+            vm.execute_program(&executable, true)
+        };
+        assert_eq!(result.unwrap(), 4);
+        // The syscall consumed 5 units on every invocation
+        assert_eq!(instruction_count, 19 + 4 * 5);
+    }
+}
+
 declare_builtin_function!(
-    /// For test_nested_vm_syscall()
-    SyscallNestedVm,
+    /// For test_lockstep_execution_divergence()
+    SyscallNondeterministic,
     fn rust(
-        _context_object: &mut TestContextObject,
-        depth: u64,
-        throw: u64,
-        version: u64,
+        _context_object: &mut TestContextObject,
+        _arg1: u64,
+        _arg2: u64,
+        _arg3: u64,
         _arg4: u64,
         _arg5: u64,
         _memory_mapping: &mut MemoryMapping,
     ) -> Result<u64, Box<dyn std::error::Error>> {
-        let (result, expected_result): (Result<u64, Box<dyn std::error::Error>>, ProgramResult) =
-            if throw == 0 {
-                (Result::Ok(42), ProgramResult::Ok(42))
-            } else {
+        static INVOCATIONS: AtomicU64 = AtomicU64::new(0);
+        Ok(INVOCATIONS.fetch_add(1, Ordering::Relaxed))
+    }
+);
+
+#[test]
+fn test_lockstep_execution_divergence() {
+    let config = Config {
+        enable_resumable_execution: true,
+        instruction_meter_checkpoint_distance: 2,
+        ..Config::default()
+    };
+    let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+    loader
+        .register_function("syscall_nondeterministic", 1, SyscallNondeterministic::vm)
+        .unwrap();
+    let mut executable = assemble::<TestContextObject>(
+        "
+        mov64 r6, 0
+        add64 r6, 1
+        jlt r6, 3, -2
+        syscall 1
+        stxdw [r10-8], r0
+        mov64 r6, 0
+        add64 r6, 1
+        jlt r6, 3, -2
+        exit",
+        Arc::new(loader),
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+    create_vm!(
+        vm,
+        &executable,
+        &mut context_object,
+        stack,
+        heap,
+        Vec::new(),
+        None
+    );
+    let error = execute_program_in_lockstep(&mut vm, &executable).unwrap_err();
+    let LockstepError::Divergence(divergence) = &error else {
+        panic!("expected a divergence, got {:?}", error);
+    };
+    // The syscall returns a different value to the JIT and the interpreter
+    assert!(divergence.previous_pc <= 3 && divergence.pc > 3);
+    assert_eq!(divergence.pc, divergence.jit.snapshot.registers[11]);
+    assert_eq!(
+        divergence.jit.snapshot.registers[0] + 1,
+        divergence.interpreter.snapshot.registers[0]
+    );
+    assert_eq!(
+        divergence.jit.instruction_count,
+        divergence.interpreter.instruction_count
+    );
+    assert_error!(divergence.jit.result, "ExecutionSuspended");
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "interpreter and JIT diverged between pc {} and pc {}",
+            divergence.previous_pc, divergence.pc
+        )
+    );
+
+    // Lockstep execution needs to stop the JIT at its checkpoints
+    let loader = Arc::new(BuiltinProgram::new_loader(
+        Config::default(),
+        FunctionRegistry::default(),
+    ));
+    let mut executable = assemble::<TestContextObject>(RESUMABLE_PROGRAM, loader).unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+    create_vm!(
+        vm,
+        &executable,
+        &mut context_object,
+        stack,
+        heap,
+        Vec::new(),
+        None
+    );
+    assert!(matches!(
+        execute_program_in_lockstep(&mut vm, &executable),
+        Err(LockstepError::UnsupportedConfig(_))
+    ));
+}
+
+#[test]
+fn test_lockstep_execution_cancelled() {
+    let config = Config {
+        enable_resumable_execution: true,
+        enable_cancellation_checks: true,
+        ..Config::default()
+    };
+    let loader = Arc::new(BuiltinProgram::new_loader(
+        config,
+        FunctionRegistry::default(),
+    ));
+    let mut executable = assemble::<TestContextObject>(
+        "
+        mov64 r0, 0x0
+        add64 r0, 0x1
+        stxdw [r10-8], r0
+        ja -3
+        exit",
+        loader,
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    for delay in [None, Some(Duration::from_millis(10))] {
+        let cancellation_handle = CancellationHandle::new();
+        let mut context_object = TestContextObject::new(u64::MAX);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        vm.cancellation_handle = Some(cancellation_handle.clone());
+        let canceller = std::thread::spawn(move || {
+            if let Some(delay) = delay {
+                std::thread::sleep(delay);
+            }
+            cancellation_handle.cancel();
+        });
+        if delay.is_none() {
+            canceller.join().unwrap();
+        }
+        // The interval in which the JIT is cancelled is compared as well
+        let (instruction_count, result) =
+            execute_program_in_lockstep(&mut vm, &executable).unwrap();
+        assert_error!(result, "ExecutionCancelled");
+        if let Some(_delay) = delay {
+            assert!(instruction_count > 0);
+        }
+        assert!(vm.registers[11] < 4);
+    }
+}
+
+#[test]
+fn test_exact_instruction_meter() {
+    let programs: [(&str, &[SBPFVersion]); 3] = [
+        (RESUMABLE_PROGRAM, &[SBPFVersion::V0, SBPFVersion::V3]),
+        (
+            "
+            ldxdw r2, [r1]
+            mov64 r0, 0
+            add64 r0, 1
+            stxb [r1+8], r0
+            add64 r2, r0
+            add64 r2, r0
+            jlt r0, 20, -5
+            mov64 r0, r2
+            exit",
+            &[SBPFVersion::V0, SBPFVersion::V3],
+        ),
+        // Without static syscalls callx can enter a run of instructions in its middle
+        (
+            "
+            mov64 r0, 0
+            lddw r8, 0x100000038
+            callx r8
+            exit
+            mov64 r0, 1
+            add64 r0, 1
+            add64 r0, 1
+            add64 r0, 1
+            add64 r0, 1
+            exit",
+            &[SBPFVersion::V0],
+        ),
+    ];
+    for (source, sbpf_versions) in programs.iter() {
+        for sbpf_version in sbpf_versions.iter() {
+            for instruction_costs in [
+                InstructionCosts::default(),
+                InstructionCosts::default()
+                    .with_class_cost(ebpf::BPF_JMP, 2)
+                    .with_class_cost(ebpf::BPF_ALU64_STORE, 3),
+            ] {
+                for enable_resumable_execution in [false, true] {
+                    let config = Config {
+                        enable_exact_instruction_meter: true,
+                        enable_resumable_execution,
+                        enable_instruction_tracing: true,
+                        instruction_costs: instruction_costs.clone(),
+                        enabled_sbpf_versions: *sbpf_version..=*sbpf_version,
+                        ..Config::default()
+                    };
+                    let loader = Arc::new(BuiltinProgram::new_loader(
+                        config,
+                        FunctionRegistry::default(),
+                    ));
+                    let mut executable = assemble::<TestContextObject>(source, loader).unwrap();
+                    executable.verify::<RequisiteVerifier>().unwrap();
+                    executable.jit_compile().unwrap();
+                    for budget in 0..300 {
+                        let mut results = Vec::new();
+                        for interpreted in [true, false] {
+                            let mut mem = [0u8; 16];
+                            mem[0..8].copy_from_slice(&0x1122334455667788u64.to_le_bytes());
+                            let mut context_object = TestContextObject::new(budget);
+                            create_vm!(
+                                vm,
+                                &executable,
+                                &mut context_object,
+                                stack,
+                                heap,
+                                vec![MemoryRegion::new_writable(&mut mem, ebpf::MM_INPUT_START)],
+                                None
+                            );
+                            let mut steps = Vec::new();
+                            let (mut instruction_count, mut result) =
+                                vm.execute_program(&executable, interpreted);
+                            // Resume with small budgets, which leaves the pc in the middle of runs
+                            while enable_resumable_execution
+                                && matches!(
+                                    result,
+                                    ProgramResult::Err(EbpfError::ExecutionSuspended)
+                                )
+                            {
+                                steps.push((instruction_count, vm.registers, vm.call_depth));
+                                vm.context_object_pointer.remaining = budget % 7 + 3;
+                                (instruction_count, result) =
+                                    vm.resume_program(&executable, interpreted);
+                            }
+                            // Errors only report the pc, the other registers are not written back
+                            let mut registers = vm.registers;
+                            if result.is_err() {
+                                registers[0..11].fill(0);
+                            }
+                            steps.push((instruction_count, registers, vm.call_depth));
+                            // The next run on the same VM starts in the root frame again
+                            vm.context_object_pointer.remaining = INSTRUCTION_METER_BUDGET;
+                            let (next_instruction_count, next_result) =
+                                vm.execute_program(&executable, interpreted);
+                            steps.push((
+                                next_instruction_count,
+                                vm.registers,
+                                next_result.unwrap(),
+                            ));
+                            results.push((
+                                format!("{result:?}"),
+                                steps,
+                                vm.context_object_pointer.remaining,
+                                vm.context_object_pointer.trace_log.clone(),
+                                mem,
+                            ));
+                        }
+                        assert_eq!(results[0], results[1]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_memory_watchpoints() {
+    for aligned_memory_mapping in [true, false] {
+        let config = Config {
+            enable_memory_watchpoints: true,
+            aligned_memory_mapping,
+            // Neither the accesses to the stack frame nor the repeated accesses may skip the watchpoints
+            eliminate_redundant_bounds_checks: true,
+            enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V0,
+            ..Config::default()
+        };
+        let loader = Arc::new(BuiltinProgram::new_loader(
+            config,
+            FunctionRegistry::default(),
+        ));
+        let mut executable = assemble::<TestContextObject>(
+            "
+            mov64 r2, 0x11
+            stxb [r1+2], r2
+            stxh [r1+4], r2
+            ldxw r3, [r1+4]
+            stxdw [r10-8], r2
+            ldxdw r0, [r10-8]
+            exit",
+            loader,
+        )
+        .unwrap();
+        executable.verify::<RequisiteVerifier>().unwrap();
+        executable.jit_compile().unwrap();
+        for interpreted in [true, false] {
+            let mut mem = [0u8; 8];
+            let mut context_object = TestContextObject::new(7);
+            create_vm!(
+                vm,
+                &executable,
+                &mut context_object,
+                stack,
+                heap,
+                vec![MemoryRegion::new_writable(&mut mem, ebpf::MM_INPUT_START)],
+                None
+            );
+            let accesses = Rc::new(RefCell::new(Vec::new()));
+            for (vm_range, access_type) in [
+                (ebpf::MM_INPUT_START + 5..ebpf::MM_INPUT_START + 6, None),
                 (
-                    Result::Err(Box::new(EbpfError::CallDepthExceeded)),
-                    ProgramResult::Err(EbpfError::SyscallError(Box::new(
-                        EbpfError::CallDepthExceeded,
-                    ))),
-                )
-            };
-        #[allow(unused_mut)]
-        if depth > 0 {
-            let mut config = Config::default();
-            let syscall_name = if version == 1 {
-                config.enabled_sbpf_versions = SBPFVersion::V0..=SBPFVersion::V0;
-                "nested_vm_syscall"
-            } else {
-                config.enabled_sbpf_versions = SBPFVersion::V3..=SBPFVersion::V3;
-                "1"
-            };
-            let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
-            loader.register_function("nested_vm_syscall", 1, SyscallNestedVm::vm).unwrap();
-            let source_code = format!(
-                "
-                ldxb r2, [r1+1]
-                ldxb r1, [r1]
-                syscall {}
-                exit",
-                syscall_name
+                    ebpf::MM_STACK_START..ebpf::MM_HEAP_START,
+                    Some(AccessType::Store),
+                ),
+            ] {
+                let accesses = accesses.clone();
+                vm.memory_mapping
+                    .add_watchpoint(
+                        vm_range,
+                        access_type,
+                        Box::new(move |access| {
+                            accesses.borrow_mut().push(*access);
+                            Ok(())
+                        }),
+                    )
+                    .unwrap();
+            }
+            let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+            assert_eq!(result.unwrap(), 0x11);
+            let stack_vm_addr = accesses.borrow()[2].vm_addr;
+            assert_eq!(
+                *accesses.borrow(),
+                [
+                    MemoryAccess {
+                        pc: 2,
+                        access_type: AccessType::Store,
+                        vm_addr: ebpf::MM_INPUT_START + 4,
+                        len: 2,
+                        value: 0x11,
+                    },
+                    MemoryAccess {
+                        pc: 3,
+                        access_type: AccessType::Load,
+                        vm_addr: ebpf::MM_INPUT_START + 4,
+                        len: 4,
+                        value: 0x11,
+                    },
+                    MemoryAccess {
+                        pc: 4,
+                        access_type: AccessType::Store,
+                        vm_addr: stack_vm_addr,
+                        len: 8,
+                        value: 0x11,
+                    },
+                ]
             );
-            let mem = [depth as u8 - 1, throw as u8];
-            let mut executable = assemble::<TestContextObject>(
-                source_code.as_str(),
-                Arc::new(loader),
-            )
-            .unwrap();
-            test_interpreter_and_jit!(
-                executable,
-                mem,
-                TestContextObject::new(if throw == 0 { 4 } else { 3 }),
-                expected_result,
+            assert!((ebpf::MM_STACK_START..ebpf::MM_HEAP_START).contains(&stack_vm_addr));
+
+            // A watchpoint can abort the execution at the instruction which accesses the range
+            vm.memory_mapping.clear_watchpoints();
+            vm.memory_mapping
+                .add_watchpoint(
+                    ebpf::MM_INPUT_START + 2..ebpf::MM_INPUT_START + 3,
+                    Some(AccessType::Store),
+                    Box::new(|_access| Err(EbpfError::SyscallError("watched byte written".into()))),
+                )
+                .unwrap();
+            vm.memory_mapping
+                .store(0u8, ebpf::MM_INPUT_START + 2)
+                .unwrap();
+            vm.context_object_pointer.remaining = 7;
+            let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+            assert_error!(result, "watched byte written");
+            assert_eq!(vm.registers[11], 1);
+            // The callback runs before the store, so the aborted store did not write
+            assert_eq!(
+                vm.memory_mapping
+                    .load::<u8>(ebpf::MM_INPUT_START + 2)
+                    .unwrap(),
+                0
             );
         }
-        result
     }
-);
+}
 
 #[test]
-fn test_nested_vm_syscall() {
-    let config = Config::default();
-    let mut context_object = TestContextObject::default();
-    let mut memory_mapping = MemoryMapping::new(vec![], &config, SBPFVersion::V3).unwrap();
-
-    // SBPFv0
-    let result = SyscallNestedVm::rust(&mut context_object, 1, 0, 1, 0, 0, &mut memory_mapping);
-    assert_eq!(result.unwrap(), 42);
-    let result = SyscallNestedVm::rust(&mut context_object, 1, 1, 1, 0, 0, &mut memory_mapping);
-    assert_error!(result, "CallDepthExceeded");
+fn test_tiered_compilation() {
+    let pool = Arc::new(JitCompilationPool::new(1));
+    for sbpf_version in [SBPFVersion::V0, SBPFVersion::V3] {
+        let config = Config {
+            tiered_compilation_threshold: 3,
+            enabled_sbpf_versions: sbpf_version..=sbpf_version,
+            ..Config::default()
+        };
+        let loader = Arc::new(BuiltinProgram::new_loader(
+            config,
+            FunctionRegistry::default(),
+        ));
+        let interpreted_executable =
+            assemble::<TestContextObject>(RESUMABLE_PROGRAM, loader.clone()).unwrap();
+        let mut executable = assemble::<TestContextObject>(RESUMABLE_PROGRAM, loader).unwrap();
+        executable.verify::<RequisiteVerifier>().unwrap();
+        executable.enable_tiered_compilation(pool.clone());
+        let run = |executable: &Executable<TestContextObject>, budget: u64, interpreted: bool| {
+            let mut context_object = TestContextObject::new(budget);
+            create_vm!(
+                vm,
+                executable,
+                &mut context_object,
+                stack,
+                heap,
+                Vec::new(),
+                None
+            );
+            let (instruction_count, result) = vm.execute_program(executable, interpreted);
+            // The host stack pointer is only set by the JIT compiled program
+            let tiered_up = !vm.host_stack_pointer.is_null();
+            (instruction_count, format!("{result:?}"), tiered_up)
+        };
+        // Runs which request the interpreter neither count invocations nor switch to the JIT
+        assert_eq!(
+            run(&executable, INSTRUCTION_METER_BUDGET, true),
+            run(&interpreted_executable, INSTRUCTION_METER_BUDGET, true)
+        );
+        assert!(executable.take_tiered_compilation_handle().is_none());
+        // The first run starts interpreted and queues the compilation on the third invocation
+        assert!(executable.get_compiled_program().is_none());
+        let (instruction_count, result, _tiered_up) =
+            run(&executable, INSTRUCTION_METER_BUDGET, false);
+        assert_eq!(result, format!("{:?}", ProgramResult::Ok(225)));
+        assert_eq!(
+            instruction_count,
+            run(&interpreted_executable, INSTRUCTION_METER_BUDGET, true).0
+        );
+        executable
+            .take_tiered_compilation_handle()
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(executable.take_tiered_compilation_handle().is_none());
+        assert!(executable.get_compiled_program().is_some());
+        // Once the compiled program is installed, runs which request the JIT start in it
+        for budget in [INSTRUCTION_METER_BUDGET, 40, 7] {
+            let (instruction_count, result, tiered_up) = run(&executable, budget, false);
+            assert!(tiered_up);
+            let (expected_instruction_count, expected_result, _) =
+                run(&interpreted_executable, budget, true);
+            assert_eq!(result, expected_result);
+            assert_eq!(instruction_count, expected_instruction_count);
+        }
+        assert_eq!(
+            run(&executable, INSTRUCTION_METER_BUDGET, true),
+            run(&interpreted_executable, INSTRUCTION_METER_BUDGET, true)
+        );
+    }
+}
 
-    // SBPFv3
-    let result = SyscallNestedVm::rust(&mut context_object, 1, 0, 2, 0, 0, &mut memory_mapping);
-    assert_eq!(result.unwrap(), 42);
-    let result = SyscallNestedVm::rust(&mut context_object, 1, 1, 2, 0, 0, &mut memory_mapping);
-    assert_error!(result, "CallDepthExceeded");
+thread_local! {
+    /// For SyscallWaitForTieredCompilation
+    static TIERED_EXECUTABLE: RefCell<Option<Arc<Executable<TestContextObject>>>> = RefCell::new(None);
 }
 
-// Instruction Meter Limit
+declare_builtin_function!(
+    /// Waits for the compilation which tiered execution queued, if any
+    SyscallWaitForTieredCompilation,
+    fn rust(
+        _context_object: &mut TestContextObject,
+        _arg1: u64,
+        _arg2: u64,
+        _arg3: u64,
+        _arg4: u64,
+        _arg5: u64,
+        _memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let executable = TIERED_EXECUTABLE.with(|executable| executable.borrow().clone().unwrap());
+        if let Some(handle) = executable.take_tiered_compilation_handle() {
+            handle.wait()?;
+        }
+        Ok(0)
+    }
+);
 
 #[test]
-fn test_tight_infinite_loop_conditional() {
-    test_interpreter_and_jit_asm!(
-        "
-        jsge r0, r0, -1
+fn test_tiered_compilation_during_run() {
+    let config = Config {
+        tiered_compilation_threshold: 3,
+        ..Config::default()
+    };
+    let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+    loader
+        .register_function(
+            "wait_for_tiered_compilation",
+            1,
+            SyscallWaitForTieredCompilation::vm,
+        )
+        .unwrap();
+    // The entrypoint and the first two calls of function_sum queue the compilation, the syscall
+    // waits for it and the fourth call continues in the JIT, which then returns into the frame of
+    // the interpreter
+    let mut executable = assemble::<TestContextObject>(
+        "
+        mov64 r6, 0
+        mov64 r0, 0
+        call function_sum
+        add64 r6, 1
+        jlt r6, 3, -3
+        mov64 r8, r0
+        syscall 1
+        mov64 r0, r8
+        call function_sum
+        exit
+        function_sum:
+        mov64 r7, 0
+        add64 r0, r7
+        add64 r7, 1
+        jlt r7, 10, -3
         exit",
-        [],
-        TestContextObject::new(4),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+        Arc::new(loader),
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.enable_tiered_compilation(Arc::new(JitCompilationPool::new(1)));
+    let executable = Arc::new(executable);
+    TIERED_EXECUTABLE.with(|tiered_executable| {
+        *tiered_executable.borrow_mut() = Some(executable.clone());
+    });
+    let executable = &executable;
+    let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+    create_vm!(
+        vm,
+        executable,
+        &mut context_object,
+        stack,
+        heap,
+        Vec::new(),
+        None
     );
+    let (_instruction_count, result) = vm.execute_program(executable, false);
+    assert_eq!(result.unwrap(), 180);
+    // The host stack pointer is only set by the JIT compiled program
+    assert!(!vm.host_stack_pointer.is_null());
 }
 
 #[test]
-fn test_tight_infinite_loop_unconditional() {
-    test_interpreter_and_jit_asm!(
+fn test_tiered_compilation_frame_pointer() {
+    let config = Config {
+        tiered_compilation_threshold: 2,
+        enabled_sbpf_versions: SBPFVersion::V0..=SBPFVersion::V0,
+        ..Config::default()
+    };
+    let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+    loader
+        .register_function(
+            "wait_for_tiered_compilation",
+            1,
+            SyscallWaitForTieredCompilation::vm,
+        )
+        .unwrap();
+    // The entrypoint and the call of function_foo queue the compilation, the syscall waits for it
+    // and the call of function_bar continues in the JIT two frames deep, which then exits the
+    // root frame
+    let mut executable = assemble::<TestContextObject>(
         "
-        ja -1
+        call function_foo
+        mov64 r0, r10
+        exit
+        function_foo:
+        syscall wait_for_tiered_compilation
+        call function_bar
+        exit
+        function_bar:
         exit",
-        [],
-        TestContextObject::new(4),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+        Arc::new(loader),
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.enable_tiered_compilation(Arc::new(JitCompilationPool::new(1)));
+    let executable = Arc::new(executable);
+    TIERED_EXECUTABLE.with(|tiered_executable| {
+        *tiered_executable.borrow_mut() = Some(executable.clone());
+    });
+    let executable = &executable;
+    let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+    create_vm!(
+        vm,
+        executable,
+        &mut context_object,
+        stack,
+        heap,
+        Vec::new(),
+        None
     );
+    // Runs which request the interpreter do not count invocations
+    let (_instruction_count, result) = vm.execute_program(executable, true);
+    let root_frame_pointer = result.unwrap();
+    assert_eq!(vm.registers[ebpf::FRAME_PTR_REG], root_frame_pointer);
+    // The first run which requests the JIT hands over two frames deep, the second starts in the JIT
+    for _ in 0..2 {
+        vm.context_object_pointer.remaining = INSTRUCTION_METER_BUDGET;
+        let (_instruction_count, result) = vm.execute_program(executable, false);
+        assert_eq!(result.unwrap(), root_frame_pointer);
+        assert_eq!(vm.registers[ebpf::FRAME_PTR_REG], root_frame_pointer);
+        // The host stack pointer is only set by the JIT compiled program
+        assert!(!vm.host_stack_pointer.is_null());
+    }
 }
 
 #[test]
-fn test_tight_infinite_recursion() {
-    test_interpreter_and_jit_asm!(
-        "
-        entrypoint:
-        mov64 r3, 0x41414141
-        call entrypoint
-        exit",
-        [],
-        TestContextObject::new(4),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+fn test_jit_compilation_pool() {
+    let run = |executable: &Executable<TestContextObject>| {
+        let mut context_object = TestContextObject::new(INSTRUCTION_METER_BUDGET);
+        create_vm!(
+            vm,
+            executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        let (_instruction_count, result) = vm.execute_program(executable, false);
+        // The host stack pointer is only set by the JIT compiled program
+        (format!("{result:?}"), !vm.host_stack_pointer.is_null())
+    };
+    let expected_result = format!("{:?}", ProgramResult::Ok(225));
+    let executables = [SBPFVersion::V0, SBPFVersion::V3]
+        .iter()
+        .map(|&sbpf_version| {
+            let config = Config {
+                enabled_sbpf_versions: sbpf_version..=sbpf_version,
+                ..Config::default()
+            };
+            let loader = Arc::new(BuiltinProgram::new_loader(
+                config,
+                FunctionRegistry::default(),
+            ));
+            let executable = assemble::<TestContextObject>(RESUMABLE_PROGRAM, loader).unwrap();
+            executable.verify::<RequisiteVerifier>().unwrap();
+            Arc::new(executable)
+        })
+        .collect::<Vec<_>>();
+    let pool = JitCompilationPool::new(2);
+    assert_eq!(pool.thread_count(), 2);
+    let handles = executables
+        .iter()
+        .map(|executable| pool.compile(executable))
+        .collect::<Vec<_>>();
+    // The executables are shared with other threads and run in the interpreter in the meantime
+    std::thread::scope(|scope| {
+        for executable in executables.iter() {
+            let expected_result = &expected_result;
+            scope.spawn(move || {
+                let compiling = executable.is_compiling_in_background();
+                let (result, jit) = run(executable);
+                assert_eq!(&result, expected_result);
+                assert!(jit || compiling);
+            });
+        }
+    });
+    for handle in handles {
+        handle.wait().unwrap();
+    }
+    for executable in executables.iter() {
+        assert!(!executable.is_compiling_in_background());
+        assert_eq!(run(executable), (expected_result.clone(), true));
+        // Compiling again keeps the installed program
+        let compiled_program = executable.get_compiled_program().unwrap() as *const _;
+        pool.compile(executable).wait().unwrap();
+        assert!(std::ptr::eq(
+            executable.get_compiled_program().unwrap(),
+            compiled_program
+        ));
+    }
+
+    // Without a compilation in the pool the JIT has to be compiled explicitly
+    let loader = Arc::new(BuiltinProgram::new_loader(
+        Config::default(),
+        FunctionRegistry::default(),
+    ));
+    let executable = assemble::<TestContextObject>(RESUMABLE_PROGRAM, loader).unwrap();
+    assert!(!executable.is_compiling_in_background());
+    assert_eq!(
+        run(&executable).0,
+        format!("{:?}", ProgramResult::Err(EbpfError::JitNotCompiled))
     );
 }
 
 #[test]
-fn test_tight_infinite_recursion_callx() {
+fn test_instruction_costs() {
+    let config = Config {
+        instruction_costs: InstructionCosts::default()
+            .with_class_cost(ebpf::BPF_JMP, 2)
+            .with_opcode_cost(ebpf::LMUL64_IMM, 4),
+        ..Config::default()
+    };
+    for (budget, expected_result) in [
+        (25, ProgramResult::Ok(27)),
+        (24, ProgramResult::Err(EbpfError::ExceededMaxInstructions)),
+        (9, ProgramResult::Err(EbpfError::ExceededMaxInstructions)),
+    ] {
+        test_interpreter_and_jit_asm!(
+            "
+            mov64 r0, 1
+            mov64 r2, 3
+            lmul64 r0, 3
+            add64 r2, -1
+            jne r2, 0, -3
+            exit",
+            config.clone(),
+            [],
+            TestContextObject::new(budget),
+            expected_result,
+        );
+    }
+    for (budget, expected_result) in [
+        (16, ProgramResult::Ok(3)),
+        (15, ProgramResult::Err(EbpfError::ExceededMaxInstructions)),
+        (7, ProgramResult::Err(EbpfError::ExceededMaxInstructions)),
+    ] {
+        test_interpreter_and_jit_asm!(
+            "
+            call function_c
+            exit
+            function_a:
+            lmul64 r0, 3
+            exit
+            function_c:
+            mov64 r0, 1
+            mov32 r1, 0x10
+            callx r1
+            exit",
+            config.clone(),
+            [],
+            TestContextObject::new(budget),
+            expected_result,
+        );
+    }
     test_interpreter_and_jit_asm!(
         "
         or64 r8, 0x18
@@ -2831,145 +5800,260 @@ fn test_tight_infinite_recursion_callx() {
         mov64 r3, 0x41414141
         callx r8
         exit",
+        config,
         [],
-        TestContextObject::new(6),
+        TestContextObject::new(20),
         ProgramResult::Err(EbpfError::ExceededMaxInstructions),
     );
 }
 
 #[test]
-fn test_instruction_count_syscall() {
-    test_syscall_asm!(
-        "
-        mov64 r2, 0x5
-        syscall {}
-        mov64 r0, 0x0
-        exit",
-        [72, 101, 108, 108, 111],
-        (
-            1 => "bpf_syscall_string" => syscalls::SyscallString::vm,
-        ),
-        TestContextObject::new(4),
-        ProgramResult::Ok(0),
-    );
+#[should_panic(expected = "instructions must cost at least one unit")]
+fn test_instruction_costs_zero() {
+    let _ = InstructionCosts::default().with_opcode_cost(ebpf::ADD64_IMM, 0);
 }
 
-#[test]
-fn test_err_instruction_count_syscall_capped() {
-    test_syscall_asm!(
-        "
-        mov64 r2, 0x5
-        syscall {}
-        mov64 r0, 0x0
-        exit",
-        [72, 101, 108, 108, 111],
-        (
-            1 => "bpf_syscall_string" => syscalls::SyscallString::vm,
-        ),
-        TestContextObject::new(3),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
-    );
-}
+declare_builtin_function!(
+    /// For test_execution_stats()
+    SyscallConsume,
+    fn rust(
+        context_object: &mut TestContextObject,
+        units: u64,
+        _arg2: u64,
+        _arg3: u64,
+        _arg4: u64,
+        _arg5: u64,
+        _memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        context_object.consume(units);
+        Ok(0)
+    }
+);
 
 #[test]
-fn test_err_non_terminate_capped() {
-    test_interpreter_and_jit_asm!(
-        "
-        mov64 r6, 0x0
-        mov64 r1, 0x0
-        mov64 r2, 0x0
-        mov64 r3, 0x0
-        mov64 r4, 0x0
-        mov64 r5, r6
-        add64 r6, 0x1
-        ja -0x8
-        exit",
-        [],
-        TestContextObject::new(7),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
-    );
-    test_interpreter_and_jit_asm!(
-        "
-        mov64 r6, 0x0
-        mov64 r1, 0x0
-        mov64 r2, 0x0
-        mov64 r3, 0x0
-        mov64 r4, 0x0
-        mov64 r5, r6
-        add64 r6, 0x1
-        ja -0x8
-        exit",
-        [],
-        TestContextObject::new(1000),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
-    );
+fn test_execution_stats() {
+    for instruction_costs in [
+        InstructionCosts::default(),
+        InstructionCosts::default().with_class_cost(ebpf::BPF_JMP, 2),
+    ] {
+        let config = Config {
+            enable_execution_stats: true,
+            instruction_costs,
+            ..Config::default()
+        };
+        let mut loader = BuiltinProgram::new_loader_with_dense_registration(config);
+        loader
+            .register_function("syscall_consume", 1, SyscallConsume::vm)
+            .unwrap();
+        let mut executable = assemble::<TestContextObject>(
+            "
+            entrypoint:
+            stxdw [r10-0x8], r1
+            ldxb r2, [r1]
+            stb [r1+0x1], 0x7
+            call function_foo
+            mov32 r3, 0x50
+            callx r3
+            mov64 r1, 0xa
+            syscall 1
+            mov64 r0, 0x0
+            exit
+            function_foo:
+            ldxdw r4, [r10-0x8]
+            exit",
+            Arc::new(loader),
+        )
+        .unwrap();
+        executable.verify::<RequisiteVerifier>().unwrap();
+        executable.jit_compile().unwrap();
+        let mut results = Vec::new();
+        for interpreted in [true, false] {
+            let input = [1u8, 2, 3, 4];
+            let mut input_copy = input;
+            let input_copy_addr = input_copy.as_mut_ptr() as u64;
+            let mut context_object = TestContextObject::new(100);
+            create_vm!(
+                vm,
+                &executable,
+                &mut context_object,
+                stack,
+                heap,
+                vec![MemoryRegion::new_cow(&input, ebpf::MM_INPUT_START, 0)],
+                Some(Box::new(move |_| Ok(input_copy_addr)))
+            );
+            let (instruction_count, result, execution_stats) =
+                vm.execute_program_with_stats(&executable, interpreted);
+            assert_eq!(result.unwrap(), 0);
+            let execution_stats = execution_stats.unwrap();
+            let function_instructions = execution_stats
+                .functions
+                .values()
+                .map(|function| function.instruction_count)
+                .sum::<u64>();
+            assert_eq!(function_instructions + 10, instruction_count);
+            assert_eq!(input_copy[1], 7);
+            results.push((instruction_count, execution_stats));
+        }
+        assert_eq!(results[0], results[1]);
+        if executable.get_config().instruction_costs.is_uniform() {
+            assert_eq!(
+                results[0],
+                (
+                    24,
+                    ExecutionStats {
+                        functions: vec![
+                            (
+                                0,
+                                FunctionStats {
+                                    name: "entrypoint".to_string(),
+                                    invocations: 1,
+                                    instruction_count: 10,
+                                }
+                            ),
+                            (
+                                10,
+                                FunctionStats {
+                                    name: "function_foo".to_string(),
+                                    invocations: 2,
+                                    instruction_count: 4,
+                                }
+                            ),
+                        ]
+                        .into_iter()
+                        .collect(),
+                        max_call_depth: 1,
+                        memory_regions: vec![
+                            (
+                                ebpf::MM_STACK_START,
+                                MemoryRegionStats {
+                                    bytes_read: 16,
+                                    bytes_written: 8,
+                                }
+                            ),
+                            (
+                                ebpf::MM_INPUT_START,
+                                MemoryRegionStats {
+                                    bytes_read: 1,
+                                    bytes_written: 1,
+                                }
+                            ),
+                        ]
+                        .into_iter()
+                        .collect(),
+                        syscalls: vec![(
+                            "syscall_consume".to_string(),
+                            SyscallStats {
+                                invocations: 1,
+                                consumed_units: 10,
+                            }
+                        )]
+                        .into_iter()
+                        .collect(),
+                        cow_copies: 1,
+                    }
+                )
+            );
+        }
+    }
 }
 
 #[test]
-fn test_err_capped_before_exception() {
-    test_interpreter_and_jit_asm!(
-        "
-        mov64 r1, 0x0
-        mov64 r2, 0x0
-        udiv64 r1, r2
-        mov64 r0, 0x0
-        exit",
-        [],
-        TestContextObject::new(2),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
-    );
-
-    test_interpreter_and_jit_asm!(
+fn test_cancellation() {
+    let config = Config {
+        enable_cancellation_checks: true,
+        ..Config::default()
+    };
+    let loader = Arc::new(BuiltinProgram::new_loader(
+        config,
+        FunctionRegistry::default(),
+    ));
+    let mut executable = assemble::<TestContextObject>(
         "
-        mov64 r1, 0x0
-        mov64 r2, 0x0
-        callx r2
         mov64 r0, 0x0
+        add64 r0, 0x1
+        ja -2
         exit",
-        [],
-        TestContextObject::new(2),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
-    );
+        loader,
+    )
+    .unwrap();
+    executable.verify::<RequisiteVerifier>().unwrap();
+    executable.jit_compile().unwrap();
+    for interpreted in [true, false] {
+        // Cancelled before the execution started
+        let cancellation_handle = CancellationHandle::new();
+        cancellation_handle.cancel();
+        let mut context_object = TestContextObject::new(u64::MAX);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        vm.cancellation_handle = Some(cancellation_handle);
+        let (instruction_count, result) = vm.execute_program(&executable, interpreted);
+        assert_error!(result, "ExecutionCancelled");
+        assert!(instruction_count <= 3);
+
+        // Cancelled from another thread
+        let cancellation_handle = CancellationHandle::new();
+        let mut context_object = TestContextObject::new(u64::MAX);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        vm.cancellation_handle = Some(cancellation_handle.clone());
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            cancellation_handle.cancel();
+        });
+        let (instruction_count, result) = vm.execute_program(&executable, interpreted);
+        canceller.join().unwrap();
+        assert_error!(result, "ExecutionCancelled");
+        assert!(instruction_count > 0);
+        assert!(vm.registers[11] < 3);
+
+        // Deadline passed
+        let mut context_object = TestContextObject::new(u64::MAX);
+        create_vm!(
+            vm,
+            &executable,
+            &mut context_object,
+            stack,
+            heap,
+            Vec::new(),
+            None
+        );
+        vm.cancellation_handle = Some(CancellationHandle::with_timeout(Duration::from_millis(10)));
+        let (_instruction_count, result) = vm.execute_program(&executable, interpreted);
+        assert_error!(result, "ExecutionCancelled");
+    }
 }
 
 #[test]
-fn test_err_exit_capped() {
-    test_interpreter_and_jit_asm!(
-        "
-        or64 r0, 0x18
-        callx r0
-        exit
-        function_foo:
-        exit
-        ",
-        [],
-        TestContextObject::new(3),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
-    );
-    test_interpreter_and_jit_asm!(
-        "
-        or64 r0, 0x18
-        callx r0
-        exit
-        function_foo:
-        mov r0, r0
-        exit
-        ",
-        [],
-        TestContextObject::new(4),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
-    );
+fn test_cancellation_handle_not_cancelled() {
+    let config = Config {
+        enable_cancellation_checks: true,
+        ..Config::default()
+    };
     test_interpreter_and_jit_asm!(
         "
-        call function_foo
-        exit
-        function_foo:
-        mov r0, r0
-        exit
-        ",
+        mov64 r0, 0x0
+        mov64 r1, 0x100
+        add64 r0, 0x1
+        jne r0, r1, -2
+        exit",
+        config,
         [],
-        TestContextObject::new(3),
-        ProgramResult::Err(EbpfError::ExceededMaxInstructions),
+        TestContextObject::new(515),
+        ProgramResult::Ok(0x100),
     );
 }
 
@@ -3371,7 +6455,14 @@ fn test_tcp_sack_nomatch() {
 
 // Fuzzy
 
-#[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#[cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 fn execute_generated_program(prog: &[u8]) -> bool {
     let max_instruction_count = 1024;
     let mem_size = 1024 * 1024;
@@ -3440,10 +6531,18 @@ fn execute_generated_program(prog: &[u8]) -> bool {
         println!("result_jit={result_jit:?}");
         let stdout = std::io::stdout();
         analysis
-            .disassemble_trace_log(&mut stdout.lock(), &tracer_interpreter.trace_log)
+            .disassemble_trace_log_with_events(
+                &mut stdout.lock(),
+                &tracer_interpreter.trace_log,
+                &tracer_interpreter.event_log,
+            )
             .unwrap();
         analysis
-            .disassemble_trace_log(&mut stdout.lock(), &tracer_jit.trace_log)
+            .disassemble_trace_log_with_events(
+                &mut stdout.lock(),
+                &tracer_jit.trace_log,
+                &tracer_jit.event_log,
+            )
             .unwrap();
         panic!();
     }
@@ -3453,7 +6552,13 @@ fn execute_generated_program(prog: &[u8]) -> bool {
     true
 }
 
-#[cfg(all(not(windows), target_arch = "x86_64"))]
+#[cfg(all(
+    not(windows),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 #[test]
 fn test_total_chaos() {
     let instruction_count = 6;
@@ -3626,7 +6731,7 @@ fn test_err_fixed_stack_out_of_bound() {
             AccessType::Store,
             0x1FFFFD000,
             1,
-            "program"
+            "program".into()
         )),
     );
 }
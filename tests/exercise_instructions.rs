@@ -1,5 +1,12 @@
 #![allow(clippy::arithmetic_side_effects)]
-#![cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+#![cfg(all(
+    feature = "jit",
+    not(target_os = "windows"),
+    any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "jit-aarch64")
+    )
+))]
 // Copyright 2020 Solana Maintainers <maintainers@solana.com>
 //
 // Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
@@ -64,7 +71,14 @@ macro_rules! test_interpreter_and_jit {
                 mem,
             )
         };
-        #[cfg(all(feature = "jit", not(target_os = "windows"), target_arch = "x86_64"))]
+        #[cfg(all(
+            feature = "jit",
+            not(target_os = "windows"),
+            any(
+                target_arch = "x86_64",
+                all(target_arch = "aarch64", feature = "jit-aarch64")
+            )
+        ))]
         {
             #[allow(unused_mut)]
             $executable.jit_compile().unwrap();
@@ -85,10 +99,18 @@ macro_rules! test_interpreter_and_jit {
                 let analysis = Analysis::from_executable(&$executable).unwrap();
                 let stdout = std::io::stdout();
                 analysis
-                    .disassemble_trace_log(&mut stdout.lock(), &_tracer_interpreter.trace_log)
+                    .disassemble_trace_log_with_events(
+                        &mut stdout.lock(),
+                        &_tracer_interpreter.trace_log,
+                        &_tracer_interpreter.event_log,
+                    )
                     .unwrap();
                 analysis
-                    .disassemble_trace_log(&mut stdout.lock(), &tracer_jit.trace_log)
+                    .disassemble_trace_log_with_events(
+                        &mut stdout.lock(),
+                        &tracer_jit.trace_log,
+                        &tracer_jit.event_log,
+                    )
                     .unwrap();
                 panic!();
             }